@@ -0,0 +1,131 @@
+//! Derive `FromRow` and `RecordBuilder` for structs, so that a sonnerie
+//! record can be read into a struct and a struct can be added to a transaction.
+//!
+//! Each field is a column, in the order of the fields. Use these
+//! through the `derive` feature of `sonnerie`, which re-exports them.
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{parse_macro_input, parse_quote, Data, DeriveInput, Fields, GenericParam, Lifetime};
+
+/// The fields of a struct, or an error for anything else
+fn fields(input: &DeriveInput) -> syn::Result<&Fields> {
+	match &input.data {
+		Data::Struct(s) if !s.fields.is_empty() => Ok(&s.fields),
+		_ => Err(syn::Error::new_spanned(
+			&input.ident,
+			"only a struct with fields can be a record",
+		)),
+	}
+}
+
+/// Read each field from a column of a record, in order.
+///
+/// The struct may borrow strings and bytes from the record
+/// if it has one lifetime parameter.
+#[proc_macro_derive(FromRow)]
+pub fn derive_from_row(input: TokenStream) -> TokenStream {
+	let input = parse_macro_input!(input as DeriveInput);
+	from_row(&input)
+		.unwrap_or_else(|e| e.to_compile_error())
+		.into()
+}
+
+fn from_row(input: &DeriveInput) -> syn::Result<TokenStream2> {
+	let name = &input.ident;
+	let fields = fields(input)?;
+
+	// the record is borrowed for the struct's lifetime, if it has one
+	let mut generics = input.generics.clone();
+	let lifetime = match generics.lifetimes().next() {
+		Some(l) => l.lifetime.clone(),
+		None => {
+			let l = Lifetime::new("'__row", proc_macro2::Span::call_site());
+			generics
+				.params
+				.insert(0, GenericParam::Lifetime(parse_quote!(#l)));
+			l
+		}
+	};
+	let where_clause = generics.make_where_clause();
+	for field in fields.iter() {
+		let ty = &field.ty;
+		where_clause
+			.predicates
+			.push(parse_quote!(#ty: ::sonnerie::FromRecord<#lifetime>));
+	}
+	let (impl_generics, _, where_clause) = generics.split_for_impl();
+	let (_, ty_generics, _) = input.generics.split_for_impl();
+
+	let value = match fields {
+		Fields::Named(named) => {
+			let names = named.named.iter().map(|f| &f.ident);
+			quote! { #name { #( #names: row.read()?, )* } }
+		}
+		_ => {
+			let reads = fields.iter().map(|_| quote! { row.read()? });
+			quote! { #name ( #( #reads, )* ) }
+		}
+	};
+
+	Ok(quote! {
+		impl #impl_generics ::sonnerie::FromRow<#lifetime> for #name #ty_generics #where_clause {
+			fn decode(row: &mut ::sonnerie::RowDecoder<#lifetime>) -> ::std::io::Result<Self> {
+				::std::result::Result::Ok(#value)
+			}
+		}
+	})
+}
+
+/// Store each field as a column of a record, in order.
+///
+/// A struct that derives this can be passed to `CreateTx::add_record`.
+#[proc_macro_derive(RecordBuilder)]
+pub fn derive_record_builder(input: TokenStream) -> TokenStream {
+	let input = parse_macro_input!(input as DeriveInput);
+	record_builder(&input)
+		.unwrap_or_else(|e| e.to_compile_error())
+		.into()
+}
+
+fn record_builder(input: &DeriveInput) -> syn::Result<TokenStream2> {
+	let name = &input.ident;
+	let fields = fields(input)?;
+
+	let mut generics = input.generics.clone();
+	let where_clause = generics.make_where_clause();
+	for field in fields.iter() {
+		let ty = &field.ty;
+		where_clause
+			.predicates
+			.push(parse_quote!(#ty: ::sonnerie::ToRecord));
+	}
+	let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+
+	let members: Vec<syn::Member> = fields
+		.iter()
+		.enumerate()
+		.map(|(n, f)| match &f.ident {
+			Some(ident) => syn::Member::Named(ident.clone()),
+			None => syn::Member::Unnamed(n.into()),
+		})
+		.collect();
+
+	Ok(quote! {
+		impl #impl_generics ::sonnerie::RecordBuilder for #name #ty_generics #where_clause {
+			fn format_str(&self, to: &mut ::sonnerie::__private::CompactString) {
+				#( ::sonnerie::ToRecord::push_format(&self.#members, to); )*
+			}
+			fn variable_size(&self) -> bool {
+				false #( || ::sonnerie::ToRecord::variable_size(&self.#members) )*
+			}
+			fn size(&self) -> usize {
+				0 #( + ::sonnerie::ToRecord::size(&self.#members) )*
+			}
+			fn store(&self, buf: &mut ::std::vec::Vec<u8>) {
+				#( ::sonnerie::ToRecord::store(&self.#members, buf); )*
+			}
+		}
+	})
+}
@@ -0,0 +1,520 @@
+//! Export records as Arrow IPC and import them back.
+//!
+//! A stream has a `key` column, a `time` column (nanoseconds since the
+//! epoch) and a column for each column of the records' format. All of the
+//! exported records must have the same format.
+
+use crate::external_sort::{ExternalSort, SortRow, SORT_MEMORY};
+use crate::row_format::Column;
+use crate::{ColumnBatches, ColumnValues, CreateTx, Record, WriteFailure};
+use arrow_array::builder::*;
+use arrow_array::cast::AsArray;
+use arrow_array::types::*;
+use arrow_array::{Array, ArrayRef, RecordBatch};
+use arrow_schema::{ArrowError, DataType, Field, Schema, TimeUnit};
+use byteorder::{BigEndian, WriteBytesExt};
+use std::io::{Read, Seek, Write};
+use std::sync::Arc;
+
+/// the number of rows in each batch of an exported stream
+pub(crate) const BATCH_ROWS: usize = 65536;
+
+fn invalid_data(e: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> std::io::Error {
+	std::io::Error::new(std::io::ErrorKind::InvalidData, e)
+}
+
+fn from_arrow(e: ArrowError) -> std::io::Error {
+	match e {
+		ArrowError::IoError(_, e) => e,
+		e => invalid_data(e),
+	}
+}
+
+/// The Arrow type of a column of type `kind`
+fn data_type(column: &Column) -> std::io::Result<DataType> {
+	Ok(match column.kind {
+		b'b' => DataType::Boolean,
+		b'c' => DataType::Int8,
+		b'C' => DataType::UInt8,
+		b'h' => DataType::Int16,
+		b'H' => DataType::UInt16,
+		b'i' => DataType::Int32,
+		b'u' => DataType::UInt32,
+		b'I' => DataType::Int64,
+		b'U' => DataType::UInt64,
+		b'f' => DataType::Float32,
+		b'F' => DataType::Float64,
+		b's' => DataType::Utf8,
+		b'x' => DataType::Binary,
+		// every i64 has at most 19 digits
+		b'd' => DataType::Decimal128(19, column.scale as i8),
+		a => return Err(invalid_data(format!("cannot export '{}'", a as char))),
+	})
+}
+
+/// The Arrow schema of records of `format`, with the names of their
+/// columns from `names` if the schema of `key` has this format
+pub(crate) fn arrow_schema(
+	key: &str,
+	format: &str,
+	names: &crate::Schema,
+) -> std::io::Result<Schema> {
+	let named = names.for_key(key).filter(|k| k.format() == format);
+	let mut fields = vec![
+		Field::new("key", DataType::Utf8, false),
+		Field::new(
+			"time",
+			DataType::Timestamp(TimeUnit::Nanosecond, None),
+			false,
+		),
+	];
+	for (n, column) in crate::row_format::columns(format.as_bytes()).enumerate() {
+		let name = match named {
+			Some(k) => k.columns()[n].name.clone(),
+			None => format!("column{}", n + 1),
+		};
+		fields.push(Field::new(name, data_type(&column)?, column.nullable));
+	}
+	Ok(Schema::new(fields))
+}
+
+/// Accumulates the values of one column
+enum Builder {
+	Bool(BooleanBuilder),
+	I8(Int8Builder),
+	U8(UInt8Builder),
+	I16(Int16Builder),
+	U16(UInt16Builder),
+	I32(Int32Builder),
+	U32(UInt32Builder),
+	I64(Int64Builder),
+	U64(UInt64Builder),
+	F32(Float32Builder),
+	F64(Float64Builder),
+	String(StringBuilder),
+	Bytes(BinaryBuilder),
+	Decimal(Decimal128Builder),
+}
+
+impl Builder {
+	fn new(data_type: &DataType) -> Builder {
+		match data_type {
+			DataType::Boolean => Builder::Bool(Default::default()),
+			DataType::Int8 => Builder::I8(Default::default()),
+			DataType::UInt8 => Builder::U8(Default::default()),
+			DataType::Int16 => Builder::I16(Default::default()),
+			DataType::UInt16 => Builder::U16(Default::default()),
+			DataType::Int32 => Builder::I32(Default::default()),
+			DataType::UInt32 => Builder::U32(Default::default()),
+			DataType::Int64 => Builder::I64(Default::default()),
+			DataType::UInt64 => Builder::U64(Default::default()),
+			DataType::Float32 => Builder::F32(Default::default()),
+			DataType::Float64 => Builder::F64(Default::default()),
+			DataType::Utf8 => Builder::String(Default::default()),
+			DataType::Binary => Builder::Bytes(Default::default()),
+			DataType::Decimal128(..) => {
+				Builder::Decimal(Decimal128Builder::default().with_data_type(data_type.clone()))
+			}
+			_ => unreachable!(),
+		}
+	}
+
+	/// append `values`, which are null where `present` is false
+	fn append(&mut self, values: &ColumnValues, present: Option<&[bool]>) {
+		macro_rules! primitive {
+			($b:expr, $v:expr) => {
+				match present {
+					Some(present) => $b.append_values($v, present),
+					None => $b.append_slice($v),
+				}
+			};
+		}
+		let is_present = |n: usize| present.is_none_or(|p| p[n]);
+
+		match (self, values) {
+			(Builder::Bool(b), ColumnValues::Bool(v)) => match present {
+				Some(present) => b.append_values(v, present).unwrap(),
+				None => b.append_slice(v),
+			},
+			(Builder::I8(b), ColumnValues::I8(v)) => primitive!(b, v),
+			(Builder::U8(b), ColumnValues::U8(v)) => primitive!(b, v),
+			(Builder::I16(b), ColumnValues::I16(v)) => primitive!(b, v),
+			(Builder::U16(b), ColumnValues::U16(v)) => primitive!(b, v),
+			(Builder::I32(b), ColumnValues::I32(v)) => primitive!(b, v),
+			(Builder::U32(b), ColumnValues::U32(v)) => primitive!(b, v),
+			(Builder::I64(b), ColumnValues::I64(v)) => primitive!(b, v),
+			(Builder::U64(b), ColumnValues::U64(v)) => primitive!(b, v),
+			(Builder::F32(b), ColumnValues::F32(v)) => primitive!(b, v),
+			(Builder::F64(b), ColumnValues::F64(v)) => primitive!(b, v),
+			(Builder::String(b), ColumnValues::String(v)) => {
+				for (n, s) in v.iter().enumerate() {
+					b.append_option(is_present(n).then_some(s));
+				}
+			}
+			(Builder::Bytes(b), ColumnValues::Bytes(v)) => {
+				for (n, s) in v.iter().enumerate() {
+					b.append_option(is_present(n).then_some(s));
+				}
+			}
+			(Builder::Decimal(b), ColumnValues::Decimal { mantissas, .. }) => {
+				for (n, m) in mantissas.iter().enumerate() {
+					b.append_option(is_present(n).then_some(*m as i128));
+				}
+			}
+			(builder, ColumnValues::Nullable { present, values }) => {
+				builder.append(values, Some(present))
+			}
+			_ => unreachable!("the batch has the format of the schema"),
+		}
+	}
+
+	fn finish(&mut self) -> ArrayRef {
+		match self {
+			Builder::Bool(b) => Arc::new(b.finish()),
+			Builder::I8(b) => Arc::new(b.finish()),
+			Builder::U8(b) => Arc::new(b.finish()),
+			Builder::I16(b) => Arc::new(b.finish()),
+			Builder::U16(b) => Arc::new(b.finish()),
+			Builder::I32(b) => Arc::new(b.finish()),
+			Builder::U32(b) => Arc::new(b.finish()),
+			Builder::I64(b) => Arc::new(b.finish()),
+			Builder::U64(b) => Arc::new(b.finish()),
+			Builder::F32(b) => Arc::new(b.finish()),
+			Builder::F64(b) => Arc::new(b.finish()),
+			Builder::String(b) => Arc::new(b.finish()),
+			Builder::Bytes(b) => Arc::new(b.finish()),
+			Builder::Decimal(b) => Arc::new(b.finish()),
+		}
+	}
+}
+
+/// Collects the records of one format into [`RecordBatch`]es
+pub(crate) struct BatchBuilder {
+	schema: Arc<Schema>,
+	format: String,
+	keys: StringBuilder,
+	times: TimestampNanosecondBuilder,
+	columns: Vec<Builder>,
+	rows: usize,
+}
+
+impl BatchBuilder {
+	pub(crate) fn new(schema: Schema, format: &str) -> BatchBuilder {
+		let columns = schema.fields()[2..]
+			.iter()
+			.map(|f| Builder::new(f.data_type()))
+			.collect();
+		BatchBuilder {
+			schema: Arc::new(schema),
+			format: format.to_owned(),
+			keys: StringBuilder::new(),
+			times: TimestampNanosecondBuilder::new(),
+			columns,
+			rows: 0,
+		}
+	}
+
+	pub(crate) fn schema(&self) -> &Arc<Schema> {
+		&self.schema
+	}
+
+	/// the number of rows that haven't been finished
+	pub(crate) fn rows(&self) -> usize {
+		self.rows
+	}
+
+	pub(crate) fn append(&mut self, batch: &crate::ColumnBatch) -> std::io::Result<()> {
+		if batch.format != self.format {
+			return Err(invalid_data(format!(
+				"the key \"{}\" has the format \"{}\", but the records before it have \"{}\"",
+				batch.key, batch.format, self.format
+			)));
+		}
+		for &ts in &batch.timestamps {
+			self.keys.append_value(&batch.key);
+			self.times.append_value(ts as i64);
+		}
+		for (builder, values) in self.columns.iter_mut().zip(&batch.columns) {
+			builder.append(values, None);
+		}
+		self.rows += batch.len();
+		Ok(())
+	}
+
+	/// The rows appended so far
+	pub(crate) fn finish(&mut self) -> std::io::Result<RecordBatch> {
+		let mut arrays: Vec<ArrayRef> =
+			vec![Arc::new(self.keys.finish()), Arc::new(self.times.finish())];
+		arrays.extend(self.columns.iter_mut().map(|c| c.finish()));
+		self.rows = 0;
+		RecordBatch::try_new(self.schema.clone(), arrays).map_err(from_arrow)
+	}
+}
+
+/// Write `records` to `out` as an Arrow IPC stream, returning the number of records
+///
+/// The value columns are named by `names` if the schema of the
+/// first key has the records' format, otherwise they're `column1`,
+/// `column2`, etc. Fails if the records don't all have the same format,
+/// including when the format of a key changed (an Arrow stream has one schema);
+/// [`crate::write_parquet`] writes each format to its own file instead.
+pub fn write_arrow<W: Write>(
+	records: impl IntoIterator<Item = Record>,
+	names: &crate::Schema,
+	out: W,
+) -> std::io::Result<u64> {
+	let mut batches = ColumnBatches::new(records).peekable();
+	let (key, format) = match batches.peek() {
+		Some(Ok(b)) => (b.key.clone(), b.format.clone()),
+		// an error is returned below
+		Some(Err(_)) | None => Default::default(),
+	};
+	let mut builder = BatchBuilder::new(arrow_schema(&key, &format, names)?, &format);
+	let mut writer =
+		arrow_ipc::writer::StreamWriter::try_new(out, builder.schema()).map_err(from_arrow)?;
+
+	let mut count = 0;
+	for batch in batches {
+		let batch = batch?;
+		builder.append(&batch)?;
+		count += batch.len() as u64;
+		if builder.rows() >= BATCH_ROWS {
+			writer.write(&builder.finish()?).map_err(from_arrow)?;
+		}
+	}
+	if builder.rows() != 0 {
+		writer.write(&builder.finish()?).map_err(from_arrow)?;
+	}
+	writer.finish().map_err(from_arrow)?;
+	Ok(count)
+}
+
+/// The format of a column of type `data_type`
+fn format_of(field: &Field, nullable: bool) -> std::io::Result<String> {
+	let kind = match field.data_type() {
+		DataType::Boolean => "b",
+		DataType::Int8 => "c",
+		DataType::UInt8 => "C",
+		DataType::Int16 => "h",
+		DataType::UInt16 => "H",
+		DataType::Int32 => "i",
+		DataType::UInt32 => "u",
+		DataType::Int64 => "I",
+		DataType::UInt64 => "U",
+		DataType::Float32 => "f",
+		DataType::Float64 => "F",
+		DataType::Utf8 | DataType::LargeUtf8 => "s",
+		DataType::Binary | DataType::LargeBinary => "x",
+		DataType::Decimal128(_, scale) if (0..=crate::MAX_DECIMAL_SCALE as i8).contains(scale) => {
+			return Ok(format!("{}d{}", if nullable { "n" } else { "" }, scale));
+		}
+		t => {
+			return Err(invalid_data(format!(
+				"the column \"{}\" has the type {}, which can't be imported",
+				field.name(),
+				t
+			)))
+		}
+	};
+	Ok(format!("{}{}", if nullable { "n" } else { "" }, kind))
+}
+
+/// Append the stored form of row `row` of `array` to `into`
+fn store_value(
+	array: &dyn Array,
+	column: &Column,
+	row: usize,
+	into: &mut Vec<u8>,
+) -> std::io::Result<()> {
+	if column.nullable {
+		let valid = array.is_valid(row);
+		into.push(valid as u8);
+		if !valid {
+			// arrow leaves the slot of a null undefined, but a null is stored as zero
+			// (a variable size column's null has a length of zero)
+			let kind = [column.kind];
+			let size = crate::row_format::row_format_size(std::str::from_utf8(&kind).unwrap());
+			into.resize(into.len() + size.unwrap_or(1), 0);
+			return Ok(());
+		}
+	}
+	match array.data_type() {
+		DataType::Boolean => into.push(array.as_boolean().value(row) as u8),
+		DataType::Int8 => into.write_i8(array.as_primitive::<Int8Type>().value(row))?,
+		DataType::UInt8 => into.write_u8(array.as_primitive::<UInt8Type>().value(row))?,
+		DataType::Int16 => {
+			into.write_i16::<BigEndian>(array.as_primitive::<Int16Type>().value(row))?
+		}
+		DataType::UInt16 => {
+			into.write_u16::<BigEndian>(array.as_primitive::<UInt16Type>().value(row))?
+		}
+		DataType::Int32 => {
+			into.write_i32::<BigEndian>(array.as_primitive::<Int32Type>().value(row))?
+		}
+		DataType::UInt32 => {
+			into.write_u32::<BigEndian>(array.as_primitive::<UInt32Type>().value(row))?
+		}
+		DataType::Int64 => {
+			into.write_i64::<BigEndian>(array.as_primitive::<Int64Type>().value(row))?
+		}
+		DataType::UInt64 => {
+			into.write_u64::<BigEndian>(array.as_primitive::<UInt64Type>().value(row))?
+		}
+		DataType::Float32 => {
+			into.write_f32::<BigEndian>(array.as_primitive::<Float32Type>().value(row))?
+		}
+		DataType::Float64 => {
+			into.write_f64::<BigEndian>(array.as_primitive::<Float64Type>().value(row))?
+		}
+		DataType::Utf8 => store_bytes(array.as_string::<i32>().value(row).as_bytes(), into),
+		DataType::LargeUtf8 => store_bytes(array.as_string::<i64>().value(row).as_bytes(), into),
+		DataType::Binary => store_bytes(array.as_binary::<i32>().value(row), into),
+		DataType::LargeBinary => store_bytes(array.as_binary::<i64>().value(row), into),
+		DataType::Decimal128(..) => {
+			let v = array.as_primitive::<Decimal128Type>().value(row);
+			let v = i64::try_from(v)
+				.map_err(|_| invalid_data(format!("the decimal {} is too large", v)))?;
+			into.write_i64::<BigEndian>(v)?;
+		}
+		_ => unreachable!("format_of accepted the type"),
+	}
+	Ok(())
+}
+
+fn store_bytes(bytes: &[u8], into: &mut Vec<u8>) {
+	let mut buf = unsigned_varint::encode::usize_buffer();
+	into.extend_from_slice(unsigned_varint::encode::usize(bytes.len(), &mut buf));
+	into.extend_from_slice(bytes);
+}
+
+/// The nanoseconds of row `row` of a `time` column
+fn timestamp(array: &dyn Array, row: usize) -> std::io::Result<crate::Timestamp> {
+	let ns = match array.data_type() {
+		DataType::Timestamp(TimeUnit::Second, _) => array
+			.as_primitive::<TimestampSecondType>()
+			.value(row)
+			.checked_mul(1_000_000_000),
+		DataType::Timestamp(TimeUnit::Millisecond, _) => array
+			.as_primitive::<TimestampMillisecondType>()
+			.value(row)
+			.checked_mul(1_000_000),
+		DataType::Timestamp(TimeUnit::Microsecond, _) => array
+			.as_primitive::<TimestampMicrosecondType>()
+			.value(row)
+			.checked_mul(1_000),
+		DataType::Timestamp(TimeUnit::Nanosecond, _) => {
+			Some(array.as_primitive::<TimestampNanosecondType>().value(row))
+		}
+		DataType::Int64 => Some(array.as_primitive::<Int64Type>().value(row)),
+		DataType::UInt64 => return Ok(array.as_primitive::<UInt64Type>().value(row)),
+		t => {
+			return Err(invalid_data(format!(
+				"the time column has the type {}, which isn't a timestamp",
+				t
+			)))
+		}
+	};
+	ns.and_then(|ns| u64::try_from(ns).ok())
+		.ok_or_else(|| invalid_data("a time is before 1970 or too large"))
+}
+
+/// The batches of an Arrow IPC stream or file, from its start
+fn batches<'r, R: Read + Seek>(
+	input: &'r mut R,
+) -> std::io::Result<Box<dyn Iterator<Item = Result<RecordBatch, ArrowError>> + 'r>> {
+	input.rewind()?;
+	let mut magic = [0u8; 6];
+	let is_file = input.read_exact(&mut magic).is_ok() && &magic == b"ARROW1";
+	input.rewind()?;
+
+	Ok(if is_file {
+		Box::new(arrow_ipc::reader::FileReader::try_new_buffered(input, None).map_err(from_arrow)?)
+	} else {
+		Box::new(
+			arrow_ipc::reader::StreamReader::try_new_buffered(input, None).map_err(from_arrow)?,
+		)
+	})
+}
+
+/// Add the records of an Arrow IPC stream or file (as written by [`write_arrow`]) to `tx`
+///
+/// The input needs a `key` column of strings and a `time` column of timestamps,
+/// neither with nulls, and every other column is a column of the records. A column
+/// is nullable if it has any nulls. The records are sorted before they're added (in
+/// temporary files in the database's directory if there are many), so the input
+/// can be in any order. Of the records with the same key and timestamp, the last
+/// is kept. Returns the number of records that were added.
+pub fn read_arrow<R: Read + Seek>(tx: &mut CreateTx, mut input: R) -> Result<u64, WriteFailure> {
+	// read the input once to find the columns with nulls,
+	// and again to sort its rows
+	let mut schema = None;
+	let mut has_nulls = vec![];
+	for batch in batches(&mut input)? {
+		let batch = batch.map_err(from_arrow)?;
+		has_nulls.resize(batch.num_columns(), false);
+		for (n, column) in batch.columns().iter().enumerate() {
+			has_nulls[n] |= column.null_count() != 0;
+		}
+		schema.get_or_insert(batch.schema());
+	}
+	let Some(schema) = schema else {
+		return Ok(0);
+	};
+
+	let column_of = |name: &str| {
+		schema
+			.index_of(name)
+			.map_err(|_| invalid_data(format!("there is no \"{}\" column", name)))
+	};
+	let key_column = column_of("key")?;
+	let time_column = column_of("time")?;
+	let value_columns: Vec<usize> = (0..schema.fields().len())
+		.filter(|&n| n != key_column && n != time_column)
+		.collect();
+
+	let mut format = String::new();
+	for &n in &value_columns {
+		format += &format_of(schema.field(n), has_nulls[n])?;
+	}
+	let layout: Vec<Column> = crate::row_format::columns(format.as_bytes()).collect();
+
+	let mut sort = ExternalSort::new(tx.dir(), SORT_MEMORY);
+	let mut rows = 0;
+	for batch in batches(&mut input)? {
+		let batch = batch.map_err(from_arrow)?;
+		let keys = batch.column(key_column);
+		let keys: Vec<Option<&str>> = match keys.data_type() {
+			DataType::Utf8 => keys.as_string::<i32>().iter().collect(),
+			DataType::LargeUtf8 => keys.as_string::<i64>().iter().collect(),
+			_ => return Err(invalid_data("the key column isn't strings").into()),
+		};
+		let times = batch.column(time_column);
+		for (row, key) in keys.into_iter().enumerate() {
+			let null = |column| invalid_data(format!("row {} has no {}", rows + row + 1, column));
+			let key = key.ok_or_else(|| null("key"))?;
+			if times.is_null(row) {
+				return Err(null("time").into());
+			}
+
+			let mut data = vec![];
+			data.write_u64::<BigEndian>(timestamp(times, row)?)?;
+			for (&n, column) in value_columns.iter().zip(&layout) {
+				store_value(batch.column(n), column, row, &mut data)?;
+			}
+			sort.push(SortRow {
+				key: key.to_owned(),
+				format: format.clone(),
+				data,
+			})?;
+		}
+		rows += batch.num_rows();
+	}
+
+	let mut count = 0;
+	for row in sort.finish()? {
+		let row = row?;
+		tx.add_record_raw(&row.key, &row.format, &row.data)?;
+		count += 1;
+	}
+	Ok(count)
+}
@@ -1,4 +1,5 @@
 use std::net::SocketAddr;
+use std::ops::Bound;
 use std::path::PathBuf;
 
 use antidote::RwLock;
@@ -7,8 +8,6 @@ use hyper::Server;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 
-use serde_derive::*;
-
 use sonnerie::*;
 
 pub use hyper::Body;
@@ -29,6 +28,43 @@ struct Opt {
 	/// Listen on this address (addr:port)
 	#[clap(short, long)]
 	listen: SocketAddr,
+
+	/// Buffer the records of PUTs in memory, and in a journal in the
+	/// database's directory, and add them to the database together.
+	#[clap(long)]
+	write_buffer: bool,
+
+	/// Add the buffered records once they use this many bytes of memory.
+	#[clap(long, requires = "write_buffer")]
+	flush_size: Option<usize>,
+
+	/// Add the buffered records once they're this many seconds old.
+	#[clap(long, requires = "write_buffer")]
+	flush_interval: Option<u64>,
+
+	/// Add the PUTs that arrive within this many milliseconds of each
+	/// other as one transaction. Each PUT succeeds once it's committed.
+	#[clap(long, conflicts_with = "write_buffer")]
+	group_commit: Option<u64>,
+
+	/// Compact the database in the background when it needs it,
+	/// as by "sonnerie compactd".
+	#[clap(long)]
+	auto_compact: bool,
+
+	/// Do a minor compaction once there are more than this many transaction
+	/// files, and a major one once there are more than this many deletes.
+	#[clap(long, default_value = "32", requires = "auto_compact")]
+	compact_max_txes: usize,
+
+	/// Do a major compaction once the transaction files are bigger
+	/// than this fraction of the main database.
+	#[clap(long, default_value = "0.1", requires = "auto_compact")]
+	compact_major_ratio: f64,
+
+	/// Check whether the database needs to be compacted this often, in seconds.
+	#[clap(long, default_value = "60", requires = "auto_compact")]
+	compact_interval: u64,
 }
 
 fn main() {
@@ -41,14 +77,65 @@ fn main() {
 		.build()
 		.expect("tokio runtime");
 
+	let buffer = opt.write_buffer.then(|| {
+		let mut buffer = WriteBuffer::open(&opt.dir).expect("opening write buffer");
+		if let Some(bytes) = opt.flush_size {
+			buffer.set_flush_size(bytes);
+		}
+		if let Some(seconds) = opt.flush_interval {
+			buffer.set_flush_interval(Duration::from_secs(seconds));
+		}
+		buffer
+	});
+
+	let reader = match &buffer {
+		Some(buffer) => buffer.reader(),
+		None => DatabaseReader::new(&opt.dir),
+	};
+
 	let srv = Tsrv {
 		dir: opt.dir.clone(),
-		shared_reader: RwLock::new(Arc::new(DatabaseReader::new(&opt.dir).unwrap())),
+		shared_reader: RwLock::new(Arc::new(reader.unwrap())),
 		shared_reader_age: RwLock::new(Some(Instant::now())),
+		buffer,
+		group: opt.group_commit.map(|millis| {
+			GroupCommit::new(&opt.dir, Duration::from_millis(millis)).expect("opening group commit")
+		}),
 	};
 
 	let srv = Arc::new(srv);
 
+	if srv.buffer.is_some() {
+		let srv = srv.clone();
+		std::thread::spawn(move || loop {
+			std::thread::sleep(Duration::from_secs(1));
+			if let Err(e) = srv.buffer.as_ref().unwrap().flush_if_due() {
+				eprintln!("error flushing the write buffer: {}", e);
+			}
+		});
+	}
+
+	if opt.auto_compact {
+		let srv = srv.clone();
+		let policy = CompactionPolicy {
+			max_transactions: opt.compact_max_txes,
+			max_transaction_ratio: opt.compact_major_ratio,
+			..Default::default()
+		};
+		let interval = Duration::from_secs(opt.compact_interval);
+		std::thread::spawn(move || loop {
+			std::thread::sleep(interval);
+			match policy.run(&srv.dir) {
+				Ok(Some(compaction)) => {
+					eprintln!("did a {} compaction", compaction);
+					*srv.shared_reader_age.write() = None;
+				}
+				Ok(None) => {}
+				Err(e) => eprintln!("error compacting: {}", e),
+			}
+		});
+	}
+
 	let make_service = hyper::service::make_service_fn(move |_conn| {
 		let srv = srv.clone();
 		async move {
@@ -81,9 +168,44 @@ struct Tsrv {
 	dir: PathBuf,
 	shared_reader: RwLock<Arc<DatabaseReader>>,
 	shared_reader_age: RwLock<Option<Instant>>,
+	buffer: Option<WriteBuffer>,
+	group: Option<GroupCommit>,
+}
+
+/// The records of a PUT
+enum PutTx<'b> {
+	Sorting(Box<SortingCreateTx>),
+	Buffered(WriteBatch<'b>),
+	Grouped(GroupBatch<'b>),
+}
+
+impl PutTx<'_> {
+	fn sink(&mut self) -> &mut dyn RecordSink {
+		match self {
+			PutTx::Sorting(tx) => &mut **tx,
+			PutTx::Buffered(batch) => batch,
+			PutTx::Grouped(batch) => batch,
+		}
+	}
+
+	fn commit(self) -> Result<(), WriteFailure> {
+		match self {
+			PutTx::Sorting(tx) => tx.commit(),
+			PutTx::Buffered(batch) => batch.commit(),
+			PutTx::Grouped(batch) => batch.commit(),
+		}
+	}
 }
 
 impl Tsrv {
+	/// Open the database, with the buffered records if there are any
+	fn open_reader(&self) -> std::io::Result<DatabaseReader> {
+		match &self.buffer {
+			Some(buffer) => buffer.reader(),
+			None => DatabaseReader::new(&self.dir),
+		}
+	}
+
 	async fn run(self: Arc<Tsrv>, req: Request) -> Result<Response, String> {
 		match *req.method() {
 			hyper::Method::GET => self.get(req).await,
@@ -95,96 +217,49 @@ impl Tsrv {
 		}
 	}
 
-	async fn put(&self, req: Request) -> Result<Response, String> {
+	async fn put(self: Arc<Self>, req: Request) -> Result<Response, String> {
 		// let db = DatabaseReader::new(&self.dir).unwrap();
-		let mut tx = CreateTx::new(&self.dir).map_err(|e| format!("create tx: {}", e))?;
-
-		/*enum CheckingFormat
-		{
-			NotYet,
-			KeyAndFmt(String, String),
-			NoMore,
-		}*/
-
-		//let mut next_key_to_check_fmt = CheckingFormat::NotYet;
-
-		let tmpfile =
-			tempfile::NamedTempFile::new().map_err(|e| format!("creating tempfile {}", e))?;
-
-		let mut sorted_file = shardio::ShardWriter::<SortingRecord>::new(
-			tmpfile.path(),
-			1024 * 100, // kind of random numbers
-			1024 * 100 * 4,
-			1024 * 100 * 4 * 4,
-		)
-		.map_err(|e| format!("opening sorted writer {}", e))?;
-
-		{
-			let mut writer = sorted_file.get_sender();
-
-			let mut lines = lines_from_request::lines(req.into_body());
-
-			while let Some(line) = lines.next().await {
-				let line = line.map_err(|e| format!("reading one row from network: {}", e))?;
-				let line =
-					String::from_utf8(line).map_err(|e| format!("data must be utf-8: {}", e))?;
-				let tail = line.trim_end();
-				if tail.is_empty() {
-					continue;
-				}
-				let (key, tail) = split_one(tail).ok_or_else(|| "reading key".to_string())?;
-				let (timestamp, tail) =
-					split_one(tail).ok_or_else(|| "reading timestamp".to_string())?;
-				let ts: Timestamp = timestamp
-					.parse()
-					.map_err(|e| format!("parsing timestamp {}", e))?;
-				let (format, tail) =
-					split_one(tail).ok_or_else(|| "reading timestamp".to_string())?;
-
-				let rec = SortingRecord {
-					key: key.to_string(),
-					ts,
-					format: format.to_string(),
-					tail: tail.to_string(),
-				};
-
-				tokio::task::block_in_place(|| -> Result<(), String> {
-					writer
-						.send(rec)
-						.map_err(|e| format!("writing to sorted tempfile: {}", e))
-				})?;
+		let mut tx = match (&self.buffer, &self.group) {
+			(Some(buffer), _) => PutTx::Buffered(buffer.batch()),
+			(None, Some(group)) => PutTx::Grouped(group.batch()),
+			(None, None) => {
+				let tx = CreateTx::new(&self.dir).map_err(|e| format!("create tx: {}", e))?;
+				PutTx::Sorting(Box::new(SortingCreateTx::new(tx)))
 			}
-		}
-
-		tokio::task::block_in_place(|| -> Result<(), String> {
-			sorted_file
-				.finish()
-				.map_err(|e| format!("doing the external sorting {}", e))?;
-
-			let reader = shardio::ShardReader::<SortingRecord>::open(tmpfile.path())
-				.map_err(|e| format!("opening sorted: {}", e))?;
+		};
 
-			let mut row_data = vec![];
+		let mut lines = lines_from_request::lines(req.into_body());
+		let mut row_data = vec![];
 
-			for record in reader
-				.iter()
-				.map_err(|e| format!("reading from sorted: {}", e))?
-			{
-				let SortingRecord {
-					key,
-					ts,
-					format,
-					tail,
-				} = record.map_err(|e| format!("parsing temporary data: {}", e))?;
-				let row_format = sonnerie::row_format::parse_row_format(&format);
-				row_format
-					.to_stored_format(ts, &tail, &mut row_data)
-					.map_err(|e| format!("parsing data according to format: {}", e))?;
-				tx.add_record_raw(&key, &format, &row_data)
-					.map_err(|e| format!("processing record {}[{}]: {:?}", key, ts, e))?;
-				row_data.clear();
+		while let Some(line) = lines.next().await {
+			let line = line.map_err(|e| format!("reading one row from network: {}", e))?;
+			let line = String::from_utf8(line).map_err(|e| format!("data must be utf-8: {}", e))?;
+			let tail = line.trim_end();
+			if tail.is_empty() {
+				continue;
 			}
+			let (key, tail) = split_one(tail).ok_or_else(|| "reading key".to_string())?;
+			let (timestamp, tail) =
+				split_one(tail).ok_or_else(|| "reading timestamp".to_string())?;
+			let ts: Timestamp = timestamp
+				.parse()
+				.map_err(|e| format!("parsing timestamp {}", e))?;
+			let (format, tail) = split_one(tail).ok_or_else(|| "reading timestamp".to_string())?;
+
+			let row_format = sonnerie::row_format::parse_row_format(&format);
+			row_data.clear();
+			row_format
+				.to_stored_format(ts, tail, &mut row_data)
+				.map_err(|e| format!("parsing data according to format: {}", e))?;
+
+			tokio::task::block_in_place(|| {
+				tx.sink()
+					.add_record_raw(&key, &format, &row_data)
+					.map_err(|e| format!("processing record {}[{}]: {:?}", key, ts, e))
+			})?;
+		}
 
+		tokio::task::block_in_place(|| -> Result<(), String> {
 			tx.commit().map_err(|e| format!("committing tx: {}", e))?;
 
 			// after a commit happens, invalidate the shared reader
@@ -222,6 +297,16 @@ impl Tsrv {
 
 		let human_dates = query_string.iter().any(|k| k.0 == "human");
 
+		let timestamps = match time_range(&query_string) {
+			Ok(t) => t,
+			Err(e) => {
+				return Ok(hyper::Response::builder()
+					.status(hyper::StatusCode::BAD_REQUEST)
+					.body(Body::from(e))
+					.expect("error request"))
+			}
+		};
+
 		let timestamp_fmt = if human_dates {
 			Default::default()
 		} else {
@@ -254,7 +339,14 @@ impl Tsrv {
 					}
 
 					if make_new_reader {
-						let newdb = Arc::new(DatabaseReader::new(&srv.dir).unwrap());
+						let newdb = match srv.open_reader() {
+							Ok(newdb) => Arc::new(newdb),
+							Err(e) => {
+								eprintln!("error opening database: {}", e);
+								*srv.shared_reader_age.write() = None;
+								return;
+							}
+						};
 						db = newdb.clone();
 						let mut rdr = srv.shared_reader.write();
 						*rdr = newdb;
@@ -265,13 +357,25 @@ impl Tsrv {
 				}
 
 				// trick sonnerie to not do an fadvise when you search for a single key
-				let searcher: Box<dyn Iterator<Item = sonnerie::Record>> = if filter.is_exact() {
-					Box::new(db.get(filter.prefix()).into_iter())
+				let searcher = if filter.is_exact() {
+					db.get(filter.prefix())
+						.with_time_range(timestamps)
+						.into_try_iter()
 				} else {
-					Box::new(db.get_filter(&filter).into_iter())
+					db.get_filter(&filter)
+						.with_time_range(timestamps)
+						.into_try_iter()
 				};
 
 				for record in searcher {
+					let record = match record {
+						Ok(record) => record,
+						Err(e) => {
+							// skip what can't be read, but keep serving the rest
+							eprintln!("error reading {}: {}", filter.prefix(), e);
+							continue;
+						}
+					};
 					let mut row: Vec<u8> = vec![];
 					sonnerie::formatted::print_record(
 						&record,
@@ -300,10 +404,23 @@ impl Tsrv {
 	}
 }
 
-#[derive(Clone, Eq, PartialEq, Serialize, Deserialize, PartialOrd, Ord)]
-struct SortingRecord {
-	key: String,
-	ts: Timestamp,
-	format: String,
-	tail: String,
+/// read the optional `after` (inclusive) and `before` (exclusive)
+/// query parameters, in nanoseconds since the epoch
+fn time_range(
+	query_string: &[(String, String)],
+) -> Result<(Bound<Timestamp>, Bound<Timestamp>), String> {
+	let mut after = Bound::Unbounded;
+	let mut before = Bound::Unbounded;
+	for (k, v) in query_string {
+		let parse = || {
+			v.parse::<Timestamp>()
+				.map_err(|e| format!("parsing {}: {}", k, e))
+		};
+		match k.as_str() {
+			"after" => after = Bound::Included(parse()?),
+			"before" => before = Bound::Excluded(parse()?),
+			_ => {}
+		}
+	}
+	Ok((after, before))
 }
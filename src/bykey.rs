@@ -1,8 +1,11 @@
-use crate::database_reader::DatabaseReader;
-use crate::key_reader::*;
+use crate::database_reader::{take_error, DatabaseReader, RecordSource, BUFFERED_TXID};
+use crate::key_reader::ReadErrors;
 use crate::merge::Merge;
+use crate::write_buffer::BufferedRecords;
 use crate::DeleteMarkerPrecomputed;
+use crate::ReadError;
 use crate::Record;
+use either::Either;
 use lending_cell::{BorrowedCell, LendingCell};
 use std::ops::Bound;
 
@@ -20,9 +23,23 @@ pub struct DatabaseKeyReader<'d> {
 	pub(crate) matcher: Option<regex::Regex>,
 	pub(crate) prefix: &'d str,
 	pub(crate) range: crate::CowStringRange<'d>,
+	pub(crate) timestamps: crate::TimestampRange,
 }
 
 impl<'d> DatabaseKeyReader<'d> {
+	/// Only read records whose timestamps are in this range
+	///
+	/// Keys that have no records in this range are not yielded.
+	///
+	/// Same as [`DatabaseRecordReader::with_time_range`](crate::DatabaseRecordReader::with_time_range).
+	pub fn with_time_range(
+		mut self,
+		timestamps: impl std::ops::RangeBounds<crate::Timestamp>,
+	) -> DatabaseKeyReader<'d> {
+		self.timestamps = crate::disassemble_range_bound(timestamps);
+		self
+	}
+
 	pub(crate) fn check(&self) {
 		match (self.range.start_bound(), self.range.end_bound()) {
 			(Bound::Unbounded, _) => {}
@@ -41,8 +58,14 @@ impl<'d> DatabaseKeyReader<'d> {
 			.txes
 			.iter()
 			.map(|tx| {
-				let filter =
-					tx.2.get_filter_range(self.matcher.clone(), self.prefix, self.range.clone());
+				let filter = tx.2.get_filter_range(
+					self.matcher.clone(),
+					self.prefix,
+					self.range.clone(),
+					self.timestamps,
+					// errors are reported when the halves are read
+					Some((tx.0, Default::default())),
+				);
 				let b = filter.compressed_bytes();
 				(filter, b)
 			})
@@ -81,6 +104,7 @@ impl<'d> DatabaseKeyReader<'d> {
 				Bound::Included(middle_start_key.to_owned()),
 			)
 				.into(),
+			timestamps: self.timestamps,
 		};
 		first_half.check();
 		if let Bound::Included(e) = self.range.start_bound() {
@@ -105,6 +129,7 @@ impl<'d> DatabaseKeyReader<'d> {
 				crate::bound_deep_copy(self.range.end_bound()),
 			)
 				.into(),
+			timestamps: self.timestamps,
 		};
 
 		if let Bound::Excluded(e) = self.range.end_bound() {
@@ -117,22 +142,63 @@ impl<'d> DatabaseKeyReader<'d> {
 
 		Some((first_half, second_half))
 	}
-}
 
-impl<'d> IntoIterator for DatabaseKeyReader<'d> {
-	type Item = KeyRecordReader<'d>;
-	type IntoIter = DatabaseKeyIterator<'d>;
+	/// Iterate over the keys, yielding an error for each segment that
+	/// can't be read (and skipping it) instead of panicking.
+	///
+	/// The segments skipped while reading a key's records are
+	/// reported before the next key.
+	///
+	/// ```no_run
+	/// # let db = sonnerie::DatabaseReader::new(std::path::Path::new("")).unwrap();
+	/// for key in db.get_range_keys(..).into_try_iter() {
+	///     match key {
+	///         Ok(key) => println!("{}", key.key()),
+	///         Err(e) => eprintln!("skipping: {}", e),
+	///     }
+	/// }
+	/// ```
+	pub fn into_try_iter(self) -> DatabaseTryKeyIterator<'d> {
+		let errors = ReadErrors::default();
+		DatabaseTryKeyIterator {
+			db: self.db,
+			keys: self.keys(Some(&errors)),
+			errors,
+			next_key: None,
+		}
+	}
 
-	fn into_iter(self) -> Self::IntoIter {
+	/// Like [`DatabaseKeyReader::into_try_iter`], but a Rayon parallel iterator
+	pub fn into_par_try_iter(self) -> DatabaseTryKeyReader<'d> {
+		DatabaseTryKeyReader { reader: self }
+	}
+
+	fn keys(self, errors: Option<&ReadErrors>) -> DatabaseKeyIterator<'d> {
 		self.check();
 
 		let mut readers = Vec::with_capacity(self.db.txes.len());
 
 		for (txid, _path, reader) in self.db.txes.iter() {
-			let iter =
-				reader.get_filter_range(self.matcher.clone(), self.prefix, self.range.clone());
-
-			readers.push((*txid, iter));
+			let iter = reader.get_filter_range(
+				self.matcher.clone(),
+				self.prefix,
+				self.range.clone(),
+				self.timestamps,
+				errors.map(|e| (*txid, e.clone())),
+			);
+
+			readers.push((*txid, Either::Left(iter)));
+		}
+		if let Some(buffered) = &self.db.buffered {
+			let records = BufferedRecords::new(
+				self.db,
+				buffered,
+				self.matcher.clone(),
+				self.prefix,
+				&self.range,
+				self.timestamps,
+			);
+			readers.push((BUFFERED_TXID, Either::Right(records)));
 		}
 		let merge = Merge::new(readers, |a, b| {
 			a.key()
@@ -140,12 +206,7 @@ impl<'d> IntoIterator for DatabaseKeyReader<'d> {
 				.then_with(|| a.timestamp_nanos().cmp(&b.timestamp_nanos()))
 		});
 
-		let filter_out: Vec<_> = self
-			.db
-			.filter_out
-			.iter()
-			.map(|(txid, _path, dm)| (*txid, DeleteMarkerPrecomputed::from_delete_marker(dm)))
-			.collect();
+		let filter_out = self.db.filter_out_for(&self.timestamps);
 
 		let mut hot_potato = HotPotato {
 			filter_out,
@@ -164,9 +225,59 @@ impl<'d> IntoIterator for DatabaseKeyReader<'d> {
 	}
 }
 
+impl<'d> IntoIterator for DatabaseKeyReader<'d> {
+	type Item = KeyRecordReader<'d>;
+	type IntoIter = DatabaseKeyIterator<'d>;
+
+	/// Iterate over the keys, panicking if a segment can't be read
+	/// (see [`DatabaseKeyReader::into_try_iter`])
+	fn into_iter(self) -> Self::IntoIter {
+		self.keys(None)
+	}
+}
+
+/// A parallel iterator over each key that reports damaged segments,
+/// made by [`DatabaseKeyReader::into_par_try_iter`]. **`feature=by-key`**
+pub struct DatabaseTryKeyReader<'d> {
+	pub(crate) reader: DatabaseKeyReader<'d>,
+}
+
+/// An iterator over each key that reports damaged segments. **`feature=by-key`**
+///
+/// Yields `Ok(`[`KeyRecordReader`]`)` for each key, and a [`ReadError`]
+/// for each segment that was skipped. Like [`DatabaseKeyIterator`], the
+/// previous [`KeyRecordReader`] must be dropped before calling `next`.
+pub struct DatabaseTryKeyIterator<'d> {
+	db: &'d DatabaseReader,
+	keys: DatabaseKeyIterator<'d>,
+	errors: ReadErrors,
+	next_key: Option<KeyRecordReader<'d>>,
+}
+
+impl<'d> Iterator for DatabaseTryKeyIterator<'d> {
+	type Item = Result<KeyRecordReader<'d>, ReadError>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		if let Some(e) = take_error(self.db, &self.errors) {
+			return Some(Err(e));
+		}
+		if let Some(key) = self.next_key.take() {
+			return Some(Ok(key));
+		}
+
+		let key = self.keys.next();
+		// finding the key may have skipped segments, report them first
+		if let Some(e) = take_error(self.db, &self.errors) {
+			self.next_key = key;
+			return Some(Err(e));
+		}
+		key.map(Ok)
+	}
+}
+
 struct HotPotato<'d> {
 	filter_out: Vec<(usize, DeleteMarkerPrecomputed<'d>)>,
-	merge: Box<Merge<StringKeyRangeReader<'d, 'd>, Record>>,
+	merge: Box<Merge<RecordSource<'d>, Record>>,
 	queued_record: Option<Record>, // record hasn't been outputted yet
 	current_key: String,
 }
@@ -184,31 +295,10 @@ impl<'d> HotPotato<'d> {
 				// select only transactions that are indexed lower than the
 				// delete transaction
 				.filter(|(del_txid, _)| txid < *del_txid)
-				// check if the record's timestamp is within filtering out
-				// this assumes that the filter_out is sorted ascending by
-				// first timestamp (which should have been done in
-				// DatabaseReader::new())
-				.filter(|(_, filter)| {
-					let record_time = record.time();
-					(filter.first_timestamp..filter.last_timestamp).contains(&record_time)
-				})
-				.filter(|(_, filter)| record.time() <= filter.last_timestamp)
 				// if any of the filters went here (i.e. any() returns a true),
 				// then that means that filter found one filter that filters out
 				// the current record. that should be discarded
-				.any(|(_, filter)| {
-					let key = record.key();
-
-					if filter.first_key > key {
-						return false;
-					}
-
-					if !filter.last_key.is_empty() && key >= filter.last_key {
-						return false;
-					}
-
-					filter.wildcard_matches(key)
-				});
+				.any(|(_, filter)| filter.deletes(&record));
 
 			if !is_filtered_out {
 				return Some(record);
@@ -0,0 +1,238 @@
+//! Read the records of a key as a vector per column.
+
+use crate::records::split_column;
+use crate::row_format::Column;
+use crate::{FromRecord, Record, Timestamp};
+use byteorder::{BigEndian, ByteOrder};
+
+/// The consecutive records of one key that have the same format,
+/// as a vector of timestamps and a vector of values per column
+#[derive(Debug, Clone, PartialEq)]
+pub struct ColumnBatch {
+	/// The key of every record
+	pub key: String,
+	/// The format of every record
+	pub format: String,
+	/// The timestamp of each record
+	pub timestamps: Vec<Timestamp>,
+	/// The values of each column, with one value per record
+	pub columns: Vec<ColumnValues>,
+	layout: Vec<Column>,
+}
+
+/// The values of one column of a [`ColumnBatch`]
+#[derive(Debug, Clone, PartialEq)]
+pub enum ColumnValues {
+	/// `b`
+	Bool(Vec<bool>),
+	/// `c`
+	I8(Vec<i8>),
+	/// `C`
+	U8(Vec<u8>),
+	/// `h`
+	I16(Vec<i16>),
+	/// `H`
+	U16(Vec<u16>),
+	/// `i`
+	I32(Vec<i32>),
+	/// `u`
+	U32(Vec<u32>),
+	/// `I`
+	I64(Vec<i64>),
+	/// `U`
+	U64(Vec<u64>),
+	/// `f`
+	F32(Vec<f32>),
+	/// `F`
+	F64(Vec<f64>),
+	/// `s`
+	String(Vec<String>),
+	/// `x`
+	Bytes(Vec<Vec<u8>>),
+	/// `d`, the mantissas of [`Decimal`](crate::Decimal)s that all have `scale`
+	Decimal { scale: u8, mantissas: Vec<i64> },
+	/// A column preceded by `n`. `values` has a zero (or empty) value
+	/// where `present` is false.
+	Nullable {
+		present: Vec<bool>,
+		values: Box<ColumnValues>,
+	},
+}
+
+impl ColumnValues {
+	fn for_column(column: &Column) -> std::io::Result<ColumnValues> {
+		let values = match column.kind {
+			b'b' => ColumnValues::Bool(vec![]),
+			b'c' => ColumnValues::I8(vec![]),
+			b'C' => ColumnValues::U8(vec![]),
+			b'h' => ColumnValues::I16(vec![]),
+			b'H' => ColumnValues::U16(vec![]),
+			b'i' => ColumnValues::I32(vec![]),
+			b'u' => ColumnValues::U32(vec![]),
+			b'I' => ColumnValues::I64(vec![]),
+			b'U' => ColumnValues::U64(vec![]),
+			b'f' => ColumnValues::F32(vec![]),
+			b'F' => ColumnValues::F64(vec![]),
+			b's' => ColumnValues::String(vec![]),
+			b'x' => ColumnValues::Bytes(vec![]),
+			b'd' => ColumnValues::Decimal {
+				scale: column.scale,
+				mantissas: vec![],
+			},
+			a => {
+				return Err(std::io::Error::new(
+					std::io::ErrorKind::InvalidData,
+					format!("format string contains invalid '{}'", a as char),
+				))
+			}
+		};
+		if column.nullable {
+			Ok(ColumnValues::Nullable {
+				present: vec![],
+				values: Box::new(values),
+			})
+		} else {
+			Ok(values)
+		}
+	}
+
+	/// append the value of a column of type `kind`, which is stored as `bytes`
+	fn push(&mut self, kind: u8, bytes: &[u8]) -> std::io::Result<()> {
+		match self {
+			ColumnValues::Bool(v) => v.push(FromRecord::get(kind, bytes)?),
+			ColumnValues::I8(v) => v.push(FromRecord::get(kind, bytes)?),
+			ColumnValues::U8(v) => v.push(FromRecord::get(kind, bytes)?),
+			ColumnValues::I16(v) => v.push(FromRecord::get(kind, bytes)?),
+			ColumnValues::U16(v) => v.push(FromRecord::get(kind, bytes)?),
+			ColumnValues::I32(v) => v.push(FromRecord::get(kind, bytes)?),
+			ColumnValues::U32(v) => v.push(FromRecord::get(kind, bytes)?),
+			ColumnValues::I64(v) => v.push(FromRecord::get(kind, bytes)?),
+			ColumnValues::U64(v) => v.push(FromRecord::get(kind, bytes)?),
+			ColumnValues::F32(v) => v.push(FromRecord::get(kind, bytes)?),
+			ColumnValues::F64(v) => v.push(FromRecord::get(kind, bytes)?),
+			ColumnValues::String(v) => v.push(FromRecord::get(kind, bytes)?),
+			ColumnValues::Bytes(v) => v.push(FromRecord::get(kind, bytes)?),
+			ColumnValues::Decimal { mantissas, .. } => mantissas.push(BigEndian::read_i64(bytes)),
+			ColumnValues::Nullable { present, values } => {
+				present.push(bytes[0] != 0);
+				values.push(kind, &bytes[1..])?;
+			}
+		}
+		Ok(())
+	}
+
+	/// The number of values
+	pub fn len(&self) -> usize {
+		match self {
+			ColumnValues::Bool(v) => v.len(),
+			ColumnValues::I8(v) => v.len(),
+			ColumnValues::U8(v) => v.len(),
+			ColumnValues::I16(v) => v.len(),
+			ColumnValues::U16(v) => v.len(),
+			ColumnValues::I32(v) => v.len(),
+			ColumnValues::U32(v) => v.len(),
+			ColumnValues::I64(v) => v.len(),
+			ColumnValues::U64(v) => v.len(),
+			ColumnValues::F32(v) => v.len(),
+			ColumnValues::F64(v) => v.len(),
+			ColumnValues::String(v) => v.len(),
+			ColumnValues::Bytes(v) => v.len(),
+			ColumnValues::Decimal { mantissas, .. } => mantissas.len(),
+			ColumnValues::Nullable { present, .. } => present.len(),
+		}
+	}
+
+	/// Returns true if there are no values
+	pub fn is_empty(&self) -> bool {
+		self.len() == 0
+	}
+}
+
+impl ColumnBatch {
+	/// An empty batch of records of `key` in the format `format`
+	pub fn new(key: &str, format: &str) -> std::io::Result<ColumnBatch> {
+		let layout: Vec<Column> = crate::row_format::columns(format.as_bytes()).collect();
+		let columns = layout
+			.iter()
+			.map(ColumnValues::for_column)
+			.collect::<std::io::Result<_>>()?;
+		Ok(ColumnBatch {
+			key: key.to_owned(),
+			format: format.to_owned(),
+			timestamps: vec![],
+			columns,
+			layout,
+		})
+	}
+
+	/// Append the values of `record`, which must have the key
+	/// and format of this batch
+	pub fn push(&mut self, record: &Record) -> std::io::Result<()> {
+		if record.key() != self.key || record.format() != self.format {
+			return Err(std::io::Error::new(
+				std::io::ErrorKind::InvalidInput,
+				format!(
+					"a record of \"{}\" in \"{}\" can't be added to a batch of \"{}\" in \"{}\"",
+					record.key(),
+					record.format(),
+					self.key,
+					self.format
+				),
+			));
+		}
+		let (timestamp, mut from) = record.raw().split_at(crate::records::TIMESTAMP_SIZE);
+		for (column, values) in self.layout.iter().zip(&mut self.columns) {
+			let bytes = split_column(column, &mut from)?;
+			values.push(column.kind, bytes)?;
+		}
+		self.timestamps.push(BigEndian::read_u64(timestamp));
+		Ok(())
+	}
+
+	/// The number of records
+	pub fn len(&self) -> usize {
+		self.timestamps.len()
+	}
+
+	/// Returns true if there are no records
+	pub fn is_empty(&self) -> bool {
+		self.timestamps.is_empty()
+	}
+}
+
+/// An iterator that collects records into a [`ColumnBatch`] for
+/// each key (and a new one each time the key's format changes)
+///
+/// A record that can't be decoded is an error, in place of its batch.
+pub struct ColumnBatches<I: Iterator<Item = Record>> {
+	records: std::iter::Peekable<I>,
+}
+
+impl<I: Iterator<Item = Record>> ColumnBatches<I> {
+	/// Batch the records of `records`, which are sorted by key
+	pub fn new(records: impl IntoIterator<IntoIter = I>) -> ColumnBatches<I> {
+		ColumnBatches {
+			records: records.into_iter().peekable(),
+		}
+	}
+}
+
+impl<I: Iterator<Item = Record>> Iterator for ColumnBatches<I> {
+	type Item = std::io::Result<ColumnBatch>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		let first = self.records.next()?;
+		let mut batch = match ColumnBatch::new(first.key(), first.format()) {
+			Ok(batch) => batch,
+			Err(e) => return Some(Err(e)),
+		};
+		let mut result = batch.push(&first);
+		while let Some(record) = self
+			.records
+			.next_if(|r| r.key() == batch.key && r.format() == batch.format)
+		{
+			result = result.and_then(|()| batch.push(&record));
+		}
+		Some(result.map(|()| batch))
+	}
+}
@@ -0,0 +1,182 @@
+//! Compact a database, and decide when it needs to be compacted.
+
+use crate::{Codec, CreateTx, DatabaseReader, WriteFailure};
+use std::fs::File;
+use std::path::Path;
+
+/// A kind of compaction
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Compaction {
+	/// Combine the transaction files into one
+	Minor,
+	/// Combine `main` and the transaction files into a new `main`,
+	/// removing the records that were deleted
+	Major,
+}
+
+impl std::fmt::Display for Compaction {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Compaction::Minor => write!(f, "minor"),
+			Compaction::Major => write!(f, "major"),
+		}
+	}
+}
+
+/// Compact the database in `dir`, returning the number of records that were written.
+///
+/// Waits for other compactions of the database to finish first (they lock
+/// the file `.compact`). Transactions can be added and the database can be
+/// read while it's compacted.
+pub fn compact(dir: &Path, compaction: Compaction, codec: Codec) -> Result<u64, WriteFailure> {
+	Ok(compact_files(dir, compaction, codec)?.unwrap_or(0))
+}
+
+/// Compact like [`compact`], returning `None` if there was nothing to compact
+fn compact_files(
+	dir: &Path,
+	compaction: Compaction,
+	codec: Codec,
+) -> Result<Option<u64>, WriteFailure> {
+	use fs2::FileExt;
+
+	let lock = File::create(dir.join(".compact"))?;
+	lock.lock_exclusive()?;
+
+	let major = compaction == Compaction::Major;
+	let mut records = None;
+	// a minor compaction only opens so many files at a time,
+	// so repeat until they're all compacted
+	loop {
+		let db = if major {
+			DatabaseReader::new(dir)?
+		} else {
+			DatabaseReader::without_main_db(dir)?
+		};
+		// only a major compaction removes delete markers
+		let deletes = major && db.delete_txes_paths().next().is_some();
+		if db.num_txes() <= 1 && !deletes {
+			break;
+		}
+
+		// create the new transaction after opening the database reader
+		let mut compacted = CreateTx::new_with_codec(dir, codec)?;
+		let records = records.get_or_insert(0);
+		for record in db.get_range(..) {
+			compacted.add_record_raw(record.key(), record.format(), record.raw())?;
+			*records += 1;
+		}
+		purge_compacted_files(compacted, dir, &db, major)?;
+	}
+	Ok(records)
+}
+
+/// Commit `compacted`, which has the records of `db`, and remove the files it replaces
+pub(crate) fn purge_compacted_files(
+	compacted: CreateTx,
+	dir: &Path,
+	db: &DatabaseReader,
+	major: bool,
+) -> std::io::Result<()> {
+	let source_transaction_paths = db.transaction_paths();
+
+	let removed_transaction_paths = if major {
+		compacted.commit_to(&dir.join("main"))?;
+		&source_transaction_paths[..]
+	} else {
+		// allow OS to atomically replace `first_path` (and don't delete it afterwards)
+		let keep_path = &source_transaction_paths.last().unwrap();
+
+		compacted.commit_to(keep_path)?;
+		&source_transaction_paths[..source_transaction_paths.len() - 1]
+	};
+
+	for txfile in removed_transaction_paths {
+		if txfile.file_name().expect("filename in txfile") == "main" {
+			continue;
+		}
+		if let Err(e) = std::fs::remove_file(txfile) {
+			eprintln!("warning: failed to remove {:?}: {}", txfile, e);
+		}
+	}
+
+	if major {
+		for txfile in db.delete_txes_paths() {
+			if let Err(e) = std::fs::remove_file(txfile) {
+				eprintln!("warning: failed to remove {:?}: {}", txfile, e);
+			}
+		}
+	}
+
+	Ok(())
+}
+
+/// When a database should be compacted
+///
+/// ```no_run
+/// let policy = sonnerie::CompactionPolicy::default();
+/// loop {
+///     if let Some(compaction) = policy.run(std::path::Path::new("db")).unwrap() {
+///         eprintln!("did a {} compaction", compaction);
+///     }
+///     std::thread::sleep(std::time::Duration::from_secs(60));
+/// }
+/// ```
+#[derive(Debug, Clone)]
+pub struct CompactionPolicy {
+	/// Do a minor compaction once there are more than this many transaction files,
+	/// and a major one once there are more than this many delete markers
+	pub max_transactions: usize,
+	/// Do a major compaction once the transaction files are bigger
+	/// than this fraction of `main` (0.1 is 10%)
+	pub max_transaction_ratio: f64,
+	/// Compress the compacted files with this codec
+	pub codec: Codec,
+}
+
+impl Default for CompactionPolicy {
+	fn default() -> CompactionPolicy {
+		CompactionPolicy {
+			max_transactions: 32,
+			max_transaction_ratio: 0.1,
+			codec: Codec::default(),
+		}
+	}
+}
+
+impl CompactionPolicy {
+	/// The compaction that the database in `dir` needs now, if any
+	pub fn check(&self, dir: &Path) -> std::io::Result<Option<Compaction>> {
+		let main = std::fs::metadata(dir.join("main"))?.len();
+		// delete markers aren't compacted, a major compaction removes them
+		let db = DatabaseReader::without_main_db(dir)?;
+		let deletes = db.delete_txes_paths().count();
+		let transactions = db.transaction_paths();
+		let transaction_bytes: u64 = transactions
+			.iter()
+			// it may have just been compacted
+			.filter_map(|path| std::fs::metadata(path).ok())
+			.map(|metadata| metadata.len())
+			.sum();
+
+		if !transactions.is_empty()
+			&& transaction_bytes as f64 > main as f64 * self.max_transaction_ratio
+			|| deletes > self.max_transactions
+		{
+			Ok(Some(Compaction::Major))
+		} else if transactions.len() > self.max_transactions {
+			Ok(Some(Compaction::Minor))
+		} else {
+			Ok(None)
+		}
+	}
+
+	/// Do the compaction that the database in `dir` needs, returning what it did,
+	/// or `None` if it didn't need one or there was nothing to compact
+	pub fn run(&self, dir: &Path) -> Result<Option<Compaction>, WriteFailure> {
+		let Some(compaction) = self.check(dir)? else {
+			return Ok(None);
+		};
+		Ok(compact_files(dir, compaction, self.codec)?.map(|_| compaction))
+	}
+}
@@ -3,11 +3,12 @@
 use crate::write::Writer;
 use std::io::{Seek, Write};
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 /// Create a transaction file in the specified db directory.
 ///
 /// Add new records with [`CreateTx::add_record`]. They must be
-/// in sorted order.
+/// in sorted order, or use a [`crate::SortingCreateTx`].
 ///
 /// After adding records, call [`CreateTx::commit`] which ensures
 /// the transaction is on disk. Not calling commit will
@@ -16,6 +17,40 @@ pub struct CreateTx {
 	writer: Writer<std::fs::File>,
 	tmp: tempfile_fast::PersistableTempFile,
 	dir: PathBuf,
+	schema: Option<crate::Schema>,
+	strict: Option<StrictFormats>,
+}
+
+/// The formats that [`CreateTx::set_strict`] checks records against
+struct StrictFormats {
+	db: Arc<crate::DatabaseReader>,
+	/// the last key that was added
+	key: Option<String>,
+	/// the format of `key`, from the database or the first record
+	/// of it in this transaction
+	format: Option<String>,
+}
+
+impl StrictFormats {
+	fn check(&mut self, key: &str, format: &str) -> Result<(), crate::WriteFailure> {
+		if self.key.as_deref() != Some(key) {
+			// the key's most recent record in the database
+			self.format = self.db.last_format(key).map_err(std::io::Error::from)?;
+			self.key = Some(key.to_owned());
+		}
+		match &self.format {
+			Some(existing) if existing != format => Err(crate::WriteFailure::FormatChanged {
+				key: key.to_owned(),
+				format: format.to_owned(),
+				existing: existing.clone(),
+			}),
+			Some(_) => Ok(()),
+			None => {
+				self.format = Some(format.to_owned());
+				Ok(())
+			}
+		}
+	}
 }
 
 impl CreateTx {
@@ -27,19 +62,74 @@ impl CreateTx {
 	/// On commit, the file is renamed to not have the ".tmp"
 	/// suffix.
 	pub fn new(dir: &Path) -> std::io::Result<CreateTx> {
+		Self::new_with_codec(dir, crate::Codec::default())
+	}
+
+	/// Open a transaction file as [`CreateTx::new`], but compress
+	/// it with a specific codec.
+	pub fn new_with_codec(dir: &Path, codec: crate::Codec) -> std::io::Result<CreateTx> {
 		let tmp = tempfile_fast::PersistableTempFile::new_in(dir)?;
 		let f = tmp.try_clone()?;
 
-		let writer = Writer::new(f);
+		let writer = Writer::with_codec(f, codec);
 
 		let tx = CreateTx {
 			writer,
 			tmp,
 			dir: dir.to_owned(),
+			schema: None,
+			strict: None,
 		};
 		Ok(tx)
 	}
 
+	/// Check each record that is added against `schema`.
+	///
+	/// Adding a record whose format isn't the format of its key's
+	/// schema then fails with [`crate::WriteFailure::SchemaViolation`].
+	/// Get the schema of a database with [`crate::Schema::load`]
+	/// or [`crate::DatabaseReader::schema`].
+	pub fn set_schema(&mut self, schema: crate::Schema) {
+		self.schema = Some(schema);
+	}
+
+	/// Reject records whose format isn't the format that their key already has.
+	///
+	/// A key's format is the format of its most recent record in `db` (its
+	/// last record in the newest transaction file that has it), or of its
+	/// first record in this transaction if it's a new key. Adding
+	/// a record of another format fails with [`crate::WriteFailure::FormatChanged`].
+	pub fn set_strict(&mut self, db: Arc<crate::DatabaseReader>) {
+		self.strict = Some(StrictFormats {
+			db,
+			key: None,
+			format: None,
+		});
+	}
+
+	/// The directory of the database
+	pub(crate) fn dir(&self) -> &Path {
+		&self.dir
+	}
+
+	/// Check the format of a record against the schema
+	pub(crate) fn check_schema(&self, key: &str, format: &str) -> Result<(), crate::WriteFailure> {
+		if let Some(schema) = &self.schema {
+			schema.check(key, format)?;
+		}
+		Ok(())
+	}
+
+	/// Check the format of a record against the schema and, in strict mode,
+	/// the key's format, which must be done in the order the records are added
+	fn check_format(&mut self, key: &str, format: &str) -> Result<(), crate::WriteFailure> {
+		self.check_schema(key, format)?;
+		if let Some(strict) = &mut self.strict {
+			strict.check(key, format)?;
+		}
+		Ok(())
+	}
+
 	/// Add a record with the given key, timestamp, and values.
 	///
 	/// The values can be encoded with the function [`crate::record()`]
@@ -74,6 +164,11 @@ impl CreateTx {
 		timestamp: chrono::NaiveDateTime,
 		values: impl crate::RecordBuilder,
 	) -> std::result::Result<(), crate::WriteFailure> {
+		if self.schema.is_some() || self.strict.is_some() {
+			let mut format = compact_str::CompactString::default();
+			values.format_str(&mut format);
+			self.check_format(key, &format)?;
+		}
 		self.writer.add_record(
 			key,
 			timestamp
@@ -102,6 +197,7 @@ impl CreateTx {
 		format: &str,
 		data: &[u8],
 	) -> std::result::Result<(), crate::write::WriteFailure> {
+		self.check_format(key, format)?;
 		self.writer.add_record_raw(key, format, data)
 	}
 
@@ -130,9 +226,9 @@ impl CreateTx {
 		let format = "\u{007f}";
 
 		let mut row_data = Vec::with_capacity(
-			first_key.as_bytes().len()
-				+ filter.as_bytes().len()
-				+ last_key.as_bytes().len()
+			first_key.len()
+				+ filter.len()
+				+ last_key.len()
 				+ 16 // length of two u64's
 				+ 27 // practical maximum length of three varints
 				+ 1, // the format
@@ -182,12 +278,11 @@ impl CreateTx {
 		let mut file = writer.finish()?;
 		file.flush()?;
 		let len = file.seek(std::io::SeekFrom::End(0))? as usize;
-		if len == 0 {
+		// an empty main replaces the old one, whose records may all have been deleted
+		if len == 0 && final_name.file_name().map(|n| n == "main") != Some(true) {
 			// don't create an empty transaction file
 			drop(file);
-			if final_name.file_name().map(|n| n == "main") != Some(true) {
-				let _ = std::fs::remove_file(final_name);
-			}
+			let _ = std::fs::remove_file(final_name);
 			return Ok(());
 		}
 		file.sync_all()?;
@@ -232,7 +327,7 @@ impl CreateTx {
 				.expect("duration_since epoch")
 				.as_nanos()
 				.try_into()
-				.map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+				.map_err(std::io::Error::other)?;
 
 			let n = format!("tx.{:016x}", timestamp);
 			let final_name = self.dir.join(n);
@@ -264,6 +359,29 @@ impl CreateTx {
 	}
 }
 
+/// Something that records can be added to, like [`CreateTx`] or [`crate::SortingCreateTx`]
+pub trait RecordSink {
+	/// Add a record with the given key, format, and payload,
+	/// like [`CreateTx::add_record_raw`]
+	fn add_record_raw(
+		&mut self,
+		key: &str,
+		format: &str,
+		data: &[u8],
+	) -> Result<(), crate::WriteFailure>;
+}
+
+impl RecordSink for CreateTx {
+	fn add_record_raw(
+		&mut self,
+		key: &str,
+		format: &str,
+		data: &[u8],
+	) -> Result<(), crate::WriteFailure> {
+		CreateTx::add_record_raw(self, key, format, data)
+	}
+}
+
 fn get_umask() -> Option<libc::mode_t> {
 	let s = std::fs::read_to_string("/proc/self/status").ok()?;
 	for line in s.split('\n') {
@@ -0,0 +1,279 @@
+//! Add records from CSV files that have a header row.
+
+use crate::external_sort::{ExternalSort, SortRow, SORT_MEMORY};
+use crate::row_format::parse_row_format;
+use crate::{CreateTx, Timestamp, WriteFailure};
+use byteorder::{BigEndian, WriteBytesExt};
+use std::io::Read;
+
+/// The number of rows that a format is inferred from
+const INFER_ROWS: usize = 1000;
+
+/// Which columns of a CSV file become the parts of a record
+#[derive(Debug, Clone)]
+pub struct CsvImport {
+	/// The header of the column of keys
+	pub key_column: String,
+	/// The header of the column of timestamps
+	pub time_column: String,
+	/// The headers of the columns of values, in order. By default, every
+	/// column that isn't the key or the time, in the order of the file.
+	pub value_columns: Option<Vec<String>>,
+	/// The format of the values. By default, each column is inferred from
+	/// the first rows as `I` if they're integers, `F` if they're numbers,
+	/// or otherwise `s`, and is nullable if any of them are empty.
+	pub format: Option<String>,
+	/// The strftime format of the timestamps. By default, they're
+	/// ISO-8601 (like `2024-01-31T14:30:00`).
+	pub timestamp_format: Option<String>,
+	/// The unit of timestamps that are integers since the epoch. Without
+	/// one, an integer isn't a timestamp.
+	pub timestamp_unit: Option<TimestampUnit>,
+	/// The byte between fields
+	pub delimiter: u8,
+}
+
+impl Default for CsvImport {
+	fn default() -> CsvImport {
+		CsvImport {
+			key_column: "key".to_owned(),
+			time_column: "time".to_owned(),
+			value_columns: None,
+			format: None,
+			timestamp_format: None,
+			timestamp_unit: None,
+			delimiter: b',',
+		}
+	}
+}
+
+/// The unit of a timestamp that is an integer since the epoch
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum TimestampUnit {
+	/// `s`
+	Seconds,
+	/// `ms`
+	Milliseconds,
+	/// `us`
+	Microseconds,
+	/// `ns`
+	Nanoseconds,
+}
+
+impl TimestampUnit {
+	fn nanos(&self) -> Timestamp {
+		match self {
+			TimestampUnit::Seconds => 1_000_000_000,
+			TimestampUnit::Milliseconds => 1_000_000,
+			TimestampUnit::Microseconds => 1_000,
+			TimestampUnit::Nanoseconds => 1,
+		}
+	}
+}
+
+/// Parses `s`, `ms`, `us` or `ns`
+impl std::str::FromStr for TimestampUnit {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"s" => Ok(TimestampUnit::Seconds),
+			"ms" => Ok(TimestampUnit::Milliseconds),
+			"us" => Ok(TimestampUnit::Microseconds),
+			"ns" => Ok(TimestampUnit::Nanoseconds),
+			s => Err(format!(
+				"unknown unit \"{}\", expected \"s\", \"ms\", \"us\" or \"ns\"",
+				s
+			)),
+		}
+	}
+}
+
+fn invalid(line: u64, message: impl std::fmt::Display) -> WriteFailure {
+	std::io::Error::new(
+		std::io::ErrorKind::InvalidData,
+		format!("line {}: {}", line, message),
+	)
+	.into()
+}
+
+fn csv_error(e: csv::Error) -> WriteFailure {
+	let line = e.position().map_or(0, |p| p.line());
+	invalid(line, e)
+}
+
+/// Parse the text of a timestamp as `format`, or as an integer of `unit`
+/// or ISO-8601 without one
+fn parse_timestamp(
+	text: &str,
+	format: Option<&str>,
+	unit: Option<TimestampUnit>,
+) -> Result<Timestamp, String> {
+	let not_timestamp = || format!("\"{}\" is not a timestamp", text);
+	let time = match format {
+		Some(format) => {
+			chrono::NaiveDateTime::parse_from_str(text, format).map_err(|_| not_timestamp())?
+		}
+		None => {
+			if let Ok(n) = text.parse::<Timestamp>() {
+				let unit = unit.ok_or_else(|| {
+					format!("\"{}\" is an integer, but there's no timestamp unit", text)
+				})?;
+				return n.checked_mul(unit.nanos()).ok_or_else(not_timestamp);
+			}
+			if let Ok(t) = chrono::DateTime::parse_from_rfc3339(text) {
+				t.naive_utc()
+			} else if let Ok(t) =
+				chrono::NaiveDateTime::parse_from_str(text, "%Y-%m-%dT%H:%M:%S%.f")
+			{
+				t
+			} else if let Ok(t) =
+				chrono::NaiveDateTime::parse_from_str(text, "%Y-%m-%d %H:%M:%S%.f")
+			{
+				t
+			} else {
+				chrono::NaiveDate::parse_from_str(text, "%Y-%m-%d")
+					.map_err(|_| not_timestamp())?
+					.and_hms_opt(0, 0, 0)
+					.unwrap()
+			}
+		}
+	};
+	time.and_utc()
+		.timestamp_nanos_opt()
+		.and_then(|t| Timestamp::try_from(t).ok())
+		.ok_or_else(not_timestamp)
+}
+
+/// The format of columns that have the values of `rows`
+fn infer_format(rows: &[csv::StringRecord], columns: &[usize]) -> String {
+	let mut format = String::new();
+	for &c in columns {
+		let values = || rows.iter().map(|r| &r[c]).filter(|v| !v.is_empty());
+		let kind = if values().all(|v| v.parse::<i64>().is_ok()) {
+			"I"
+		} else if values().all(|v| v.parse::<f64>().is_ok()) {
+			"F"
+		} else {
+			"s"
+		};
+		if kind != "s" && rows.iter().any(|r| r[c].is_empty()) {
+			format.push('n');
+		}
+		format += kind;
+	}
+	format
+}
+
+/// Add the rows of a CSV file to `tx`, returning the number of records.
+///
+/// The first row of the file names the columns, which are mapped to the
+/// key, timestamp and values of records by `columns`. The rows can be in
+/// any order, they're sorted (on disk, if there are many) before they're
+/// added. A row with the same key and timestamp as an earlier row replaces it.
+///
+/// In a nullable column, an empty field is null.
+pub fn add_from_csv<R: Read>(
+	tx: &mut CreateTx,
+	input: R,
+	columns: &CsvImport,
+) -> Result<u64, WriteFailure> {
+	let mut reader = csv::ReaderBuilder::new()
+		.delimiter(columns.delimiter)
+		.from_reader(input);
+	let headers = reader.headers().map_err(csv_error)?.clone();
+	let position = |name: &str| {
+		headers
+			.iter()
+			.position(|h| h == name)
+			.ok_or_else(|| invalid(1, format!("there is no column \"{}\"", name)))
+	};
+	let key_column = position(&columns.key_column)?;
+	let time_column = position(&columns.time_column)?;
+	let value_columns: Vec<usize> = match &columns.value_columns {
+		Some(names) => names
+			.iter()
+			.map(|n| position(n))
+			.collect::<Result<_, _>>()?,
+		None => (0..headers.len())
+			.filter(|&c| c != key_column && c != time_column)
+			.collect(),
+	};
+
+	let mut records = reader.into_records();
+	let mut first_rows = vec![];
+	let format = match &columns.format {
+		Some(format) => format.clone(),
+		None => {
+			for record in records.by_ref().take(INFER_ROWS) {
+				first_rows.push(record.map_err(csv_error)?);
+			}
+			infer_format(&first_rows, &value_columns)
+		}
+	};
+	let layout: Vec<_> = crate::row_format::columns(format.as_bytes()).collect();
+	if !crate::row_format::is_valid_format(&format) || layout.len() != value_columns.len() {
+		return Err(invalid(
+			1,
+			format!(
+				"\"{}\" is not a format of {} columns",
+				format,
+				value_columns.len()
+			),
+		));
+	}
+	let row_format = parse_row_format(&format);
+
+	let mut sort = ExternalSort::new(tx.dir(), SORT_MEMORY);
+	for record in first_rows.into_iter().map(Ok).chain(records) {
+		let record = record.map_err(csv_error)?;
+		let line = record.position().map_or(0, |p| p.line());
+		let get = |c: usize| {
+			record
+				.get(c)
+				.ok_or_else(|| invalid(line, format!("there is no column {}", c + 1)))
+		};
+
+		let key = get(key_column)?;
+		let time = get(time_column)?;
+		let timestamp = parse_timestamp(
+			time,
+			columns.timestamp_format.as_deref(),
+			columns.timestamp_unit,
+		)
+		.map_err(|e| invalid(line, e))?;
+
+		let mut data = vec![];
+		data.write_u64::<BigEndian>(timestamp)?;
+		for ((&c, column), element) in value_columns.iter().zip(&layout).zip(row_format.elements())
+		{
+			let value = get(c)?;
+			let text = if column.nullable && value.is_empty() {
+				"-".into()
+			} else if column.nullable && value == "-" {
+				"\\-".into()
+			} else {
+				escape_string::escape(value)
+			};
+			let rest = element
+				.to_stored_format(&text, &mut data)
+				.map_err(|e| invalid(line, format!("column \"{}\": {}", &headers[c], e)))?;
+			if !rest.is_empty() {
+				return Err(invalid(line, format!("\"{}\" is not one value", value)));
+			}
+		}
+		sort.push(SortRow {
+			key: key.to_owned(),
+			format: format.clone(),
+			data,
+		})?;
+	}
+
+	let mut count = 0;
+	for row in sort.finish()? {
+		let row = row?;
+		tx.add_record_raw(&row.key, &row.format, &row.data)?;
+		count += 1;
+	}
+	Ok(count)
+}
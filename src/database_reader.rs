@@ -7,6 +7,8 @@ use std::path::{Path, PathBuf};
 use crate::key_reader::*;
 use crate::merge::Merge;
 use crate::segment_reader::DeleteMarker;
+use crate::write_buffer::BufferedRecords;
+use crate::ReadError;
 use crate::Record;
 use crate::Wildcard;
 use std::ops::Bound;
@@ -23,15 +25,48 @@ use crate::bykey::DatabaseKeyReader;
 /// very large number of transaction files.
 const MAX_FILES_TO_COMPACT: usize = 1000;
 
+/// A damaged part of a transaction file, as found by [`DatabaseReader::verify`]
+#[derive(Debug, Clone)]
+pub struct CorruptSegment {
+	/// the transaction file (or `main`)
+	pub path: PathBuf,
+	/// the byte offset of the segment in the file
+	pub offset: usize,
+	/// the first key the segment may contain
+	pub first_key: String,
+	/// the last key the segment may contain (empty if unknown)
+	pub last_key: String,
+	/// what is wrong with the segment
+	pub error: String,
+}
+
+impl std::fmt::Display for CorruptSegment {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		write!(
+			f,
+			"{}: offset {}, keys {:?} to {:?}: {}",
+			self.path.display(),
+			self.offset,
+			self.first_key,
+			self.last_key,
+			self.error
+		)
+	}
+}
+
 /// Read a database in key-timestamp sorted format.
 ///
 /// Open a database with [`new`](#method.new) and then [`get`](#method.get),
 /// [`get_filter`](#method.get_filter) or [`get_range`](#method.get_range) to select which keys to read.
 pub struct DatabaseReader {
-	_dir: PathBuf,
+	dir: PathBuf,
 	pub(crate) txes: Vec<(usize, PathBuf, Reader)>,
 	pub(crate) filter_out: Vec<(usize, PathBuf, DeleteMarker)>,
 	empty_files: Vec<PathBuf>,
+	/// loaded when it's first used, so a bad schema file only fails what needs it
+	schema: std::sync::OnceLock<crate::Schema>,
+	/// the records of a [`crate::WriteBuffer`], which are newer than any transaction
+	pub(crate) buffered: Option<std::sync::Arc<crate::write_buffer::BufferedMap>>,
 }
 
 impl DatabaseReader {
@@ -125,8 +160,10 @@ impl DatabaseReader {
 			return Ok(DatabaseReader {
 				txes,
 				filter_out,
-				_dir: dir.to_owned(),
+				dir: dir.to_owned(),
 				empty_files,
+				schema: Default::default(),
+				buffered: None,
 			});
 		}
 	}
@@ -141,6 +178,18 @@ impl DatabaseReader {
 		self.txes.len()
 	}
 
+	/// The names of the columns of keys, from the database's schema file.
+	///
+	/// The file is read the first time this is called, and fails if it
+	/// can't be read.
+	pub fn schema(&self) -> std::io::Result<&crate::Schema> {
+		if let Some(schema) = self.schema.get() {
+			return Ok(schema);
+		}
+		let schema = crate::Schema::load(&self.dir)?;
+		Ok(self.schema.get_or_init(|| schema))
+	}
+
 	/// Get the filenames of each transaction.
 	///
 	/// This is useful for compacting, because after
@@ -156,6 +205,100 @@ impl DatabaseReader {
 		self.txes.iter().map(|(_, e, _)| e.clone()).collect()
 	}
 
+	/// The delete markers that can apply to records within `timestamps`
+	pub(crate) fn filter_out_for(
+		&self,
+		timestamps: &crate::TimestampRange,
+	) -> Vec<(usize, DeleteMarkerPrecomputed<'_>)> {
+		self.filter_out
+			.iter()
+			.map(|(txid, _path, dm)| (*txid, DeleteMarkerPrecomputed::from_delete_marker(dm)))
+			.filter(|(_, dm)| dm.overlaps_timestamps(timestamps))
+			.collect()
+	}
+
+	/// The delete markers that can apply to records within `timestamps`,
+	/// with the time that each was committed (in nanoseconds since the epoch).
+	///
+	/// Buffered records are only deleted by markers that were committed after
+	/// they were buffered.
+	pub(crate) fn filter_out_by_time(
+		&self,
+		timestamps: &crate::TimestampRange,
+	) -> Vec<(crate::Timestamp, DeleteMarkerPrecomputed<'_>)> {
+		self.filter_out
+			.iter()
+			.map(|(_txid, path, dm)| {
+				(
+					committed_at(path),
+					DeleteMarkerPrecomputed::from_delete_marker(dm),
+				)
+			})
+			.filter(|(_, dm)| dm.overlaps_timestamps(timestamps))
+			.collect()
+	}
+
+	/// The format of the most recent record of `key`, which is the last
+	/// record of the key in the newest transaction that has it
+	pub(crate) fn last_format(&self, key: &str) -> Result<Option<String>, ReadError> {
+		for (txid, _path, reader) in self.txes.iter().rev() {
+			if !reader.segments.may_contain(key) {
+				continue;
+			}
+			let Some((format, timestamp)) = reader.segments.last_of(key)? else {
+				continue;
+			};
+			let time = chrono::DateTime::from_timestamp_nanos(timestamp as i64).naive_utc();
+			let deleted = self
+				.filter_out
+				.iter()
+				.filter(|(del_txid, _, _)| txid < del_txid)
+				.any(|(_, _, dm)| {
+					DeleteMarkerPrecomputed::from_delete_marker(dm).deletes_at(key, time)
+				});
+			if deleted {
+				// an earlier record may not have been deleted
+				return Ok(self
+					.get(key)
+					.into_iter()
+					.last()
+					.map(|r| r.format().to_owned()));
+			}
+			return Ok(Some(format));
+		}
+		Ok(None)
+	}
+
+	/// Check every segment of `main` and of each transaction file.
+	///
+	/// Returns the segments that are corrupt, an empty `Vec` means
+	/// the database is intact.
+	pub fn verify(&self) -> std::io::Result<Vec<CorruptSegment>> {
+		let mut corrupt = vec![];
+
+		let mut verify = |path: &Path, segments: &crate::segment_reader::SegmentReader| {
+			segments.verify(|offset, first_key, last_key, error| {
+				corrupt.push(CorruptSegment {
+					path: path.to_owned(),
+					offset,
+					first_key: first_key.to_owned(),
+					last_key: last_key.to_owned(),
+					error,
+				})
+			});
+		};
+
+		for (_, path, reader) in &self.txes {
+			verify(path, &reader.segments);
+		}
+		for (_, path, _) in &self.filter_out {
+			let segments = crate::segment_reader::SegmentReader::map(&mut File::open(path)?)?;
+			verify(path, &segments);
+		}
+
+		Ok(corrupt)
+	}
+
 	/// Get the filenames of the transactions that have a delete marker in them.
 	pub fn delete_txes_paths(&self) -> impl Iterator<Item = &Path> {
 		self.filter_out.iter().map(|(_, path, _)| &**path)
@@ -171,6 +314,7 @@ impl DatabaseReader {
 			matcher: None,
 			prefix: "",
 			range: crate::disassemble_range_bound(key..=key).into(),
+			timestamps: (Bound::Unbounded, Bound::Unbounded),
 		}
 	}
 
@@ -191,6 +335,7 @@ impl DatabaseReader {
 			matcher: None,
 			prefix: "",
 			range: crate::disassemble_range_bound(range).into(),
+			timestamps: (Bound::Unbounded, Bound::Unbounded),
 		}
 	}
 
@@ -226,6 +371,7 @@ impl DatabaseReader {
 			matcher: None,
 			prefix: "",
 			range: crate::disassemble_range_bound(range).into(),
+			timestamps: (Bound::Unbounded, Bound::Unbounded),
 		}
 	}
 
@@ -240,6 +386,7 @@ impl DatabaseReader {
 				matcher: wildcard.as_regex(),
 				prefix: wildcard.prefix(),
 				range: crate::disassemble_range_bound(wildcard.prefix()..=wildcard.prefix()).into(),
+				timestamps: (Bound::Unbounded, Bound::Unbounded),
 			}
 		} else {
 			DatabaseRecordReader {
@@ -247,6 +394,7 @@ impl DatabaseReader {
 				matcher: wildcard.as_regex(),
 				prefix: wildcard.prefix(),
 				range: crate::disassemble_range_bound(wildcard.prefix()..).into(),
+				timestamps: (Bound::Unbounded, Bound::Unbounded),
 			}
 		}
 	}
@@ -266,6 +414,7 @@ impl DatabaseReader {
 				matcher: wildcard.as_regex(),
 				prefix: wildcard.prefix(),
 				range: crate::disassemble_range_bound(wildcard.prefix()..=wildcard.prefix()).into(),
+				timestamps: (Bound::Unbounded, Bound::Unbounded),
 			}
 		} else {
 			DatabaseKeyReader {
@@ -273,6 +422,7 @@ impl DatabaseReader {
 				matcher: wildcard.as_regex(),
 				prefix: wildcard.prefix(),
 				range: crate::disassemble_range_bound(wildcard.prefix()..).into(),
+				timestamps: (Bound::Unbounded, Bound::Unbounded),
 			}
 		}
 	}
@@ -290,9 +440,25 @@ pub struct DatabaseRecordReader<'d> {
 	matcher: Option<regex::Regex>,
 	prefix: &'d str,
 	range: crate::CowStringRange<'d>,
+	timestamps: crate::TimestampRange,
 }
 
 impl<'d> DatabaseRecordReader<'d> {
+	/// Only read records whose timestamps are in this range
+	///
+	/// Example: `rdr.get_range(..).with_time_range(after..before)`
+	///
+	/// Segments that are entirely outside of the range are skipped
+	/// without being decompressed, so restricting a query to a
+	/// recent period of time is much faster than filtering every record.
+	pub fn with_time_range(
+		mut self,
+		timestamps: impl std::ops::RangeBounds<crate::Timestamp>,
+	) -> DatabaseRecordReader<'d> {
+		self.timestamps = crate::disassemble_range_bound(timestamps);
+		self
+	}
+
 	pub(crate) fn check(&self) {
 		match (self.range.start_bound(), self.range.end_bound()) {
 			(Bound::Unbounded, _) => {}
@@ -311,8 +477,14 @@ impl<'d> DatabaseRecordReader<'d> {
 			.txes
 			.iter()
 			.map(|tx| {
-				let filter =
-					tx.2.get_filter_range(self.matcher.clone(), self.prefix, self.range.clone());
+				// errors are reported when the halves are read
+				let filter = tx.2.get_filter_range(
+					self.matcher.clone(),
+					self.prefix,
+					self.range.clone(),
+					self.timestamps,
+					Some((tx.0, Default::default())),
+				);
 				let b = filter.compressed_bytes();
 				(filter, b)
 			})
@@ -350,6 +522,7 @@ impl<'d> DatabaseRecordReader<'d> {
 				Bound::Included(middle_start_key.to_owned()),
 			)
 				.into(),
+			timestamps: self.timestamps,
 		};
 		first_half.check();
 		if let Bound::Included(e) = self.range.start_bound() {
@@ -374,6 +547,7 @@ impl<'d> DatabaseRecordReader<'d> {
 				crate::bound_deep_copy(self.range.end_bound()),
 			)
 				.into(),
+			timestamps: self.timestamps,
 		};
 
 		if let Bound::Excluded(e) = self.range.end_bound() {
@@ -388,20 +562,123 @@ impl<'d> DatabaseRecordReader<'d> {
 	}
 }
 
-impl<'d> IntoIterator for DatabaseRecordReader<'d> {
-	type Item = Record;
-	type IntoIter = DatabaseRecordIterator<'d>;
+/// When the transaction file at `path` was committed, from its name
+/// ("tx." and the nanoseconds since the epoch in hex), or the end
+/// of time if it isn't named like that
+fn committed_at(path: &Path) -> crate::Timestamp {
+	path.file_name()
+		.and_then(|name| name.to_str())
+		.and_then(|name| name.strip_prefix("tx."))
+		.and_then(|time| crate::Timestamp::from_str_radix(time, 16).ok())
+		.unwrap_or(crate::Timestamp::MAX)
+}
 
-	fn into_iter(self) -> Self::IntoIter {
+/// The records of a transaction file, or the buffered records
+pub(crate) type RecordSource<'d> = Either<StringKeyRangeReader<'d, 'd>, BufferedRecords<'d>>;
+
+/// The source index of buffered records in a [`Merge`], they're newer than
+/// the records of every transaction. They're deleted by the markers that were
+/// committed after they were buffered (see [`BufferedRecords`]) instead of by txid.
+pub(crate) const BUFFERED_TXID: usize = usize::MAX;
+
+impl<'d> DatabaseRecordReader<'d> {
+	/// Iterate over the records, yielding an error for each segment
+	/// that can't be read (and skipping it) instead of panicking.
+	///
+	/// ```no_run
+	/// # let db = sonnerie::DatabaseReader::new(std::path::Path::new("")).unwrap();
+	/// for record in db.get_range(..).into_try_iter() {
+	///     match record {
+	///         Ok(record) => println!("{}", record.key()),
+	///         Err(e) => eprintln!("skipping: {}", e),
+	///     }
+	/// }
+	/// ```
+	pub fn into_try_iter(self) -> DatabaseTryRecordIterator<'d> {
+		let errors = ReadErrors::default();
+		DatabaseTryRecordIterator {
+			db: self.db,
+			records: self.records(Some(&errors)),
+			errors,
+			next_record: None,
+		}
+	}
+
+	/// Like [`DatabaseRecordReader::into_try_iter`], but a Rayon parallel iterator
+	///
+	/// ```no_run
+	/// # let db = sonnerie::DatabaseReader::new(std::path::Path::new("")).unwrap();
+	/// use rayon::prelude::*;
+	/// let errors = db.get_range(..).into_par_try_iter().filter(|r| r.is_err()).count();
+	/// ```
+	pub fn into_par_try_iter(self) -> DatabaseTryRecordReader<'d> {
+		DatabaseTryRecordReader { reader: self }
+	}
+
+	/// Collect the records into a [`ColumnBatch`](crate::ColumnBatch) per key,
+	/// with a vector of values for each column.
+	///
+	/// A key whose format changes gets a new batch for each format.
+	/// A record that can't be decoded is an error.
+	///
+	/// ```no_run
+	/// # let db = sonnerie::DatabaseReader::new(std::path::Path::new("")).unwrap();
+	/// for batch in db.get_filter(&sonnerie::Wildcard::new("sensor.%")).into_batches() {
+	///     let batch = batch.unwrap();
+	///     if let sonnerie::ColumnValues::F64(values) = &batch.columns[0] {
+	///         let mean = values.iter().sum::<f64>() / values.len() as f64;
+	///         println!("{} {}", batch.key, mean);
+	///     }
+	/// }
+	/// ```
+	pub fn into_batches(self) -> crate::ColumnBatches<DatabaseRecordIterator<'d>> {
+		crate::ColumnBatches::new(self)
+	}
+
+	fn buffered_records(
+		&self,
+		buffered: &'d crate::write_buffer::BufferedMap,
+	) -> BufferedRecords<'d> {
+		BufferedRecords::new(
+			self.db,
+			buffered,
+			self.matcher.clone(),
+			self.prefix,
+			&self.range,
+			self.timestamps,
+		)
+	}
+
+	fn records(self, errors: Option<&ReadErrors>) -> DatabaseRecordIterator<'d> {
 		self.check();
 
 		let mut readers = Vec::with_capacity(self.db.txes.len());
 
-		for (txid, _path, reader) in self.db.txes.iter() {
-			let iter =
-				reader.get_filter_range(self.matcher.clone(), self.prefix, self.range.clone());
+		// a lookup of one key can skip the files that don't have it
+		let exact_key = match (self.range.start_bound(), self.range.end_bound()) {
+			(Bound::Included(a), Bound::Included(b)) if a == b && self.matcher.is_none() => Some(a),
+			_ => None,
+		};
 
-			readers.push((*txid, iter));
+		for (txid, _path, reader) in self.db.txes.iter() {
+			if exact_key.is_some_and(|key| !reader.segments.may_contain(key)) {
+				continue;
+			}
+			let iter = reader.get_filter_range(
+				self.matcher.clone(),
+				self.prefix,
+				self.range.clone(),
+				self.timestamps,
+				errors.map(|e| (*txid, e.clone())),
+			);
+
+			readers.push((*txid, Either::Left(iter)));
+		}
+		if let Some(buffered) = &self.db.buffered {
+			readers.push((
+				BUFFERED_TXID,
+				Either::Right(self.buffered_records(buffered)),
+			));
 		}
 		let merge = Merge::new(readers, |a, b| {
 			a.key()
@@ -409,12 +686,7 @@ impl<'d> IntoIterator for DatabaseRecordReader<'d> {
 				.then_with(|| a.timestamp_nanos().cmp(&b.timestamp_nanos()))
 		});
 
-		let filter_out: Vec<_> = self
-			.db
-			.filter_out
-			.iter()
-			.map(|(txid, _path, dm)| (*txid, DeleteMarkerPrecomputed::from_delete_marker(dm)))
-			.collect();
+		let filter_out = self.db.filter_out_for(&self.timestamps);
 
 		DatabaseRecordIterator {
 			filter_out,
@@ -423,13 +695,89 @@ impl<'d> IntoIterator for DatabaseRecordReader<'d> {
 	}
 }
 
+impl<'d> IntoIterator for DatabaseRecordReader<'d> {
+	type Item = Record;
+	type IntoIter = DatabaseRecordIterator<'d>;
+
+	/// Iterate over the records, panicking if a segment can't be read
+	/// (see [`DatabaseRecordReader::into_try_iter`])
+	fn into_iter(self) -> Self::IntoIter {
+		self.records(None)
+	}
+}
+
+/// An iterator over the filtered keys in a database that
+/// reports damaged segments.
+///
+/// Yields `Ok(`[`Record`]`)` for each row in the database, sorted by
+/// key and timestamp, and a [`ReadError`] for each segment that was skipped.
+pub struct DatabaseTryRecordIterator<'d> {
+	db: &'d DatabaseReader,
+	records: DatabaseRecordIterator<'d>,
+	errors: ReadErrors,
+	next_record: Option<Record>,
+}
+
+impl<'d> DatabaseTryRecordIterator<'d> {
+	fn next_error(&self) -> Option<ReadError> {
+		take_error(self.db, &self.errors)
+	}
+}
+
+/// Take the first of `errors`, with the path of the transaction file it's from
+pub(crate) fn take_error(db: &DatabaseReader, errors: &ReadErrors) -> Option<ReadError> {
+	let mut errors = errors.lock();
+	if errors.is_empty() {
+		return None;
+	}
+	let (txid, mut e) = errors.remove(0);
+	if let ReadError::CorruptSegment { path, .. } = &mut e {
+		*path = db
+			.txes
+			.iter()
+			.find(|tx| tx.0 == txid)
+			.map(|tx| tx.1.clone());
+	}
+	Some(e)
+}
+
+impl<'d> Iterator for DatabaseTryRecordIterator<'d> {
+	type Item = Result<Record, ReadError>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		if let Some(e) = self.next_error() {
+			return Some(Err(e));
+		}
+		if let Some(record) = self.next_record.take() {
+			return Some(Ok(record));
+		}
+
+		let record = self.records.next();
+		// reading may have skipped segments, report them first
+		if let Some(e) = self.next_error() {
+			self.next_record = record;
+			return Some(Err(e));
+		}
+		record.map(Ok)
+	}
+}
+
+/// A parallel iterator over the filtered keys in a database that reports
+/// damaged segments, made by [`DatabaseRecordReader::into_par_try_iter`]
+///
+/// Yields `Ok(`[`Record`]`)` for each row in the database, and a [`ReadError`]
+/// for each segment that was skipped.
+pub struct DatabaseTryRecordReader<'d> {
+	pub(crate) reader: DatabaseRecordReader<'d>,
+}
+
 /// An iterator over the filtered keys in a database.
 ///
 /// Yields an [`Record`](record/struct.Record.html)
 /// for each row in the database, sorted by key and timestamp.
 pub struct DatabaseRecordIterator<'d> {
 	filter_out: Vec<(usize, DeleteMarkerPrecomputed<'d>)>,
-	merge: Box<Merge<StringKeyRangeReader<'d, 'd>, Record>>,
+	merge: Box<Merge<RecordSource<'d>, Record>>,
 }
 
 pub(crate) struct DeleteMarkerPrecomputed<'a> {
@@ -461,6 +809,53 @@ impl<'a> DeleteMarkerPrecomputed<'a> {
 		}
 	}
 
+	/// returns false if this marker can't delete any record within `timestamps`
+	pub(crate) fn overlaps_timestamps(&self, timestamps: &crate::TimestampRange) -> bool {
+		// timestamps that can't be represented are past the end of time
+		let nanos = |t: NaiveDateTime| {
+			t.and_utc()
+				.timestamp_nanos_opt()
+				.map_or(crate::Timestamp::MAX, |n| n as crate::Timestamp)
+		};
+		// the marker deletes `first_timestamp .. last_timestamp`
+		let first = nanos(self.first_timestamp);
+		let last = nanos(self.last_timestamp);
+
+		let after_start = match timestamps.0 {
+			Bound::Included(t) => t < last,
+			Bound::Excluded(t) => t.saturating_add(1) < last,
+			Bound::Unbounded => first < last,
+		};
+		let before_end = match timestamps.1 {
+			Bound::Included(t) => t >= first,
+			Bound::Excluded(t) => t > first,
+			Bound::Unbounded => true,
+		};
+		after_start && before_end
+	}
+
+	/// returns true if this marker deletes `record`
+	pub(crate) fn deletes(&self, record: &Record) -> bool {
+		self.deletes_at(record.key(), record.time())
+	}
+
+	/// returns true if this marker deletes a record of `key` at `time`
+	pub(crate) fn deletes_at(&self, key: &str, time: NaiveDateTime) -> bool {
+		// check if the record's timestamp is within filtering out
+		if !(self.first_timestamp..self.last_timestamp).contains(&time) {
+			return false;
+		}
+
+		if self.first_key > key {
+			return false;
+		}
+		if !self.last_key.is_empty() && key >= self.last_key {
+			return false;
+		}
+
+		self.wildcard_matches(key)
+	}
+
 	pub(crate) fn wildcard_matches(&self, key: &str) -> bool {
 		use Either::*;
 
@@ -482,31 +877,10 @@ impl<'d> Iterator for DatabaseRecordIterator<'d> {
 				// select only transactions that are indexed lower than the
 				// delete transaction
 				.filter(|(del_txid, _)| txid < *del_txid)
-				// check if the record's timestamp is within filtering out
-				// this assumes that the filter_out is sorted ascending by
-				// first timestamp (which should have been done in
-				// DatabaseReader::new())
-				.filter(|(_, filter)| {
-					let record_time = record.time();
-					(filter.first_timestamp..filter.last_timestamp).contains(&record_time)
-				})
-				.filter(|(_, filter)| record.time() <= filter.last_timestamp)
 				// if any of the filters went here (i.e. any() returns a true),
 				// then that means that filter found one filter that filters out
 				// the current record. that should be discarded
-				.any(|(_, filter)| {
-					let key = record.key();
-
-					if filter.first_key > key {
-						return false;
-					}
-
-					if !filter.last_key.is_empty() && key >= filter.last_key {
-						return false;
-					}
-
-					filter.wildcard_matches(key)
-				});
+				.any(|(_, filter)| filter.deletes(&record));
 
 			if !is_filtered_out {
 				return Some(record);
@@ -0,0 +1,146 @@
+//! A fixed-point decimal number, stored in `d` columns.
+
+/// A decimal number, `mantissa / 10^scale`
+///
+/// This is what a `d` column stores, for example a column of format
+/// `d4` stores `12.5` as a mantissa of `125000`. Since the scale is
+/// part of the format, decimals are exact and each value of a column
+/// has the same number of decimal places.
+///
+/// Store the values of a nullable column (`nd`) as [`NullableDecimal`]s.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Decimal {
+	/// The value times `10^scale`
+	pub mantissa: i64,
+	/// The number of decimal places, at most [`MAX_DECIMAL_SCALE`]
+	pub scale: u8,
+}
+
+/// The largest scale, more decimal places than this don't fit in an `i64`
+pub const MAX_DECIMAL_SCALE: u8 = 18;
+
+impl Decimal {
+	/// The decimal `mantissa / 10^scale`
+	///
+	/// Panics if `scale` is more than [`MAX_DECIMAL_SCALE`].
+	pub fn new(mantissa: i64, scale: u8) -> Decimal {
+		assert!(
+			scale <= MAX_DECIMAL_SCALE,
+			"the scale of a decimal can't be more than {}",
+			MAX_DECIMAL_SCALE
+		);
+		Decimal { mantissa, scale }
+	}
+
+	/// Parse `text` (like `-12.5`) as a decimal with `scale` decimal places
+	///
+	/// Fails if `text` has more decimal places than `scale`,
+	/// it's never rounded.
+	pub fn parse_with_scale(text: &str, scale: u8) -> Result<Decimal, String> {
+		let invalid = || format!("\"{}\" is not a decimal with scale {}", text, scale);
+		if scale > MAX_DECIMAL_SCALE {
+			return Err(invalid());
+		}
+
+		let (negative, digits) = match text.strip_prefix('-') {
+			Some(d) => (true, d),
+			None => (false, text),
+		};
+		let (whole, fraction) = digits.split_once('.').unwrap_or((digits, ""));
+		if whole.is_empty() && fraction.is_empty()
+			|| fraction.len() > scale as usize
+			|| !whole
+				.bytes()
+				.chain(fraction.bytes())
+				.all(|c| c.is_ascii_digit())
+		{
+			return Err(invalid());
+		}
+
+		let mut mantissa = 0i64;
+		let padding = std::iter::repeat_n(b'0', scale as usize - fraction.len());
+		for c in whole.bytes().chain(fraction.bytes()).chain(padding) {
+			let digit = (c - b'0') as i64;
+			mantissa = mantissa
+				.checked_mul(10)
+				.and_then(|m| {
+					if negative {
+						m.checked_sub(digit)
+					} else {
+						m.checked_add(digit)
+					}
+				})
+				.ok_or_else(invalid)?;
+		}
+		Ok(Decimal { mantissa, scale })
+	}
+
+	/// The nearest `f64`, which may not be exact
+	pub fn to_f64(&self) -> f64 {
+		self.mantissa as f64 / 10f64.powi(self.scale as i32)
+	}
+}
+
+/// A value of a nullable decimal column (`nd`), which has a scale even when it's null
+///
+/// An `Option<Decimal>` can be read from the column, but it can't be stored:
+/// a `None` wouldn't have the scale that's part of the column's format.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct NullableDecimal {
+	/// The value times `10^scale`, or `None` if it's null
+	pub mantissa: Option<i64>,
+	/// The number of decimal places, at most [`MAX_DECIMAL_SCALE`]
+	pub scale: u8,
+}
+
+impl NullableDecimal {
+	/// A null in a column with `scale` decimal places
+	pub fn null(scale: u8) -> NullableDecimal {
+		NullableDecimal {
+			mantissa: None,
+			scale,
+		}
+	}
+
+	/// The decimal, or `None` if it's null
+	pub fn get(&self) -> Option<Decimal> {
+		self.mantissa
+			.map(|mantissa| Decimal::new(mantissa, self.scale))
+	}
+}
+
+impl From<Decimal> for NullableDecimal {
+	fn from(d: Decimal) -> NullableDecimal {
+		NullableDecimal {
+			mantissa: Some(d.mantissa),
+			scale: d.scale,
+		}
+	}
+}
+
+/// Parses a decimal with as many decimal places as it has
+impl std::str::FromStr for Decimal {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let scale = s.split_once('.').map_or(0, |(_, f)| f.len());
+		Decimal::parse_with_scale(s, scale.try_into().unwrap_or(u8::MAX))
+	}
+}
+
+/// Prints all of the decimal places, even trailing zeros
+impl std::fmt::Display for Decimal {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		let sign = if self.mantissa < 0 { "-" } else { "" };
+		let digits = self.mantissa.unsigned_abs().to_string();
+		let scale = self.scale as usize;
+		if scale == 0 {
+			write!(f, "{}{}", sign, digits)
+		} else if digits.len() > scale {
+			let (whole, fraction) = digits.split_at(digits.len() - scale);
+			write!(f, "{}{}.{}", sign, whole, fraction)
+		} else {
+			write!(f, "{}0.{:0>scale$}", sign, digits, scale = scale)
+		}
+	}
+}
@@ -0,0 +1,413 @@
+//! Sort records that may not fit in memory, for adding to a transaction.
+//!
+//! Records are sorted in memory until they use too much of it, then
+//! that sorted run is written to a temporary file. The runs are merged
+//! when the records are read back.
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+
+/// How much memory the records of a run may use by default
+pub(crate) const SORT_MEMORY: usize = 256 * 1024 * 1024;
+
+/// A record in the form that [`crate::CreateTx::add_record_raw`] takes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct SortRow {
+	pub key: String,
+	pub format: String,
+	/// the timestamp followed by the values
+	pub data: Vec<u8>,
+}
+
+impl SortRow {
+	fn timestamp(&self) -> &[u8] {
+		&self.data[0..crate::records::TIMESTAMP_SIZE]
+	}
+
+	fn same_record(&self, other: &SortRow) -> bool {
+		self.key == other.key && self.timestamp() == other.timestamp()
+	}
+
+	/// the memory this uses, roughly
+	fn size(&self) -> usize {
+		std::mem::size_of::<SortRow>() + self.key.len() + self.format.len() + self.data.len()
+	}
+
+	pub(crate) fn write(&self, to: &mut impl Write) -> std::io::Result<()> {
+		for field in [self.key.as_bytes(), self.format.as_bytes(), &self.data] {
+			to.write_u32::<BigEndian>(field.len() as u32)?;
+			to.write_all(field)?;
+		}
+		Ok(())
+	}
+
+	pub(crate) fn read(from: &mut impl Read) -> std::io::Result<Option<SortRow>> {
+		let read_field = |from: &mut dyn Read| -> std::io::Result<Vec<u8>> {
+			let mut field = vec![0; from.read_u32::<BigEndian>()? as usize];
+			from.read_exact(&mut field)?;
+			Ok(field)
+		};
+		let key = match read_field(from) {
+			Ok(key) => key,
+			Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+			Err(e) => return Err(e),
+		};
+		let utf8 = |s: Vec<u8>| {
+			String::from_utf8(s)
+				.map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+		};
+		Ok(Some(SortRow {
+			key: utf8(key)?,
+			format: utf8(read_field(from)?)?,
+			data: read_field(from)?,
+		}))
+	}
+}
+
+/// Sorts records by key and timestamp
+pub(crate) struct ExternalSort {
+	dir: PathBuf,
+	memory: usize,
+	rows: Vec<SortRow>,
+	size: usize,
+	runs: Vec<Run>,
+}
+
+/// The sorted runs of an [`ExternalSort`], to merge into another one
+pub(crate) struct Runs {
+	runs: Vec<Run>,
+	/// the memory that the runs in memory use
+	size: usize,
+}
+
+impl ExternalSort {
+	/// Sort with runs of up to `memory` bytes, written to temporary files in `dir`
+	pub(crate) fn new(dir: &Path, memory: usize) -> ExternalSort {
+		ExternalSort {
+			dir: dir.to_owned(),
+			memory,
+			rows: vec![],
+			size: 0,
+			runs: vec![],
+		}
+	}
+
+	/// Write runs when the rows in memory use `memory` bytes
+	pub(crate) fn set_memory(&mut self, memory: usize) {
+		self.memory = memory;
+	}
+
+	/// Write runs to temporary files in `dir`
+	pub(crate) fn set_dir(&mut self, dir: &Path) {
+		self.dir = dir.to_owned();
+	}
+
+	pub(crate) fn push(&mut self, row: SortRow) -> std::io::Result<()> {
+		self.size += row.size();
+		self.rows.push(row);
+		if self.size >= self.memory {
+			self.spill()?;
+		}
+		Ok(())
+	}
+
+	/// write the rows in memory to a new run
+	fn spill(&mut self) -> std::io::Result<()> {
+		sort(&mut self.rows);
+		let file = write_run(&self.dir, &self.rows)?;
+		self.rows.clear();
+		self.runs.push(Run::File(file));
+		self.size = 0;
+		Ok(())
+	}
+
+	/// True if no rows were pushed or appended
+	pub(crate) fn is_empty(&self) -> bool {
+		self.rows.is_empty() && self.runs.is_empty()
+	}
+
+	/// Sort the rows in memory and take every run, without writing them
+	pub(crate) fn into_runs(mut self) -> Runs {
+		if !self.rows.is_empty() {
+			sort(&mut self.rows);
+			self.runs.push(Run::Memory(self.rows.into_iter()));
+		}
+		Runs {
+			runs: self.runs,
+			size: self.size,
+		}
+	}
+
+	/// Merge the runs of another sort, as if its rows were pushed now.
+	///
+	/// Once the runs in memory use `memory` bytes, they're written
+	/// to temporary files. If that fails, `runs` aren't merged.
+	pub(crate) fn append(&mut self, mut runs: Runs) -> std::io::Result<()> {
+		// the rows in memory were pushed before them
+		if !self.rows.is_empty() {
+			sort(&mut self.rows);
+			let rows = std::mem::take(&mut self.rows);
+			self.runs.push(Run::Memory(rows.into_iter()));
+		}
+		if self.size + runs.size >= self.memory {
+			write_memory_runs(&self.dir, &mut runs.runs)?;
+			write_memory_runs(&self.dir, &mut self.runs)?;
+			self.size = 0;
+		} else {
+			self.size += runs.size;
+		}
+		self.runs.extend(runs.runs);
+		Ok(())
+	}
+
+	/// The rows in order of key, then timestamp.
+	///
+	/// Of the rows with the same key and timestamp, only the one
+	/// that was pushed last is kept.
+	pub(crate) fn finish(mut self) -> std::io::Result<SortedRows> {
+		sort(&mut self.rows);
+		let mut runs = self.runs;
+		runs.push(Run::Memory(self.rows.into_iter()));
+
+		let mut heap = BinaryHeap::new();
+		for (n, run) in runs.iter_mut().enumerate() {
+			if let Some(row) = run.next()? {
+				heap.push(Reverse(Head { row, run: n }));
+			}
+		}
+		Ok(SortedRows {
+			runs,
+			heap,
+			pending: None,
+		})
+	}
+}
+
+/// sort stably, so that rows with the same key and timestamp stay in the order they were pushed
+fn sort(rows: &mut [SortRow]) {
+	rows.sort_by(|a, b| {
+		a.key
+			.as_bytes()
+			.cmp(b.key.as_bytes())
+			.then_with(|| a.timestamp().cmp(b.timestamp()))
+	});
+}
+
+/// write sorted rows to a temporary file in `dir`
+fn write_run(dir: &Path, rows: &[SortRow]) -> std::io::Result<BufReader<File>> {
+	let mut file = BufWriter::new(tempfile::tempfile_in(dir)?);
+	for row in rows {
+		row.write(&mut file)?;
+	}
+	let mut file = file.into_inner().map_err(|e| e.into_error())?;
+	file.rewind()?;
+	Ok(BufReader::new(file))
+}
+
+/// write each run in memory to a temporary file in `dir`, in its place
+fn write_memory_runs(dir: &Path, runs: &mut [Run]) -> std::io::Result<()> {
+	for run in runs {
+		if let Run::Memory(rows) = run {
+			*run = Run::File(write_run(dir, rows.as_slice())?);
+		}
+	}
+	Ok(())
+}
+
+enum Run {
+	File(BufReader<File>),
+	Memory(std::vec::IntoIter<SortRow>),
+}
+
+impl Run {
+	fn next(&mut self) -> std::io::Result<Option<SortRow>> {
+		match self {
+			Run::File(f) => SortRow::read(f),
+			Run::Memory(rows) => Ok(rows.next()),
+		}
+	}
+}
+
+/// The first remaining row of a run
+struct Head {
+	row: SortRow,
+	run: usize,
+}
+
+impl Head {
+	fn sort_key(&self) -> (&[u8], &[u8], usize) {
+		(self.row.key.as_bytes(), self.row.timestamp(), self.run)
+	}
+}
+
+impl PartialEq for Head {
+	fn eq(&self, other: &Head) -> bool {
+		self.sort_key() == other.sort_key()
+	}
+}
+
+impl Eq for Head {}
+
+impl PartialOrd for Head {
+	fn partial_cmp(&self, other: &Head) -> Option<std::cmp::Ordering> {
+		Some(self.cmp(other))
+	}
+}
+
+impl Ord for Head {
+	fn cmp(&self, other: &Head) -> std::cmp::Ordering {
+		// earlier runs have rows that were pushed earlier
+		self.sort_key().cmp(&other.sort_key())
+	}
+}
+
+/// The rows of an [`ExternalSort`], in order
+pub(crate) struct SortedRows {
+	runs: Vec<Run>,
+	heap: BinaryHeap<Reverse<Head>>,
+	/// the last row with its key and timestamp that has been seen so far
+	pending: Option<SortRow>,
+}
+
+impl SortedRows {
+	fn next_row(&mut self) -> std::io::Result<Option<SortRow>> {
+		let Some(Reverse(Head { row, run })) = self.heap.pop() else {
+			return Ok(None);
+		};
+		if let Some(next) = self.runs[run].next()? {
+			self.heap.push(Reverse(Head { row: next, run }));
+		}
+		Ok(Some(row))
+	}
+}
+
+impl Iterator for SortedRows {
+	type Item = std::io::Result<SortRow>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		loop {
+			let row = match self.next_row() {
+				Ok(row) => row,
+				Err(e) => return Some(Err(e)),
+			};
+			match (row, self.pending.take()) {
+				(Some(row), Some(pending)) if row.same_record(&pending) => {
+					self.pending = Some(row);
+				}
+				(Some(row), Some(pending)) => {
+					self.pending = Some(row);
+					return Some(Ok(pending));
+				}
+				(Some(row), None) => self.pending = Some(row),
+				(None, pending) => return pending.map(Ok),
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn row(key: &str, ts: u64, value: u8) -> SortRow {
+		let mut data = ts.to_be_bytes().to_vec();
+		data.push(value);
+		SortRow {
+			key: key.to_owned(),
+			format: "C".to_owned(),
+			data,
+		}
+	}
+
+	#[test]
+	fn sort_runs() {
+		let dir = tempfile::TempDir::new().unwrap();
+		// a run of every two rows
+		let mut sort = ExternalSort::new(dir.path(), 2 * row("b", 0, 0).size());
+		let rows = [
+			row("b", 2, 0),
+			row("a", 5, 1),
+			row("b", 1, 2),
+			row("a", 5, 3),
+			row("c", 0, 4),
+			row("a", 1, 5),
+			row("b", 2, 6),
+		];
+		for r in rows.iter().cloned() {
+			sort.push(r).unwrap();
+		}
+		assert_eq!(sort.runs.len(), 3);
+
+		let sorted: Vec<SortRow> = sort.finish().unwrap().map(Result::unwrap).collect();
+		assert_eq!(
+			sorted,
+			[
+				row("a", 1, 5),
+				row("a", 5, 3),
+				row("b", 1, 2),
+				row("b", 2, 6),
+				row("c", 0, 4),
+			]
+		);
+	}
+
+	#[test]
+	fn append_runs() {
+		let dir = tempfile::TempDir::new().unwrap();
+		let mut first = ExternalSort::new(dir.path(), usize::MAX);
+		first.push(row("a", 5, 0)).unwrap();
+		first.push(row("b", 1, 1)).unwrap();
+		// with its rows in temporary files
+		let mut second = ExternalSort::new(dir.path(), row("b", 0, 0).size());
+		second.push(row("b", 1, 2)).unwrap();
+		second.push(row("a", 1, 3)).unwrap();
+		first.append(second.into_runs()).unwrap();
+		first.push(row("a", 1, 4)).unwrap();
+		assert!(!first.is_empty());
+
+		let sorted: Vec<SortRow> = first.finish().unwrap().map(Result::unwrap).collect();
+		assert_eq!(sorted, [row("a", 1, 4), row("a", 5, 0), row("b", 1, 2)]);
+	}
+
+	#[test]
+	fn append_spills() {
+		let dir = tempfile::TempDir::new().unwrap();
+		let size = row("a", 0, 0).size();
+		// room for three rows in memory
+		let mut group = ExternalSort::new(dir.path(), 3 * size);
+		let in_memory = |sort: &ExternalSort| {
+			sort.runs
+				.iter()
+				.filter(|run| matches!(run, Run::Memory(_)))
+				.count()
+		};
+		for n in 0..3 {
+			let mut batch = ExternalSort::new(dir.path(), usize::MAX);
+			batch.push(row("a", 1, n)).unwrap();
+			batch.push(row(&format!("k{}", n), 1, n)).unwrap();
+			group.append(batch.into_runs()).unwrap();
+			if n == 0 {
+				assert_eq!(in_memory(&group), 1);
+			}
+		}
+		// the runs were written once they used more than three rows
+		assert_eq!(group.runs.len(), 3);
+		assert_eq!(in_memory(&group), 1);
+		assert_eq!(group.size, size + row("k2", 1, 2).size());
+
+		let sorted: Vec<SortRow> = group.finish().unwrap().map(Result::unwrap).collect();
+		assert_eq!(
+			sorted,
+			[
+				row("a", 1, 2),
+				row("k0", 1, 0),
+				row("k1", 1, 1),
+				row("k2", 1, 2)
+			]
+		);
+	}
+}
@@ -5,57 +5,99 @@ use crate::Timestamp;
 use byteorder::ByteOrder;
 use escape_string::split_one;
 
+/// Why a line of text couldn't be added
+#[derive(thiserror::Error, Debug)]
+pub enum IngestFailure {
+	/// The input couldn't be read, or isn't UTF-8
+	#[error("unable to read the input")]
+	Read(#[source] std::io::Error),
+	/// The line ends after the key
+	#[error("there is no timestamp")]
+	MissingTimestamp,
+	/// The line ends after the timestamp, but should have a format
+	#[error("there is no format")]
+	MissingFormat,
+	/// The timestamp isn't in the expected format
+	#[error("unable to parse the timestamp")]
+	Timestamp,
+	/// The format isn't a valid format
+	#[error("invalid format")]
+	Format,
+	/// A value doesn't have the type of its column
+	#[error("invalid value: {0}")]
+	Value(String),
+	/// There are more values than the format has columns
+	#[error("too many values")]
+	TooManyValues,
+	/// A word ends with a backslash
+	#[error("ends with a backslash")]
+	Escape,
+	/// The record couldn't be added to the transaction
+	#[error(transparent)]
+	Write(Box<crate::WriteFailure>),
+}
+
+impl From<crate::WriteFailure> for IngestFailure {
+	fn from(e: crate::WriteFailure) -> Self {
+		IngestFailure::Write(Box::new(e))
+	}
+}
+
+/// A line of text that couldn't be added, and why
+#[derive(thiserror::Error, Debug)]
+#[error("line {line}{}: {reason} (\"{text}\")", .column.map(|c| format!(", column {}", c)).unwrap_or_default())]
+pub struct IngestError {
+	/// The number of the line, the first is 1
+	pub line: u64,
+	/// The column of values that has the error, the first is 1
+	pub column: Option<usize>,
+	/// The text of the column, or of the line if the error isn't in a column
+	pub text: String,
+	/// What's wrong
+	pub reason: IngestFailure,
+}
+
+impl IngestError {
+	/// Returns true if the error is in the text of the line, rather than in
+	/// reading the input or writing the transaction, so the line can be skipped
+	pub fn is_bad_line(&self) -> bool {
+		match &self.reason {
+			IngestFailure::Read(_) => false,
+			IngestFailure::Write(e) => !matches!(**e, crate::WriteFailure::IOError(_)),
+			_ => true,
+		}
+	}
+}
+
+/// Fails with the error in `rejected`
+impl From<IngestError> for crate::WriteFailure {
+	fn from(rejected: IngestError) -> Self {
+		std::io::Error::new(std::io::ErrorKind::InvalidData, rejected).into()
+	}
+}
+
 /// Read keys from a text stream and insert it into a transaction
 ///
 /// Parameters:
-/// * `tx` - a transaction to write into
-/// * `db` - the database that is type-checked against
+/// * `tx` - a transaction to write into, like a [`crate::CreateTx`]
 /// * `format` - the format of each row. If each row
-/// contains its own format, you can instead use [`add_from_stream_with_fmt`].
+///   contains its own format, you can instead use [`add_from_stream_with_fmt`].
 /// * `input` - a text stream to read from, the keys are formatted as
-/// `label timestamp value [value ...]`. Whitespace is escaped with a backslash.
+///   `label timestamp value [value ...]`. Whitespace is escaped with a backslash.
 /// * `timestamp` - the strftime-like format to parse timestamps as. If `None`, use
-/// epoch nanos.
-/// * `nocheck` - turns off slow type checking (with `db`).
+///   epoch nanos.
+///
+/// Stops at the first line that can't be added. Use [`add_from_stream_rejecting`]
+/// to skip bad lines instead.
 pub fn add_from_stream<R: std::io::BufRead>(
-	tx: &mut crate::CreateTx,
+	tx: &mut (impl crate::RecordSink + ?Sized),
 	format: &str,
 	input: &mut R,
 	timestamp_format: Option<&str>,
-) -> Result<(), crate::WriteFailure> {
-	let row_format = parse_row_format(format);
-
-	let mut line = String::new();
-	let mut row_data = vec![]; // this is single allocation only
-
-	while 0 != input.read_line(&mut line).unwrap() {
-		let tail = line.trim_end();
-		if tail.is_empty() {
-			continue;
-		}
-		let (key, tail) = split_one(tail).unwrap();
-		let (timestamp, tail) = split_one(tail).unwrap();
-		let ts: Timestamp;
-		if let Some(f) = timestamp_format.as_ref() {
-			let n = chrono::NaiveDateTime::parse_from_str(&timestamp, f)
-				.expect("parsing timestamp according to format");
-			ts = n
-				.and_utc()
-				.timestamp_nanos_opt()
-				.ok_or(crate::WriteFailure::UnableToParseTimestamp)? as Timestamp;
-		} else {
-			ts = timestamp.parse().expect("parsing timestamp");
-		}
-
-		row_format
-			.to_stored_format(ts, tail, &mut row_data)
-			.unwrap_or_else(|_| panic!("parsing values \"{}\"", tail));
-
-		tx.add_record_raw(&key, format, &row_data)?;
-		row_data.clear();
-		line.clear();
-	}
-
+) -> Result<(), IngestError> {
+	add_from_stream_rejecting(tx, Some(format), input, timestamp_format, &mut |e, _| {
+		Err(e)
+	})?;
 	Ok(())
 }
 
@@ -64,45 +106,141 @@ pub fn add_from_stream<R: std::io::BufRead>(
 /// Like [`add_from_stream`] except the format string
 /// comes after the timestamp
 pub fn add_from_stream_with_fmt<R: std::io::BufRead>(
-	tx: &mut crate::CreateTx,
+	tx: &mut (impl crate::RecordSink + ?Sized),
 	input: &mut R,
 	timestamp_format: Option<&str>,
-) -> Result<(), crate::WriteFailure> {
+) -> Result<(), IngestError> {
+	add_from_stream_rejecting(tx, None, input, timestamp_format, &mut |e, _| Err(e))?;
+	Ok(())
+}
+
+/// Reads from text like [`add_from_stream`], or like [`add_from_stream_with_fmt`]
+/// if `format` is `None`, passing each bad line to `reject`.
+///
+/// `reject` gets the error and the text of the line. The line is skipped
+/// if it returns `Ok`, otherwise adding stops with its error. Errors in
+/// reading the input or writing the transaction always stop it (see
+/// [`IngestError::is_bad_line`]). Returns the number of lines that were rejected.
+pub fn add_from_stream_rejecting<R: std::io::BufRead>(
+	tx: &mut (impl crate::RecordSink + ?Sized),
+	format: Option<&str>,
+	input: &mut R,
+	timestamp_format: Option<&str>,
+	reject: &mut dyn FnMut(IngestError, &str) -> Result<(), IngestError>,
+) -> Result<u64, IngestError> {
+	let row_format = format.map(parse_row_format);
+
 	let mut line = String::new();
-	let mut row_data = vec![];
+	let mut row_data = vec![]; // this is single allocation only
+	let mut line_no = 0;
+	let mut rejected = 0;
 
-	while 0 != input.read_line(&mut line).unwrap() {
+	loop {
+		line.clear();
+		line_no += 1;
+		let read = input.read_line(&mut line).map_err(|e| IngestError {
+			line: line_no,
+			column: None,
+			text: String::new(),
+			reason: IngestFailure::Read(e),
+		})?;
+		if read == 0 {
+			break;
+		}
 		let tail = line.trim_end();
 		if tail.is_empty() {
 			continue;
 		}
-		let (key, tail) = split_one(tail).unwrap();
-		let (timestamp, tail) = split_one(tail).unwrap();
-		let ts: Timestamp;
-		if let Some(f) = timestamp_format.as_ref() {
-			let n = chrono::NaiveDateTime::parse_from_str(&timestamp, f)
-				.expect("parsing timestamp according to format");
-			ts = n
-				.and_utc()
-				.timestamp_nanos_opt()
-				.ok_or(crate::WriteFailure::UnableToParseTimestamp)? as Timestamp;
-		} else {
-			ts = timestamp.parse().expect("parsing timestamp");
+		row_data.clear();
+
+		let added = parse_line(
+			tail,
+			format,
+			row_format.as_deref(),
+			timestamp_format,
+			&mut row_data,
+		)
+		.and_then(|(key, format)| {
+			tx.add_record_raw(&key, &format, &row_data)
+				.map_err(|e| (None, tail.to_owned(), e.into()))
+		});
+		if let Err((column, text, reason)) = added {
+			let e = IngestError {
+				line: line_no,
+				column,
+				text,
+				reason,
+			};
+			if !e.is_bad_line() {
+				return Err(e);
+			}
+			reject(e, tail)?;
+			rejected += 1;
 		}
+	}
 
-		let (format, values) = split_one(tail).unwrap();
-		let row_format = parse_row_format(&format);
+	Ok(rejected)
+}
 
-		row_format
-			.to_stored_format(ts, values, &mut row_data)
-			.unwrap();
+/// The column, text and reason of an error in a line
+type LineError = (Option<usize>, String, IngestFailure);
 
-		tx.add_record_raw(&key, &format, &row_data)?;
-		row_data.clear();
-		line.clear();
+/// Parse a line into `row_data`, returning its key and format
+fn parse_line<'l>(
+	line: &'l str,
+	format: Option<&'l str>,
+	row_format: Option<&dyn RowFormat>,
+	timestamp_format: Option<&str>,
+	row_data: &mut Vec<u8>,
+) -> Result<(std::borrow::Cow<'l, str>, std::borrow::Cow<'l, str>), LineError> {
+	let whole_line = |reason| (None, line.to_owned(), reason);
+
+	let (key, tail) = split_one(line).ok_or_else(|| whole_line(IngestFailure::Escape))?;
+	let (timestamp, tail) = split_one(tail).ok_or_else(|| whole_line(IngestFailure::Escape))?;
+	if timestamp.is_empty() {
+		return Err(whole_line(IngestFailure::MissingTimestamp));
+	}
+	let ts: Timestamp = if let Some(f) = timestamp_format {
+		chrono::NaiveDateTime::parse_from_str(&timestamp, f)
+			.ok()
+			.and_then(|n| n.and_utc().timestamp_nanos_opt())
+			.and_then(|n| Timestamp::try_from(n).ok())
+	} else {
+		timestamp.parse().ok()
 	}
+	.ok_or_else(|| (None, timestamp.to_string(), IngestFailure::Timestamp))?;
 
-	Ok(())
+	let parsed;
+	let (format, row_format, tail) = match (format, row_format) {
+		(Some(f), Some(r)) => (f.into(), r, tail),
+		_ => {
+			let (f, values) = split_one(tail).ok_or_else(|| whole_line(IngestFailure::Escape))?;
+			if f.is_empty() {
+				return Err(whole_line(IngestFailure::MissingFormat));
+			}
+			if !is_valid_format(&f) {
+				return Err((None, f.into_owned(), IngestFailure::Format));
+			}
+			parsed = parse_row_format(&f);
+			(f, &*parsed, values)
+		}
+	};
+
+	row_data.extend_from_slice(&ts.to_be_bytes());
+	let mut values = tail;
+	for (idx, e) in row_format.elements().iter().enumerate() {
+		let column = Some(idx + 1);
+		let (word, _) =
+			split_one(values).ok_or_else(|| (column, values.to_owned(), IngestFailure::Escape))?;
+		values = e
+			.to_stored_format(values, row_data)
+			.map_err(|why| (column, word.into_owned(), IngestFailure::Value(why)))?;
+	}
+	if !values.is_empty() {
+		let column = Some(row_format.elements().len() + 1);
+		return Err((column, values.to_owned(), IngestFailure::TooManyValues));
+	}
+	Ok((key, format))
 }
 
 /// Print the record format (`uUfF`) right after the timestamp
@@ -196,3 +334,317 @@ pub fn print_record<W: std::io::Write>(
 	}
 	Ok(())
 }
+
+/// The layout of each record written by [`print_record_as`]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum OutputFormat {
+	/// The format of [`print_record`], which [`add_from_stream`] reads
+	Text,
+	/// Comma-separated values, quoted if necessary. A null is an empty field.
+	Csv,
+	/// Tab-separated values, in which a tab, newline, carriage return
+	/// or backslash is escaped with a backslash. A null is an empty field.
+	Tsv,
+	/// A JSON object per record, like `{"key":"a","time":"…","values":[1.5,null,"x"]}`,
+	/// or with `"values":{"temperature":1.5,…}` if the key has a schema of the record's format.
+	/// Integers, floats and decimals are numbers, `x` columns are strings of hexadecimal,
+	/// and a float that is not finite is null.
+	JsonLines,
+}
+
+/// Write the text format by default
+impl std::default::Default for OutputFormat {
+	fn default() -> Self {
+		OutputFormat::Text
+	}
+}
+
+impl std::str::FromStr for OutputFormat {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"text" => Ok(OutputFormat::Text),
+			"csv" => Ok(OutputFormat::Csv),
+			"tsv" => Ok(OutputFormat::Tsv),
+			"jsonl" => Ok(OutputFormat::JsonLines),
+			s => Err(format!(
+				"unknown output format \"{}\", expected text, csv, tsv or jsonl",
+				s
+			)),
+		}
+	}
+}
+
+/// The value of one column
+enum Value<'a> {
+	Null,
+	Bool(bool),
+	Int(i64),
+	UInt(u64),
+	F32(f32),
+	F64(f64),
+	String(&'a str),
+	Bytes(&'a [u8]),
+	Decimal(crate::Decimal),
+}
+
+impl Value<'_> {
+	fn is_number(&self) -> bool {
+		match self {
+			Value::Int(_) | Value::UInt(_) | Value::Decimal(_) => true,
+			Value::F32(v) => v.is_finite(),
+			Value::F64(v) => v.is_finite(),
+			_ => false,
+		}
+	}
+}
+
+impl std::fmt::Display for Value<'_> {
+	/// the value as text, without escaping
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Value::Null => Ok(()),
+			Value::Bool(v) => write!(f, "{}", v),
+			Value::Int(v) => write!(f, "{}", v),
+			Value::UInt(v) => write!(f, "{}", v),
+			Value::F32(v) => write!(f, "{}", v),
+			Value::F64(v) => write!(f, "{}", v),
+			Value::String(v) => write!(f, "{}", v),
+			Value::Bytes(v) => v.iter().try_for_each(|b| write!(f, "{:02x}", b)),
+			Value::Decimal(v) => write!(f, "{}", v),
+		}
+	}
+}
+
+/// The values of the columns of `record`
+fn values(record: &crate::Record) -> std::io::Result<Vec<Value<'_>>> {
+	use crate::FromRecord;
+
+	let mut from = &record.raw()[crate::records::TIMESTAMP_SIZE..];
+	columns(record.format().as_bytes())
+		.map(|column| {
+			let mut bytes = crate::records::split_column(&column, &mut from)?;
+			if column.nullable {
+				if bytes[0] == 0 {
+					return Ok(Value::Null);
+				}
+				bytes = &bytes[1..];
+			}
+			let kind = column.kind;
+			Ok(match kind {
+				b'b' => Value::Bool(FromRecord::get(kind, bytes)?),
+				b'c' | b'h' | b'i' | b'I' => Value::Int(FromRecord::get(kind, bytes)?),
+				b'C' | b'H' | b'u' | b'U' => Value::UInt(FromRecord::get(kind, bytes)?),
+				b'f' => Value::F32(FromRecord::get(kind, bytes)?),
+				b'F' => Value::F64(FromRecord::get(kind, bytes)?),
+				b's' => Value::String(FromRecord::get(kind, bytes)?),
+				b'x' => Value::Bytes(FromRecord::get(kind, bytes)?),
+				b'd' => Value::Decimal(crate::Decimal::new(
+					byteorder::BigEndian::read_i64(bytes),
+					column.scale,
+				)),
+				a => {
+					return Err(std::io::Error::new(
+						std::io::ErrorKind::InvalidData,
+						format!("format string contains invalid '{}'", a as char),
+					))
+				}
+			})
+		})
+		.collect()
+}
+
+/// Write `text` as a CSV field, in quotes if it has a comma, quote or line break
+fn write_csv_field<W: std::io::Write>(out: &mut W, text: &str) -> std::io::Result<()> {
+	if text.contains([',', '"', '\n', '\r']) {
+		write!(out, "\"{}\"", text.replace('"', "\"\""))
+	} else {
+		write!(out, "{}", text)
+	}
+}
+
+/// Write `text` as a TSV field, escaping tabs, line breaks and backslashes
+fn write_tsv_field<W: std::io::Write>(out: &mut W, text: &str) -> std::io::Result<()> {
+	for c in text.chars() {
+		match c {
+			'\t' => write!(out, "\\t")?,
+			'\n' => write!(out, "\\n")?,
+			'\r' => write!(out, "\\r")?,
+			'\\' => write!(out, "\\\\")?,
+			c => write!(out, "{}", c)?,
+		}
+	}
+	Ok(())
+}
+
+/// Write `text` as a JSON string
+fn write_json_string<W: std::io::Write>(out: &mut W, text: &str) -> std::io::Result<()> {
+	write!(out, "\"")?;
+	for c in text.chars() {
+		match c {
+			'"' => write!(out, "\\\"")?,
+			'\\' => write!(out, "\\\\")?,
+			'\n' => write!(out, "\\n")?,
+			'\r' => write!(out, "\\r")?,
+			'\t' => write!(out, "\\t")?,
+			c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32)?,
+			c => write!(out, "{}", c)?,
+		}
+	}
+	write!(out, "\"")
+}
+
+/// The columns of `record` in `names`, if its key has a schema of its format
+fn named_columns<'s>(
+	record: &crate::Record,
+	names: &'s crate::Schema,
+) -> Option<&'s [crate::ColumnSchema]> {
+	names
+		.for_key(record.key())
+		.filter(|k| k.format() == record.format())
+		.map(|k| k.columns())
+}
+
+/// Write the header row of CSV or TSV `output`, and a line break, with the names
+/// of what [`print_record_as`] writes of records like `record`
+///
+/// The value columns are named by `names` if the key of `record` has a schema of
+/// its format, otherwise they're `column1`, `column2`, etc. The other layouts
+/// have no header, so nothing is written.
+pub fn print_header_as<W: std::io::Write>(
+	record: &crate::Record,
+	out: &mut W,
+	output: OutputFormat,
+	print_record_format: PrintRecordFormat,
+	column_selection: &choice_string::Selection,
+	names: &crate::Schema,
+) -> std::io::Result<()> {
+	let (write_field, separator) = match output {
+		OutputFormat::Csv => (write_csv_field::<W> as fn(&mut W, &str) -> _, ","),
+		OutputFormat::Tsv => (write_tsv_field::<W> as fn(&mut W, &str) -> _, "\t"),
+		OutputFormat::Text | OutputFormat::JsonLines => return Ok(()),
+	};
+	write!(out, "key{}time", separator)?;
+	if let PrintRecordFormat::Yes = print_record_format {
+		write!(out, "{}format", separator)?;
+	}
+	let named = named_columns(record, names);
+	let columns = crate::row_format::columns(record.format().as_bytes()).count();
+	for idx in 0..columns {
+		if !column_selection.contains_item(idx + 1) {
+			continue;
+		}
+		write!(out, "{}", separator)?;
+		match named {
+			Some(named) => write_field(out, &named[idx].name)?,
+			None => write!(out, "column{}", idx + 1)?,
+		}
+	}
+	writeln!(out)
+}
+
+/// Write a record to a stream in the layout of `output`
+///
+/// Like [`print_record`], the key comes first, followed by the timestamp
+/// (a number if `print_timestamp` is [`PrintTimestamp::Nanos`] or
+/// [`PrintTimestamp::Seconds`]), the format if `print_record_format` says so,
+/// and then the selected columns, which JSON names by `names` (see
+/// [`OutputFormat::JsonLines`]). No line break is written after the record.
+pub fn print_record_as<W: std::io::Write>(
+	record: &crate::Record,
+	out: &mut W,
+	output: OutputFormat,
+	print_timestamp: PrintTimestamp<'_>,
+	print_record_format: PrintRecordFormat,
+	column_selection: &choice_string::Selection,
+	names: &crate::Schema,
+) -> std::io::Result<()> {
+	let write_field = match output {
+		OutputFormat::Text => {
+			return print_record(
+				record,
+				out,
+				print_timestamp,
+				print_record_format,
+				column_selection,
+			)
+		}
+		OutputFormat::Csv => write_csv_field::<W>,
+		OutputFormat::Tsv => write_tsv_field::<W>,
+		OutputFormat::JsonLines => write_json_string::<W>,
+	};
+	let separator = match output {
+		OutputFormat::Tsv => "\t",
+		_ => ",",
+	};
+	let json = output == OutputFormat::JsonLines;
+
+	let ts = record.timestamp_nanos();
+	let (time, time_is_number) = match print_timestamp {
+		PrintTimestamp::Nanos => (ts.to_string(), true),
+		PrintTimestamp::Seconds => ((ts / 1_000_000_000).to_string(), true),
+		PrintTimestamp::FormatString(strf) => {
+			let ts = chrono::DateTime::from_timestamp(
+				(ts / 1_000_000_000) as i64,
+				(ts % 1_000_000_000) as u32,
+			)
+			.unwrap();
+			(ts.format(strf).to_string(), false)
+		}
+	};
+
+	if json {
+		write!(out, "{{\"key\":")?;
+	}
+	write_field(out, record.key())?;
+	write!(out, "{}", separator)?;
+	if json {
+		write!(out, "\"time\":")?;
+	}
+	if time_is_number {
+		write!(out, "{}", time)?;
+	} else {
+		write_field(out, &time)?;
+	}
+	if let PrintRecordFormat::Yes = print_record_format {
+		write!(out, "{}", separator)?;
+		if json {
+			write!(out, "\"format\":")?;
+		}
+		write_field(out, record.format())?;
+	}
+
+	let named = named_columns(record, names).filter(|_| json);
+	if json {
+		let open = if named.is_some() { "{" } else { "[" };
+		write!(out, ",\"values\":{}", open)?;
+	}
+	let values = values(record)?;
+	let mut first = true;
+	for (idx, value) in values.iter().enumerate() {
+		if !column_selection.contains_item(idx + 1) {
+			continue;
+		}
+		if !json || !first {
+			write!(out, "{}", separator)?;
+		}
+		first = false;
+		if let Some(named) = named {
+			write_json_string(out, &named[idx].name)?;
+			write!(out, ":")?;
+		}
+		match value {
+			Value::Null if json => write!(out, "null")?,
+			Value::F32(_) | Value::F64(_) if json && !value.is_number() => write!(out, "null")?,
+			Value::Bool(v) if json => write!(out, "{}", v)?,
+			v if json && v.is_number() => write!(out, "{}", v)?,
+			v => write_field(out, &v.to_string())?,
+		}
+	}
+	if json {
+		let close = if named.is_some() { "}" } else { "]" };
+		write!(out, "{}}}", close)?;
+	}
+	Ok(())
+}
@@ -0,0 +1,203 @@
+//! Commit the records of concurrent writers together, as one transaction.
+//!
+//! The first batch that's committed starts a group and waits for the
+//! group's window to end, while the batches that are committed in the
+//! meantime join it. Then it commits the group and tells the others.
+
+use crate::external_sort::{ExternalSort, Runs, SortRow, SORT_MEMORY};
+use crate::{CreateTx, WriteFailure};
+use antidote::{Condvar, Mutex};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Commits the batches of records that are committed close together as one transaction.
+///
+/// Add records with a [`GroupBatch`] from [`GroupCommit::batch`].
+/// [`GroupBatch::commit`] returns once the group it joined is committed.
+///
+/// Records are checked against the database's schema when they're added to
+/// a batch, so a bad record fails its batch before the batch joins a group.
+///
+/// ```no_run
+/// let group = sonnerie::GroupCommit::new(
+///    std::path::Path::new("db"),
+///    std::time::Duration::from_millis(50),
+/// ).unwrap();
+/// let mut batch = group.batch();
+/// batch.add_record(
+///    "key name",
+///    "2010-01-01T00:00:01".parse().unwrap(),
+///    sonnerie::record(1u32),
+///  ).unwrap();
+/// batch.commit().unwrap();
+/// ```
+pub struct GroupCommit {
+	dir: PathBuf,
+	schema: crate::Schema,
+	window: Duration,
+	batch_memory: usize,
+	group_memory: usize,
+	/// the records of the group that batches join now, and its result
+	current: Mutex<Option<(ExternalSort, Arc<Group>)>>,
+}
+
+/// The result of a group, once it's committed
+struct Group {
+	result: Mutex<Option<Result<(), Arc<WriteFailure>>>>,
+	committed: Condvar,
+}
+
+impl GroupCommit {
+	/// Commit the batches that are committed to the database in `dir`
+	/// within `window` of the first batch of a group together
+	pub fn new(dir: &Path, window: Duration) -> std::io::Result<GroupCommit> {
+		Ok(GroupCommit {
+			dir: dir.to_owned(),
+			schema: crate::Schema::load(dir)?,
+			window,
+			batch_memory: 16 * 1024 * 1024,
+			group_memory: SORT_MEMORY,
+			current: Mutex::new(None),
+		})
+	}
+
+	/// Write the records of a batch to a temporary file once they use
+	/// about this many bytes of memory. The default is 16 MiB.
+	pub fn set_batch_memory(&mut self, bytes: usize) {
+		self.batch_memory = bytes;
+	}
+
+	/// Write the records of the batches of a group to temporary files
+	/// once they use about this many bytes of memory together.
+	/// The default is 256 MiB.
+	pub fn set_group_memory(&mut self, bytes: usize) {
+		self.group_memory = bytes;
+	}
+
+	/// Start adding a batch of records, in any order
+	pub fn batch(&self) -> GroupBatch<'_> {
+		GroupBatch {
+			group: self,
+			sort: ExternalSort::new(&self.dir, self.batch_memory),
+		}
+	}
+
+	/// Add the records of a batch to the current group, starting one
+	/// if there isn't one, and wait for it to be committed
+	fn join(&self, runs: Runs) -> Result<(), WriteFailure> {
+		let (group, first) = {
+			let mut current = self.current.lock();
+			let first = current.is_none();
+			let (sort, group) = current.get_or_insert_with(|| {
+				let group = Group {
+					result: Mutex::new(None),
+					committed: Condvar::new(),
+				};
+				(
+					ExternalSort::new(&self.dir, self.group_memory),
+					Arc::new(group),
+				)
+			});
+			if let Err(e) = sort.append(runs) {
+				// no batch is waiting for the group that was just started
+				if first {
+					*current = None;
+				}
+				return Err(e.into());
+			}
+			(group.clone(), first)
+		};
+
+		if first {
+			std::thread::sleep(self.window);
+			// batches that are committed from now on start another group
+			let (sort, _) = self.current.lock().take().expect("the group is current");
+			let result = self.commit_group(sort).map_err(Arc::new);
+			*group.result.lock() = Some(result);
+			group.committed.notify_all();
+		}
+
+		let mut result = group.result.lock();
+		while result.is_none() {
+			result = group.committed.wait(result);
+		}
+		result
+			.clone()
+			.unwrap()
+			.map_err(WriteFailure::GroupCommitFailed)
+	}
+
+	fn commit_group(&self, sort: ExternalSort) -> Result<(), WriteFailure> {
+		let mut tx = CreateTx::new(&self.dir)?;
+		for row in sort.finish()? {
+			let SortRow { key, format, data } = row?;
+			tx.add_record_raw(&key, &format, &data)?;
+		}
+		tx.commit()?;
+		Ok(())
+	}
+}
+
+/// Records to commit with a [`GroupCommit`].
+///
+/// The records are kept in memory, and in temporary files once they use
+/// more than the batch memory, and are lost if the batch isn't committed.
+/// Of the records with the same key and timestamp, the one that was added
+/// last is kept, also between the batches of a group.
+pub struct GroupBatch<'g> {
+	group: &'g GroupCommit,
+	sort: ExternalSort,
+}
+
+impl GroupBatch<'_> {
+	/// Add a record like [`CreateTx::add_record`], but in any order
+	pub fn add_record(
+		&mut self,
+		key: &str,
+		timestamp: chrono::NaiveDateTime,
+		values: impl crate::RecordBuilder,
+	) -> Result<(), WriteFailure> {
+		let mut format = compact_str::CompactString::default();
+		values.format_str(&mut format);
+		let timestamp = timestamp
+			.and_utc()
+			.timestamp_nanos_opt()
+			.ok_or(WriteFailure::UnableToParseTimestamp)? as crate::Timestamp;
+		let mut data = timestamp.to_be_bytes().to_vec();
+		values.store(&mut data);
+		self.add_record_raw(key, &format, &data)
+	}
+
+	/// Add a record like [`CreateTx::add_record_raw`], but in any order
+	pub fn add_record_raw(
+		&mut self,
+		key: &str,
+		format: &str,
+		data: &[u8],
+	) -> Result<(), WriteFailure> {
+		// the records must be valid now, or they'd fail the whole group
+		crate::write::check_record_length(format, data)?;
+		self.group.schema.check(key, format)?;
+		self.sort.push(SortRow {
+			key: key.to_owned(),
+			format: format.to_owned(),
+			data: data.to_owned(),
+		})?;
+		Ok(())
+	}
+
+	/// Join the current group, and return once it's committed
+	pub fn commit(self) -> Result<(), WriteFailure> {
+		if self.sort.is_empty() {
+			return Ok(());
+		}
+		self.group.join(self.sort.into_runs())
+	}
+}
+
+impl crate::RecordSink for GroupBatch<'_> {
+	fn add_record_raw(&mut self, key: &str, format: &str, data: &[u8]) -> Result<(), WriteFailure> {
+		GroupBatch::add_record_raw(self, key, format, data)
+	}
+}
@@ -11,6 +11,10 @@ use std::ops::Bound::*;
 use std::ops::RangeBounds;
 use std::sync::Arc as Rc;
 
+/// Where readers report the segments they can't read (instead of panicking),
+/// along with the id of the transaction
+pub(crate) type ReadErrors = Rc<antidote::Mutex<Vec<(usize, ReadError)>>>;
+
 /// Read and filter keys from a single transaction file
 pub struct Reader {
 	pub(crate) segments: SegmentReader,
@@ -51,7 +55,13 @@ impl Reader {
 		&'rdr self,
 		range: impl RangeBounds<&'k str> + 'k + Clone,
 	) -> StringKeyRangeReader<'rdr, 'k> {
-		self.get_filter_range(None, "", crate::disassemble_range_bound(range).into())
+		self.get_filter_range(
+			None,
+			"",
+			crate::disassemble_range_bound(range).into(),
+			(Unbounded, Unbounded),
+			None,
+		)
 	}
 
 	/// Get a reader that filters on SQL's "LIKE"-like syntax.
@@ -77,35 +87,21 @@ impl Reader {
 		matcher: Option<regex::Regex>,
 		prefix: &'k str,
 		range: crate::CowStringRange<'k>,
+		timestamps: crate::TimestampRange,
+		errors: Option<(usize, ReadErrors)>,
 	) -> StringKeyRangeReader<'rdr, 'k> {
-		let mut data = vec![];
-
 		let segment = match range.start_bound() {
 			Included(v) | Excluded(v) => self.segments.find(v),
 			Unbounded => self.segments.first(),
 		};
 
-		if let Some(d) = segment.as_ref() {
-			{
-				// don't do posix_fadvise if we're looking up a single key
-				let do_advise = match (range.start_bound(), range.end_bound()) {
-					(Included(v1), Included(v2)) => v1 != v2,
-					_ => true,
-				};
-				if do_advise {
-					self.segments.advise(d);
-				}
-			}
-
-			crate::segment_reader::decode_into_with_unescaping(&mut data, d.payload);
-		}
-
-		StringKeyRangeReader {
+		let mut reader = StringKeyRangeReader {
 			reader: self,
 			range,
-			decoded: Rc::new(data),
+			timestamps,
+			decoded: Rc::new(vec![]),
 			pos: 0,
-			segment,
+			segment: None,
 			current_key_text_len: 0,
 			current_key_text_pos: 0,
 			current_fmt_text_len: 0,
@@ -116,7 +112,29 @@ impl Reader {
 			_phantom: std::marker::PhantomData,
 			prefix,
 			matcher,
+			errors,
+		};
+
+		if let (Unbounded, Some(first)) = (reader.range.start_bound(), &segment) {
+			if let Some(e) = self.segments.unreadable(0, first.segment_offset) {
+				reader.report(e);
+			}
+		}
+		let segment = reader.skip_segments_outside_timestamps(segment);
+
+		if let Some(d) = segment.as_ref() {
+			// don't do posix_fadvise if we're looking up a single key
+			let do_advise = match (reader.range.start_bound(), reader.range.end_bound()) {
+				(Included(v1), Included(v2)) => v1 != v2,
+				_ => true,
+			};
+			if do_advise {
+				self.segments.advise(d);
+			}
 		}
+
+		reader.load_segment(segment);
+		reader
 	}
 	/// Print diagnostic information about this transaction file.
 	///
@@ -132,6 +150,8 @@ impl Reader {
 pub struct StringKeyRangeReader<'rdr, 'k> {
 	pub(crate) reader: &'rdr Reader,
 	pub(crate) range: crate::CowStringRange<'k>,
+	/// only records with these timestamps are yielded
+	pub(crate) timestamps: crate::TimestampRange,
 	decoded: Rc<Vec<u8>>,
 	pos: usize,
 	current_key_text_pos: usize,
@@ -146,10 +166,18 @@ pub struct StringKeyRangeReader<'rdr, 'k> {
 	pub(crate) segment: Option<Segment<'rdr>>,
 	pub(crate) matcher: Option<regex::Regex>,
 	pub(crate) prefix: &'k str,
+	/// if None, panic on segments that can't be read
+	errors: Option<(usize, ReadErrors)>,
 	_phantom: std::marker::PhantomData<&'k str>,
 }
 
 impl<'rdr, 'k> StringKeyRangeReader<'rdr, 'k> {
+	/// Collect the records into a [`ColumnBatch`](crate::ColumnBatch) per key,
+	/// as [`DatabaseRecordReader::into_batches`](crate::DatabaseRecordReader::into_batches)
+	pub fn into_batches(self) -> crate::ColumnBatches<Self> {
+		crate::ColumnBatches::new(self)
+	}
+
 	/// Determines the on-disk size of this range of data,
 	/// which is useful for estimating progress and size.
 	pub fn compressed_bytes(&self) -> usize {
@@ -200,16 +228,83 @@ impl<'rdr, 'k> StringKeyRangeReader<'rdr, 'k> {
 		range_bytes
 	}
 
+	/// true if no key in `segment` or any following it can be in my range
+	fn segment_after_range(&self, segment: &Segment) -> bool {
+		let key = segment.first_key;
+		match self.range.end_bound() {
+			Bound::Included(v) => key > v,
+			Bound::Excluded(v) => key >= v,
+			Unbounded => key > self.prefix && !key.starts_with(self.prefix),
+		}
+	}
+
+	/// starting at `segment`, find the first segment that could have a record
+	/// in my range of timestamps, without decompressing anything
+	fn skip_segments_outside_timestamps(
+		&self,
+		mut segment: Option<Segment<'rdr>>,
+	) -> Option<Segment<'rdr>> {
+		while let Some(s) = segment.as_ref() {
+			if self.segment_after_range(s) {
+				return None;
+			}
+			if s.overlaps_timestamps(&self.timestamps) {
+				break;
+			}
+			segment = self.segment_after(s);
+		}
+		segment
+	}
+
+	/// the segment after `segment`, reporting the bytes
+	/// before it that can't be read as a segment
+	fn segment_after(&self, segment: &Segment<'rdr>) -> Option<Segment<'rdr>> {
+		let segments = &self.reader.segments;
+		let next = segments.segment_after(segment);
+		let end = segment.segment_offset + segment.stride;
+		let next_offset = next
+			.as_ref()
+			.map_or(segments.number_of_bytes(), |s| s.segment_offset);
+		if let Some(e) = segments.unreadable(end, next_offset) {
+			self.report(e);
+		}
+		next
+	}
+
+	/// report a segment that can't be read, or panic if there's nowhere to report it
+	fn report(&self, e: ReadError) {
+		let Some((txid, errors)) = &self.errors else {
+			panic!("{}, run \"sonnerie verify\"", e);
+		};
+		errors.lock().push((*txid, e));
+	}
+
+	/// report that a record of the current segment can't be read, and skip the rest of it
+	fn corrupt_record(&mut self, reason: &str) {
+		if let Some(segment) = self.segment.as_ref() {
+			self.report(ReadError::corrupt(segment, reason));
+		}
+		self.pos = self.decoded.len();
+		self.current_key_data_end = self.decoded.len();
+	}
+
 	fn next_segment(&mut self) {
+		let segment = self.segment.take().unwrap();
+		let s = self.segment_after(&segment);
+		self.load_segment(s);
+	}
+
+	/// decode the first segment from `segment` on that is in my range
+	/// and can be read, reporting (or panicking on) those that can't be
+	fn load_segment(&mut self, mut segment: Option<Segment<'rdr>>) {
 		self.pos = 0;
 
-		let s = self
-			.reader
-			.segments
-			.segment_after(&self.segment.take().unwrap());
-		self.segment = s;
+		loop {
+			segment = self.skip_segments_outside_timestamps(segment);
+			let Some(s) = segment.as_ref() else {
+				break;
+			};
 
-		if let Some(s) = self.segment.as_ref() {
 			let reuse_vec = std::mem::replace(&mut self.decoded, Rc::new(vec![]));
 			let mut old_vec;
 			if let Ok(maybe_old_vec) = Rc::try_unwrap(reuse_vec) {
@@ -218,50 +313,47 @@ impl<'rdr, 'k> StringKeyRangeReader<'rdr, 'k> {
 				old_vec = vec![];
 			}
 			old_vec.clear();
-			crate::segment_reader::decode_into_with_unescaping(&mut old_vec, s.payload);
+			let decoded = crate::segment_reader::decode_into_with_unescaping(&mut old_vec, s);
 			self.decoded = Rc::new(old_vec);
+
+			match decoded {
+				Ok(()) => break,
+				Err(e) => {
+					self.report(e);
+					segment = self.segment_after(s);
+				}
+			}
 		}
+		self.segment = segment;
 	}
 
 	fn next_key(&mut self) -> bool {
 		while let Some(segment) = self.segment.as_ref() {
 			while self.pos != self.decoded.len() {
 				let data = &self.decoded;
-				let klen = BigEndian::read_u32(&data[self.pos..self.pos + 4]) as usize;
-				let flen = BigEndian::read_u32(&data[self.pos + 4..self.pos + 8]) as usize;
-
-				let pos = if segment.segment_version == 0x0000 {
-					self.pos + 12
-				} else {
-					self.pos + 8
+				let entry = match key_entry(segment, data, self.pos) {
+					Ok(entry) => entry,
+					Err(e) => {
+						// skip the rest of the segment
+						self.report(e);
+						self.pos = data.len();
+						self.current_key_data_end = data.len();
+						continue;
+					}
 				};
+				let key = entry.key;
 
-				let dlen = BigEndian::read_u32(&data[pos..pos + 4]) as usize;
+				self.current_key_text_pos = entry.key_pos;
+				self.current_key_text_len = key.len();
+				self.current_fmt_text_pos = entry.key_pos + key.len();
+				self.current_fmt_text_len = entry.format.len();
 
-				let pos = pos + 4;
+				let size = crate::row_format::row_format_size(entry.format);
+				self.current_record_len = size;
+				self.current_key_record_len = size;
 
-				let key = &data[pos..pos + klen];
-				let key = std::str::from_utf8(key).expect("input data is not utf8");
-				let fmt = &data[pos + klen..pos + klen + flen];
-				let fmt = std::str::from_utf8(fmt).expect("input data is not utf8");
-
-				self.current_key_text_pos = pos;
-				self.current_key_text_len = klen;
-				self.current_fmt_text_pos = pos + klen;
-				self.current_fmt_text_len = flen;
-				let pos = pos + klen + flen;
-
-				if let Some(len) = crate::row_format::row_format_size(fmt) {
-					self.current_record_len = Some(len);
-					self.pos = pos;
-					self.current_key_record_len = Some(len);
-				} else {
-					self.current_record_len = None;
-					self.current_key_record_len = None;
-				}
-
-				self.pos = pos;
-				self.current_key_data_end = pos + dlen;
+				self.pos = entry.data.start;
+				self.current_key_data_end = entry.data.end;
 
 				match self.range.start_bound() {
 					Bound::Included(v) => {
@@ -322,36 +414,62 @@ impl<'rdr, 'k> StringKeyRangeReader<'rdr, 'k> {
 impl<'rdr, 'k> Iterator for StringKeyRangeReader<'rdr, 'k> {
 	type Item = Record;
 	fn next(&mut self) -> Option<Self::Item> {
-		self.segment.as_ref()?;
-
-		if self.pos == self.current_key_data_end && !self.next_key() {
-			return None;
-		}
+		loop {
+			self.segment.as_ref()?;
 
-		let data = self.decoded.clone();
+			if self.pos == self.current_key_data_end && !self.next_key() {
+				return None;
+			}
 
-		let current_record_len;
-		if let Some(len) = self.current_record_len {
-			current_record_len = len;
-		} else {
-			let data = &data[self.pos..];
-			let (len, tail) = unsigned_varint::decode::u64(data).unwrap();
-			let varint_len = data.len() - tail.len();
-			self.pos += varint_len;
-			current_record_len = len as usize;
-		}
+			let current_record_len;
+			if let Some(len) = self.current_record_len {
+				current_record_len = len;
+			} else {
+				let data = &self.decoded[self.pos..self.current_key_data_end];
+				let Ok((len, tail)) = unsigned_varint::decode::usize(data) else {
+					self.corrupt_record("invalid record length");
+					continue;
+				};
+				let varint_len = data.len() - tail.len();
+				self.pos += varint_len;
+				current_record_len = len;
+			}
 
-		let r = Record {
-			key_pos: self.current_key_text_pos,
-			key_len: self.current_key_text_len,
-			fmt_pos: self.current_fmt_text_pos,
-			fmt_len: self.current_fmt_text_len,
-			value_pos: self.pos,
-			value_len: current_record_len + crate::TIMESTAMP_SIZE,
-			data,
-		};
+			let value_pos = self.pos;
+			// the length may be anything if the data is corrupt
+			let end = current_record_len
+				.checked_add(crate::TIMESTAMP_SIZE)
+				.and_then(|len| value_pos.checked_add(len))
+				.filter(|&end| end <= self.current_key_data_end);
+			let Some(end) = end else {
+				self.corrupt_record("truncated record");
+				continue;
+			};
+			self.pos = end;
+
+			let ts = BigEndian::read_u64(&self.decoded[value_pos..value_pos + 8]);
+			if !self.timestamps.contains(&ts) {
+				let after_end = match self.timestamps.1 {
+					Bound::Included(t) => ts > t,
+					Bound::Excluded(t) => ts >= t,
+					Unbounded => false,
+				};
+				if after_end {
+					// the timestamps in a key are sorted, so skip the rest of it
+					self.pos = self.current_key_data_end;
+				}
+				continue;
+			}
 
-		self.pos += current_record_len + crate::TIMESTAMP_SIZE;
-		Some(r)
+			return Some(Record {
+				key_pos: self.current_key_text_pos,
+				key_len: self.current_key_text_len,
+				fmt_pos: self.current_fmt_text_pos,
+				fmt_len: self.current_fmt_text_len,
+				value_pos,
+				value_len: current_record_len + crate::TIMESTAMP_SIZE,
+				data: self.decoded.clone(),
+			});
+		}
 	}
 }
@@ -1,36 +1,82 @@
+#[cfg(feature = "arrow")]
+mod arrow;
 #[cfg(feature = "by-key")]
 mod bykey;
+mod columnar;
+mod compaction;
 mod create_tx;
+#[cfg(feature = "csv")]
+mod csv_import;
 pub(crate) mod database_reader;
+mod decimal;
+mod external_sort;
 pub mod formatted;
+mod group_commit;
 mod key_reader;
 pub(crate) mod merge;
+#[cfg(feature = "parquet")]
+mod parquet;
+pub(crate) mod payload_encoding;
 pub(crate) mod rayon;
 mod records;
+mod repair;
 pub mod row_format;
+mod schema;
 pub(crate) mod segment;
+pub(crate) mod segment_index;
 pub(crate) mod segment_reader;
+mod sorting_tx;
 mod wildcard;
 pub(crate) mod write;
+mod write_buffer;
 
-pub use write::WriteFailure;
+pub use segment_reader::ReadError;
+pub use write::{Codec, WriteFailure};
 
+#[cfg(feature = "arrow")]
+pub use arrow::{read_arrow, write_arrow};
 #[cfg(feature = "by-key")]
 pub use bykey::*;
+pub use columnar::*;
+pub use compaction::{compact, Compaction, CompactionPolicy};
 pub use create_tx::*;
+#[cfg(feature = "csv")]
+pub use csv_import::{add_from_csv, CsvImport, TimestampUnit};
 pub use database_reader::*;
+pub use decimal::*;
+pub use group_commit::{GroupBatch, GroupCommit};
 pub use key_reader::*;
+#[cfg(feature = "parquet")]
+pub use parquet::{write_parquet, Partitioning, PARQUET_ROW_GROUP_ROWS};
 pub use records::*;
+pub use repair::*;
+pub use schema::*;
 pub(crate) use segment::*;
+#[cfg(feature = "derive")]
+pub use sonnerie_derive::{FromRow, RecordBuilder};
+pub use sorting_tx::*;
 pub use wildcard::*;
+pub use write_buffer::{WriteBatch, WriteBuffer, WRITE_BUFFER_JOURNAL};
 #[cfg(test)]
 mod tests;
 
+// lets the code generated by sonnerie-derive refer to `::sonnerie` in tests
+extern crate self as sonnerie;
+
+// used by the code generated by sonnerie-derive, not part of public api
+#[doc(hidden)]
+pub mod __private {
+	pub use compact_str::CompactString;
+}
+
 /// Nanoseconds since the unix epoch
 pub type Timestamp = u64;
 
 use std::ops::{Bound, RangeBounds};
 
+/// A range of timestamps that a query is restricted to
+pub(crate) type TimestampRange = (Bound<Timestamp>, Bound<Timestamp>);
+
 pub(crate) fn disassemble_range_bound<'k, T: Copy>(
 	rb: impl RangeBounds<T> + 'k,
 ) -> (Bound<T>, Bound<T>) {
@@ -176,35 +222,5 @@ pub fn _purge_compacted_files(
 	db: &DatabaseReader,
 	major: bool,
 ) -> std::io::Result<()> {
-	let source_transaction_paths = db.transaction_paths();
-
-	let removed_transaction_paths = if major {
-		compacted.commit_to(&dir.join("main"))?;
-		&source_transaction_paths[..]
-	} else {
-		// allow OS to atomically replace `first_path` (and don't delete it afterwards)
-		let keep_path = &source_transaction_paths.last().unwrap();
-
-		compacted.commit_to(keep_path)?;
-		&source_transaction_paths[..source_transaction_paths.len() - 1]
-	};
-
-	for txfile in removed_transaction_paths {
-		if txfile.file_name().expect("filename in txfile") == "main" {
-			continue;
-		}
-		if let Err(e) = std::fs::remove_file(txfile) {
-			eprintln!("warning: failed to remove {:?}: {}", txfile, e);
-		}
-	}
-
-	if major {
-		for txfile in db.delete_txes_paths() {
-			if let Err(e) = std::fs::remove_file(txfile) {
-				eprintln!("warning: failed to remove {:?}: {}", txfile, e);
-			}
-		}
-	}
-
-	Ok(())
+	compaction::purge_compacted_files(compacted, dir, db, major)
 }
@@ -31,6 +31,27 @@ enum Command {
 		/// Instead of nanoseconds since the epoch, use this strftime format.
 		#[clap(long)]
 		timestamp_format: Option<String>,
+
+		/// Fail if a key already has a format other than the format of its rows.
+		/// With --unsorted, this is checked once the lines are sorted, so the
+		/// error has no line number and the line can't be skipped.
+		#[clap(long)]
+		strict: bool,
+
+		/// The lines can be in any order. They're sorted before they're
+		/// added, in temporary files in the database's directory if there are many.
+		/// Of the lines with the same key and timestamp, the last is kept.
+		#[clap(long)]
+		unsorted: bool,
+
+		/// Add the lines that can be, and report the others on stderr,
+		/// instead of adding nothing if any line is bad.
+		#[clap(long)]
+		skip_bad_lines: bool,
+
+		/// Write the lines that couldn't be added to this file, as with --skip-bad-lines.
+		#[clap(long)]
+		reject_file: Option<PathBuf>,
 	},
 	/// Deletes records.
 	Delete {
@@ -83,6 +104,56 @@ enum Command {
 		/// With --gegnum, instead of nanoseconds since the epoch, use this strftime format.
 		#[clap(long, requires = "gegnum")]
 		timestamp_format: Option<String>,
+
+		/// Compress the compacted data with this codec: "lz4", "none",
+		/// "zstd" (level 3) or "zstd:LEVEL" (1-22). Zstandard is slower, but much smaller.
+		#[clap(long, default_value = "lz4")]
+		codec: Codec,
+	},
+	/// Compacts the database whenever it needs it, until it's stopped.
+	///
+	/// A minor compaction happens once there are more than --max-txes
+	/// transaction files, and a major one once they're bigger than
+	/// --major-ratio of the main database, or there are more than
+	/// --max-txes deletes.
+	Compactd {
+		/// Do a minor compaction once there are more than this many transaction
+		/// files, and a major one once there are more than this many deletes.
+		#[clap(long, default_value = "32")]
+		max_txes: usize,
+
+		/// Do a major compaction once the transaction files are bigger
+		/// than this fraction of the main database.
+		#[clap(long, default_value = "0.1")]
+		major_ratio: f64,
+
+		/// Check the database this often, in seconds.
+		#[clap(long, default_value = "60")]
+		interval: u64,
+
+		/// Compress the compacted data with this codec, as with "compact".
+		#[clap(long, default_value = "lz4")]
+		codec: Codec,
+	},
+	/// Checks every segment of the database for corruption.
+	///
+	/// Prints the file, offset and key range of each corrupt segment,
+	/// and fails if there are any.
+	Verify,
+	/// Salvages damaged transaction files.
+	///
+	/// Every readable segment of each file is copied into a new file
+	/// that replaces it, the damaged original is kept with a "damaged."
+	/// prefix, and a ".1", ".2"... suffix if it was repaired before.
+	/// What couldn't be recovered is printed.
+	Repair {
+		/// The files to repair (such as "main" or "tx.0123"). By default,
+		/// the files in which "verify" finds corruption.
+		files: Vec<PathBuf>,
+
+		/// Only report what would be lost, don't change anything.
+		#[clap(long)]
+		dry_run: bool,
 	},
 	/// Reads records.
 	Read {
@@ -135,8 +206,112 @@ enum Command {
 		/// Output only these columns, specified as comma-delimited numbers and hyphenated
 		/// ranges, as though entered as the "print these pages" text. The first column
 		/// is '1'. Example: 1,3,5-7
+		///
+		/// Columns can also be given by the names in the database's schema file.
+		/// Example: temperature,humidity
 		#[clap(long, short = 'C')]
 		columns: Option<String>,
+
+		/// Write records as "text" (the format that "add" reads), "csv", "tsv"
+		/// (escaping only tabs, line breaks and backslashes) or "jsonl" (a JSON
+		/// object per record, with typed values).
+		#[clap(long, default_value = "text")]
+		output: formatted::OutputFormat,
+	},
+	/// Writes records in a columnar format.
+	#[cfg(feature = "arrow")]
+	Export {
+		/// Write an Arrow IPC stream to stdout, with a "key" column, a "time" column
+		/// and a column for each column of the records. The records must all have
+		/// the same format, even those of one key; use --parquet for keys whose
+		/// format changed.
+		#[cfg_attr(
+			feature = "parquet",
+			clap(long, required_unless_present = "parquet", conflicts_with = "parquet")
+		)]
+		#[cfg_attr(not(feature = "parquet"), clap(long, required = true))]
+		arrow: bool,
+
+		/// Write Parquet files with the columns of --arrow into directories of this
+		/// directory, in parallel.
+		#[cfg(feature = "parquet")]
+		#[clap(long, value_name = "DIR")]
+		parquet: Option<PathBuf>,
+
+		/// With --parquet, make a directory for each key prefix ("prefix:SEPARATOR",
+		/// like "prefix:.") or for each period of time ("time:hour", "time:day",
+		/// "time:week" or "time:SECONDS"). The default is "prefix:.".
+		#[cfg(feature = "parquet")]
+		#[clap(long, requires = "parquet", conflicts_with = "arrow")]
+		partition: Option<Partitioning>,
+
+		/// Select the keys to export, "%" is the wildcard.
+		#[clap(required_unless_present_any = ["before_key", "after_key"])]
+		filter: Option<String>,
+
+		/// Export values before (but not including) this key.
+		#[clap(long, conflicts_with = "filter")]
+		before_key: Option<String>,
+
+		/// Export values after (and including) this key.
+		#[clap(long, conflicts_with = "filter")]
+		after_key: Option<String>,
+
+		/// Export values before (but not including) this time
+		/// (in ISO-9601 format, date, seconds, or nanosecond precision).
+		#[clap(long)]
+		before_time: Option<EasyNaiveDateTime>,
+
+		/// Export values after (and including) this time, as --before-time.
+		#[clap(long)]
+		after_time: Option<EasyNaiveDateTime>,
+	},
+	/// Adds records from a file in a columnar format, in any order.
+	#[cfg(feature = "arrow")]
+	Import {
+		/// Read an Arrow IPC stream or file, with a "key" column of strings,
+		/// a "time" column of timestamps and a column for each column of the records.
+		#[clap(long, required = true)]
+		arrow: bool,
+
+		/// Read this file instead of stdin.
+		file: Option<PathBuf>,
+	},
+	/// Adds records from a CSV file with a header row, in any order.
+	#[cfg(feature = "csv")]
+	ImportCsv {
+		/// Read this file instead of stdin.
+		file: Option<PathBuf>,
+
+		/// The column of keys.
+		#[clap(long, default_value = "key")]
+		key_column: String,
+
+		/// The column of timestamps.
+		#[clap(long, default_value = "time")]
+		time_column: String,
+
+		/// The columns of values, in order, separated by commas. By default,
+		/// every other column.
+		#[clap(long, short = 'C', value_delimiter = ',')]
+		columns: Option<Vec<String>>,
+
+		/// The format of the values. By default, it's inferred from the first rows.
+		#[clap(short, long)]
+		format: Option<String>,
+
+		/// Instead of ISO-8601, use this strftime format.
+		#[clap(long)]
+		timestamp_format: Option<String>,
+
+		/// Read timestamps that are integers since the epoch in this unit:
+		/// "s", "ms", "us" or "ns". Without it, an integer isn't a timestamp.
+		#[clap(long, conflicts_with = "timestamp_format")]
+		timestamp_unit: Option<TimestampUnit>,
+
+		/// The character between fields.
+		#[clap(long, default_value = ",")]
+		delimiter: char,
 	},
 }
 
@@ -147,18 +322,48 @@ fn main() -> std::io::Result<()> {
 		Command::Add {
 			format,
 			timestamp_format,
-		} => add(&opt.dir, &format, timestamp_format.as_deref()),
+			strict,
+			unsorted,
+			skip_bad_lines,
+			reject_file,
+		} => add(
+			&opt.dir,
+			&format,
+			timestamp_format.as_deref(),
+			strict,
+			unsorted,
+			skip_bad_lines,
+			reject_file.as_deref(),
+		),
 		Command::Compact {
 			major,
 			gegnum,
 			timestamp_format,
+			codec,
 		} => compact(
 			&opt.dir,
 			major,
 			gegnum.as_deref(),
 			timestamp_format.as_deref(),
+			codec,
 		)
 		.expect("compacting"),
+		Command::Compactd {
+			max_txes,
+			major_ratio,
+			interval,
+			codec,
+		} => compactd(
+			&opt.dir,
+			&CompactionPolicy {
+				max_transactions: max_txes,
+				max_transaction_ratio: major_ratio,
+				codec,
+			},
+			std::time::Duration::from_secs(interval),
+		),
+		Command::Verify => verify(&opt.dir)?,
+		Command::Repair { files, dry_run } => repair(&opt.dir, files, dry_run).expect("repairing"),
 		Command::Delete {
 			filter,
 			after_key,
@@ -196,16 +401,22 @@ fn main() -> std::io::Result<()> {
 			after_time,
 			parallel,
 			columns,
+			output,
 		} => {
-			let after_time = after_time.map(|t| {
-				t.0.and_utc()
-					.timestamp_nanos_opt()
-					.expect(EXPECT_TIMESTAMP_CORRECTNESS) as u64
+			use std::ops::Bound;
+			let after_time = after_time.map_or(Bound::Unbounded, |t| {
+				Bound::Included(
+					t.0.and_utc()
+						.timestamp_nanos_opt()
+						.expect(EXPECT_TIMESTAMP_CORRECTNESS) as u64,
+				)
 			});
-			let before_time = before_time.map(|t| {
-				t.0.and_utc()
-					.timestamp_nanos_opt()
-					.expect(EXPECT_TIMESTAMP_CORRECTNESS) as u64
+			let before_time = before_time.map_or(Bound::Unbounded, |t| {
+				Bound::Excluded(
+					t.0.and_utc()
+						.timestamp_nanos_opt()
+						.expect(EXPECT_TIMESTAMP_CORRECTNESS) as u64,
+				)
 			});
 
 			let stdout = std::io::stdout();
@@ -227,13 +438,34 @@ fn main() -> std::io::Result<()> {
 				formatted::PrintTimestamp::FormatString(&timestamp_format)
 			};
 
-			let column_selection = columns
-				.map(|c| choice_string::parse(&c).unwrap())
-				.unwrap_or(choice_string::Selection::All);
+			// columns are either numbers or names from the schema
+			let mut column_names: Option<Vec<String>> = None;
+			let column_selection = match columns {
+				None => choice_string::Selection::All,
+				Some(c) if c.chars().all(|c| c.is_ascii_digit() || ",- ".contains(c)) => {
+					choice_string::parse(&c).unwrap()
+				}
+				Some(c) => {
+					column_names = Some(c.split(',').map(|n| n.trim().to_owned()).collect());
+					choice_string::Selection::None
+				}
+			};
+			// a selection by name needs the schema, and the other outputs name columns with it
+			let no_schema = Schema::default();
+			let schema = if column_names.is_some() {
+				db.schema()?
+			} else if output != formatted::OutputFormat::Text {
+				db.schema().unwrap_or_else(|e| {
+					eprintln!("warning: not naming columns: {}", e);
+					&no_schema
+				})
+			} else {
+				&no_schema
+			};
 
 			macro_rules! filter_parallel {
 				($filter:expr) => {{
-					let filter = $filter;
+					let filter = $filter.with_time_range((after_time, before_time));
 
 					use std::io::BufWriter;
 					use std::process::*;
@@ -248,55 +480,87 @@ fn main() -> std::io::Result<()> {
 							.spawn()
 							.unwrap();
 						let stdout = BufWriter::new(child.stdin.take().unwrap());
-						(child, stdout)
+						(child, stdout, String::new(), true)
 					};
 
-					filter
-						.into_par_iter()
-						.for_each_init(subproc, |(_, out), record| {
-							let ts = record.timestamp_nanos();
-							if let Some(after_time) = after_time {
-								if ts < after_time {
-									return;
-								}
-							}
-							if let Some(before_time) = before_time {
-								if ts >= before_time {
-									return;
+					filter.into_par_iter().for_each_init(
+						subproc,
+						|(_, out, skipped_key, needs_header), record| {
+							let named;
+							let column_selection = match &column_names {
+								Some(names) => {
+									let Some(n) =
+										named_columns(schema, &record, names, skipped_key)
+									else {
+										return;
+									};
+									named = n;
+									&named
 								}
+								None => &column_selection,
+							};
+							if std::mem::take(needs_header) {
+								formatted::print_header_as(
+									&record,
+									out,
+									output,
+									print_record_format,
+									column_selection,
+									schema,
+								)
+								.expect("failed to write to subprocess");
 							}
-							formatted::print_record(
+							formatted::print_record_as(
 								&record,
 								out,
+								output,
 								print_timestamp,
 								print_record_format,
-								&column_selection,
+								column_selection,
+								schema,
 							)
 							.expect("failed to write to subprocess");
 							writeln!(out, "").expect("failed to write to subprocess");
-						});
+						},
+					);
 				}};
 			}
 			macro_rules! filter {
 				($filter:expr) => {{
-					for record in $filter {
-						let ts = record.timestamp_nanos();
-						if let Some(after_time) = after_time {
-							if ts < after_time {
-								continue;
-							}
-						}
-						if let Some(before_time) = before_time {
-							if ts >= before_time {
-								continue;
+					let mut skipped_key = String::new();
+					let mut needs_header = true;
+					for record in $filter.with_time_range((after_time, before_time)) {
+						let named;
+						let column_selection = match &column_names {
+							Some(names) => {
+								let Some(n) =
+									named_columns(schema, &record, names, &mut skipped_key)
+								else {
+									continue;
+								};
+								named = n;
+								&named
 							}
+							None => &column_selection,
+						};
+						if std::mem::take(&mut needs_header) {
+							formatted::print_header_as(
+								&record,
+								&mut stdout,
+								output,
+								print_record_format,
+								column_selection,
+								schema,
+							)?;
 						}
-						formatted::print_record(
+						formatted::print_record_as(
 							&record,
 							&mut stdout,
+							output,
 							print_timestamp,
 							print_record_format,
-							&column_selection,
+							column_selection,
+							schema,
 						)?;
 						writeln!(&mut stdout, "")?;
 					}
@@ -327,6 +591,101 @@ fn main() -> std::io::Result<()> {
 				}
 			}
 		}
+		#[cfg(feature = "arrow")]
+		Command::Export {
+			arrow: _,
+			#[cfg(feature = "parquet")]
+			parquet,
+			#[cfg(feature = "parquet")]
+			partition,
+			filter,
+			before_key,
+			after_key,
+			before_time,
+			after_time,
+		} => {
+			use std::ops::Bound;
+			let nanos = |t: EasyNaiveDateTime| {
+				t.0.and_utc()
+					.timestamp_nanos_opt()
+					.expect(EXPECT_TIMESTAMP_CORRECTNESS) as u64
+			};
+			let time_range = (
+				after_time.map_or(Bound::Unbounded, |t| Bound::Included(nanos(t))),
+				before_time.map_or(Bound::Unbounded, |t| Bound::Excluded(nanos(t))),
+			);
+
+			let db = DatabaseReader::new(&opt.dir)?;
+			write_empty_file_warnings(&mut std::io::stderr().lock(), db.empty_transaction_files())
+				.unwrap();
+			let wildcard = filter.as_deref().map(Wildcard::new);
+			let records = match (after_key.as_deref(), before_key.as_deref(), &wildcard) {
+				(Some(a), None, None) => db.get_range(a..),
+				(None, Some(b), None) => db.get_range(..b),
+				(Some(a), Some(b), None) => db.get_range(a..b),
+				(None, None, Some(w)) => db.get_filter(w),
+				_ => unreachable!(),
+			};
+
+			let records = records.with_time_range(time_range);
+
+			#[cfg(feature = "parquet")]
+			if let Some(dir) = parquet {
+				let partition = partition.unwrap_or(Partitioning::KeyPrefix { separator: '.' });
+				write_parquet(records, db.schema()?, &partition, &dir)?;
+				return Ok(());
+			}
+			let stdout = std::io::stdout();
+			let stdout = std::io::BufWriter::new(stdout.lock());
+			write_arrow(records, db.schema()?, stdout)?;
+		}
+		#[cfg(feature = "arrow")]
+		Command::Import { arrow: _, file } => {
+			let mut tx = CreateTx::new(&opt.dir).expect("creating tx");
+			tx.set_schema(DatabaseReader::new(&opt.dir)?.schema()?.clone());
+			let imported = match file {
+				Some(file) => read_arrow(&mut tx, std::io::BufReader::new(File::open(file)?)),
+				None => {
+					// the input is read twice, so copy it to a file next to the database
+					let mut input = tempfile::tempfile_in(&opt.dir)?;
+					std::io::copy(&mut std::io::stdin().lock(), &mut input)?;
+					read_arrow(&mut tx, std::io::BufReader::new(input))
+				}
+			};
+			import_or_exit(tx, imported);
+		}
+		#[cfg(feature = "csv")]
+		Command::ImportCsv {
+			file,
+			key_column,
+			time_column,
+			columns,
+			format,
+			timestamp_format,
+			timestamp_unit,
+			delimiter,
+		} => {
+			if !delimiter.is_ascii() {
+				eprintln!("the delimiter must be an ASCII character");
+				std::process::exit(1);
+			}
+			let import = CsvImport {
+				key_column,
+				time_column,
+				value_columns: columns,
+				format,
+				timestamp_format,
+				timestamp_unit,
+				delimiter: delimiter as u8,
+			};
+			let mut tx = CreateTx::new(&opt.dir).expect("creating tx");
+			tx.set_schema(DatabaseReader::new(&opt.dir)?.schema()?.clone());
+			let imported = match file {
+				Some(file) => add_from_csv(&mut tx, File::open(file)?, &import),
+				None => add_from_csv(&mut tx, std::io::stdin().lock(), &import),
+			};
+			import_or_exit(tx, imported);
+		}
 	}
 
 	Ok(())
@@ -341,16 +700,106 @@ fn main() -> std::io::Result<()> {
 //
 // delete's approach is to copy what add_from_stream does and call
 // CreateTx::add_record with a prepared bare payload
-fn add(dir: &Path, fmt: &str, ts_format: Option<&str>) {
+fn add(
+	dir: &Path,
+	fmt: &str,
+	ts_format: Option<&str>,
+	strict: bool,
+	unsorted: bool,
+	skip_bad_lines: bool,
+	reject_file: Option<&Path>,
+) {
 	let db = DatabaseReader::new(dir).expect("opening db");
 	write_empty_file_warnings(&mut std::io::stderr().lock(), db.empty_transaction_files()).unwrap();
 	let mut tx = CreateTx::new(dir).expect("creating tx");
+	tx.set_schema(db.schema().expect("reading the schema").clone());
+	if strict {
+		tx.set_strict(std::sync::Arc::new(db));
+	}
+	let mut tx = if unsorted {
+		AddTx::Unsorted(SortingCreateTx::new(tx))
+	} else {
+		AddTx::Sorted(tx)
+	};
 
 	let stdin = std::io::stdin();
 	let mut stdin = stdin.lock();
 
-	formatted::add_from_stream(&mut tx, fmt, &mut stdin, ts_format).expect("adding value");
-	tx.commit().expect("failed to commit transaction");
+	let mut rejects = reject_file
+		.map(|f| std::io::BufWriter::new(File::create(f).expect("creating reject file")));
+	let skip_bad_lines = skip_bad_lines || rejects.is_some();
+	let added = formatted::add_from_stream_rejecting(
+		tx.sink(),
+		Some(fmt),
+		&mut stdin,
+		ts_format,
+		&mut |e, line| {
+			if !skip_bad_lines {
+				return Err(e);
+			}
+			eprintln!("skipping {}", e);
+			if let Some(rejects) = &mut rejects {
+				writeln!(rejects, "{}", line).expect("writing reject file");
+			}
+			Ok(())
+		},
+	);
+	match added {
+		Ok(0) => {}
+		Ok(skipped) => eprintln!("skipped {} bad lines", skipped),
+		Err(e) => {
+			drop(tx);
+			eprintln!("{}", e);
+			std::process::exit(1);
+		}
+	}
+	if let Some(mut rejects) = rejects {
+		rejects.flush().expect("writing reject file");
+	}
+	if let Err(e) = tx.commit() {
+		eprintln!("{}", e);
+		std::process::exit(1);
+	}
+}
+
+/// Commit the transaction of an import, or print why it failed and exit
+#[cfg(any(feature = "arrow", feature = "csv"))]
+fn import_or_exit(tx: CreateTx, imported: Result<u64, WriteFailure>) {
+	if let Err(e) = imported {
+		drop(tx);
+		match e {
+			// like "line 2: ...", for the errors of the input
+			WriteFailure::IOError(e) => eprintln!("{}", e),
+			e => eprintln!("{}", e),
+		}
+		std::process::exit(1);
+	}
+	if let Err(e) = tx.commit() {
+		eprintln!("{}", e);
+		std::process::exit(1);
+	}
+}
+
+/// The transaction of `add`, for sorted or unsorted input
+enum AddTx {
+	Sorted(CreateTx),
+	Unsorted(SortingCreateTx),
+}
+
+impl AddTx {
+	fn sink(&mut self) -> &mut dyn RecordSink {
+		match self {
+			AddTx::Sorted(tx) => tx,
+			AddTx::Unsorted(tx) => tx,
+		}
+	}
+
+	fn commit(self) -> Result<(), WriteFailure> {
+		match self {
+			AddTx::Sorted(tx) => Ok(tx.commit()?),
+			AddTx::Unsorted(tx) => tx.commit(),
+		}
+	}
 }
 
 // delete prepares a payload, as detailed by the specification
@@ -394,14 +843,104 @@ fn delete(
 	tx.commit().expect("failed to commit transaction");
 }
 
+fn verify(dir: &Path) -> std::io::Result<()> {
+	let db = DatabaseReader::new(dir)?;
+	let corrupt = db.verify()?;
+	for c in &corrupt {
+		println!("{}", c);
+	}
+	if !corrupt.is_empty() {
+		eprintln!("found {} corrupt segments", corrupt.len());
+		std::process::exit(1);
+	}
+	Ok(())
+}
+
+fn repair(dir: &Path, files: Vec<PathBuf>, dry_run: bool) -> Result<(), WriteFailure> {
+	use fs2::FileExt;
+
+	// don't let a compaction remove the files while we replace them
+	let lock = File::create(dir.join(".compact"))?;
+	lock.lock_exclusive()?;
+
+	let mut paths: Vec<PathBuf> = files.iter().map(|f| dir.join(f)).collect();
+	if paths.is_empty() {
+		let db = DatabaseReader::new(dir)?;
+		paths = db.verify()?.into_iter().map(|c| c.path).collect();
+		paths.dedup();
+	}
+
+	for path in paths {
+		let mut tx = CreateTx::new(dir)?;
+		let salvaged = sonnerie::salvage(&path, &mut tx)?;
+		for lost in &salvaged.lost {
+			println!("lost {}", lost);
+		}
+		eprintln!(
+			"{}: recovered {} records in {} segments, lost {} parts",
+			path.display(),
+			salvaged.records,
+			salvaged.segments,
+			salvaged.lost.len(),
+		);
+		if dry_run || salvaged.lost.is_empty() {
+			continue;
+		}
+
+		let name = path.file_name().expect("file name").to_string_lossy();
+		let kept = keep_damaged(dir, &path, &name)?;
+		eprintln!(
+			"{}: the damaged file is kept as {}",
+			path.display(),
+			kept.display()
+		);
+		if salvaged.records == 0 && name == "main" {
+			// committing nothing would leave `main` as it is
+			File::create(&path)?;
+		} else {
+			tx.commit_to(&path)?;
+		}
+	}
+	Ok(())
+}
+
+/// Link the damaged file at `path` as "damaged.{name}", or with a ".1",
+/// ".2"... suffix if a file from an earlier repair has that name
+fn keep_damaged(dir: &Path, path: &Path, name: &str) -> std::io::Result<PathBuf> {
+	for n in 0.. {
+		let kept = match n {
+			0 => dir.join(format!("damaged.{}", name)),
+			n => dir.join(format!("damaged.{}.{}", name, n)),
+		};
+		match std::fs::hard_link(path, &kept) {
+			Ok(()) => return Ok(kept),
+			Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+			Err(e) => return Err(e),
+		}
+	}
+	unreachable!()
+}
+
 fn compact(
 	dir: &Path,
 	major: bool,
 	gegnum: Option<&std::ffi::OsStr>,
 	ts_format: Option<&str>,
+	codec: Codec,
 ) -> Result<(), crate::WriteFailure> {
 	use fs2::FileExt;
 
+	let Some(gegnum) = gegnum else {
+		let compaction = if major {
+			Compaction::Major
+		} else {
+			Compaction::Minor
+		};
+		let n = sonnerie::compact(dir, compaction, codec)?;
+		eprintln!("compacted {} records", n);
+		return Ok(());
+	};
+
 	let lock = File::create(dir.join(".compact"))?;
 	lock.lock_exclusive()?;
 
@@ -424,73 +963,61 @@ fn compact(
 		}
 		let db = std::sync::Arc::new(db);
 
-		let mut compacted = CreateTx::new(dir)?;
-
-		if let Some(gegnum) = gegnum {
-			let mut child = std::process::Command::new("/bin/sh")
-				.arg("-c")
-				.arg(gegnum)
-				.stdin(std::process::Stdio::piped())
-				.stdout(std::process::Stdio::piped())
-				.spawn()
-				.expect("unable to run --gegnum process");
-
-			let childinput = child.stdin.take().expect("process had no stdin");
-			let mut childinput = std::io::BufWriter::new(childinput);
-
-			let ts_format_cloned = ts_format.map(|m| m.to_owned());
-
-			// a thread that reads from "db" and writes to the child
-			let reader_db = db.clone();
-			let reader_thread = std::thread::spawn(move || -> std::io::Result<()> {
-				let timestamp_format = if let Some(ts_format) = &ts_format_cloned {
-					formatted::PrintTimestamp::FormatString(ts_format)
-				} else {
-					formatted::PrintTimestamp::Nanos
-				};
-
-				let reader = reader_db.get_range(..);
-				for record in reader {
-					formatted::print_record(
-						&record,
-						&mut childinput,
-						timestamp_format,
-						formatted::PrintRecordFormat::Yes,
-						&choice_string::Selection::All,
-					)?;
-					writeln!(&mut childinput)?;
-				}
-				Ok(())
-			});
+		let mut compacted = CreateTx::new_with_codec(dir, codec)?;
 
-			let childoutput = child.stdout.take().expect("process had no stdout");
-			let mut childoutput = std::io::BufReader::new(childoutput);
-			formatted::add_from_stream_with_fmt(&mut compacted, &mut childoutput, ts_format)?;
-
-			reader_thread
-				.join()
-				.expect("failed to join subprocess writing thread")
-				.expect("child writer failed");
-			let result = child.wait()?;
-			if !result.success() {
-				panic!("child process failed: cancelling compact");
-			}
-		} else {
-			{
-				let ps = db.transaction_paths();
-				if ps.len() == 1 && ps[0].file_name().expect("filename") == "main" {
-					eprintln!("nothing to do");
-					return Ok(());
-				}
-			}
-			// create the new transaction after opening the database reader
-			let reader = db.get_range(..);
-			let mut n = 0u64;
+		let mut child = std::process::Command::new("/bin/sh")
+			.arg("-c")
+			.arg(gegnum)
+			.stdin(std::process::Stdio::piped())
+			.stdout(std::process::Stdio::piped())
+			.spawn()
+			.expect("unable to run --gegnum process");
+
+		let childinput = child.stdin.take().expect("process had no stdin");
+		let mut childinput = std::io::BufWriter::new(childinput);
+
+		let ts_format_cloned = ts_format.map(|m| m.to_owned());
+
+		// a thread that reads from "db" and writes to the child
+		let reader_db = db.clone();
+		let reader_thread = std::thread::spawn(move || -> std::io::Result<()> {
+			let timestamp_format = if let Some(ts_format) = &ts_format_cloned {
+				formatted::PrintTimestamp::FormatString(ts_format)
+			} else {
+				formatted::PrintTimestamp::Nanos
+			};
+
+			let reader = reader_db.get_range(..);
 			for record in reader {
-				compacted.add_record_raw(record.key(), record.format(), record.raw())?;
-				n += 1;
+				formatted::print_record(
+					&record,
+					&mut childinput,
+					timestamp_format,
+					formatted::PrintRecordFormat::Yes,
+					&choice_string::Selection::All,
+				)?;
+				writeln!(&mut childinput)?;
 			}
-			eprintln!("compacted {} records", n);
+			Ok(())
+		});
+
+		let childoutput = child.stdout.take().expect("process had no stdout");
+		let mut childoutput = std::io::BufReader::new(childoutput);
+		if let Err(e) =
+			formatted::add_from_stream_with_fmt(&mut compacted, &mut childoutput, ts_format)
+		{
+			let _ = child.kill();
+			let _ = child.wait();
+			return Err(e.into());
+		}
+
+		reader_thread
+			.join()
+			.expect("failed to join subprocess writing thread")
+			.expect("child writer failed");
+		let result = child.wait()?;
+		if !result.success() {
+			panic!("child process failed: cancelling compact");
 		}
 
 		sonnerie::_purge_compacted_files(compacted, dir, &db, major).expect("failure compacting");
@@ -498,6 +1025,46 @@ fn compact(
 	Ok(())
 }
 
+/// Run `policy` on the database every `interval`
+fn compactd(dir: &Path, policy: &CompactionPolicy, interval: std::time::Duration) -> ! {
+	loop {
+		match policy.run(dir) {
+			Ok(Some(compaction)) => eprintln!("did a {} compaction", compaction),
+			Ok(None) => {}
+			Err(e) => eprintln!("error compacting: {}", e),
+		}
+		std::thread::sleep(interval);
+	}
+}
+
+/// The selection of the columns called `names` in the schema of `record`'s key,
+/// or None if it doesn't have them. The record is then skipped, with a warning
+/// the first time for each key (`skipped_key` is the last key that was warned about)
+fn named_columns(
+	schema: &Schema,
+	record: &Record,
+	names: &[String],
+	skipped_key: &mut String,
+) -> Option<choice_string::Selection> {
+	let columns = names
+		.iter()
+		.map(|name| {
+			let idx = schema.column_index(record.key(), record.format(), name)?;
+			Ok(choice_string::SomeElementType::Individual(idx + 1))
+		})
+		.collect::<std::io::Result<_>>();
+	match columns {
+		Ok(columns) => Some(choice_string::Selection::Some(columns)),
+		Err(e) => {
+			if skipped_key != record.key() {
+				eprintln!("warning: skipping records: {}", e);
+				skipped_key.replace_range(.., record.key());
+			}
+			None
+		}
+	}
+}
+
 fn write_empty_file_warnings(
 	out: &mut impl std::io::Write,
 	names: &[PathBuf],
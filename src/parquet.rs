@@ -0,0 +1,319 @@
+//! Export records as Parquet files, partitioned by key prefix or by time.
+//!
+//! Each partition is a directory named like `prefix=sensor` or
+//! `time=2024-01-01T000000`, with a file for each format of the records
+//! in it and for each part of the database that was exported in parallel
+//! (and more if a part had too many files open).
+//! The files have the columns of [`write_arrow`](crate::write_arrow).
+
+use crate::arrow::{arrow_schema, BatchBuilder, BATCH_ROWS};
+use crate::{ColumnBatch, DatabaseRecordReader, Record, Schema, Timestamp};
+use ::parquet::arrow::ArrowWriter;
+use ::parquet::basic::Compression;
+use ::parquet::file::properties::WriterProperties;
+use rayon::prelude::*;
+use std::collections::hash_map::{Entry, HashMap};
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+/// The number of rows in each row group of an exported file
+pub const PARQUET_ROW_GROUP_ROWS: usize = 256 * 1024;
+
+/// How many files each parallel part of an export keeps open. When it
+/// needs another, the least recently used is finished.
+pub(crate) const PARQUET_OPEN_FILES: usize = 64;
+
+/// How [`write_parquet`] divides records into directories
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Partitioning {
+	/// A directory for each part of the key before `separator`
+	/// (the whole key if it has none)
+	KeyPrefix { separator: char },
+	/// A directory for each period of `seconds` since the epoch
+	Time { seconds: u64 },
+}
+
+impl Partitioning {
+	/// The name of the directory of a record of `key` at `timestamp`
+	fn directory(&self, key: &str, timestamp: Timestamp) -> String {
+		match self {
+			Partitioning::KeyPrefix { separator } => {
+				format!("prefix={}", escape(key.split(*separator).next().unwrap()))
+			}
+			Partitioning::Time { seconds: period } => {
+				let seconds = timestamp / 1_000_000_000;
+				let start = (seconds - seconds % period) as i64;
+				let start = chrono::DateTime::from_timestamp(start, 0).unwrap();
+				format!("time={}", start.format("%Y-%m-%dT%H%M%S"))
+			}
+		}
+	}
+}
+
+impl std::str::FromStr for Partitioning {
+	type Err = String;
+
+	/// `prefix:SEPARATOR`, or `time:` followed by `hour`, `day`, `week` or a number of seconds
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		if let Some(separator) = s.strip_prefix("prefix:") {
+			let mut chars = separator.chars();
+			match (chars.next(), chars.next()) {
+				(Some(separator), None) => Ok(Partitioning::KeyPrefix { separator }),
+				_ => Err(format!("\"{}\" is not a single character", separator)),
+			}
+		} else if let Some(period) = s.strip_prefix("time:") {
+			let seconds = match period {
+				"hour" => 3600,
+				"day" => 86400,
+				"week" => 7 * 86400,
+				n => n
+					.parse()
+					.ok()
+					.filter(|&n| n != 0)
+					.ok_or_else(|| format!("invalid period \"{}\"", n))?,
+			};
+			Ok(Partitioning::Time { seconds })
+		} else {
+			Err(format!(
+				"unknown partitioning \"{}\", expected \"prefix:SEPARATOR\" or \"time:PERIOD\"",
+				s
+			))
+		}
+	}
+}
+
+/// Percent-encode the bytes of `s` that don't belong in a file name
+fn escape(s: &str) -> String {
+	let mut escaped = String::with_capacity(s.len());
+	for b in s.bytes() {
+		if b.is_ascii_alphanumeric() || b"-_.~".contains(&b) {
+			escaped.push(b as char);
+		} else {
+			escaped += &format!("%{:02X}", b);
+		}
+	}
+	escaped
+}
+
+/// An open Parquet file of one partition and format
+struct PartitionFile {
+	builder: BatchBuilder,
+	writer: ArrowWriter<BufWriter<File>>,
+	/// when a batch was last written to it, to find the least recently used
+	used: u64,
+}
+
+impl PartitionFile {
+	fn close(mut self) -> std::io::Result<()> {
+		if self.builder.rows() != 0 {
+			self.writer.write(&self.builder.finish()?)?;
+		}
+		self.writer.close()?;
+		Ok(())
+	}
+}
+
+/// The files written by one parallel part of an export
+struct Partitions<'a> {
+	dir: &'a Path,
+	partitioning: &'a Partitioning,
+	names: &'a Schema,
+	part: usize,
+	/// the consecutive records of a key in one partition
+	batch: Option<(String, ColumnBatch)>,
+	files: HashMap<(String, String), PartitionFile>,
+	max_open_files: usize,
+	/// the number of batches written, and of files opened
+	batches: u64,
+	opened: usize,
+	/// every file of the export, which are removed if it fails
+	created: &'a Mutex<Vec<PathBuf>>,
+	rows: u64,
+}
+
+impl<'a> Partitions<'a> {
+	fn push(&mut self, record: Record) -> std::io::Result<()> {
+		let directory = self
+			.partitioning
+			.directory(record.key(), record.timestamp_nanos());
+		match &mut self.batch {
+			Some((d, batch))
+				if *d == directory
+					&& batch.key == record.key()
+					&& batch.format == record.format() =>
+			{
+				batch.push(&record)
+			}
+			_ => {
+				self.write_batch()?;
+				let mut batch = ColumnBatch::new(record.key(), record.format())?;
+				batch.push(&record)?;
+				self.batch = Some((directory, batch));
+				Ok(())
+			}
+		}
+	}
+
+	fn write_batch(&mut self) -> std::io::Result<()> {
+		let Some((directory, batch)) = self.batch.take() else {
+			return Ok(());
+		};
+		let file_key = (directory, batch.format.clone());
+		if !self.files.contains_key(&file_key) && self.files.len() >= self.max_open_files {
+			let least_recent = self
+				.files
+				.iter()
+				.min_by_key(|(_, file)| file.used)
+				.map(|(k, _)| k.clone())
+				.unwrap();
+			self.files.remove(&least_recent).unwrap().close()?;
+		}
+		let file = match self.files.entry(file_key) {
+			Entry::Occupied(e) => e.into_mut(),
+			Entry::Vacant(e) => {
+				let (directory, format) = e.key();
+				let schema = arrow_schema(&batch.key, format, self.names)?;
+				let builder = BatchBuilder::new(schema, format);
+
+				let directory = self.dir.join(directory);
+				std::fs::create_dir_all(&directory)?;
+				let path = directory.join(format!(
+					"part-{:05}-{:05}-{}.parquet",
+					self.part, self.opened, format
+				));
+				self.opened += 1;
+				let properties = WriterProperties::builder()
+					.set_max_row_group_size(PARQUET_ROW_GROUP_ROWS)
+					.set_compression(Compression::SNAPPY)
+					.build();
+				self.created.lock().unwrap().push(path.clone());
+				let writer = ArrowWriter::try_new(
+					BufWriter::new(File::create(path)?),
+					builder.schema().clone(),
+					Some(properties),
+				)?;
+				e.insert(PartitionFile {
+					builder,
+					writer,
+					used: 0,
+				})
+			}
+		};
+		self.batches += 1;
+		file.used = self.batches;
+		file.builder.append(&batch)?;
+		if file.builder.rows() >= BATCH_ROWS {
+			file.writer.write(&file.builder.finish()?)?;
+		}
+		self.rows += batch.len() as u64;
+		Ok(())
+	}
+
+	fn finish(mut self) -> std::io::Result<u64> {
+		self.write_batch()?;
+		for (_, file) in self.files {
+			file.close()?;
+		}
+		Ok(self.rows)
+	}
+}
+
+/// Remove the files of an earlier export to `dir`, from the
+/// partition directories in it
+fn remove_earlier_export(dir: &Path) -> std::io::Result<()> {
+	let partitions = match std::fs::read_dir(dir) {
+		Ok(partitions) => partitions,
+		Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+		Err(e) => return Err(e),
+	};
+	for partition in partitions {
+		let partition = partition?;
+		let name = partition.file_name();
+		let name = name.to_string_lossy();
+		if !(name.starts_with("prefix=") || name.starts_with("time="))
+			|| !partition.file_type()?.is_dir()
+		{
+			continue;
+		}
+		for file in std::fs::read_dir(partition.path())? {
+			let file = file?;
+			let name = file.file_name();
+			let name = name.to_string_lossy();
+			if name.starts_with("part-")
+				&& name.ends_with(".parquet")
+				&& file.file_type()?.is_file()
+			{
+				std::fs::remove_file(file.path())?;
+			}
+		}
+	}
+	Ok(())
+}
+
+/// Write `records` to Parquet files in directories of `dir`, returning the number of records
+///
+/// The parts of the database are exported in parallel with rayon, each key is
+/// read by only one thread. Columns are named by `names` like in
+/// [`write_arrow`](crate::write_arrow). The files of an earlier export to `dir`
+/// are removed first, and if the export fails, the files it wrote are removed.
+pub fn write_parquet(
+	records: DatabaseRecordReader<'_>,
+	names: &Schema,
+	partitioning: &Partitioning,
+	dir: &Path,
+) -> std::io::Result<u64> {
+	write_parquet_files(records, names, partitioning, dir, PARQUET_OPEN_FILES)
+}
+
+pub(crate) fn write_parquet_files(
+	records: DatabaseRecordReader<'_>,
+	names: &Schema,
+	partitioning: &Partitioning,
+	dir: &Path,
+	max_open_files: usize,
+) -> std::io::Result<u64> {
+	if let Partitioning::Time { seconds: 0 } = partitioning {
+		return Err(std::io::Error::new(
+			std::io::ErrorKind::InvalidInput,
+			"can't partition by periods of 0 seconds",
+		));
+	}
+	remove_earlier_export(dir)?;
+
+	let parts = AtomicUsize::new(0);
+	let created = Mutex::new(vec![]);
+	let rows = records
+		.into_par_iter()
+		.try_fold(
+			|| Partitions {
+				dir,
+				partitioning,
+				names,
+				part: parts.fetch_add(1, Ordering::Relaxed),
+				batch: None,
+				files: HashMap::new(),
+				max_open_files,
+				batches: 0,
+				opened: 0,
+				created: &created,
+				rows: 0,
+			},
+			|mut partitions, record| {
+				partitions.push(record)?;
+				Ok(partitions)
+			},
+		)
+		.map(|partitions: std::io::Result<Partitions>| partitions?.finish())
+		.try_reduce(|| 0, |a, b| Ok(a + b));
+
+	if rows.is_err() {
+		// don't leave files without a footer
+		for path in created.into_inner().unwrap() {
+			let _ = std::fs::remove_file(path);
+		}
+	}
+	rows
+}
@@ -0,0 +1,361 @@
+//! The compact payload encoding of segment version 0x0300.
+//!
+//! The payload is first built in the plain format (described in `file_format.md`)
+//! and then converted by [`encode`] before it's compressed. After decompressing,
+//! [`decode`] restores the plain format, so the rest of the readers don't
+//! need to know about it.
+//!
+//! For each key, timestamps are stored as the delta of their delta, as a zigzag
+//! varint. `f` and `F` columns are XORed with the previous value in the same
+//! column, and only the bytes of the XOR between its leading and trailing
+//! zero bytes are stored (after a byte that counts those zero bytes), so
+//! a repeated value takes a single byte. All other columns are stored unchanged.
+
+use byteorder::{BigEndian, ByteOrder};
+
+use unsigned_varint::decode;
+use unsigned_varint::encode;
+
+/// Returns true if I know how to encode each column in `fmt`
+fn can_encode(fmt: &[u8]) -> bool {
+	fmt.iter().all(|c| {
+		matches!(
+			c,
+			b'f' | b'F'
+				| b'u' | b'U'
+				| b'i' | b'I'
+				| b's' | b'b'
+				| b'c' | b'C'
+				| b'h' | b'H'
+				| b'x' | b'n'
+				| b'd' | b'0'..=b'9'
+		)
+	})
+}
+
+/// Returns true if records in `fmt` start with a varint of their length
+fn is_variable_size(fmt: &[u8]) -> bool {
+	fmt.iter().any(|c| matches!(c, b's' | b'x'))
+}
+
+fn zigzag(v: i64) -> u64 {
+	((v << 1) ^ (v >> 63)) as u64
+}
+
+fn unzigzag(v: u64) -> i64 {
+	((v >> 1) as i64) ^ -((v & 1) as i64)
+}
+
+fn write_u64(into: &mut Vec<u8>, v: u64) {
+	let mut buf = encode::u64_buffer();
+	into.extend_from_slice(encode::u64(v, &mut buf));
+}
+
+/// store the `width` least significant bytes of `xor` without its
+/// leading and trailing zero bytes
+fn write_xor(into: &mut Vec<u8>, xor: u64, width: usize) {
+	let bytes = &xor.to_be_bytes()[8 - width..];
+	let leading = bytes.iter().take_while(|b| **b == 0).count();
+	let trailing = if leading == width {
+		0
+	} else {
+		bytes.iter().rev().take_while(|b| **b == 0).count()
+	};
+	into.push(((leading << 4) | trailing) as u8);
+	into.extend_from_slice(&bytes[leading..width - trailing]);
+}
+
+/// Convert a plain payload from `from` into the compact encoding
+pub(crate) fn encode(from: &[u8], into: &mut Vec<u8>) {
+	let mut pos = 0;
+	while pos != from.len() {
+		let klen = BigEndian::read_u32(&from[pos..pos + 4]) as usize;
+		let flen = BigEndian::read_u32(&from[pos + 4..pos + 8]) as usize;
+		let dlen = BigEndian::read_u32(&from[pos + 8..pos + 12]) as usize;
+		let header_end = pos + 12 + klen + flen;
+		let fmt = &from[pos + 12 + klen..header_end];
+		let data = &from[header_end..header_end + dlen];
+
+		let at = into.len();
+		into.extend_from_slice(&from[pos..header_end]);
+
+		if can_encode(fmt) {
+			encode_key_data(fmt, data, into);
+		} else {
+			// delete markers are stored unchanged
+			into.extend_from_slice(data);
+		}
+
+		let encoded_len = into.len() - (at + header_end - pos);
+		BigEndian::write_u32(&mut into[at + 8..at + 12], encoded_len as u32);
+
+		pos = header_end + dlen;
+	}
+}
+
+fn encode_key_data(fmt: &[u8], mut data: &[u8], into: &mut Vec<u8>) {
+	let variable_size = is_variable_size(fmt);
+	let mut prev_ts = 0u64;
+	let mut prev_delta = 0i64;
+	let mut prev_values = vec![0u64; fmt.len()];
+
+	while !data.is_empty() {
+		if variable_size {
+			let (len, tail) = decode::u64(data).expect("record length in payload");
+			write_u64(into, len);
+			data = tail;
+		}
+
+		let ts = BigEndian::read_u64(&data[0..8]);
+		let delta = ts.wrapping_sub(prev_ts) as i64;
+		write_u64(into, zigzag(delta.wrapping_sub(prev_delta)));
+		prev_ts = ts;
+		prev_delta = delta;
+		data = &data[8..];
+
+		for (col, c) in fmt.iter().enumerate() {
+			match c {
+				b'f' => {
+					let v = BigEndian::read_u32(&data[0..4]) as u64;
+					write_xor(into, v ^ prev_values[col], 4);
+					prev_values[col] = v;
+					data = &data[4..];
+				}
+				b'F' => {
+					let v = BigEndian::read_u64(&data[0..8]);
+					write_xor(into, v ^ prev_values[col], 8);
+					prev_values[col] = v;
+					data = &data[8..];
+				}
+				b'u' | b'i' => {
+					into.extend_from_slice(&data[0..4]);
+					data = &data[4..];
+				}
+				b'U' | b'I' | b'd' => {
+					into.extend_from_slice(&data[0..8]);
+					data = &data[8..];
+				}
+				// the scale of a `d` column, which isn't stored
+				b'0'..=b'9' => {}
+				// `n` is the byte that says whether the next column is null
+				b'b' | b'c' | b'C' | b'n' => {
+					into.push(data[0]);
+					data = &data[1..];
+				}
+				b'h' | b'H' => {
+					into.extend_from_slice(&data[0..2]);
+					data = &data[2..];
+				}
+				b's' | b'x' => {
+					let (len, tail) = decode::usize(data).expect("string length in payload");
+					let len = len + (data.len() - tail.len());
+					into.extend_from_slice(&data[0..len]);
+					data = &data[len..];
+				}
+				_ => unreachable!(),
+			}
+		}
+	}
+}
+
+/// split `len` bytes off the front of `data`
+fn take<'a>(data: &mut &'a [u8], len: usize) -> Result<&'a [u8], &'static str> {
+	if data.len() < len {
+		return Err("truncated payload");
+	}
+	let (head, tail) = data.split_at(len);
+	*data = tail;
+	Ok(head)
+}
+
+fn take_varint(data: &mut &[u8]) -> Result<u64, &'static str> {
+	let (v, tail) = decode::u64(data).map_err(|_| "invalid varint in payload")?;
+	*data = tail;
+	Ok(v)
+}
+
+fn read_xor(data: &mut &[u8], width: usize) -> Result<u64, &'static str> {
+	let control = take(data, 1)?[0];
+	let leading = (control >> 4) as usize;
+	let trailing = (control & 0x0f) as usize;
+	let len = width
+		.checked_sub(leading + trailing)
+		.ok_or("invalid float in payload")?;
+	let mut bytes = [0u8; 8];
+	bytes[8 - width + leading..8 - trailing].copy_from_slice(take(data, len)?);
+	Ok(u64::from_be_bytes(bytes))
+}
+
+/// Convert a compact payload from `from` back into the plain encoding
+pub(crate) fn decode(mut from: &[u8], into: &mut Vec<u8>) -> Result<(), &'static str> {
+	while !from.is_empty() {
+		let header = take(&mut from, 12)?;
+		let klen = BigEndian::read_u32(&header[0..4]) as usize;
+		let flen = BigEndian::read_u32(&header[4..8]) as usize;
+		let encoded_len = BigEndian::read_u32(&header[8..12]) as usize;
+		let key = take(&mut from, klen)?;
+		let fmt = take(&mut from, flen)?;
+		let data = take(&mut from, encoded_len)?;
+
+		let at = into.len();
+		into.extend_from_slice(header);
+		into.extend_from_slice(key);
+		into.extend_from_slice(fmt);
+		let data_at = into.len();
+
+		if can_encode(fmt) {
+			decode_key_data(fmt, data, into)?;
+		} else {
+			into.extend_from_slice(data);
+		}
+
+		let dlen = into.len() - data_at;
+		BigEndian::write_u32(&mut into[at + 8..at + 12], dlen as u32);
+	}
+	Ok(())
+}
+
+fn decode_key_data(fmt: &[u8], mut data: &[u8], into: &mut Vec<u8>) -> Result<(), &'static str> {
+	let variable_size = is_variable_size(fmt);
+	let mut prev_ts = 0u64;
+	let mut prev_delta = 0i64;
+	let mut prev_values = vec![0u64; fmt.len()];
+
+	while !data.is_empty() {
+		if variable_size {
+			let len = take_varint(&mut data)?;
+			write_u64(into, len);
+		}
+
+		let dod = take_varint(&mut data)?;
+		let delta = prev_delta.wrapping_add(unzigzag(dod));
+		let ts = prev_ts.wrapping_add(delta as u64);
+		into.extend_from_slice(&ts.to_be_bytes());
+		prev_ts = ts;
+		prev_delta = delta;
+
+		for (col, c) in fmt.iter().enumerate() {
+			match c {
+				b'f' => {
+					let v = read_xor(&mut data, 4)? ^ prev_values[col];
+					into.extend_from_slice(&(v as u32).to_be_bytes());
+					prev_values[col] = v;
+				}
+				b'F' => {
+					let v = read_xor(&mut data, 8)? ^ prev_values[col];
+					into.extend_from_slice(&v.to_be_bytes());
+					prev_values[col] = v;
+				}
+				b'u' | b'i' => into.extend_from_slice(take(&mut data, 4)?),
+				b'U' | b'I' | b'd' => into.extend_from_slice(take(&mut data, 8)?),
+				b'0'..=b'9' => {}
+				b'b' | b'c' | b'C' | b'n' => into.extend_from_slice(take(&mut data, 1)?),
+				b'h' | b'H' => into.extend_from_slice(take(&mut data, 2)?),
+				b's' | b'x' => {
+					// copy the length along with the string
+					let mut string = data;
+					let len = take_varint(&mut string)? as usize;
+					let len = len + (data.len() - string.len());
+					into.extend_from_slice(take(&mut data, len)?);
+				}
+				_ => unreachable!(),
+			}
+		}
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::write::Writer;
+
+	fn payload_of(build: impl FnOnce(&mut Writer<Vec<u8>>)) -> Vec<u8> {
+		let mut w = Writer::new(vec![]);
+		build(&mut w);
+		let file = w.finish().unwrap();
+		let segment = crate::Segment::scan(&file, 0).unwrap();
+		assert_eq!(segment.segment_version, 0x0500);
+		let mut plain = vec![];
+		crate::segment_reader::decode_into_with_unescaping(&mut plain, &segment).unwrap();
+		plain
+	}
+
+	fn round_trip(plain: &[u8]) -> usize {
+		let mut encoded = vec![];
+		super::encode(plain, &mut encoded);
+		let mut decoded = vec![];
+		super::decode(&encoded, &mut decoded).unwrap();
+		assert_eq!(plain, &decoded[..]);
+		encoded.len()
+	}
+
+	#[test]
+	fn regular_series() {
+		let plain = payload_of(|w| {
+			for n in 0..1000u64 {
+				let mut buf = [0u8; 16];
+				buf[0..8].copy_from_slice(
+					&(1_600_000_000_000_000_000 + n * 60_000_000_000).to_be_bytes(),
+				);
+				buf[8..16].copy_from_slice(&(20.5f64 + (n % 3) as f64).to_be_bytes());
+				w.add_record_raw("sensor", "F", &buf).unwrap();
+			}
+		});
+		let encoded_len = round_trip(&plain);
+		assert!(
+			encoded_len * 3 < plain.len(),
+			"{} {}",
+			encoded_len,
+			plain.len()
+		);
+	}
+
+	#[test]
+	fn mixed_formats() {
+		let plain = payload_of(|w| {
+			w.add_record_raw(
+				"a",
+				"fiU",
+				b"\0\0\0\0\0\0\0\x05\x3f\x80\0\0\xff\xff\xff\xfe\0\0\0\0\0\0\0\x07",
+			)
+			.unwrap();
+			w.add_record_raw(
+				"a",
+				"fiU",
+				b"\xff\0\0\0\0\0\0\x03\x3f\x80\0\x01\0\0\0\x02\0\0\0\0\0\0\0\x09",
+			)
+			.unwrap();
+			w.add_record_raw("b", "sf", b"\0\0\0\0\0\0\0\x01\x05hello\x40\0\0\0")
+				.unwrap();
+			w.add_record_raw("b", "sf", b"\0\0\0\0\0\0\0\x02\0\x40\0\0\x01")
+				.unwrap();
+			w.add_record_raw(
+				"bb",
+				"bcChHx",
+				b"\0\0\0\0\0\0\0\x01\x01\xff\x07\x80\0\xff\xff\x02\xde\xad",
+			)
+			.unwrap();
+			w.add_record_raw(
+				"bc",
+				"nfnsnU",
+				b"\0\0\0\0\0\0\0\x01\0\0\0\0\0\x01\x01a\0\0\0\0\0\0\0\0\0",
+			)
+			.unwrap();
+			w.add_record_raw(
+				"bc",
+				"nfnsnU",
+				b"\0\0\0\0\0\0\0\x02\x01\x3f\x80\0\0\0\0\x01\0\0\0\0\0\0\0\x07",
+			)
+			.unwrap();
+			w.add_record_raw(
+				"bd",
+				"d12nd4",
+				b"\0\0\0\0\0\0\0\x01\xff\xff\xff\xff\xff\xff\xff\x85\x01\0\0\0\0\0\x01\xe2\x40",
+			)
+			.unwrap();
+			w.add_record_raw("c", "\x7f", b"\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0")
+				.unwrap();
+		});
+		round_trip(&plain);
+	}
+}
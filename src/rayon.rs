@@ -91,3 +91,90 @@ impl<'k> UnindexedProducer for KeyProducer<'k> {
 		folder.consume_iter(self.reader)
 	}
 }
+
+struct TryRecordProducer<'k> {
+	reader: DatabaseRecordReader<'k>,
+}
+
+impl<'k> ParallelIterator for DatabaseTryRecordReader<'k> {
+	type Item = Result<Record, ReadError>;
+
+	fn drive_unindexed<C>(self, consumer: C) -> C::Result
+	where
+		C: UnindexedConsumer<Self::Item>,
+	{
+		bridge_unindexed(
+			TryRecordProducer {
+				reader: self.reader,
+			},
+			consumer,
+		)
+	}
+}
+
+impl<'k> UnindexedProducer for TryRecordProducer<'k> {
+	type Item = Result<Record, ReadError>;
+
+	fn split(self) -> (TryRecordProducer<'k>, Option<TryRecordProducer<'k>>) {
+		if let Some((first, second)) = self.reader.split() {
+			(
+				TryRecordProducer { reader: first },
+				Some(TryRecordProducer { reader: second }),
+			)
+		} else {
+			(self, None)
+		}
+	}
+
+	fn fold_with<F>(self, folder: F) -> F
+	where
+		F: Folder<Self::Item>,
+	{
+		folder.consume_iter(self.reader.into_try_iter())
+	}
+}
+
+#[cfg(feature = "by-key")]
+struct TryKeyProducer<'k> {
+	reader: DatabaseKeyReader<'k>,
+}
+
+#[cfg(feature = "by-key")]
+impl<'k> ParallelIterator for DatabaseTryKeyReader<'k> {
+	type Item = Result<KeyRecordReader<'k>, ReadError>;
+
+	fn drive_unindexed<C>(self, consumer: C) -> C::Result
+	where
+		C: UnindexedConsumer<Self::Item>,
+	{
+		bridge_unindexed(
+			TryKeyProducer {
+				reader: self.reader,
+			},
+			consumer,
+		)
+	}
+}
+
+#[cfg(feature = "by-key")]
+impl<'k> UnindexedProducer for TryKeyProducer<'k> {
+	type Item = Result<KeyRecordReader<'k>, ReadError>;
+
+	fn split(self) -> (TryKeyProducer<'k>, Option<TryKeyProducer<'k>>) {
+		if let Some((first, second)) = self.reader.split() {
+			(
+				TryKeyProducer { reader: first },
+				Some(TryKeyProducer { reader: second }),
+			)
+		} else {
+			(self, None)
+		}
+	}
+
+	fn fold_with<F>(self, folder: F) -> F
+	where
+		F: Folder<Self::Item>,
+	{
+		folder.consume_iter(self.reader.into_try_iter())
+	}
+}
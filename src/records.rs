@@ -5,6 +5,10 @@ use std::sync::Arc;
 pub(crate) const TIMESTAMP_SIZE: usize = 8;
 
 use byteorder::{BigEndian, ByteOrder, WriteBytesExt};
+use std::fmt::Write as _;
+
+use crate::row_format::Column;
+use crate::{Decimal, NullableDecimal};
 
 /// Stores a single timestamp for a single key of data
 ///
@@ -23,8 +27,13 @@ impl std::fmt::Debug for Record {
 	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
 		write!(f, "Record {{ key={}, t={}", self.key(), self.time())?;
 
-		for (idx, c) in self.format().chars().enumerate() {
-			match c {
+		let columns = crate::row_format::columns(self.format().as_bytes());
+		for (idx, column) in columns.enumerate() {
+			if column.nullable && self.get_checked::<Null>(idx).is_ok() {
+				write!(f, ", null")?;
+				continue;
+			}
+			match column.kind as char {
 				'f' => write!(f, ", {}", self.get::<f32>(idx))?,
 				'F' => write!(f, ", {}", self.get::<f64>(idx))?,
 				'i' => write!(f, ", {}", self.get::<i32>(idx))?,
@@ -32,6 +41,13 @@ impl std::fmt::Debug for Record {
 				'u' => write!(f, ", {}", self.get::<u32>(idx))?,
 				'U' => write!(f, ", {}", self.get::<u64>(idx))?,
 				's' => write!(f, ", \"{}\"", self.get::<&str>(idx).escape_default())?,
+				'b' => write!(f, ", {}", self.get::<bool>(idx))?,
+				'c' => write!(f, ", {}", self.get::<i8>(idx))?,
+				'C' => write!(f, ", {}", self.get::<u8>(idx))?,
+				'h' => write!(f, ", {}", self.get::<i16>(idx))?,
+				'H' => write!(f, ", {}", self.get::<u16>(idx))?,
+				'x' => write!(f, ", {:?}", self.get::<&[u8]>(idx))?,
+				'd' => write!(f, ", {}", self.get::<Decimal>(idx))?,
 				a => panic!("unknown format column '{a}'"),
 			}
 		}
@@ -76,40 +92,25 @@ impl Record {
 	/// requested type. For example, if the column stores a 'u' (32-bit unsigned), then
 	/// it can be read into a `u32` or a `u64`. However, it's a failure to read the column
 	/// as a `u32` if the column stores a `U`, even if the stored value itself can be
-	/// represented in a `u32`.
+	/// represented in a `u32`. Strings (`s`) can also be read as bytes.
+	///
+	/// A nullable column must be read as an `Option`, which is `None` if the column is
+	/// null. Columns that aren't nullable can also be read as an `Option`.
+	///
+	/// Decimal (`d`) columns can only be read as a [`Decimal`].
 	pub fn get_checked<'a, T: FromRecord<'a>>(&'a self, col: usize) -> std::io::Result<T> {
-		let fmt = self.format().as_bytes();
+		let mut columns = crate::row_format::columns(self.format().as_bytes());
 		let mut from = &self.raw()[TIMESTAMP_SIZE..];
 
-		if fmt.len() <= col {
-			return Err(std::io::Error::new(
-				std::io::ErrorKind::UnexpectedEof,
-				"column out of range",
-			));
-		}
-
-		for code in fmt.iter().take(col) {
-			match code {
-				b'i' | b'u' | b'f' => from = &from[4..],
-				b'I' | b'U' | b'F' => from = &from[8..],
-				b's' => {
-					let (len, tail) = unsigned_varint::decode::u64(from).map_err(|e| {
-						std::io::Error::new(std::io::ErrorKind::InvalidData, format!("{:?}", e))
-					})?;
-					from = &tail[len as usize..];
-				}
-				a => {
-					return Err(std::io::Error::new(
-						std::io::ErrorKind::InvalidData,
-						format!("format string contains invalid '{}'", *a as char),
-					))
-				}
-			}
+		for column in columns.by_ref().take(col) {
+			split_column(&column, &mut from)?;
 		}
 
-		let fmt_char = fmt[col];
-
-		FromRecord::get(fmt_char, from)
+		let column = columns.next().ok_or_else(|| {
+			std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "column out of range")
+		})?;
+		let bytes = split_column(&column, &mut from)?;
+		read_column(&column, bytes)
 	}
 
 	/// Read a column, turning errors into panics
@@ -126,6 +127,58 @@ impl Record {
 		self.get(0)
 	}
 
+	/// Read all of the columns at once, returning possible errors
+	///
+	/// `T` is a tuple with a type for each column, or a struct that
+	/// derives [`FromRow`] (with the `derive` feature). Each column is read as
+	/// by [`Record::get_checked`], but the format is only parsed once. It's an error if the record doesn't have
+	/// exactly one column for each value of `T`.
+	///
+	/// ```no_run
+	/// # fn f(record: sonnerie::Record) {
+	/// let (temperature, count, name) = record.decode::<(f64, u32, &str)>();
+	/// # }
+	/// ```
+	pub fn decode_checked<'a, T: FromRow<'a>>(&'a self) -> std::io::Result<T> {
+		let mut row = RowDecoder {
+			format: self.format().as_bytes().iter().peekable(),
+			data: &self.raw()[TIMESTAMP_SIZE..],
+		};
+		let value = T::decode(&mut row)?;
+		row.finish()?;
+		Ok(value)
+	}
+
+	/// Read all of the columns at once, turning errors into panics
+	///
+	/// Same as [`Record::decode_checked`]`().unwrap()`
+	pub fn decode<'a, T: FromRow<'a>>(&'a self) -> T {
+		self.decode_checked().expect("unable to decode record")
+	}
+
+	/// Read the column called `name` in the schema of this record's key,
+	/// turning errors into panics
+	///
+	/// Same as [`Record::get_checked_by_name`]`(schema, name).unwrap()`
+	pub fn get_by_name<'a, T: FromRecord<'a>>(&'a self, schema: &crate::Schema, name: &str) -> T {
+		self.get_checked_by_name(schema, name)
+			.expect("unable to read column")
+	}
+
+	/// Read the column called `name` in the schema of this record's key
+	///
+	/// Fails if the key has no schema, if its schema has no such column
+	/// or if this record doesn't have the format of the schema, and
+	/// otherwise as [`Record::get_checked`].
+	pub fn get_checked_by_name<'a, T: FromRecord<'a>>(
+		&'a self,
+		schema: &crate::Schema,
+		name: &str,
+	) -> std::io::Result<T> {
+		let col = schema.column_index(self.key(), self.format(), name)?;
+		self.get_checked(col)
+	}
+
 	/// The encoded payload of this data. The timestamp and as 8
 	/// bytes in nanoseconds, and then each column in turn
 	pub fn raw(&self) -> &[u8] {
@@ -133,12 +186,162 @@ impl Record {
 	}
 }
 
+fn truncated() -> std::io::Error {
+	std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "record is truncated")
+}
+
+/// Split the bytes of `column` (with its null byte) off the front of `from`
+pub(crate) fn split_column<'a>(column: &Column, from: &mut &'a [u8]) -> std::io::Result<&'a [u8]> {
+	let null_byte = column.nullable as usize;
+	let size = match column.kind {
+		b'i' | b'u' | b'f' => 4,
+		b'I' | b'U' | b'F' | b'd' => 8,
+		b'b' | b'c' | b'C' => 1,
+		b'h' | b'H' => 2,
+		b's' | b'x' => {
+			let value = from.get(null_byte..).ok_or_else(truncated)?;
+			let (len, tail) = unsigned_varint::decode::u64(value).map_err(|e| {
+				std::io::Error::new(std::io::ErrorKind::InvalidData, format!("{:?}", e))
+			})?;
+			len as usize + (value.len() - tail.len())
+		}
+		a => {
+			return Err(std::io::Error::new(
+				std::io::ErrorKind::InvalidData,
+				format!("format string contains invalid '{}'", a as char),
+			))
+		}
+	};
+	let (bytes, tail) = from
+		.split_at_checked(null_byte + size)
+		.ok_or_else(truncated)?;
+	*from = tail;
+	Ok(bytes)
+}
+
+/// Read the value of `column` from its `bytes`
+fn read_column<'a, T: FromRecord<'a>>(column: &Column, mut bytes: &'a [u8]) -> std::io::Result<T> {
+	if column.nullable {
+		if bytes[0] == 0 {
+			return T::null(column.kind);
+		}
+		bytes = &bytes[1..];
+	}
+	if column.kind == b'd' {
+		return T::get_decimal(Decimal::new(BigEndian::read_i64(bytes), column.scale));
+	}
+	T::get(column.kind, bytes)
+}
+
+/// Reads the columns of a record one after another, for [`FromRow`]
+pub struct RowDecoder<'a> {
+	format: std::iter::Peekable<std::slice::Iter<'a, u8>>,
+	data: &'a [u8],
+}
+
+impl<'a> RowDecoder<'a> {
+	/// Read the next column, as [`Record::get_checked`] does
+	pub fn read<T: FromRecord<'a>>(&mut self) -> std::io::Result<T> {
+		let column = crate::row_format::next_column(&mut self.format).ok_or_else(|| {
+			std::io::Error::new(
+				std::io::ErrorKind::InvalidData,
+				"the record has fewer columns than were read",
+			)
+		})?;
+		let bytes = split_column(&column, &mut self.data)?;
+		read_column(&column, bytes)
+	}
+
+	/// Fails if there are columns that weren't read
+	fn finish(mut self) -> std::io::Result<()> {
+		if self.format.peek().is_some() {
+			return Err(std::io::Error::new(
+				std::io::ErrorKind::InvalidData,
+				"the record has more columns than were read",
+			));
+		}
+		Ok(())
+	}
+}
+
+/// Implements conversions from all the columns of a [`Record`] to a Rust type
+///
+/// This is implemented for tuples of up to 12 [`FromRecord`] types
+/// and can be derived for structs.
+pub trait FromRow<'a>: Sized {
+	/// Read each column from `row`, in order
+	fn decode(row: &mut RowDecoder<'a>) -> std::io::Result<Self>;
+}
+
+macro_rules! tuple_impls {
+	($($name:ident)+) => {
+		impl<'a, $($name: FromRecord<'a>),+> FromRow<'a> for ($($name,)+) {
+			fn decode(row: &mut RowDecoder<'a>) -> std::io::Result<Self> {
+				Ok(($(row.read::<$name>()?,)+))
+			}
+		}
+
+		#[allow(non_snake_case)]
+		impl<$($name: ToRecord),+> RecordBuilder for ($($name,)+) {
+			fn format_str(&self, to: &mut compact_str::CompactString) {
+				let ($($name,)+) = self;
+				$($name.push_format(to);)+
+			}
+			fn variable_size(&self) -> bool {
+				let ($($name,)+) = self;
+				false $(|| $name.variable_size())+
+			}
+			fn size(&self) -> usize {
+				let ($($name,)+) = self;
+				0 $(+ $name.size())+
+			}
+			fn store(&self, buf: &mut Vec<u8>) {
+				let ($($name,)+) = self;
+				$($name.store(buf);)+
+			}
+		}
+	};
+}
+
+tuple_impls! { A }
+tuple_impls! { A B }
+tuple_impls! { A B C }
+tuple_impls! { A B C D }
+tuple_impls! { A B C D E }
+tuple_impls! { A B C D E F }
+tuple_impls! { A B C D E F G }
+tuple_impls! { A B C D E F G H }
+tuple_impls! { A B C D E F G H I }
+tuple_impls! { A B C D E F G H I J }
+tuple_impls! { A B C D E F G H I J K }
+tuple_impls! { A B C D E F G H I J K L }
+
 /// Implements conversions from Rust types to Sonnerie records
 pub trait ToRecord {
 	fn store(&self, buf: &mut Vec<u8>);
 	fn format_char(&self) -> u8;
 	fn size(&self) -> usize;
 	fn variable_size(&self) -> bool;
+	/// If true, the format is preceded by `n`, and [`ToRecord::store`]
+	/// stores the byte that says whether the value is null
+	fn nullable(&self) -> bool {
+		false
+	}
+	/// The scale of a decimal (`d`), which follows the format character
+	fn scale(&self) -> Option<u8> {
+		None
+	}
+	/// Append the format of this column to `to`
+	#[doc(hidden)]
+	fn push_format(&self, to: &mut compact_str::CompactString) {
+		if self.nullable() {
+			to.push('n');
+		}
+		to.push(self.format_char() as char);
+		if let Some(scale) = self.scale() {
+			write!(to, "{}", scale).unwrap();
+		}
+	}
 }
 
 impl ToRecord for i32 {
@@ -228,6 +431,79 @@ impl ToRecord for f64 {
 	}
 }
 
+impl ToRecord for bool {
+	fn store(&self, buf: &mut Vec<u8>) {
+		buf.push(*self as u8);
+	}
+	fn format_char(&self) -> u8 {
+		b'b'
+	}
+	fn size(&self) -> usize {
+		1
+	}
+	fn variable_size(&self) -> bool {
+		false
+	}
+}
+
+impl ToRecord for i8 {
+	fn store(&self, buf: &mut Vec<u8>) {
+		buf.write_i8(*self).unwrap();
+	}
+	fn format_char(&self) -> u8 {
+		b'c'
+	}
+	fn size(&self) -> usize {
+		1
+	}
+	fn variable_size(&self) -> bool {
+		false
+	}
+}
+impl ToRecord for u8 {
+	fn store(&self, buf: &mut Vec<u8>) {
+		buf.write_u8(*self).unwrap();
+	}
+	fn format_char(&self) -> u8 {
+		b'C'
+	}
+	fn size(&self) -> usize {
+		1
+	}
+	fn variable_size(&self) -> bool {
+		false
+	}
+}
+
+impl ToRecord for i16 {
+	fn store(&self, buf: &mut Vec<u8>) {
+		buf.write_i16::<BigEndian>(*self).unwrap();
+	}
+	fn format_char(&self) -> u8 {
+		b'h'
+	}
+	fn size(&self) -> usize {
+		2
+	}
+	fn variable_size(&self) -> bool {
+		false
+	}
+}
+impl ToRecord for u16 {
+	fn store(&self, buf: &mut Vec<u8>) {
+		buf.write_u16::<BigEndian>(*self).unwrap();
+	}
+	fn format_char(&self) -> u8 {
+		b'H'
+	}
+	fn size(&self) -> usize {
+		2
+	}
+	fn variable_size(&self) -> bool {
+		false
+	}
+}
+
 impl ToRecord for &str {
 	fn store(&self, buf: &mut Vec<u8>) {
 		let len = self.len();
@@ -264,6 +540,114 @@ impl ToRecord for String {
 	}
 }
 
+impl ToRecord for &[u8] {
+	fn store(&self, buf: &mut Vec<u8>) {
+		let mut lenbuf = unsigned_varint::encode::usize_buffer();
+		let lenbuf = unsigned_varint::encode::usize(self.len(), &mut lenbuf);
+		buf.extend_from_slice(lenbuf);
+		buf.extend_from_slice(self);
+	}
+	fn format_char(&self) -> u8 {
+		b'x'
+	}
+	fn size(&self) -> usize {
+		let mut buf = unsigned_varint::encode::usize_buffer();
+		let buf = unsigned_varint::encode::usize(self.len(), &mut buf);
+		buf.len() + self.len()
+	}
+	fn variable_size(&self) -> bool {
+		true
+	}
+}
+
+impl ToRecord for Vec<u8> {
+	fn store(&self, buf: &mut Vec<u8>) {
+		self.as_slice().store(buf)
+	}
+	fn format_char(&self) -> u8 {
+		self.as_slice().format_char()
+	}
+	fn size(&self) -> usize {
+		self.as_slice().size()
+	}
+	fn variable_size(&self) -> bool {
+		self.as_slice().variable_size()
+	}
+}
+
+/// A nullable column, `None` is stored as null
+impl<T: ToRecord + Default> ToRecord for Option<T> {
+	fn store(&self, buf: &mut Vec<u8>) {
+		match self {
+			Some(v) => {
+				buf.push(1);
+				v.store(buf);
+			}
+			None => {
+				buf.push(0);
+				// a zero, or an empty string
+				T::default().store(buf);
+			}
+		}
+	}
+	fn format_char(&self) -> u8 {
+		T::default().format_char()
+	}
+	fn size(&self) -> usize {
+		match self {
+			Some(v) => 1 + v.size(),
+			None => 1 + T::default().size(),
+		}
+	}
+	fn variable_size(&self) -> bool {
+		T::default().variable_size()
+	}
+	fn nullable(&self) -> bool {
+		true
+	}
+}
+
+impl ToRecord for Decimal {
+	fn store(&self, buf: &mut Vec<u8>) {
+		buf.write_i64::<BigEndian>(self.mantissa).unwrap();
+	}
+	fn format_char(&self) -> u8 {
+		b'd'
+	}
+	fn size(&self) -> usize {
+		8
+	}
+	fn variable_size(&self) -> bool {
+		false
+	}
+	fn scale(&self) -> Option<u8> {
+		Some(self.scale)
+	}
+}
+
+impl ToRecord for NullableDecimal {
+	fn store(&self, buf: &mut Vec<u8>) {
+		buf.push(self.mantissa.is_some() as u8);
+		buf.write_i64::<BigEndian>(self.mantissa.unwrap_or(0))
+			.unwrap();
+	}
+	fn format_char(&self) -> u8 {
+		b'd'
+	}
+	fn size(&self) -> usize {
+		1 + 8
+	}
+	fn variable_size(&self) -> bool {
+		false
+	}
+	fn nullable(&self) -> bool {
+		true
+	}
+	fn scale(&self) -> Option<u8> {
+		Some(self.scale)
+	}
+}
+
 /// Converts multiple-column data to the internal encoding
 ///
 /// Create this type with [`crate::record()`]
@@ -306,7 +690,7 @@ where
 {
 	fn format_str(&self, to: &mut compact_str::CompactString) {
 		self.tail.format_str(to);
-		to.push(self.value.format_char() as char);
+		self.value.push_format(to);
 	}
 	fn variable_size(&self) -> bool {
 		if self.value.variable_size() {
@@ -372,10 +756,10 @@ pub fn record<Rec: ToRecord>(value: Rec) -> BuildingRecord<Rec, RecordBuilderEnd
 
 impl RecordBuilder for &[&dyn ToRecord] {
 	fn format_str(&self, fmt: &mut compact_str::CompactString) {
-		self.iter().for_each(|v| fmt.push(v.format_char().into()));
+		self.iter().for_each(|v| v.push_format(fmt));
 	}
 	fn variable_size(&self) -> bool {
-		self.iter().map(|m| m.variable_size()).any(|a| a)
+		self.iter().any(|m| m.variable_size())
 	}
 	fn size(&self) -> usize {
 		self.iter().map(|m| m.size()).sum::<usize>()
@@ -387,10 +771,10 @@ impl RecordBuilder for &[&dyn ToRecord] {
 
 impl RecordBuilder for [&dyn ToRecord] {
 	fn format_str(&self, fmt: &mut compact_str::CompactString) {
-		self.iter().for_each(|v| fmt.push(v.format_char().into()));
+		self.iter().for_each(|v| v.push_format(fmt));
 	}
 	fn variable_size(&self) -> bool {
-		self.iter().map(|m| m.variable_size()).any(|a| a)
+		self.iter().any(|m| m.variable_size())
 	}
 	fn size(&self) -> usize {
 		self.iter().map(|m| m.size()).sum::<usize>()
@@ -402,10 +786,10 @@ impl RecordBuilder for [&dyn ToRecord] {
 
 impl<const N: usize> RecordBuilder for &[&dyn ToRecord; N] {
 	fn format_str(&self, fmt: &mut compact_str::CompactString) {
-		self.iter().for_each(|v| fmt.push(v.format_char().into()));
+		self.iter().for_each(|v| v.push_format(fmt));
 	}
 	fn variable_size(&self) -> bool {
-		self.iter().map(|m| m.variable_size()).any(|a| a)
+		self.iter().any(|m| m.variable_size())
 	}
 	fn size(&self) -> usize {
 		self.iter().map(|m| m.size()).sum::<usize>()
@@ -418,24 +802,119 @@ impl<const N: usize> RecordBuilder for &[&dyn ToRecord; N] {
 /// Implements conversions from [`Record`] columns to Rust types
 pub trait FromRecord<'a>: Sized {
 	fn get(fmt_char: u8, bytes: &'a [u8]) -> std::io::Result<Self>;
+	/// The value of a null column, which is an error unless `Self` is an `Option`
+	fn null(fmt_char: u8) -> std::io::Result<Self> {
+		Err(std::io::Error::new(
+			std::io::ErrorKind::InvalidData,
+			format!("the '{}' column is null", fmt_char as char),
+		))
+	}
+	/// The value of a decimal (`d`) column, which is an error
+	/// unless `Self` is a [`Decimal`]
+	fn get_decimal(_value: Decimal) -> std::io::Result<Self> {
+		Err(std::io::Error::new(
+			std::io::ErrorKind::InvalidData,
+			"cannot decode from 'd', read it as a Decimal",
+		))
+	}
 }
 
-impl<'a> FromRecord<'a> for i32 {
+impl<'a, T: FromRecord<'a>> FromRecord<'a> for Option<T> {
 	fn get(fmt_char: u8, bytes: &'a [u8]) -> std::io::Result<Self> {
-		if fmt_char != b'i' {
+		T::get(fmt_char, bytes).map(Some)
+	}
+	fn null(_fmt_char: u8) -> std::io::Result<Self> {
+		Ok(None)
+	}
+	fn get_decimal(value: Decimal) -> std::io::Result<Self> {
+		T::get_decimal(value).map(Some)
+	}
+}
+
+impl<'a> FromRecord<'a> for Decimal {
+	fn get(fmt_char: u8, _bytes: &'a [u8]) -> std::io::Result<Self> {
+		Err(std::io::Error::new(
+			std::io::ErrorKind::InvalidData,
+			format!("cannot decode Decimal from '{}'", fmt_char as char),
+		))
+	}
+	fn get_decimal(value: Decimal) -> std::io::Result<Self> {
+		Ok(value)
+	}
+}
+
+/// Succeeds only if the column is null
+struct Null;
+impl<'a> FromRecord<'a> for Null {
+	fn get(fmt_char: u8, _bytes: &'a [u8]) -> std::io::Result<Self> {
+		Err(std::io::Error::new(
+			std::io::ErrorKind::InvalidData,
+			format!("the '{}' column is not null", fmt_char as char),
+		))
+	}
+	fn null(_fmt_char: u8) -> std::io::Result<Self> {
+		Ok(Null)
+	}
+}
+
+impl<'a> FromRecord<'a> for bool {
+	fn get(fmt_char: u8, bytes: &'a [u8]) -> std::io::Result<Self> {
+		if fmt_char != b'b' {
 			return Err(std::io::Error::new(
 				std::io::ErrorKind::InvalidData,
-				format!("cannot decode i32 from '{}'", fmt_char as char),
+				format!("cannot decode bool from '{}'", fmt_char as char),
 			));
 		}
-		Ok(BigEndian::read_i32(bytes))
+		Ok(bytes[0] != 0)
 	}
 }
 
-impl<'a> FromRecord<'a> for i64 {
+impl<'a> FromRecord<'a> for i8 {
+	fn get(fmt_char: u8, bytes: &'a [u8]) -> std::io::Result<Self> {
+		if fmt_char != b'c' {
+			return Err(std::io::Error::new(
+				std::io::ErrorKind::InvalidData,
+				format!("cannot decode i8 from '{}'", fmt_char as char),
+			));
+		}
+		Ok(bytes[0] as i8)
+	}
+}
+
+impl<'a> FromRecord<'a> for i16 {
+	fn get(fmt_char: u8, bytes: &'a [u8]) -> std::io::Result<Self> {
+		if fmt_char == b'c' {
+			Ok(bytes[0] as i8 as i16)
+		} else if fmt_char == b'h' {
+			Ok(BigEndian::read_i16(bytes))
+		} else {
+			Err(std::io::Error::new(
+				std::io::ErrorKind::InvalidData,
+				format!("cannot decode i16 from '{}'", fmt_char as char),
+			))
+		}
+	}
+}
+
+impl<'a> FromRecord<'a> for i32 {
 	fn get(fmt_char: u8, bytes: &'a [u8]) -> std::io::Result<Self> {
 		if fmt_char == b'i' {
-			Ok(BigEndian::read_i32(bytes) as i64)
+			Ok(BigEndian::read_i32(bytes))
+		} else if fmt_char == b'c' || fmt_char == b'h' {
+			Ok(i16::get(fmt_char, bytes)? as i32)
+		} else {
+			Err(std::io::Error::new(
+				std::io::ErrorKind::InvalidData,
+				format!("cannot decode i32 from '{}'", fmt_char as char),
+			))
+		}
+	}
+}
+
+impl<'a> FromRecord<'a> for i64 {
+	fn get(fmt_char: u8, bytes: &'a [u8]) -> std::io::Result<Self> {
+		if fmt_char == b'i' || fmt_char == b'c' || fmt_char == b'h' {
+			Ok(i32::get(fmt_char, bytes)? as i64)
 		} else if fmt_char == b'I' {
 			Ok(BigEndian::read_i64(bytes))
 		} else {
@@ -447,22 +926,52 @@ impl<'a> FromRecord<'a> for i64 {
 	}
 }
 
+impl<'a> FromRecord<'a> for u8 {
+	fn get(fmt_char: u8, bytes: &'a [u8]) -> std::io::Result<Self> {
+		if fmt_char != b'C' {
+			return Err(std::io::Error::new(
+				std::io::ErrorKind::InvalidData,
+				format!("cannot decode u8 from '{}'", fmt_char as char),
+			));
+		}
+		Ok(bytes[0])
+	}
+}
+
+impl<'a> FromRecord<'a> for u16 {
+	fn get(fmt_char: u8, bytes: &'a [u8]) -> std::io::Result<Self> {
+		if fmt_char == b'C' {
+			Ok(bytes[0] as u16)
+		} else if fmt_char == b'H' {
+			Ok(BigEndian::read_u16(bytes))
+		} else {
+			Err(std::io::Error::new(
+				std::io::ErrorKind::InvalidData,
+				format!("cannot decode u16 from '{}'", fmt_char as char),
+			))
+		}
+	}
+}
+
 impl<'a> FromRecord<'a> for u32 {
 	fn get(fmt_char: u8, bytes: &[u8]) -> std::io::Result<Self> {
-		if fmt_char != b'u' {
-			return Err(std::io::Error::new(
+		if fmt_char == b'u' {
+			Ok(BigEndian::read_u32(bytes))
+		} else if fmt_char == b'C' || fmt_char == b'H' {
+			Ok(u16::get(fmt_char, bytes)? as u32)
+		} else {
+			Err(std::io::Error::new(
 				std::io::ErrorKind::InvalidData,
 				format!("cannot decode u32 from '{}'", fmt_char as char),
-			));
+			))
 		}
-		Ok(BigEndian::read_u32(bytes))
 	}
 }
 
 impl<'a> FromRecord<'a> for u64 {
 	fn get(fmt_char: u8, bytes: &'a [u8]) -> std::io::Result<Self> {
-		if fmt_char == b'u' {
-			Ok(BigEndian::read_u32(bytes) as u64)
+		if fmt_char == b'u' || fmt_char == b'C' || fmt_char == b'H' {
+			Ok(u32::get(fmt_char, bytes)? as u64)
 		} else if fmt_char == b'U' {
 			Ok(BigEndian::read_u64(bytes))
 		} else {
@@ -521,7 +1030,36 @@ impl<'a> FromRecord<'a> for &'a str {
 			std::io::Error::new(std::io::ErrorKind::InvalidData, format!("{:?}", e))
 		})?;
 
-		std::str::from_utf8(&tail[..len as usize])
+		let string = tail.get(..len as usize).ok_or_else(|| {
+			std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "string is truncated")
+		})?;
+		std::str::from_utf8(string)
 			.map_err(|k| std::io::Error::new(std::io::ErrorKind::InvalidData, k))
 	}
 }
+
+impl<'a> FromRecord<'a> for &'a [u8] {
+	fn get(fmt_char: u8, bytes: &'a [u8]) -> std::io::Result<Self> {
+		if fmt_char != b'x' && fmt_char != b's' {
+			return Err(std::io::Error::new(
+				std::io::ErrorKind::InvalidData,
+				format!("cannot decode bytes from '{}'", fmt_char as char),
+			));
+		}
+
+		let (len, tail) = unsigned_varint::decode::u64(bytes).map_err(|e| {
+			std::io::Error::new(std::io::ErrorKind::InvalidData, format!("{:?}", e))
+		})?;
+
+		tail.get(..len as usize).ok_or_else(|| {
+			std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "bytes are truncated")
+		})
+	}
+}
+
+impl<'a> FromRecord<'a> for Vec<u8> {
+	fn get(fmt_char: u8, bytes: &'a [u8]) -> std::io::Result<Self> {
+		let b: &[u8] = FromRecord::get(fmt_char, bytes)?;
+		Ok(b.to_vec())
+	}
+}
@@ -0,0 +1,127 @@
+//! Recover what can still be read from a damaged transaction file.
+
+use crate::segment_reader::{decode_into_with_unescaping, for_each_record, SegmentReader};
+use crate::{CorruptSegment, CreateTx, WriteFailure};
+use byteorder::{BigEndian, ByteOrder};
+use std::path::Path;
+
+/// What [`salvage`] recovered from a damaged transaction file
+#[derive(Debug, Default)]
+pub struct Salvaged {
+	/// the number of segments that were copied
+	pub segments: usize,
+	/// the number of records in those segments
+	pub records: usize,
+	/// the parts of the file that couldn't be read
+	pub lost: Vec<CorruptSegment>,
+}
+
+/// Copy every readable segment of the transaction file at `path` into `tx`.
+///
+/// After a segment that can't be read, reading continues at the
+/// next segment header. Everything that was skipped is listed
+/// in [`Salvaged::lost`].
+///
+/// `path` can also be `main` or a transaction containing a delete,
+/// it's up to the caller to decide where to commit `tx`.
+pub fn salvage(path: &Path, tx: &mut CreateTx) -> Result<Salvaged, WriteFailure> {
+	let segments = SegmentReader::map(&mut std::fs::File::open(path)?)?;
+
+	let mut salvaged = Salvaged::default();
+	let lost = |offset: usize, first_key: &str, last_key: &str, error: String| CorruptSegment {
+		path: path.to_owned(),
+		offset,
+		first_key: first_key.to_owned(),
+		last_key: last_key.to_owned(),
+		error,
+	};
+
+	// the last key and timestamp copied, what follows must sort after it
+	let mut last_written: Option<(String, crate::Timestamp)> = None;
+	let mut last_key = String::new();
+	let mut expected_offset = 0;
+	let mut decoded = vec![];
+
+	let mut segment = segments.first();
+	while let Some(s) = segment.take() {
+		if s.segment_offset != expected_offset {
+			let unreadable = s.segment_offset - expected_offset;
+			salvaged.lost.push(lost(
+				expected_offset,
+				&last_key,
+				s.first_key,
+				format!("{} unreadable bytes", unreadable),
+			));
+		}
+		last_key.replace_range(.., s.last_key);
+
+		decoded.clear();
+		let mut records = vec![];
+		let decoded_records = decode_into_with_unescaping(&mut decoded, &s).and_then(|_| {
+			for_each_record(&s, &decoded, |key, format, data| {
+				records.push((key, format, data))
+			})
+		});
+		match decoded_records {
+			Ok(()) => {}
+			Err(e) => {
+				let reason = match e {
+					crate::ReadError::CorruptSegment { reason, .. } => reason,
+					e => e.to_string(),
+				};
+				salvaged
+					.lost
+					.push(lost(s.segment_offset, s.first_key, s.last_key, reason));
+				// the header may be damaged too, so don't trust its length
+				// and resynchronise on the very next segment header
+				segment = segments.scan_from(s.segment_offset + 1);
+				expected_offset = segment
+					.as_ref()
+					.map_or(segments.number_of_bytes(), |n| n.segment_offset);
+				continue;
+			}
+		}
+
+		expected_offset = s.segment_offset + s.stride;
+		segment = segments.segment_after(&s);
+
+		let in_order = match (records.first(), &last_written) {
+			(Some((key, _, data)), Some((lk, lt))) => {
+				(*key, BigEndian::read_u64(data)) > (lk.as_str(), *lt)
+			}
+			_ => true,
+		};
+		if !in_order {
+			salvaged.lost.push(lost(
+				s.segment_offset,
+				s.first_key,
+				s.last_key,
+				"records are out of order".to_string(),
+			));
+			continue;
+		}
+
+		for (key, format, data) in &records {
+			tx.add_record_raw(key, format, data)?;
+		}
+		if let Some((key, _, data)) = records.last() {
+			last_written = Some((key.to_string(), BigEndian::read_u64(data)));
+		}
+		salvaged.segments += 1;
+		salvaged.records += records.len();
+	}
+
+	if expected_offset < segments.number_of_bytes() {
+		salvaged.lost.push(lost(
+			expected_offset,
+			&last_key,
+			"",
+			format!(
+				"{} unreadable bytes",
+				segments.number_of_bytes() - expected_offset
+			),
+		));
+	}
+
+	Ok(salvaged)
+}
@@ -61,19 +61,37 @@ impl RowFormat for RowFormatImpl {
 /// * `f` -> 32-bit unsigned float
 /// * `F` -> 64-bit unsigned float
 /// * `s` -> variable size string type
+/// * `b` -> boolean, stored as one byte
+/// * `c` -> 8-bit signed integer
+/// * `C` -> 8-bit unsigned integer
+/// * `h` -> 16-bit signed integer
+/// * `H` -> 16-bit unsigned integer
+/// * `x` -> variable size bytes, written in hexadecimal in the text format
+/// * `d` -> a [`Decimal`](crate::Decimal), followed by its scale, the number of
+///   decimal places (from 0 to 18). `d4` is a 64-bit integer with four
+///   implied decimal places
+///
+/// Any of these may be preceded by `n` to make the column nullable. A nullable
+/// column is stored as a byte that is 0 if the column is null and 1 otherwise,
+/// followed by the value (which is zero if the column is null). In the text format,
+/// a null column is written as `-`.
 ///
 /// Potential future types:
-/// * decimal
 /// * large integers, floats (128 bit, 256 bit)
-/// to indicate "typical size"). The typical size is useful
-/// for knowing how big to make the blocks
+///   to indicate "typical size"). The typical size is useful
+///   for knowing how big to make the blocks
 pub fn parse_row_format(human: &str) -> Box<dyn RowFormat> {
 	let mut size = 0usize;
 	let mut has_size = true;
 	let mut elements: Vec<Box<dyn Element>> = Vec::with_capacity(human.len());
 
-	for t in human.bytes() {
-		match t {
+	for Column {
+		nullable,
+		kind,
+		scale,
+	} in columns(human.as_bytes())
+	{
+		match kind {
 			b'i' => {
 				size += 4;
 				elements.push(Box::new(ElementI32));
@@ -102,10 +120,51 @@ pub fn parse_row_format(human: &str) -> Box<dyn RowFormat> {
 				has_size = false;
 				elements.push(Box::new(ElementString));
 			}
+			b'b' => {
+				size += 1;
+				elements.push(Box::new(ElementBool));
+			}
+			b'c' => {
+				size += 1;
+				elements.push(Box::new(ElementI8));
+			}
+			b'C' => {
+				size += 1;
+				elements.push(Box::new(ElementU8));
+			}
+			b'h' => {
+				size += 2;
+				elements.push(Box::new(ElementI16));
+			}
+			b'H' => {
+				size += 2;
+				elements.push(Box::new(ElementU16));
+			}
+			b'x' => {
+				has_size = false;
+				elements.push(Box::new(ElementBytes));
+			}
+			b'd' => {
+				if scale > crate::MAX_DECIMAL_SCALE {
+					panic!("invalid decimal scale {}", scale);
+				}
+				size += 8;
+				elements.push(Box::new(ElementDecimal(scale)));
+			}
 			a => {
 				panic!("invalid format character '{}'", a);
 			}
 		}
+		if nullable {
+			let inner = elements.pop().unwrap();
+			// the null value of a variable size column has a length of 0
+			let null = match row_format_size(std::str::from_utf8(&[kind]).unwrap()) {
+				Some(size) => vec![0; size],
+				None => vec![0],
+			};
+			size += 1;
+			elements.push(Box::new(ElementNullable { inner, null }));
+		}
 	}
 
 	Box::new(RowFormatImpl {
@@ -115,11 +174,11 @@ pub fn parse_row_format(human: &str) -> Box<dyn RowFormat> {
 }
 
 pub fn row_format_size(human: &str) -> Option<usize> {
-	let human = human.as_bytes();
+	let mut human = human.as_bytes().iter().peekable();
 
 	let mut size = 0usize;
 
-	for t in human {
+	while let Some(t) = human.next() {
 		match t {
 			b'i' => size += 4,
 			b'u' => size += 4,
@@ -127,7 +186,14 @@ pub fn row_format_size(human: &str) -> Option<usize> {
 			b'U' => size += 8,
 			b'f' => size += 4,
 			b'F' => size += 8,
-			b's' => return None,
+			b'd' => {
+				size += 8;
+				// the scale of a decimal
+				while human.next_if(|c| c.is_ascii_digit()).is_some() {}
+			}
+			b'b' | b'c' | b'C' | b'n' => size += 1,
+			b'h' | b'H' => size += 2,
+			b's' | b'x' => return None,
 			b'\x7f' => return None,
 			a => {
 				panic!("invalid format character '{}'", a);
@@ -138,6 +204,58 @@ pub fn row_format_size(human: &str) -> Option<usize> {
 	Some(size)
 }
 
+/// Returns false if `human` has a character that isn't a column type
+pub(crate) fn is_valid_format(human: &str) -> bool {
+	human == "\x7f"
+		|| columns(human.as_bytes()).all(|c| {
+			matches!(
+				c.kind,
+				b'i' | b'u'
+					| b'I' | b'U' | b'f'
+					| b'F' | b's' | b'b'
+					| b'c' | b'C' | b'h'
+					| b'H' | b'x'
+			) || c.kind == b'd' && c.scale <= crate::MAX_DECIMAL_SCALE
+		})
+}
+
+/// One column of a format string
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Column {
+	/// the column is preceded by `n`
+	pub(crate) nullable: bool,
+	/// the format character
+	pub(crate) kind: u8,
+	/// the number of decimal places of a `d` column
+	pub(crate) scale: u8,
+}
+
+/// The columns of the format string `fmt`
+pub(crate) fn columns(fmt: &[u8]) -> impl Iterator<Item = Column> + '_ {
+	let mut fmt = fmt.iter().peekable();
+	std::iter::from_fn(move || next_column(&mut fmt))
+}
+
+/// Parse the column at the front of the format string `fmt`
+pub(crate) fn next_column(fmt: &mut std::iter::Peekable<std::slice::Iter<u8>>) -> Option<Column> {
+	let mut kind = *fmt.next()?;
+	let nullable = kind == b'n';
+	if nullable {
+		kind = *fmt.next().unwrap_or(&b'n');
+	}
+	let mut scale = 0u8;
+	if kind == b'd' {
+		while let Some(digit) = fmt.next_if(|c| c.is_ascii_digit()) {
+			scale = scale.saturating_mul(10).saturating_add(digit - b'0');
+		}
+	}
+	Some(Column {
+		nullable,
+		kind,
+		scale,
+	})
+}
+
 pub trait Element {
 	fn to_stored_format<'s>(&self, from: &'s str, dest: &mut Vec<u8>) -> Result<&'s str, String>;
 	fn to_protocol_format<'a>(
@@ -265,7 +383,7 @@ impl Element for ElementF32 {
 		let (t, rest) = split_one(from).unwrap();
 
 		let v = if t == "nan" {
-			::std::f32::NAN
+			f32::NAN
 		} else {
 			t.parse()
 				.map_err(|e| format!("while parsing {}: {}", t, e))?
@@ -295,7 +413,7 @@ impl Element for ElementF64 {
 		let (t, rest) = split_one(from).unwrap();
 
 		let v = if t == "nan" {
-			::std::f64::NAN
+			f64::NAN
 		} else {
 			t.parse()
 				.map_err(|e| format!("while parsing {}: {}", t, e))?
@@ -341,3 +459,227 @@ impl Element for ElementString {
 		Ok(&tail[len as usize..])
 	}
 }
+
+struct ElementBool;
+impl Element for ElementBool {
+	fn to_stored_format<'s>(&self, from: &'s str, dest: &mut Vec<u8>) -> Result<&'s str, String> {
+		let (t, rest) = split_one(from).unwrap();
+
+		let v = match &*t {
+			"true" | "1" => 1,
+			"false" | "0" => 0,
+			t => return Err(format!("while parsing {}: not a boolean", t)),
+		};
+		dest.push(v);
+
+		Ok(rest)
+	}
+	fn to_protocol_format<'a>(
+		&self,
+		from: &'a [u8],
+		dest: &mut dyn ::std::io::Write,
+	) -> ::std::io::Result<&'a [u8]> {
+		write!(dest, "{}", from[0] != 0)?;
+		Ok(&from[1..])
+	}
+}
+
+struct ElementI8;
+impl Element for ElementI8 {
+	fn to_stored_format<'s>(&self, from: &'s str, dest: &mut Vec<u8>) -> Result<&'s str, String> {
+		let (t, rest) = split_one(from).unwrap();
+
+		let v: i8 = t
+			.parse()
+			.map_err(|e| format!("while parsing {}: {}", t, e))?;
+		dest.push(v as u8);
+
+		Ok(rest)
+	}
+	fn to_protocol_format<'a>(
+		&self,
+		from: &'a [u8],
+		dest: &mut dyn ::std::io::Write,
+	) -> ::std::io::Result<&'a [u8]> {
+		write!(dest, "{}", from[0] as i8)?;
+		Ok(&from[1..])
+	}
+}
+
+struct ElementU8;
+impl Element for ElementU8 {
+	fn to_stored_format<'s>(&self, from: &'s str, dest: &mut Vec<u8>) -> Result<&'s str, String> {
+		let (t, rest) = split_one(from).unwrap();
+
+		let v: u8 = t
+			.parse()
+			.map_err(|e| format!("while parsing {}: {}", t, e))?;
+		dest.push(v);
+
+		Ok(rest)
+	}
+	fn to_protocol_format<'a>(
+		&self,
+		from: &'a [u8],
+		dest: &mut dyn ::std::io::Write,
+	) -> ::std::io::Result<&'a [u8]> {
+		write!(dest, "{}", from[0])?;
+		Ok(&from[1..])
+	}
+}
+
+struct ElementI16;
+impl Element for ElementI16 {
+	fn to_stored_format<'s>(&self, from: &'s str, dest: &mut Vec<u8>) -> Result<&'s str, String> {
+		let at = dest.len();
+		dest.resize(at + 2, 0);
+		let dest = &mut dest[at..];
+
+		let (t, rest) = split_one(from).unwrap();
+
+		let v = t
+			.parse()
+			.map_err(|e| format!("while parsing {}: {}", t, e))?;
+		BigEndian::write_i16(dest, v);
+
+		Ok(rest)
+	}
+	fn to_protocol_format<'a>(
+		&self,
+		from: &'a [u8],
+		dest: &mut dyn ::std::io::Write,
+	) -> ::std::io::Result<&'a [u8]> {
+		let v: i16 = BigEndian::read_i16(&from[0..2]);
+		write!(dest, "{}", v)?;
+		Ok(&from[2..])
+	}
+}
+
+struct ElementU16;
+impl Element for ElementU16 {
+	fn to_stored_format<'s>(&self, from: &'s str, dest: &mut Vec<u8>) -> Result<&'s str, String> {
+		let at = dest.len();
+		dest.resize(at + 2, 0);
+		let dest = &mut dest[at..];
+
+		let (t, rest) = split_one(from).unwrap();
+
+		let v = t
+			.parse()
+			.map_err(|e| format!("while parsing {}: {}", t, e))?;
+		BigEndian::write_u16(dest, v);
+
+		Ok(rest)
+	}
+	fn to_protocol_format<'a>(
+		&self,
+		from: &'a [u8],
+		dest: &mut dyn ::std::io::Write,
+	) -> ::std::io::Result<&'a [u8]> {
+		let v: u16 = BigEndian::read_u16(&from[0..2]);
+		write!(dest, "{}", v)?;
+		Ok(&from[2..])
+	}
+}
+
+struct ElementBytes;
+impl Element for ElementBytes {
+	fn to_stored_format<'s>(&self, from: &'s str, dest: &mut Vec<u8>) -> Result<&'s str, String> {
+		let (t, rest) = split_one(from).unwrap();
+
+		if t.len() % 2 != 0 || !t.bytes().all(|c| c.is_ascii_hexdigit()) {
+			return Err(format!("while parsing {}: not hexadecimal bytes", t));
+		}
+		let mut buf = unsigned_varint::encode::u64_buffer();
+		let encoded_len = unsigned_varint::encode::u64(t.len() as u64 / 2, &mut buf);
+		dest.extend_from_slice(encoded_len);
+		for pair in t.as_bytes().chunks(2) {
+			let pair = std::str::from_utf8(pair).unwrap();
+			dest.push(u8::from_str_radix(pair, 16).unwrap());
+		}
+		Ok(rest)
+	}
+	fn to_protocol_format<'a>(
+		&self,
+		from: &'a [u8],
+		dest: &mut dyn ::std::io::Write,
+	) -> ::std::io::Result<&'a [u8]> {
+		let (len, tail) = unsigned_varint::decode::u64(from).map_err(|e| {
+			std::io::Error::new(std::io::ErrorKind::InvalidData, format!("{:?}", e))
+		})?;
+
+		for b in &tail[0..len as usize] {
+			write!(dest, "{:02x}", b)?;
+		}
+		Ok(&tail[len as usize..])
+	}
+}
+
+/// A column that may be null, see [`parse_row_format`]
+struct ElementNullable {
+	inner: Box<dyn Element>,
+	/// what's stored in place of the value of a null column
+	null: Vec<u8>,
+}
+impl Element for ElementNullable {
+	fn to_stored_format<'s>(&self, from: &'s str, dest: &mut Vec<u8>) -> Result<&'s str, String> {
+		let (t, rest) = split_one(from).ok_or_else(|| format!("Unable to parse \"{}\"", from))?;
+
+		// an escaped `\-` is not null
+		if matches!(t, std::borrow::Cow::Borrowed("-")) {
+			dest.push(0);
+			dest.extend_from_slice(&self.null);
+			Ok(rest)
+		} else {
+			dest.push(1);
+			self.inner.to_stored_format(from, dest)
+		}
+	}
+	fn to_protocol_format<'a>(
+		&self,
+		from: &'a [u8],
+		dest: &mut dyn ::std::io::Write,
+	) -> ::std::io::Result<&'a [u8]> {
+		if from[0] == 0 {
+			write!(dest, "-")?;
+			return self
+				.inner
+				.to_protocol_format(&from[1..], &mut std::io::sink());
+		}
+
+		let mut value = vec![];
+		let rest = self.inner.to_protocol_format(&from[1..], &mut value)?;
+		if value == b"-" {
+			write!(dest, "\\-")?;
+		} else {
+			dest.write_all(&value)?;
+		}
+		Ok(rest)
+	}
+}
+
+struct ElementDecimal(u8);
+impl Element for ElementDecimal {
+	fn to_stored_format<'s>(&self, from: &'s str, dest: &mut Vec<u8>) -> Result<&'s str, String> {
+		let at = dest.len();
+		dest.resize(at + 8, 0);
+		let dest = &mut dest[at..];
+
+		let (t, rest) = split_one(from).unwrap();
+
+		let v = crate::Decimal::parse_with_scale(&t, self.0)
+			.map_err(|e| format!("while parsing {}: {}", t, e))?;
+		BigEndian::write_i64(dest, v.mantissa);
+
+		Ok(rest)
+	}
+	fn to_protocol_format<'a>(
+		&self,
+		from: &'a [u8],
+		dest: &mut dyn ::std::io::Write,
+	) -> ::std::io::Result<&'a [u8]> {
+		let v = crate::Decimal::new(BigEndian::read_i64(&from[0..8]), self.0);
+		write!(dest, "{}", v)?;
+		Ok(&from[8..])
+	}
+}
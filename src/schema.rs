@@ -0,0 +1,237 @@
+//! Name the columns of keys with a `schema` file in the database directory.
+//!
+//! Each line of the file is a [`Wildcard`] followed by the columns
+//! of the keys it matches, each as a name and a type separated by a colon:
+//!
+//! ```text
+//! # comments start with a hash
+//! sensor.%  temperature:F humidity:nF count:u
+//! price.%   bid:d4 ask:d4
+//! ```
+//!
+//! A key gets the columns of the first line that matches it, keys that
+//! no line matches have no schema. Like in the text format, whitespace
+//! in a pattern or name is escaped with a backslash.
+
+use crate::Wildcard;
+use escape_string::split;
+use std::path::Path;
+
+/// The name of the schema file in a database directory
+pub const SCHEMA_FILE_NAME: &str = "schema";
+
+/// The named columns of all the keys in a database
+#[derive(Debug, Clone, Default)]
+pub struct Schema {
+	keys: Vec<KeySchema>,
+}
+
+/// The columns of the keys that match one pattern
+#[derive(Debug, Clone)]
+pub struct KeySchema {
+	pattern: String,
+	prefix: String,
+	regex: Option<regex::Regex>,
+	columns: Vec<ColumnSchema>,
+	format: String,
+}
+
+/// The name and type of one column
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ColumnSchema {
+	/// The name of the column
+	pub name: String,
+	/// The format of the column, like `F` or `nd4`
+	pub format: String,
+}
+
+fn invalid(line: usize, message: String) -> std::io::Error {
+	std::io::Error::new(
+		std::io::ErrorKind::InvalidData,
+		format!("schema line {}: {}", line, message),
+	)
+}
+
+impl Schema {
+	/// Read the schema file of the database in `dir`.
+	///
+	/// A database without a schema file has an empty schema.
+	pub fn load(dir: &Path) -> std::io::Result<Schema> {
+		match std::fs::read_to_string(dir.join(SCHEMA_FILE_NAME)) {
+			Ok(text) => Schema::parse(&text),
+			Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Schema::default()),
+			Err(e) => Err(e),
+		}
+	}
+
+	/// Parse the contents of a schema file
+	pub fn parse(text: &str) -> std::io::Result<Schema> {
+		let mut keys = vec![];
+		for (line_no, line) in text.lines().enumerate() {
+			let line_no = line_no + 1;
+			let line = line.trim();
+			if line.is_empty() || line.starts_with('#') {
+				continue;
+			}
+			let words =
+				split(line).ok_or_else(|| invalid(line_no, "ends with a backslash".to_owned()))?;
+			let mut words = words.into_iter().filter(|w| !w.is_empty());
+			let pattern = words.next().unwrap();
+
+			let mut columns: Vec<ColumnSchema> = vec![];
+			for column in words {
+				let (name, format) = column.rsplit_once(':').ok_or_else(|| {
+					invalid(line_no, format!("\"{}\" is not a name:type", column))
+				})?;
+				let is_one_column = crate::row_format::columns(format.as_bytes()).count() == 1;
+				if !is_one_column || !crate::row_format::is_valid_format(format) {
+					return Err(invalid(
+						line_no,
+						format!("\"{}\" is not the type of a column", format),
+					));
+				}
+				if name.is_empty() || columns.iter().any(|c| c.name == name) {
+					return Err(invalid(
+						line_no,
+						format!("invalid or repeated column name \"{}\"", name),
+					));
+				}
+				columns.push(ColumnSchema {
+					name: name.to_owned(),
+					format: format.to_owned(),
+				});
+			}
+			if columns.is_empty() {
+				return Err(invalid(line_no, format!("\"{}\" has no columns", pattern)));
+			}
+
+			let wildcard = Wildcard::new(&pattern);
+			keys.push(KeySchema {
+				prefix: wildcard.prefix().to_owned(),
+				regex: wildcard.as_regex(),
+				format: columns.iter().map(|c| &c.format[..]).collect(),
+				columns,
+				pattern: pattern.into_owned(),
+			});
+		}
+		Ok(Schema { keys })
+	}
+
+	/// The columns of `key`, from the first pattern that matches it
+	pub fn for_key(&self, key: &str) -> Option<&KeySchema> {
+		self.keys.iter().find(|k| k.matches(key))
+	}
+
+	/// Returns true if the schema file names no columns
+	pub fn is_empty(&self) -> bool {
+		self.keys.is_empty()
+	}
+
+	/// Check that records of `key` can have the format `format`.
+	///
+	/// Keys without a schema can have any format.
+	pub fn check(&self, key: &str, format: &str) -> Result<(), crate::WriteFailure> {
+		match self.for_key(key) {
+			Some(k) if k.format != format => Err(crate::WriteFailure::SchemaViolation {
+				key: key.to_owned(),
+				format: format.to_owned(),
+				expected: k.format.clone(),
+			}),
+			_ => Ok(()),
+		}
+	}
+
+	/// The index of the column `name` of a record of `key` in the format `format`.
+	///
+	/// Fails if the key has no schema, if the schema has no such column, or
+	/// if the record doesn't have the format of the schema.
+	pub fn column_index(&self, key: &str, format: &str, name: &str) -> std::io::Result<usize> {
+		let not_found = |why: String| std::io::Error::new(std::io::ErrorKind::NotFound, why);
+		let k = self
+			.for_key(key)
+			.ok_or_else(|| not_found(format!("the key \"{}\" has no schema", key)))?;
+		if k.format != format {
+			return Err(std::io::Error::new(
+				std::io::ErrorKind::InvalidData,
+				format!(
+					"a record of \"{}\" has the format \"{}\", but its schema is \"{}\"",
+					key, format, k.format
+				),
+			));
+		}
+		k.columns
+			.iter()
+			.position(|c| c.name == name)
+			.ok_or_else(|| {
+				not_found(format!(
+					"the schema of \"{}\" has no column \"{}\"",
+					key, name
+				))
+			})
+	}
+}
+
+impl KeySchema {
+	/// The wildcard that this schema applies to
+	pub fn pattern(&self) -> &str {
+		&self.pattern
+	}
+
+	/// The columns, in order
+	pub fn columns(&self) -> &[ColumnSchema] {
+		&self.columns
+	}
+
+	/// The format string of a record with these columns
+	pub fn format(&self) -> &str {
+		&self.format
+	}
+
+	/// Returns true if `key` matches this pattern
+	pub fn matches(&self, key: &str) -> bool {
+		match &self.regex {
+			Some(re) => re.is_match(key),
+			None => key.starts_with(&self.prefix),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn parse() {
+		let schema = Schema::parse(
+			"# sensors\n\
+			sensor.% temperature:F humidity:nF count:u\n\
+			\n\
+			price.% bid:d4 ask:d4\n\
+			%        value\\ name:s\n",
+		)
+		.unwrap();
+
+		let sensor = schema.for_key("sensor.a").unwrap();
+		assert_eq!(sensor.pattern(), "sensor.%");
+		assert_eq!(sensor.format(), "FnFu");
+		assert_eq!(sensor.columns()[1].name, "humidity");
+		assert_eq!(schema.for_key("price.x").unwrap().format(), "d4d4");
+		assert_eq!(
+			schema.for_key("other").unwrap().columns()[0].name,
+			"value name"
+		);
+
+		assert_eq!(schema.column_index("sensor.a", "FnFu", "count").unwrap(), 2);
+		assert!(schema.column_index("sensor.a", "FnFu", "bid").is_err());
+		assert!(schema.column_index("sensor.a", "FFu", "count").is_err());
+
+		assert!(schema.check("sensor.a", "FnFu").is_ok());
+		assert!(schema.check("price.a", "d4d2").is_err());
+
+		assert!(Schema::parse("a% x:ff").is_err());
+		assert!(Schema::parse("a% x:q").is_err());
+		assert!(Schema::parse("a% x:f x:f").is_err());
+		assert!(Schema::parse("a% x").is_err());
+		assert!(Schema::parse("a%").is_err());
+	}
+}
@@ -1,6 +1,9 @@
 use byteorder::{BigEndian, ByteOrder};
 use static_init::dynamic;
 
+use crate::{Codec, Timestamp, TimestampRange};
+use std::ops::Bound;
+
 pub(crate) const SEGMENT_INVOCATION: &[u8; 14] = b"@TSDB_SEGMENT_";
 pub(crate) const ESCAPE_SEGMENT_INVOCATION: &[u8; 16] = b"@TSDB_SEGMENT_\xff\xff";
 
@@ -30,6 +33,14 @@ pub(crate) struct Segment<'data> {
 	pub(crate) this_key_prev: usize,
 	pub(crate) segment_version: u16,
 	pub(crate) stride: usize, // bytes from the start of the invocation to the next invocation
+	/// the earliest timestamp of any record in this segment (0 if unknown)
+	pub(crate) min_timestamp: Timestamp,
+	/// the latest timestamp of any record in this segment (`Timestamp::MAX` if unknown)
+	pub(crate) max_timestamp: Timestamp,
+	/// how `payload` is compressed
+	pub(crate) codec: Codec,
+	/// the CRC-32 of `payload`, if the segment has one
+	pub(crate) checksum: Option<u32>,
 }
 
 impl<'data> std::fmt::Debug for Segment<'data> {
@@ -41,13 +52,22 @@ impl<'data> std::fmt::Debug for Segment<'data> {
 			.field("prev_size", &self.prev_size)
 			.field("this_key_prev", &self.this_key_prev)
 			.field("stride", &self.stride)
+			.field("min_timestamp", &self.min_timestamp)
+			.field("max_timestamp", &self.max_timestamp)
+			.field("codec", &self.codec)
+			.field("checksum", &self.checksum)
 			.finish()
 	}
 }
 
+/// a key from a segment header
+fn key(bytes: &[u8]) -> Result<&str, String> {
+	std::str::from_utf8(bytes).map_err(|_| "segment key is not utf-8".to_string())
+}
+
 impl<'data> Segment<'data> {
 	// read from `from` until I find a header, returning it
-	// if there is one
+	// if there is one. Headers that can't be read are skipped.
 	pub(crate) fn scan(from: &'data [u8], origin: usize) -> Option<Segment<'data>> {
 		// relative_search_start makes it possible to skip invocation escape sequences
 		let mut relative_search_start = 0;
@@ -56,112 +76,178 @@ impl<'data> Segment<'data> {
 				self::FINDER_SEGMENT_INVOCATION.find(&from[relative_search_start..])?;
 			let header =
 				&from[relative_search_start + invocation_relative_at + SEGMENT_INVOCATION.len()..];
-			if header.len() < 2 {
-				// invalid
-				return None;
-			}
 			let segment_offset = invocation_relative_at + relative_search_start + origin;
 
-			let segment_version = BigEndian::read_u16(&header[0..2]);
+			match Segment::parse(header, segment_offset) {
+				Ok(Some(segment)) => return Some(segment),
+				// we found the escape character, or a damaged header
+				Ok(None) | Err(_) => {
+					relative_search_start += invocation_relative_at + SEGMENT_INVOCATION.len();
+				}
+			}
+		}
+	}
 
-			match segment_version {
-				0 => {
-					if header.len() < 18 {
-						return None;
-					}
-					// the length of the first key
-					let len1 = BigEndian::read_u32(&header[2..6]) as usize;
-					// the length of the last key
-					let len2 = BigEndian::read_u32(&header[6..10]) as usize;
-					// the length of the payload
-					let len3 = BigEndian::read_u32(&header[10..14]) as usize;
-					// the compressed size of the previous segment
-					let prev_size = BigEndian::read_u32(&header[14..18]) as usize;
-
-					let at = 18;
-
-					if header[at..].len() < len1 + len2 + len3 {
-						return None;
-					}
+	/// why the first segment header in `from` can't be read, if it can't
+	pub(crate) fn scan_error(from: &[u8]) -> Option<String> {
+		let at = find_segment_invocation(from)?;
+		Segment::parse(&from[at + SEGMENT_INVOCATION.len()..], at).err()
+	}
 
-					let first_key = &header[at..at + len1];
-					let first_key = std::str::from_utf8(first_key).expect("first_key is not utf-8");
-
-					let at = at + len1;
-					let last_key = &header[at..at + len2];
-					let last_key = std::str::from_utf8(last_key).expect("last_key is not utf-8");
-
-					let header_len = 18 + len1 + len2;
-					let payload = &header[header_len..header_len + len3];
-
-					return Some(Segment {
-						first_key,
-						last_key,
-						payload,
-						segment_offset,
-						prev_size,
-						this_key_prev: 0,
-						segment_version,
-						stride: SEGMENT_INVOCATION.len() + header_len + len3,
-					});
-				}
+	/// read the header that follows an invocation, None if it's the escape sequence
+	fn parse(header: &'data [u8], segment_offset: usize) -> Result<Option<Segment<'data>>, String> {
+		let truncated = || "truncated segment header".to_string();
+		if header.len() < 2 {
+			return Err(truncated());
+		}
 
-				0x0100 => {
-					use unsigned_varint::decode::u32 as v32;
-					let from = &header[2..];
-
-					// the length of the first key
-					let (len1, from) = v32(from).ok()?;
-					// the length of the last key
-					let (len2, from) = v32(from).ok()?;
-					// the length of the payload
-					let (len3, from) = v32(from).ok()?;
-					// the compressed size of the previous segment
-					let (prev_size, from) = v32(from).ok()?;
-					// how many bytes we need to reverse to get to the start
-					// of this key
-					let (this_key_prev, from) = v32(from).ok()?;
-
-					let len1 = len1 as usize;
-					let len2 = len2 as usize;
-					let len3 = len3 as usize;
-					let prev_size = prev_size as usize;
-					let this_key_prev = this_key_prev as usize;
-
-					if from.len() < len1 + len2 + len3 {
-						return None;
-					}
+		let segment_version = BigEndian::read_u16(&header[0..2]);
 
-					let header_len = len1 + len2 + (header.len() - from.len());
-					let first_key = &from[0..len1];
-					let first_key = std::str::from_utf8(first_key).expect("first_key is not utf-8");
-					let last_key = &from[len1..len1 + len2];
-					let last_key = std::str::from_utf8(last_key).expect("last_key is not utf-8");
-
-					let payload = &header[header_len..header_len + len3];
-
-					return Some(Segment {
-						first_key,
-						last_key,
-						payload,
-						segment_offset,
-						prev_size,
-						this_key_prev,
-						segment_version,
-						stride: SEGMENT_INVOCATION.len() + header_len + len3,
-					});
+		match segment_version {
+			0 => {
+				if header.len() < 18 {
+					return Err(truncated());
 				}
-
-				0xffff => {
-					// we found the escape character
-					relative_search_start = invocation_relative_at + SEGMENT_INVOCATION.len();
-					continue;
+				// the length of the first key
+				let len1 = BigEndian::read_u32(&header[2..6]) as usize;
+				// the length of the last key
+				let len2 = BigEndian::read_u32(&header[6..10]) as usize;
+				// the length of the payload
+				let len3 = BigEndian::read_u32(&header[10..14]) as usize;
+				// the compressed size of the previous segment
+				let prev_size = BigEndian::read_u32(&header[14..18]) as usize;
+
+				let at = 18;
+
+				if header[at..].len() < len1 + len2 + len3 {
+					return Err(truncated());
 				}
-				a => {
-					eprintln!("warning: invalid segment version {}", a);
-					return None;
+
+				let first_key = key(&header[at..at + len1])?;
+
+				let at = at + len1;
+				let last_key = key(&header[at..at + len2])?;
+
+				let header_len = 18 + len1 + len2;
+				let payload = &header[header_len..header_len + len3];
+
+				Ok(Some(Segment {
+					first_key,
+					last_key,
+					payload,
+					segment_offset,
+					prev_size,
+					this_key_prev: 0,
+					segment_version,
+					stride: SEGMENT_INVOCATION.len() + header_len + len3,
+					min_timestamp: 0,
+					max_timestamp: Timestamp::MAX,
+					codec: Codec::Lz4,
+					checksum: None,
+				}))
+			}
+
+			0x0100 | 0x0200 | 0x0300 | 0x0400 | 0x0500 => {
+				let v32 = |from| unsigned_varint::decode::u32(from).map_err(|_| truncated());
+				let v64 = |from| unsigned_varint::decode::u64(from).map_err(|_| truncated());
+				let from = &header[2..];
+
+				// the length of the first key
+				let (len1, from) = v32(from)?;
+				// the length of the last key
+				let (len2, from) = v32(from)?;
+				// the length of the payload
+				let (len3, from) = v32(from)?;
+				// the compressed size of the previous segment
+				let (prev_size, from) = v32(from)?;
+				// how many bytes we need to reverse to get to the start
+				// of this key
+				let (this_key_prev, from) = v32(from)?;
+
+				// 0x0200 also knows the range of timestamps in the payload
+				let (min_timestamp, max_timestamp, from) = if segment_version >= 0x0200 {
+					let (min_timestamp, from) = v64(from)?;
+					let (max_timestamp, from) = v64(from)?;
+					(min_timestamp, max_timestamp, from)
+				} else {
+					(0, Timestamp::MAX, from)
+				};
+
+				// 0x0400 also says how the payload is compressed
+				let (codec, from) = if segment_version >= 0x0400 {
+					let (&id, from) = from.split_first().ok_or_else(truncated)?;
+					let codec = Codec::from_id(id)
+						.ok_or_else(|| format!("invalid segment codec {}", id))?;
+					(codec, from)
+				} else {
+					(Codec::Lz4, from)
+				};
+
+				// 0x0500 also has a checksum of the payload
+				let (checksum, from) = if segment_version >= 0x0500 {
+					if from.len() < 4 {
+						return Err(truncated());
+					}
+					(Some(BigEndian::read_u32(&from[0..4])), &from[4..])
+				} else {
+					(None, from)
+				};
+
+				let len1 = len1 as usize;
+				let len2 = len2 as usize;
+				let len3 = len3 as usize;
+				let prev_size = prev_size as usize;
+				let this_key_prev = this_key_prev as usize;
+
+				if from.len() < len1 + len2 + len3 {
+					return Err(truncated());
 				}
+
+				let header_len = len1 + len2 + (header.len() - from.len());
+				let first_key = key(&from[0..len1])?;
+				let last_key = key(&from[len1..len1 + len2])?;
+
+				let payload = &header[header_len..header_len + len3];
+
+				Ok(Some(Segment {
+					first_key,
+					last_key,
+					payload,
+					segment_offset,
+					prev_size,
+					this_key_prev,
+					segment_version,
+					stride: SEGMENT_INVOCATION.len() + header_len + len3,
+					min_timestamp,
+					max_timestamp,
+					codec,
+					checksum,
+				}))
 			}
+
+			0xffff => Ok(None),
+			a => Err(format!("invalid segment version {:#06x}", a)),
 		}
 	}
+
+	/// returns false if the payload has been corrupted
+	pub(crate) fn checksum_matches(&self) -> bool {
+		self.checksum
+			.is_none_or(|checksum| crc32fast::hash(self.payload) == checksum)
+	}
+
+	/// returns false if no record in this segment can be in `timestamps`
+	pub(crate) fn overlaps_timestamps(&self, timestamps: &TimestampRange) -> bool {
+		let after_start = match timestamps.0 {
+			Bound::Included(t) => self.max_timestamp >= t,
+			Bound::Excluded(t) => self.max_timestamp > t,
+			Bound::Unbounded => true,
+		};
+		let before_end = match timestamps.1 {
+			Bound::Included(t) => self.min_timestamp <= t,
+			Bound::Excluded(t) => self.min_timestamp < t,
+			Bound::Unbounded => true,
+		};
+		after_start && before_end
+	}
 }
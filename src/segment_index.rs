@@ -0,0 +1,268 @@
+//! The index of segments written after the last segment of a file.
+//!
+//! With the index, finding the segment that contains a key is a binary
+//! search in memory instead of a binary search on the file, which needs
+//! to read (and scan for a segment header at) each probed position.
+//!
+//! Since version 0x0200, the index also has a Bloom filter of the keys
+//! in the file, so a lookup of a key that isn't in the file usually
+//! doesn't need to read any segment.
+//!
+//! Files without an index (or with a damaged one) are still searched
+//! the old way.
+
+use crate::segment::{ESCAPE_SEGMENT_INVOCATION, SEGMENT_INVOCATION};
+use byteorder::{BigEndian, ByteOrder};
+
+pub(crate) const INDEX_INVOCATION: &[u8; 12] = b"@TSDB_INDEX_";
+const INDEX_VERSION: u16 = 0x0200;
+/// the stored length, checksum, invocation and version
+const TRAILER_LEN: usize = 4 + 4 + INDEX_INVOCATION.len() + 2;
+
+/// The key range and position of one segment
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct IndexEntry {
+	pub(crate) first_key: String,
+	pub(crate) last_key: String,
+	pub(crate) offset: usize,
+}
+
+/// the number of bits set in a Bloom filter for each key
+const BLOOM_HASHES: u32 = 7;
+/// the size of a Bloom filter, which makes about 1% of lookups
+/// of a missing key a false positive
+const BLOOM_BITS_PER_KEY: usize = 10;
+
+/// A Bloom filter of the keys in a file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct BloomFilter {
+	hashes: u32,
+	bits: Vec<u8>,
+}
+
+/// The hash of a key that its bits in a [`BloomFilter`] are derived from
+///
+/// This is 64-bit FNV-1a followed by MurmurHash3's finalizer, the
+/// hash is part of the file format so it can never change.
+pub(crate) fn key_hash(key: &[u8]) -> u64 {
+	let mut h = 0xcbf29ce484222325u64;
+	for b in key {
+		h ^= *b as u64;
+		h = h.wrapping_mul(0x100000001b3);
+	}
+	h ^= h >> 33;
+	h = h.wrapping_mul(0xff51afd7ed558ccd);
+	h ^= h >> 33;
+	h = h.wrapping_mul(0xc4ceb9fe1a85ec53);
+	h ^ (h >> 33)
+}
+
+impl BloomFilter {
+	/// A filter of the keys with the given [`key_hash`]es
+	pub(crate) fn new(key_hashes: &[u64]) -> BloomFilter {
+		let bytes = (key_hashes.len() * BLOOM_BITS_PER_KEY).div_ceil(8).max(8);
+		let mut filter = BloomFilter {
+			hashes: BLOOM_HASHES,
+			bits: vec![0; bytes],
+		};
+		for &h in key_hashes {
+			for bit in filter.bits_of(h) {
+				filter.bits[bit / 8] |= 1 << (bit % 8);
+			}
+		}
+		filter
+	}
+
+	/// the bits of the key with the hash `h`, by double hashing
+	fn bits_of(&self, h: u64) -> impl Iterator<Item = usize> {
+		let len = self.bits.len() as u64 * 8;
+		let h1 = h & 0xffff_ffff;
+		let h2 = (h >> 32) | 1;
+		(0..self.hashes as u64).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % len) as usize)
+	}
+
+	/// returns false if `key` is definitely not in the file
+	pub(crate) fn may_contain(&self, key: &str) -> bool {
+		self.bits_of(key_hash(key.as_bytes()))
+			.all(|bit| self.bits[bit / 8] & (1 << (bit % 8)) != 0)
+	}
+}
+
+/// The index at the end of a file
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct Index {
+	/// every segment in the file, in order
+	pub(crate) segments: Vec<IndexEntry>,
+	/// the keys in the file, if the index has a filter
+	pub(crate) keys: Option<BloomFilter>,
+}
+
+fn write_varint(into: &mut Vec<u8>, v: u64) {
+	let mut buf = unsigned_varint::encode::u64_buffer();
+	into.extend_from_slice(unsigned_varint::encode::u64(v, &mut buf));
+}
+
+/// The index of segments in `entries` (which are in file order) and
+/// the filter of `keys`, ready to be appended to the file
+pub(crate) fn encode(entries: &[IndexEntry], keys: &BloomFilter) -> Vec<u8> {
+	let mut body = vec![];
+	write_varint(&mut body, entries.len() as u64);
+	for entry in entries {
+		write_varint(&mut body, entry.offset as u64);
+		write_varint(&mut body, entry.first_key.len() as u64);
+		body.extend_from_slice(entry.first_key.as_bytes());
+		write_varint(&mut body, entry.last_key.len() as u64);
+		body.extend_from_slice(entry.last_key.as_bytes());
+	}
+	write_varint(&mut body, keys.hashes as u64);
+	write_varint(&mut body, keys.bits.len() as u64);
+	body.extend_from_slice(&keys.bits);
+
+	// keys may contain a segment header, so escape them the same
+	// way payloads are escaped
+	let mut stored = Vec::with_capacity(body.len() + TRAILER_LEN);
+	let mut start = 0;
+	while let Some(pos) = crate::segment::find_segment_invocation(&body[start..]) {
+		stored.extend_from_slice(&body[start..start + pos]);
+		stored.extend_from_slice(ESCAPE_SEGMENT_INVOCATION);
+		start += pos + SEGMENT_INVOCATION.len();
+	}
+	stored.extend_from_slice(&body[start..]);
+
+	let stored_len = stored.len() as u32;
+	let checksum = crc32fast::hash(&stored);
+	stored.extend_from_slice(&stored_len.to_be_bytes());
+	stored.extend_from_slice(&checksum.to_be_bytes());
+	stored.extend_from_slice(INDEX_INVOCATION);
+	stored.extend_from_slice(&INDEX_VERSION.to_be_bytes());
+	stored
+}
+
+/// Read the index at the end of `file`, returning the offset the
+/// index starts at and its entries.
+///
+/// Returns None if the file has no index or if it's damaged.
+pub(crate) fn decode(file: &[u8]) -> Option<(usize, Index)> {
+	let trailer_at = file.len().checked_sub(TRAILER_LEN)?;
+	let trailer = &file[trailer_at..];
+	if &trailer[8..8 + INDEX_INVOCATION.len()] != INDEX_INVOCATION {
+		return None;
+	}
+	let version = BigEndian::read_u16(&trailer[TRAILER_LEN - 2..]);
+	if version != 0x0100 && version != 0x0200 {
+		eprintln!("warning: invalid segment index version {}", version);
+		return None;
+	}
+	let stored_len = BigEndian::read_u32(&trailer[0..4]) as usize;
+	let checksum = BigEndian::read_u32(&trailer[4..8]);
+	let index_at = trailer_at.checked_sub(stored_len)?;
+	let stored = &file[index_at..trailer_at];
+	if crc32fast::hash(stored) != checksum {
+		eprintln!("warning: segment index is damaged, ignoring it");
+		return None;
+	}
+
+	let mut body = Vec::with_capacity(stored.len());
+	let mut start = 0;
+	while let Some(pos) = crate::segment::find_escape_segment_invocation(&stored[start..]) {
+		body.extend_from_slice(&stored[start..start + pos]);
+		body.extend_from_slice(SEGMENT_INVOCATION);
+		start += pos + ESCAPE_SEGMENT_INVOCATION.len();
+	}
+	body.extend_from_slice(&stored[start..]);
+
+	fn varint(data: &mut &[u8]) -> Option<usize> {
+		let (v, tail) = unsigned_varint::decode::usize(data).ok()?;
+		*data = tail;
+		Some(v)
+	}
+	fn string(data: &mut &[u8]) -> Option<String> {
+		let len = varint(data)?;
+		let s = std::str::from_utf8(data.get(0..len)?).ok()?.to_owned();
+		*data = &data[len..];
+		Some(s)
+	}
+
+	let mut data = &body[..];
+	let count = varint(&mut data)?;
+	let mut entries = Vec::with_capacity(count.min(data.len()));
+	for _ in 0..count {
+		let offset = varint(&mut data)?;
+		let first_key = string(&mut data)?;
+		let last_key = string(&mut data)?;
+		entries.push(IndexEntry {
+			first_key,
+			last_key,
+			offset,
+		});
+	}
+
+	// 0x0200 also has a filter of the keys
+	let keys = if version >= 0x0200 {
+		let hashes = varint(&mut data)? as u32;
+		let len = varint(&mut data)?;
+		let bits = data.get(0..len)?.to_vec();
+		data = &data[len..];
+		if bits.is_empty() {
+			return None;
+		}
+		Some(BloomFilter { hashes, bits })
+	} else {
+		None
+	};
+
+	// make sure a search on the index can't go wrong
+	let sorted = entries
+		.windows(2)
+		.all(|w| w[0].offset < w[1].offset && w[0].last_key <= w[1].first_key);
+	let in_bounds = entries.iter().all(|e| e.offset < index_at);
+	if !data.is_empty() || !sorted || !in_bounds {
+		eprintln!("warning: segment index is invalid, ignoring it");
+		return None;
+	}
+
+	Some((
+		index_at,
+		Index {
+			segments: entries,
+			keys,
+		},
+	))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn round_trip() {
+		let entries = vec![
+			IndexEntry {
+				first_key: "a".to_string(),
+				last_key: "a@TSDB_SEGMENT_".to_string(),
+				offset: 0,
+			},
+			IndexEntry {
+				first_key: "b".to_string(),
+				last_key: "c".to_string(),
+				offset: 1000,
+			},
+		];
+		let keys = BloomFilter::new(&[key_hash(b"a"), key_hash(b"b"), key_hash(b"c")]);
+		let mut file = vec![0u8; 1100];
+		file.extend_from_slice(&encode(&entries, &keys));
+		assert!(crate::Segment::scan(&file, 0).is_none());
+		let (index_at, index) = decode(&file).unwrap();
+		assert_eq!(index_at, 1100);
+		assert_eq!(index.segments, entries);
+
+		let keys = index.keys.unwrap();
+		assert!(keys.may_contain("a") && keys.may_contain("b") && keys.may_contain("c"));
+		assert!(!keys.may_contain("d"));
+
+		// a damaged index is ignored
+		let n = file.len() - TRAILER_LEN - 1;
+		file[n] ^= 1;
+		assert_eq!(decode(&file), None);
+	}
+}
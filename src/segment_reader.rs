@@ -1,6 +1,9 @@
 //use byteorder::{BigEndian};
 
+use crate::segment_index::{BloomFilter, IndexEntry};
 use crate::Segment;
+use crate::Timestamp;
+use byteorder::{BigEndian, ByteOrder};
 use chrono::DateTime;
 use chrono::NaiveDateTime;
 use either::Either;
@@ -9,93 +12,95 @@ use std::io::Seek;
 
 pub(crate) struct SegmentReader {
 	map: memmap::Mmap,
+	/// the length of the segments, not including the index
 	len: usize,
+	/// the key range and offset of every segment, if the file has an index
+	index: Option<Vec<IndexEntry>>,
+	/// the keys in the file, if the index has a filter
+	keys: Option<BloomFilter>,
 }
 
 impl SegmentReader {
 	pub(crate) fn open(
 		file: &mut std::fs::File,
 	) -> std::io::Result<Either<SegmentReader, DeleteMarker>> {
-		use byteorder::BigEndian;
-		use byteorder::ByteOrder as _;
 		use Either::*;
 
-		let len = file.seek(std::io::SeekFrom::End(0))? as usize;
-		let map = unsafe { memmap::Mmap::map(file)? };
-		let reader = SegmentReader { map, len };
+		let reader = SegmentReader::map(file)?;
 
 		if let Some(segment) = reader.first() {
 			// read the payload of the segment and check its first few bytes
 			let mut buffer = vec![];
-			decode_into_with_unescaping(&mut buffer, segment.payload);
-
-			// bytes 0 .. 4 are the key length
-			// bytes 4 .. 8 are the format string length
-			// bytes 8 .. 12 are the payload length
-			// next comes the key string
-			// next comes the format string
-			// we need to read from bytes 12 + key_length to
-			// 12 + key_length + fmt_length to get the format string
-
-			let key_length = BigEndian::read_u32(&buffer[0..4]);
-			let format_length = BigEndian::read_u32(&buffer[4..8]);
-
-			let fmt_from = (12 + key_length) as usize;
-			let fmt_to = (12 + key_length + format_length) as usize;
+			let entry = decode_into_with_unescaping(&mut buffer, &segment)
+				.and_then(|()| key_entry(&segment, &buffer, 0));
+			let Ok(entry) = entry else {
+				// a corrupt first segment is reported when it's read
+				return Ok(Left(reader));
+			};
+			let fmt_to = entry.key_pos + entry.key.len() + entry.format.len();
+
+			if entry.format == "\u{007f}" {
+				let invalid = |e: &str| {
+					std::io::Error::new(
+						std::io::ErrorKind::InvalidData,
+						format!("invalid delete marker: {}", e),
+					)
+				};
+				let varint = |slice| {
+					unsigned_varint::decode::usize(slice)
+						.map_err(|_| invalid("failed to read varint: not enough bytes"))
+				};
+				let slice = |slice: &[u8], len| {
+					slice
+						.get(0..len)
+						.ok_or_else(|| invalid("not enough bytes"))
+						.map(|s| s.to_vec())
+				};
+				let string = |bytes| {
+					String::from_utf8(bytes).map_err(|_| invalid("not a valid utf-8 string"))
+				};
+				let timestamp = |slice: Vec<u8>| {
+					let ts_u64 = BigEndian::read_u64(&slice);
+					DateTime::from_timestamp(
+						(ts_u64 / 1_000_000_000) as i64,
+						(ts_u64 % 1_000_000_000) as u32,
+					)
+					.ok_or_else(|| invalid("timestamp out of range"))
+				};
 
-			if &buffer[fmt_from..fmt_to] == "\u{007f}".as_bytes() {
 				// first varint will be the size of payload minus 8 bytes
 				// it will be disregarded
-				let (_payload_len, next_slice) = unsigned_varint::decode::usize(&buffer[fmt_to..])
-					.expect("Failed to read varint: not enough bytes");
+				let (_payload_len, next_slice) = varint(&buffer[fmt_to..])?;
 
 				// second varint will be the size of first key
-				let (fkey_len, next_slice) = unsigned_varint::decode::usize(next_slice)
-					.expect("Failed to read varint; not enough bytes");
+				let (fkey_len, next_slice) = varint(next_slice)?;
 				// if second varint is nonzero, the next set of bytes is the
 				// first key
-				let first_key_slice = &next_slice[0..fkey_len];
-				let first_key = String::from_utf8(first_key_slice.to_owned())
-					.expect("Failed to read string: not a valid utf-8 string");
-				assert_eq!(first_key, segment.first_key);
+				let first_key = string(slice(next_slice, fkey_len)?)?;
+				if first_key != segment.first_key {
+					return Err(invalid("first key doesn't match the segment"));
+				}
+				let next_slice = &next_slice[fkey_len..];
 
 				// first 8 bytes being the first timestamp
-				let ts_slice = &next_slice[fkey_len..];
-				let ts_u64 = BigEndian::read_u64(ts_slice);
-				let start_ts = DateTime::from_timestamp(
-					(ts_u64 / 1_000_000_000) as i64,
-					(ts_u64 % 1_000_000_000) as u32,
-				)
-				.unwrap();
+				let start_ts = timestamp(slice(next_slice, 8)?)?;
 
 				// next 8 bytes being the last timestamp
-				let ts_slice = &next_slice[fkey_len + 8..fkey_len + 16];
-				let ts_u64 = BigEndian::read_u64(ts_slice);
-				let end_ts = DateTime::from_timestamp(
-					(ts_u64 / 1_000_000_000) as i64,
-					(ts_u64 % 1_000_000_000) as u32,
-				)
-				.unwrap();
+				let end_ts = timestamp(slice(&next_slice[8..], 8)?)?;
 
 				// next set of bytes is a varint containing the length of the
 				// wildcard
-				let (wc_len, next_slice) =
-					unsigned_varint::decode::usize(&next_slice[fkey_len + 16..])
-						.expect("Failed to read varint: not enough bytes");
+				let (wc_len, next_slice) = varint(&next_slice[16..])?;
 
 				// read, from the next slice, the slice for the filter string
-				let wildcard_slice = &next_slice[0..wc_len];
-				let wildcard = String::from_utf8(wildcard_slice.to_vec()).unwrap();
+				let wildcard = string(slice(next_slice, wc_len)?)?;
 
 				// next set of bytes is also another varint containing the
 				// length of the last key
-				let (lkey_len, next_slice) = unsigned_varint::decode::usize(&next_slice[wc_len..])
-					.expect("Failed to read varint: not enough bytes");
+				let (lkey_len, next_slice) = varint(&next_slice[wc_len..])?;
 
 				// read from the next slice, the slice for the last key
-				let last_key_slice = &next_slice[0..lkey_len];
-				let last_key = String::from_utf8(last_key_slice.to_owned())
-					.expect("Failed to read string: not a valid utf-8 string");
+				let last_key = string(slice(next_slice, lkey_len)?)?;
 				// unlike here, we cannot test whether segment's last key equals
 				// this last key because the interface that sets the first key
 				// up in the segment header bases on the first key in a
@@ -116,6 +121,44 @@ impl SegmentReader {
 		Ok(Left(reader))
 	}
 
+	/// open a file without checking whether it's a delete marker
+	pub(crate) fn map(file: &mut std::fs::File) -> std::io::Result<SegmentReader> {
+		let len = file.seek(std::io::SeekFrom::End(0))? as usize;
+		let map = unsafe { memmap::Mmap::map(file)? };
+		let (len, index, keys) = match crate::segment_index::decode(&map[..len]) {
+			Some((index_at, index)) => (index_at, Some(index.segments), index.keys),
+			None => (len, None, None),
+		};
+		Ok(SegmentReader {
+			map,
+			len,
+			index,
+			keys,
+		})
+	}
+
+	/// returns false if the file definitely doesn't contain `key`
+	pub(crate) fn may_contain(&self, key: &str) -> bool {
+		self.keys.as_ref().is_none_or(|keys| keys.may_contain(key))
+	}
+
+	/// the segments of the file, without the index
+	fn data(&self) -> &[u8] {
+		&self.map[..self.len]
+	}
+
+	/// the segment at `offset`, which comes from the index
+	fn indexed_segment(&self, offset: usize) -> Option<Segment<'_>> {
+		let segment = Segment::scan(&self.data()[offset..], offset)?;
+		if segment.segment_offset != offset {
+			eprintln!(
+				"warning: segment index doesn't match the segment at {}",
+				offset
+			);
+		}
+		Some(segment)
+	}
+
 	/// instructs the OS I'm going to sequentially read starting here
 	pub(crate) fn advise(&self, from: &Segment) {
 		use libc::{c_void, sysconf, _SC_PAGESIZE};
@@ -141,6 +184,27 @@ impl SegmentReader {
 		self.len
 	}
 
+	/// The error of the bytes from `from` to `to`, which are between
+	/// two segments (or after the last one) but aren't a segment
+	pub(crate) fn unreadable(&self, from: usize, to: usize) -> Option<ReadError> {
+		if from >= to {
+			return None;
+		}
+		Some(ReadError::CorruptSegment {
+			path: None,
+			offset: from,
+			reason: self.unreadable_reason(from, to),
+		})
+	}
+
+	fn unreadable_reason(&self, from: usize, to: usize) -> String {
+		let bytes = format!("{} unreadable bytes", to - from);
+		match Segment::scan_error(&self.data()[from..to]) {
+			Some(e) => format!("{} ({})", e, bytes),
+			None => bytes,
+		}
+	}
+
 	pub(crate) fn print_info<W: std::io::Write>(&self, w: &mut W) -> std::io::Result<()> {
 		let mut segment = self.first();
 		while let Some(s) = segment.take() {
@@ -149,33 +213,107 @@ impl SegmentReader {
 			writeln!(
 				w,
 				"first_key=\"{}\", last_key=\"{}\", \
-				offset={}, len={}, prev_sz={}, this_key_prev={}",
+				offset={}, len={}, prev_sz={}, this_key_prev={}, \
+				min_ts={}, max_ts={}, codec={:?}",
 				fk,
 				lk,
 				s.segment_offset,
 				s.payload.len(),
 				s.prev_size,
 				s.this_key_prev,
+				s.min_timestamp,
+				s.max_timestamp,
+				s.codec,
 			)?;
 			segment = self.segment_after(&s);
 		}
+		if let Some(index) = &self.index {
+			writeln!(
+				w,
+				"index of {} segments at offset={}",
+				index.len(),
+				self.len
+			)?;
+		}
 		Ok(())
 	}
 
-	pub(crate) fn first(&self) -> Option<Segment> {
-		Segment::scan(&self.map[..], 0)
+	/// Check every segment, calling `report` with the offset, the key
+	/// range and a description of each one that is corrupt
+	///
+	/// Bytes that can't be read as a segment are reported with the keys
+	/// of the segments around them.
+	pub(crate) fn verify(&self, mut report: impl FnMut(usize, &str, &str, String)) {
+		let mut expected_offset = 0;
+		let mut last_key = "";
+		let mut segment = self.first();
+		while let Some(s) = segment.take() {
+			if s.segment_offset != expected_offset {
+				report(
+					expected_offset,
+					last_key,
+					s.first_key,
+					self.unreadable_reason(expected_offset, s.segment_offset),
+				);
+			}
+			if !s.checksum_matches() {
+				report(
+					s.segment_offset,
+					s.first_key,
+					s.last_key,
+					"checksum mismatch".to_string(),
+				);
+			} else if s.checksum.is_none() {
+				// without a checksum, the best we can do is decode it
+				let mut decoded = vec![];
+				let decodes = decode_into_with_unescaping(&mut decoded, &s)
+					.and_then(|()| for_each_record(&s, &decoded, |_, _, _| {}));
+				if let Err(e) = decodes {
+					let reason = match e {
+						ReadError::CorruptSegment { reason, .. } => reason,
+						e => e.to_string(),
+					};
+					report(s.segment_offset, s.first_key, s.last_key, reason);
+				}
+			}
+			expected_offset = s.segment_offset + s.stride;
+			last_key = s.last_key;
+			segment = self.segment_after(&s);
+		}
+		if expected_offset < self.len {
+			report(
+				expected_offset,
+				last_key,
+				"",
+				self.unreadable_reason(expected_offset, self.len),
+			);
+		}
+	}
+
+	pub(crate) fn first(&self) -> Option<Segment<'_>> {
+		Segment::scan(self.data(), 0)
 	}
 
-	pub(crate) fn scan_from(&self, pos: usize) -> Option<Segment> {
-		Segment::scan(&self.map[pos..], pos)
+	pub(crate) fn scan_from(&self, pos: usize) -> Option<Segment<'_>> {
+		Segment::scan(&self.data()[pos..], pos)
 	}
 
 	pub(crate) fn find<'s>(&'s self, key: &str) -> Option<Segment<'s>> {
+		if let Some(index) = &self.index {
+			// the first segment that could contain key
+			let at = index.partition_point(|e| e.last_key.as_str() < key);
+			let entry = index.get(at)?;
+			if at != 0 && key < entry.first_key.as_str() {
+				return None;
+			}
+			return self.indexed_segment(entry.offset);
+		}
+
 		// do a binary search for the segment that contains key
 		let mut begin = 0;
 		let mut end = self.len - 1;
 
-		let data = &self.map;
+		let data = self.data();
 
 		loop {
 			let mut pos = (end - begin) / 2 + begin;
@@ -235,11 +373,19 @@ impl SegmentReader {
 
 	/// do a binary search for the first segment after the one
 	/// that contains `key`.
-	pub(crate) fn find_after(&self, cmp: impl Fn(&str) -> std::cmp::Ordering) -> Option<Segment> {
+	pub(crate) fn find_after(
+		&self,
+		cmp: impl Fn(&str) -> std::cmp::Ordering,
+	) -> Option<Segment<'_>> {
+		if let Some(index) = &self.index {
+			let at = index.partition_point(|e| cmp(&e.last_key).is_lt());
+			return self.indexed_segment(index.get(at)?.offset);
+		}
+
 		let mut begin = 0;
 		let mut end = self.len - 1;
 
-		let data = &self.map;
+		let data = self.data();
 
 		loop {
 			let mut pos = (end - begin) / 2 + begin;
@@ -291,14 +437,208 @@ impl SegmentReader {
 		}
 	}
 
+	/// The format and timestamp of the last record of `key`,
+	/// decoding only the last segment that has the key
+	pub(crate) fn last_of(&self, key: &str) -> Result<Option<(String, Timestamp)>, ReadError> {
+		let segment = if let Some(index) = &self.index {
+			let at = index.partition_point(|e| e.first_key.as_str() <= key);
+			match at.checked_sub(1) {
+				Some(at) => self.indexed_segment(index[at].offset),
+				None => None,
+			}
+		} else {
+			// `find` gives the first segment of the key, step to its last one
+			let mut segment = self.find(key);
+			while let Some(next) = segment.as_ref().and_then(|s| self.segment_after(s)) {
+				if next.first_key > key {
+					break;
+				}
+				segment = Some(next);
+			}
+			segment
+		};
+		let Some(segment) = segment.filter(|s| s.first_key <= key && key <= s.last_key) else {
+			return Ok(None);
+		};
+
+		let mut decoded = vec![];
+		decode_into_with_unescaping(&mut decoded, &segment)?;
+		let mut last = None;
+		for_each_record(&segment, &decoded, |k, format, record| {
+			if k == key {
+				last = Some((format, record));
+			}
+		})?;
+		Ok(last.map(|(format, record)| (format.to_owned(), BigEndian::read_u64(&record[..8]))))
+	}
+
 	pub(crate) fn segment_after<'s>(&'s self, segment: &Segment<'s>) -> Option<Segment<'s>> {
-		let data = &self.map;
 		let next = segment.segment_offset + segment.stride;
-		Segment::scan(&data[next..], next)
+		Segment::scan(&self.data()[next..], next)
+	}
+}
+
+/// A reason data could not be read
+#[derive(thiserror::Error, Debug)]
+pub enum ReadError {
+	/// A segment of a transaction file is damaged. `path` is
+	/// the file, if known
+	#[error("corrupt segment at offset {offset}{}: {reason}", in_file(.path))]
+	CorruptSegment {
+		path: Option<std::path::PathBuf>,
+		offset: usize,
+		reason: String,
+	},
+	/// An IO error from the OS
+	#[error("io error: {0}")]
+	IOError(#[from] std::io::Error),
+}
+
+fn in_file(path: &Option<std::path::PathBuf>) -> String {
+	path.as_ref()
+		.map(|p| format!(" of {}", p.display()))
+		.unwrap_or_default()
+}
+
+impl ReadError {
+	pub(crate) fn corrupt(segment: &Segment, reason: impl ToString) -> ReadError {
+		ReadError::CorruptSegment {
+			path: None,
+			offset: segment.segment_offset,
+			reason: reason.to_string(),
+		}
+	}
+}
+
+impl From<ReadError> for std::io::Error {
+	fn from(e: ReadError) -> std::io::Error {
+		match e {
+			ReadError::IOError(e) => e,
+			e => std::io::Error::new(std::io::ErrorKind::InvalidData, e),
+		}
+	}
+}
+
+/// Decompress the payload of `segment` into `into`, checking its checksum.
+/// The records in it are checked as they're read ([`key_entry`], [`for_each_record`])
+pub(crate) fn decode_into_with_unescaping(
+	into: &mut Vec<u8>,
+	segment: &Segment,
+) -> Result<(), ReadError> {
+	if !segment.checksum_matches() {
+		return Err(ReadError::corrupt(segment, "checksum mismatch"));
+	}
+
+	let corrupt = |e: std::io::Error| ReadError::corrupt(segment, e);
+	if segment.segment_version >= 0x0300 {
+		let mut encoded = vec![];
+		decompress_into(&mut encoded, segment).map_err(corrupt)?;
+		crate::payload_encoding::decode(&encoded, into)
+			.map_err(|e| ReadError::corrupt(segment, e))?;
+	} else {
+		decompress_into(into, segment).map_err(corrupt)?;
+	}
+	Ok(())
+}
+
+/// A key in a decoded payload, followed by its records
+pub(crate) struct KeyEntry<'d> {
+	pub(crate) key: &'d str,
+	pub(crate) format: &'d str,
+	/// where `key` is in the payload, `format` follows it
+	pub(crate) key_pos: usize,
+	/// where the key's records are in the payload
+	pub(crate) data: std::ops::Range<usize>,
+}
+
+/// Read the key that starts at `pos` of the decoded payload of `segment`,
+/// checking that it's in bounds
+pub(crate) fn key_entry<'d>(
+	segment: &Segment,
+	decoded: &'d [u8],
+	pos: usize,
+) -> Result<KeyEntry<'d>, ReadError> {
+	let invalid = |e: &str| ReadError::corrupt(segment, e);
+	let utf8 = |b: &'d [u8]| std::str::from_utf8(b).map_err(|_| invalid("key is not utf-8"));
+
+	// version 0 has four more bytes before the length of the data
+	let at = if segment.segment_version == 0x0000 {
+		pos + 16
+	} else {
+		pos + 12
+	};
+	let header = decoded
+		.get(pos..at)
+		.ok_or_else(|| invalid("truncated payload"))?;
+	let klen = BigEndian::read_u32(&header[0..4]) as usize;
+	let flen = BigEndian::read_u32(&header[4..8]) as usize;
+	let dlen = BigEndian::read_u32(&header[header.len() - 4..]) as usize;
+
+	let key = utf8(
+		decoded
+			.get(at..at + klen)
+			.ok_or_else(|| invalid("truncated key"))?,
+	)?;
+	let format = utf8(
+		decoded
+			.get(at + klen..at + klen + flen)
+			.ok_or_else(|| invalid("truncated format"))?,
+	)?;
+	let data = at + klen + flen..at + klen + flen + dlen;
+	if data.end > decoded.len() {
+		return Err(invalid("truncated data"));
+	}
+	if !crate::row_format::is_valid_format(format) {
+		return Err(invalid("invalid format string"));
+	}
+	Ok(KeyEntry {
+		key,
+		format,
+		key_pos: at,
+		data,
+	})
+}
+
+/// Call `f` with the key, format and data of each record in the
+/// decoded payload of `segment`
+pub(crate) fn for_each_record<'d>(
+	segment: &Segment,
+	decoded: &'d [u8],
+	mut f: impl FnMut(&'d str, &'d str, &'d [u8]),
+) -> Result<(), ReadError> {
+	let invalid = |e: &str| ReadError::corrupt(segment, e);
+
+	let mut pos = 0;
+	while pos != decoded.len() {
+		let KeyEntry {
+			key, format, data, ..
+		} = key_entry(segment, decoded, pos)?;
+		pos = data.end;
+		let mut data = &decoded[data];
+
+		let size = crate::row_format::row_format_size(format);
+		while !data.is_empty() {
+			let len = if let Some(size) = size {
+				size
+			} else {
+				let (len, tail) = unsigned_varint::decode::usize(data)
+					.map_err(|_| invalid("invalid record length"))?;
+				data = tail;
+				len
+			} + crate::TIMESTAMP_SIZE;
+			let record = data
+				.get(0..len)
+				.ok_or_else(|| invalid("truncated record"))?;
+			f(key, format, record);
+			data = &data[len..];
+		}
 	}
+	Ok(())
 }
 
-pub(crate) fn decode_into_with_unescaping(into: &mut Vec<u8>, from: &[u8]) {
+/// unescape and decompress the payload of `segment`
+fn decompress_into(into: &mut Vec<u8>, segment: &Segment) -> std::io::Result<()> {
+	let from = segment.payload;
 	let mut segmented: smallvec::SmallVec<[_; 4]> = smallvec::smallvec![];
 	{
 		let mut start = 0;
@@ -320,8 +660,14 @@ pub(crate) fn decode_into_with_unescaping(into: &mut Vec<u8>, from: &[u8]) {
 		}
 	}
 
-	let mut decoder = lz4::Decoder::new(reader.expect("empty segment")).expect("lz4 decoding");
-	decoder.read_to_end(into).expect("lz4 decoding 2");
+	let reader = reader.expect("empty segment");
+	let mut decoder: Box<dyn Read> = match segment.codec {
+		crate::Codec::None => reader,
+		crate::Codec::Lz4 => Box::new(lz4::Decoder::new(reader)?),
+		crate::Codec::Zstd(_) => Box::new(zstd::stream::read::Decoder::new(reader)?),
+	};
+	decoder.read_to_end(into)?;
+	Ok(())
 }
 
 #[derive(Debug, Clone)]
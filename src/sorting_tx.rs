@@ -0,0 +1,109 @@
+//! Add records in any order by sorting them before they're written.
+
+use crate::external_sort::{ExternalSort, SortRow, SORT_MEMORY};
+use crate::{CreateTx, WriteFailure};
+use std::path::Path;
+
+/// A transaction that accepts records in any order.
+///
+/// Records are sorted in memory, and in temporary files once they
+/// use more than the memory budget, then added to the [`CreateTx`]
+/// when the transaction is committed. Of the records with the same
+/// key and timestamp, the one that was added last is kept.
+///
+/// If the transaction is strict ([`CreateTx::set_strict`]), formats are
+/// checked against the key's format in sorted order, so a format change
+/// is an error of [`SortingCreateTx::commit`].
+///
+/// ```no_run
+/// # let tx = sonnerie::CreateTx::new(std::path::Path::new("")).unwrap();
+/// let mut transaction = sonnerie::SortingCreateTx::new(tx);
+/// transaction.add_record(
+///    "key b",
+///    "2010-01-01T00:00:01".parse().unwrap(),
+///    sonnerie::record(2u32),
+///  ).unwrap();
+/// transaction.add_record(
+///    "key a",
+///    "2010-01-01T00:00:01".parse().unwrap(),
+///    sonnerie::record(1u32),
+///  ).unwrap();
+/// transaction.commit().unwrap();
+/// ```
+pub struct SortingCreateTx {
+	tx: CreateTx,
+	sort: ExternalSort,
+}
+
+impl SortingCreateTx {
+	/// Sort the records of `tx`, with temporary files in the database's directory
+	pub fn new(tx: CreateTx) -> SortingCreateTx {
+		let sort = ExternalSort::new(tx.dir(), SORT_MEMORY);
+		SortingCreateTx { tx, sort }
+	}
+
+	/// Write records to a temporary file once they use about this many bytes
+	/// of memory. The default is 256 MiB.
+	pub fn set_memory_budget(&mut self, bytes: usize) {
+		self.sort.set_memory(bytes);
+	}
+
+	/// Put temporary files in `dir` instead of the database's directory
+	pub fn set_temp_dir(&mut self, dir: &Path) {
+		self.sort.set_dir(dir);
+	}
+
+	/// Add a record like [`CreateTx::add_record`], but in any order
+	pub fn add_record(
+		&mut self,
+		key: &str,
+		timestamp: chrono::NaiveDateTime,
+		values: impl crate::RecordBuilder,
+	) -> Result<(), WriteFailure> {
+		let mut format = compact_str::CompactString::default();
+		values.format_str(&mut format);
+		let timestamp = timestamp
+			.and_utc()
+			.timestamp_nanos_opt()
+			.ok_or(WriteFailure::UnableToParseTimestamp)? as crate::Timestamp;
+		let mut data = timestamp.to_be_bytes().to_vec();
+		values.store(&mut data);
+		self.add_record_raw(key, &format, &data)
+	}
+
+	/// Add a record like [`CreateTx::add_record_raw`], but in any order
+	pub fn add_record_raw(
+		&mut self,
+		key: &str,
+		format: &str,
+		data: &[u8],
+	) -> Result<(), WriteFailure> {
+		crate::write::check_record_length(format, data)?;
+		// a strict transaction checks formats when the records are added
+		// to it in order, while the rows are sorted by key
+		self.tx.check_schema(key, format)?;
+		self.sort.push(SortRow {
+			key: key.to_owned(),
+			format: format.to_owned(),
+			data: data.to_owned(),
+		})?;
+		Ok(())
+	}
+
+	/// Sort the records, add them to the transaction and commit it
+	pub fn commit(self) -> Result<(), WriteFailure> {
+		let mut tx = self.tx;
+		for row in self.sort.finish()? {
+			let row = row?;
+			tx.add_record_raw(&row.key, &row.format, &row.data)?;
+		}
+		tx.commit()?;
+		Ok(())
+	}
+}
+
+impl crate::RecordSink for SortingCreateTx {
+	fn add_record_raw(&mut self, key: &str, format: &str, data: &[u8]) -> Result<(), WriteFailure> {
+		SortingCreateTx::add_record_raw(self, key, format, data)
+	}
+}
@@ -3,6 +3,8 @@ use crate::formatted::*;
 use crate::segment_reader::*;
 use crate::write::Writer;
 use crate::CreateTx;
+use crate::Decimal;
+use crate::NullableDecimal;
 use crate::Reader;
 
 use crate::record;
@@ -255,6 +257,390 @@ fn basic_huge() {
 	assert_eq!(i.count(), 901000);
 }
 
+#[test]
+fn time_range_skips_segments() {
+	use std::ops::Bound;
+	let t = tempfile::TempDir::new().unwrap();
+
+	{
+		let w = std::fs::File::create(t.path().join("main")).unwrap();
+		let w = BufWriter::new(w);
+
+		let mut w = Writer::new(w);
+		write_many(&mut w, "a", 0..100000);
+		write_many(&mut w, "b", 100000..200000);
+		write_many(&mut w, "c", 200000..300000);
+		w.finish().unwrap();
+	}
+
+	let w = std::fs::File::open(t.path().join("main")).unwrap();
+	let o = Reader::new(w).unwrap().left().unwrap();
+	let first = o.segments.first().unwrap();
+	assert_eq!(first.first_key, "a");
+	assert_eq!(first.min_timestamp, 0);
+	assert_eq!(first.max_timestamp, 99999);
+
+	let s = o.get_filter_range(
+		None,
+		"",
+		crate::disassemble_range_bound::<&str>(..).into(),
+		(Bound::Included(150000), Bound::Excluded(250000)),
+		None,
+	);
+	// segment "a" is never decoded
+	assert_eq!(s.segment.as_ref().unwrap().first_key, "b");
+	assert_eq!(s.into_iter().count(), 100000);
+
+	let s = o.get_filter_range(
+		None,
+		"",
+		crate::disassemble_range_bound("a"..="a").into(),
+		(Bound::Included(150000), Bound::Unbounded),
+		None,
+	);
+	assert!(s.segment.is_none());
+
+	let db = DatabaseReader::new(t.path()).unwrap();
+	let mut records = db.get_range(..).with_time_range(99999..=100000).into_iter();
+	let r = records.next().unwrap();
+	assert_eq!((r.key(), r.timestamp_nanos()), ("a", 99999));
+	let r = records.next().unwrap();
+	assert_eq!((r.key(), r.timestamp_nanos()), ("b", 100000));
+	assert!(records.next().is_none());
+}
+
+#[test]
+fn key_filters() {
+	let t = tempfile::TempDir::new().unwrap();
+	std::fs::File::create(t.path().join("main")).unwrap();
+
+	// each transaction has every tenth key
+	for tx_n in 0..10 {
+		let mut tx = CreateTx::new(t.path()).unwrap();
+		for k in (tx_n..1000).step_by(10) {
+			let ts = chrono::DateTime::from_timestamp(tx_n as i64, 0).unwrap();
+			tx.add_record(&format!("key{:04}", k), ts.naive_utc(), record(k as u32))
+				.unwrap();
+		}
+		tx.commit().unwrap();
+	}
+
+	let db = DatabaseReader::new(t.path()).unwrap();
+	assert_eq!(db.txes.len(), 10);
+	let mut false_positives = 0;
+	for k in 0..1000 {
+		let key = format!("key{:04}", k);
+		for (n, tx) in db.txes.iter().enumerate() {
+			if n == k % 10 {
+				assert!(tx.2.segments.may_contain(&key));
+			} else if tx.2.segments.may_contain(&key) {
+				false_positives += 1;
+			}
+		}
+		let records: Vec<_> = db.get(&key).into_iter().collect();
+		assert_eq!(records.len(), 1);
+		assert_eq!(records[0].get::<u32>(0), k as u32);
+	}
+	assert!(false_positives < 9000 / 20, "{}", false_positives);
+	assert_eq!(db.get("key").into_iter().count(), 0);
+}
+
+#[test]
+fn codecs() {
+	use crate::Codec;
+	let t = tempfile::TempDir::new().unwrap();
+	std::fs::File::create(t.path().join("main")).unwrap();
+
+	for codec in [Codec::None, Codec::Lz4, Codec::Zstd(3)] {
+		let mut tx = CreateTx::new_with_codec(t.path(), codec).unwrap();
+		let key = format!("{:?}", codec);
+		for n in 0..10000u32 {
+			tx.add_record(
+				&key,
+				chrono::DateTime::from_timestamp(n as i64, 0)
+					.unwrap()
+					.naive_utc(),
+				record(n).add(format!("value {}", n % 7).as_str()),
+			)
+			.unwrap();
+		}
+		tx.commit().unwrap();
+	}
+
+	let db = DatabaseReader::new(t.path()).unwrap();
+	for key in ["Lz4", "None", "Zstd(3)"] {
+		let mut n = 0u32;
+		for r in db.get(key) {
+			assert_eq!(r.timestamp_nanos(), n as u64 * 1_000_000_000);
+			assert_eq!(r.get::<u32>(0), n);
+			assert_eq!(r.get::<&str>(1), format!("value {}", n % 7));
+			n += 1;
+		}
+		assert_eq!(n, 10000);
+	}
+
+	assert_eq!("zstd:7".parse::<Codec>().unwrap(), Codec::Zstd(7));
+	assert_eq!("zstd".parse::<Codec>().unwrap(), Codec::Zstd(3));
+	assert!("zstd:0".parse::<Codec>().is_err());
+	assert!("zstd:23".parse::<Codec>().is_err());
+	assert_eq!("none".parse::<Codec>().unwrap(), Codec::None);
+	assert!("gzip".parse::<Codec>().is_err());
+}
+
+#[test]
+fn verify_checksums() {
+	let t = tempfile::TempDir::new().unwrap();
+
+	{
+		let w = std::fs::File::create(t.path().join("main")).unwrap();
+		let mut w = Writer::new(BufWriter::new(w));
+		write_many(&mut w, "a", 0..100000);
+		write_many(&mut w, "b", 0..100000);
+		w.finish().unwrap();
+	}
+
+	let db = DatabaseReader::new(t.path()).unwrap();
+	assert!(db.verify().unwrap().is_empty());
+
+	// flip a bit in the payload of the second segment
+	let (offset, first_key, last_key, byte) = {
+		let segments = &db.txes[0].2.segments;
+		let second = segments.segment_after(&segments.first().unwrap()).unwrap();
+		// the payload is at the end of the segment
+		let byte = second.segment_offset + second.stride - second.payload.len();
+		(
+			second.segment_offset,
+			second.first_key.to_owned(),
+			second.last_key.to_owned(),
+			byte + 100,
+		)
+	};
+	drop(db);
+	let mut data = std::fs::read(t.path().join("main")).unwrap();
+	data[byte] ^= 1;
+	std::fs::write(t.path().join("main"), data).unwrap();
+
+	let db = DatabaseReader::new(t.path()).unwrap();
+	let corrupt = db.verify().unwrap();
+	assert_eq!(corrupt.len(), 1);
+	assert_eq!(corrupt[0].path, t.path().join("main"));
+	assert_eq!(corrupt[0].offset, offset);
+	assert_eq!(corrupt[0].first_key, first_key);
+	assert_eq!(corrupt[0].last_key, last_key);
+	assert_eq!(corrupt[0].error, "checksum mismatch");
+}
+
+#[test]
+fn read_errors() {
+	let t = tempfile::TempDir::new().unwrap();
+
+	{
+		let w = std::fs::File::create(t.path().join("main")).unwrap();
+		let mut w = Writer::new(BufWriter::new(w));
+		for key in ["a", "b", "c", "d"] {
+			write_many(&mut w, key, 0..100000);
+		}
+		w.finish().unwrap();
+	}
+
+	// damage the payload of the second segment
+	let mut data = std::fs::read(t.path().join("main")).unwrap();
+	let (offset, lost) = {
+		let db = DatabaseReader::new(t.path()).unwrap();
+		let segments = &db.txes[0].2.segments;
+		let second = segments.segment_after(&segments.first().unwrap()).unwrap();
+		data[second.segment_offset + second.stride - 10] ^= 1;
+
+		let mut plain = vec![];
+		crate::segment_reader::decode_into_with_unescaping(&mut plain, &second).unwrap();
+		let mut lost = 0;
+		crate::segment_reader::for_each_record(&second, &plain, |_, _, _| lost += 1).unwrap();
+		(second.segment_offset, lost)
+	};
+	std::fs::write(t.path().join("main"), data).unwrap();
+
+	let db = DatabaseReader::new(t.path()).unwrap();
+	let mut records = 0;
+	let mut errors = vec![];
+	for r in db.get_range(..).into_try_iter() {
+		match r {
+			Ok(_) => records += 1,
+			Err(e) => errors.push(e),
+		}
+	}
+	assert_eq!(records, 400000 - lost);
+	assert_eq!(errors.len(), 1);
+	match &errors[0] {
+		crate::ReadError::CorruptSegment {
+			path,
+			offset: o,
+			reason,
+		} => {
+			assert_eq!(path.as_deref(), Some(&*t.path().join("main")));
+			assert_eq!(*o, offset);
+			assert_eq!(reason, "checksum mismatch");
+		}
+		e => panic!("{}", e),
+	}
+
+	let panicked = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+		db.get_range(..).into_iter().count()
+	}));
+	assert!(panicked.is_err());
+
+	// and in parallel
+	use rayon::prelude::*;
+	let (ok, err): (Vec<_>, Vec<_>) = db
+		.get_range(..)
+		.into_par_try_iter()
+		.partition(|r| r.is_ok());
+	assert_eq!((ok.len(), err.len()), (400000 - lost, 1));
+
+	// and by key
+	#[cfg(feature = "by-key")]
+	{
+		let mut records = 0;
+		let mut errors = 0;
+		for key in db.get_range_keys(..).into_try_iter() {
+			match key {
+				Ok(key) => records += key.count(),
+				Err(_) => errors += 1,
+			}
+		}
+		assert_eq!((records, errors), (400000 - lost, 1));
+
+		let counts: Vec<_> = db
+			.get_range_keys(..)
+			.into_par_try_iter()
+			.map(|key| key.map(|key| key.count()))
+			.collect();
+		let records: usize = counts.iter().filter_map(|c| c.as_ref().ok()).sum();
+		let errors = counts.iter().filter(|c| c.is_err()).count();
+		assert_eq!((records, errors), (400000 - lost, 1));
+	}
+}
+
+#[test]
+fn read_damaged_header() {
+	let t = tempfile::TempDir::new().unwrap();
+
+	{
+		let w = std::fs::File::create(t.path().join("main")).unwrap();
+		let mut w = Writer::new(BufWriter::new(w));
+		for key in ["a", "b", "c", "d"] {
+			write_many(&mut w, key, 0..100000);
+		}
+		w.finish().unwrap();
+	}
+
+	// make the first key in the header of the second segment not utf-8
+	let mut data = std::fs::read(t.path().join("main")).unwrap();
+	let (offset, lost) = {
+		let db = DatabaseReader::new(t.path()).unwrap();
+		let segments = &db.txes[0].2.segments;
+		let second = segments.segment_after(&segments.first().unwrap()).unwrap();
+		// the keys are just before the payload
+		let payload = second.segment_offset + second.stride - second.payload.len();
+		data[payload - second.last_key.len() - second.first_key.len()] = 0xff;
+
+		let mut plain = vec![];
+		crate::segment_reader::decode_into_with_unescaping(&mut plain, &second).unwrap();
+		let mut lost = 0;
+		crate::segment_reader::for_each_record(&second, &plain, |_, _, _| lost += 1).unwrap();
+		(second.segment_offset, lost)
+	};
+	std::fs::write(t.path().join("main"), data).unwrap();
+
+	let db = DatabaseReader::new(t.path()).unwrap();
+	let mut records = 0;
+	let mut errors = vec![];
+	for r in db.get_range(..).into_try_iter() {
+		match r {
+			Ok(_) => records += 1,
+			Err(e) => errors.push(e),
+		}
+	}
+	// the segments after the damaged one are still read
+	assert_eq!(records, 400000 - lost);
+	assert_eq!(errors.len(), 1);
+	match &errors[0] {
+		crate::ReadError::CorruptSegment {
+			offset: o, reason, ..
+		} => {
+			assert_eq!(*o, offset);
+			assert!(reason.starts_with("segment key is not utf-8"), "{}", reason);
+		}
+		e => panic!("{}", e),
+	}
+
+	let corrupt = db.verify().unwrap();
+	assert_eq!(corrupt.len(), 1);
+	assert_eq!(corrupt[0].offset, offset);
+	assert!(corrupt[0].error.starts_with("segment key is not utf-8"));
+}
+
+#[test]
+fn salvage_damaged() {
+	let t = tempfile::TempDir::new().unwrap();
+	let damaged = t.path().join("damaged");
+
+	{
+		let w = std::fs::File::create(&damaged).unwrap();
+		let mut w = Writer::new(BufWriter::new(w));
+		for key in ["a", "b", "c", "d", "e", "f"] {
+			write_many(&mut w, key, 0..100000);
+		}
+		w.finish().unwrap();
+	}
+
+	let mut data = std::fs::read(&damaged).unwrap();
+	let (second, fourth) = {
+		let segments =
+			crate::segment_reader::SegmentReader::map(&mut std::fs::File::open(&damaged).unwrap())
+				.unwrap();
+		let first = segments.first().unwrap();
+		let second = segments.segment_after(&first).unwrap();
+		let third = segments.segment_after(&second).unwrap();
+		let fourth = segments.segment_after(&third).unwrap();
+
+		// damage the payload of the second segment and the header of the fourth
+		data[second.segment_offset + second.stride - 10] ^= 1;
+		data[fourth.segment_offset] = b'#';
+		(
+			(second.segment_offset, second.first_key.to_owned()),
+			(
+				fourth.segment_offset,
+				fourth.stride,
+				third.last_key.to_owned(),
+			),
+		)
+	};
+	std::fs::write(&damaged, data).unwrap();
+
+	let db = t.path().join("db");
+	std::fs::create_dir(&db).unwrap();
+	std::fs::File::create(db.join("main")).unwrap();
+	let mut tx = CreateTx::new(&db).unwrap();
+	let salvaged = crate::salvage(&damaged, &mut tx).unwrap();
+	tx.commit().unwrap();
+
+	assert_eq!(salvaged.lost.len(), 2);
+	assert_eq!(salvaged.lost[0].offset, second.0);
+	assert_eq!(salvaged.lost[0].first_key, second.1);
+	assert_eq!(salvaged.lost[0].error, "checksum mismatch");
+	assert_eq!(salvaged.lost[1].offset, fourth.0);
+	assert_eq!(salvaged.lost[1].first_key, fourth.2);
+	assert_eq!(
+		salvaged.lost[1].error,
+		format!("{} unreadable bytes", fourth.1)
+	);
+
+	let db = DatabaseReader::new(&db).unwrap();
+	assert_eq!(db.get_range(..).into_iter().count(), salvaged.records);
+	assert!(salvaged.records < 600000);
+	assert!(salvaged.records > 300000);
+}
+
 #[test]
 fn range_before() {
 	let t = tempfile::TempDir::new().unwrap();
@@ -358,49 +744,921 @@ fn multicolumn_select() {
 	let s = o.get_range("a".."z");
 	let mut i = s.into_iter();
 
-	let mut out = vec![];
-	print_record(
-		&i.next().expect("row1"),
-		&mut out,
-		PrintTimestamp::FormatString("%F_%T"),
-		PrintRecordFormat::Yes,
-		&choice_string::parse("1").unwrap(),
+	let mut out = vec![];
+	print_record(
+		&i.next().expect("row1"),
+		&mut out,
+		PrintTimestamp::FormatString("%F_%T"),
+		PrintRecordFormat::Yes,
+		&choice_string::parse("1").unwrap(),
+	)
+	.expect("formatting");
+	std::io::Write::write_all(&mut out, b"\n").unwrap();
+	print_record(
+		&i.next().expect("row2"),
+		&mut out,
+		PrintTimestamp::FormatString("%F_%T"),
+		PrintRecordFormat::Yes,
+		&choice_string::parse("2").unwrap(),
+	)
+	.expect("formatting");
+	assert!(i.next().is_none());
+
+	assert_eq!(
+		&String::from_utf8(out).unwrap(),
+		"\
+			a\t2010-01-01_00:00:00\tuu\t10\n\
+			a\t2010-01-02_00:00:00\tuu\t30\
+		"
+	);
+}
+
+#[test]
+#[should_panic]
+fn violate_time_order() {
+	let t = tempfile::TempDir::new().unwrap();
+
+	{
+		let mut tx = CreateTx::new(t.path()).expect("creating tx");
+		let data = "a 2010-01-01_00:00:00 10\n\
+			a 2010-01-01_00:00:00 20\n";
+
+		add_from_stream(&mut tx, "u", &mut std::io::Cursor::new(data), Some("%F_%T"))
+			.expect("writing");
+		tx.commit_to(&t.path().join("main")).expect("committed");
+	}
+}
+
+#[test]
+fn small_and_bytes_columns() {
+	let t = tempfile::TempDir::new().unwrap();
+
+	let data = "\
+		a\t2010-01-01_00:00:00\tbcChHx\ttrue -128 255 -300 65535 00ff10\n\
+		b\t2010-01-02_00:00:00\txb\t0A 0\
+		";
+	{
+		let mut tx = CreateTx::new(t.path()).expect("creating tx");
+
+		add_from_stream_with_fmt(&mut tx, &mut std::io::Cursor::new(data), Some("%F_%T"))
+			.expect("writing");
+		tx.add_record(
+			"c",
+			"2010-01-01T00:00:00".parse().unwrap(),
+			record(false)
+				.add(7i8)
+				.add(8u8)
+				.add(-9i16)
+				.add(10u16)
+				.add(&b"\x01\x02"[..]),
+		)
+		.unwrap();
+		tx.commit_to(&t.path().join("main")).expect("committed");
+	}
+
+	let w = std::fs::File::open(t.path().join("main")).unwrap();
+	let o = Reader::new(w).unwrap().left().unwrap();
+	let records: Vec<_> = o.get_range(..).collect();
+	assert!(format!("{:?}", records[2]).ends_with(", false, 7, 8, -9, 10, [1, 2] }"));
+
+	let mut out = vec![];
+	for r in &records {
+		print_record(
+			r,
+			&mut out,
+			PrintTimestamp::FormatString("%F_%T"),
+			PrintRecordFormat::Yes,
+			&choice_string::Selection::All,
+		)
+		.expect("formatting");
+		std::io::Write::write_all(&mut out, b"\n").unwrap();
+	}
+	assert_eq!(
+		String::from_utf8(out).unwrap(),
+		"\
+		a\t2010-01-01_00:00:00\tbcChHx\ttrue -128 255 -300 65535 00ff10\n\
+		b\t2010-01-02_00:00:00\txb\t0a false\n\
+		c\t2010-01-01_00:00:00\tbcChHx\tfalse 7 8 -9 10 0102\n\
+		"
+	);
+
+	let a = &records[0];
+	assert!(a.get::<bool>(0));
+	assert_eq!(a.get::<i8>(1), -128);
+	assert_eq!(a.get::<i64>(1), -128);
+	assert_eq!(a.get::<u8>(2), 255);
+	assert_eq!(a.get::<u32>(2), 255);
+	assert_eq!(a.get::<i16>(3), -300);
+	assert_eq!(a.get::<i32>(3), -300);
+	assert_eq!(a.get::<u16>(4), 65535);
+	assert_eq!(a.get::<u64>(4), 65535);
+	assert_eq!(a.get::<&[u8]>(5), b"\x00\xff\x10");
+	assert!(a.get_checked::<i8>(2).is_err());
+	assert!(a.get_checked::<u16>(3).is_err());
+	assert_eq!(records[1].get::<Vec<u8>>(0), b"\n");
+	assert_eq!(records[2].get::<&[u8]>(5), b"\x01\x02");
+}
+
+#[test]
+fn nullable_columns() {
+	let t = tempfile::TempDir::new().unwrap();
+
+	let data = "\
+		a\t2010-01-01_00:00:00\tnfnsu\t- \\- 1\n\
+		a\t2010-01-02_00:00:00\tnfnsu\t1.5 - 2\n\
+		b\t2010-01-01_00:00:00\tnx\t-\
+		";
+	{
+		let mut tx = CreateTx::new(t.path()).expect("creating tx");
+
+		add_from_stream_with_fmt(&mut tx, &mut std::io::Cursor::new(data), Some("%F_%T"))
+			.expect("writing");
+		tx.add_record(
+			"c",
+			"2010-01-01T00:00:00".parse().unwrap(),
+			record(Some(-1i8)).add(None::<&str>).add(3u32),
+		)
+		.unwrap();
+		tx.commit_to(&t.path().join("main")).expect("committed");
+	}
+
+	let w = std::fs::File::open(t.path().join("main")).unwrap();
+	let o = Reader::new(w).unwrap().left().unwrap();
+	let records: Vec<_> = o.get_range(..).collect();
+
+	let mut out = vec![];
+	for r in &records {
+		print_record(
+			r,
+			&mut out,
+			PrintTimestamp::FormatString("%F_%T"),
+			PrintRecordFormat::Yes,
+			&choice_string::Selection::All,
+		)
+		.expect("formatting");
+		std::io::Write::write_all(&mut out, b"\n").unwrap();
+	}
+	assert_eq!(
+		String::from_utf8(out).unwrap(),
+		"\
+		a\t2010-01-01_00:00:00\tnfnsu\t- \\- 1\n\
+		a\t2010-01-02_00:00:00\tnfnsu\t1.50000000000000000 - 2\n\
+		b\t2010-01-01_00:00:00\tnx\t-\n\
+		c\t2010-01-01_00:00:00\tncnsu\t-1 - 3\n\
+		"
+	);
+
+	assert_eq!(records[0].get::<Option<f32>>(0), None);
+	assert_eq!(records[0].get::<Option<&str>>(1), Some("-"));
+	assert_eq!(records[0].get::<u32>(2), 1);
+	assert_eq!(records[0].get::<Option<u32>>(2), Some(1));
+	assert!(records[0].get_checked::<f32>(0).is_err());
+	assert_eq!(records[1].get::<Option<f64>>(0), Some(1.5));
+	assert_eq!(records[1].get::<Option<String>>(1), None);
+	assert_eq!(records[2].get::<Option<&[u8]>>(0), None);
+	assert_eq!(records[3].get::<Option<i8>>(0), Some(-1));
+	assert!(format!("{:?}", records[3]).ends_with(", -1, null, 3 }"));
+}
+
+#[test]
+fn decimal_columns() {
+	let t = tempfile::TempDir::new().unwrap();
+
+	let data = "\
+		a\t2010-01-01_00:00:00\td4\t12.5\n\
+		a\t2010-01-02_00:00:00\td4\t-0.0001\n\
+		b\t2010-01-01_00:00:00\tnd2u\t- 1\n\
+		b\t2010-01-02_00:00:00\tnd2u\t3 2\
+		";
+	{
+		let mut tx = CreateTx::new(t.path()).expect("creating tx");
+
+		add_from_stream_with_fmt(&mut tx, &mut std::io::Cursor::new(data), Some("%F_%T"))
+			.expect("writing");
+		tx.add_record(
+			"c",
+			"2010-01-01T00:00:00".parse().unwrap(),
+			record(Decimal::new(-1234, 3)).add(NullableDecimal::from(Decimal::new(5, 0))),
+		)
+		.unwrap();
+		tx.commit_to(&t.path().join("main")).expect("committed");
+	}
+
+	let w = std::fs::File::open(t.path().join("main")).unwrap();
+	let o = Reader::new(w).unwrap().left().unwrap();
+	let records: Vec<_> = o.get_range(..).collect();
+
+	let mut out = vec![];
+	for r in &records {
+		print_record(
+			r,
+			&mut out,
+			PrintTimestamp::FormatString("%F_%T"),
+			PrintRecordFormat::Yes,
+			&choice_string::Selection::All,
+		)
+		.expect("formatting");
+		std::io::Write::write_all(&mut out, b"\n").unwrap();
+	}
+	assert_eq!(
+		String::from_utf8(out).unwrap(),
+		"\
+		a\t2010-01-01_00:00:00\td4\t12.5000\n\
+		a\t2010-01-02_00:00:00\td4\t-0.0001\n\
+		b\t2010-01-01_00:00:00\tnd2u\t- 1\n\
+		b\t2010-01-02_00:00:00\tnd2u\t3.00 2\n\
+		c\t2010-01-01_00:00:00\td3nd0\t-1.234 5\n\
+		"
+	);
+
+	assert_eq!(records[0].get::<Decimal>(0), Decimal::new(125000, 4));
+	assert_eq!(records[1].get::<Decimal>(0).to_f64(), -0.0001);
+	assert!(records[0].get_checked::<f64>(0).is_err());
+	assert_eq!(records[2].get::<Option<Decimal>>(0), None);
+	assert_eq!(
+		records[3].get::<Option<Decimal>>(0),
+		Some(Decimal::new(300, 2))
+	);
+	assert_eq!(records[3].get::<u32>(1), 2);
+	assert!(format!("{:?}", records[4]).ends_with(", -1.234, 5 }"));
+
+	// too many decimal places are an error, never rounded
+	assert!(Decimal::parse_with_scale("1.005", 2).is_err());
+	assert_eq!("-1.50".parse(), Ok(Decimal::new(-150, 2)));
+}
+
+#[test]
+fn nullable_decimal_column() {
+	let t = tempfile::TempDir::new().unwrap();
+	{
+		let mut tx = CreateTx::new(t.path()).expect("creating tx");
+		let values = [
+			NullableDecimal::from(Decimal::new(125, 2)),
+			NullableDecimal::null(2),
+			NullableDecimal::from(Decimal::new(-5, 2)),
+			NullableDecimal::null(2),
+		];
+		for (i, value) in values.into_iter().enumerate() {
+			let ts = chrono::DateTime::from_timestamp(i as i64, 0).unwrap();
+			tx.add_record("a", ts.naive_utc(), record(value)).unwrap();
+		}
+		tx.commit_to(&t.path().join("main")).expect("committed");
+	}
+
+	let db = DatabaseReader::new(t.path()).unwrap();
+	let records: Vec<_> = db.get("a").into_iter().collect();
+	assert!(records.iter().all(|r| r.format() == "nd2"));
+	let values: Vec<_> = records
+		.iter()
+		.map(|r| r.get::<Option<Decimal>>(0))
+		.collect();
+	assert_eq!(
+		values,
+		[
+			Some(Decimal::new(125, 2)),
+			None,
+			Some(Decimal::new(-5, 2)),
+			None
+		]
+	);
+}
+
+#[test]
+fn invalid_formats() {
+	let t = tempfile::TempDir::new().unwrap();
+	let ts = "2010-01-01T00:00:00".parse().unwrap();
+	{
+		let mut tx = CreateTx::new(t.path()).expect("creating tx");
+		tx.add_record("a", ts, record(1u32)).unwrap();
+		// formats the reader would reject
+		assert!(matches!(
+			tx.add_record_raw("b", "u5", &[0; 12]),
+			Err(crate::WriteFailure::InvalidFormat(f)) if f == "u5"
+		));
+		let too_precise = Decimal {
+			mantissa: 1,
+			scale: 25,
+		};
+		assert!(matches!(
+			tx.add_record("b", ts, record(too_precise)),
+			Err(crate::WriteFailure::InvalidFormat(f)) if f == "d25"
+		));
+		tx.add_record("c", ts, record(Decimal::new(1, 18))).unwrap();
+		tx.commit_to(&t.path().join("main")).expect("committed");
+	}
+
+	let db = DatabaseReader::new(t.path()).unwrap();
+	let keys: Vec<String> = db
+		.get_range(..)
+		.into_try_iter()
+		.map(|r| r.unwrap().key().to_owned())
+		.collect();
+	assert_eq!(keys, ["a", "c"]);
+	assert!(std::panic::catch_unwind(|| Decimal::new(1, 19)).is_err());
+}
+
+#[test]
+fn schema_columns() {
+	let t = tempfile::TempDir::new().unwrap();
+	std::fs::File::create(t.path().join("main")).unwrap();
+	std::fs::write(
+		t.path().join("schema"),
+		"sensor.%\ttemperature:F humidity:nF count:u\n",
+	)
+	.unwrap();
+
+	let schema = crate::Schema::load(t.path()).unwrap();
+	let ts = "2010-01-01T00:00:00".parse().unwrap();
+	{
+		let mut tx = CreateTx::new(t.path()).unwrap();
+		tx.set_schema(schema.clone());
+		tx.add_record("other", ts, record(1u8)).unwrap();
+		tx.add_record("sensor.a", ts, record(20.5f64).add(None::<f64>).add(3u32))
+			.unwrap();
+		match tx.add_record("sensor.b", ts, record(20.5f64).add(3u32)) {
+			Err(crate::WriteFailure::SchemaViolation {
+				key,
+				format,
+				expected,
+			}) => {
+				assert_eq!(key, "sensor.b");
+				assert_eq!(format, "Fu");
+				assert_eq!(expected, "FnFu");
+			}
+			e => panic!("expected a schema violation, got {:?}", e),
+		}
+		assert!(tx.add_record_raw("sensor.c", "Fu", &[0; 20]).is_err());
+		tx.commit().unwrap();
+	}
+
+	let db = DatabaseReader::new(t.path()).unwrap();
+	let schema = db.schema().unwrap();
+	assert_eq!(schema.for_key("sensor.a").unwrap().format(), "FnFu");
+	let records: Vec<_> = db.get_range(..).into_iter().collect();
+	assert_eq!(records.len(), 2);
+	assert!(records[0]
+		.get_checked_by_name::<u8>(schema, "count")
+		.is_err());
+	let sensor = &records[1];
+	assert_eq!(sensor.get_by_name::<f64>(schema, "temperature"), 20.5);
+	assert_eq!(sensor.get_by_name::<Option<f64>>(schema, "humidity"), None);
+	assert_eq!(sensor.get_by_name::<u32>(schema, "count"), 3);
+	assert!(sensor.get_checked_by_name::<u32>(schema, "bid").is_err());
+
+	// a bad schema file only fails what uses the schema
+	std::fs::write(t.path().join("schema"), "sensor.% temperature\n").unwrap();
+	let db = DatabaseReader::new(t.path()).unwrap();
+	assert_eq!(db.get_range(..).into_iter().count(), 2);
+	assert!(db.schema().is_err());
+}
+
+#[test]
+fn strict_formats() {
+	let t = tempfile::TempDir::new().unwrap();
+	std::fs::File::create(t.path().join("main")).unwrap();
+	let ts = |s: i64| chrono::DateTime::from_timestamp(s, 0).unwrap().naive_utc();
+
+	let mut tx = CreateTx::new(t.path()).unwrap();
+	tx.add_record("a", ts(1), record(1.5f32)).unwrap();
+	tx.add_record("a", ts(2), record(1u32)).unwrap();
+	tx.commit().unwrap();
+
+	let db = std::sync::Arc::new(DatabaseReader::new(t.path()).unwrap());
+	let mut tx = CreateTx::new(t.path()).unwrap();
+	tx.set_strict(db.clone());
+	// "a" has the format of its most recent record
+	tx.add_record("a", ts(3), record(2u32)).unwrap();
+	match tx.add_record("a", ts(4), record(2.5f32)) {
+		Err(crate::WriteFailure::FormatChanged {
+			key,
+			format,
+			existing,
+		}) => {
+			assert_eq!(key, "a");
+			assert_eq!(format, "f");
+			assert_eq!(existing, "u");
+		}
+		e => panic!("expected a format change, got {:?}", e),
+	}
+	// a new key has the format of its first record
+	tx.add_record("b", ts(1), record("x")).unwrap();
+	tx.add_record("b", ts(2), record("y")).unwrap();
+	assert!(tx.add_record_raw("b", "u", &[0; 12]).is_err());
+	tx.commit().unwrap();
+
+	// without strict mode, formats can change
+	let mut tx = CreateTx::new(t.path()).unwrap();
+	tx.add_record("a", ts(5), record(2.5f32)).unwrap();
+	tx.commit().unwrap();
+
+	// the format is from the last segment of the key in the newest
+	// transaction that has it, even if another has later records
+	let mut tx = CreateTx::new(t.path()).unwrap();
+	for i in 0..100_000 {
+		tx.add_record("c", ts(i), record(i as u32)).unwrap();
+	}
+	tx.commit().unwrap();
+	let mut tx = CreateTx::new(t.path()).unwrap();
+	tx.add_record("c", ts(0), record(0.5f32)).unwrap();
+	tx.commit().unwrap();
+	// and deleted records don't count
+	let mut tx = CreateTx::new(t.path()).unwrap();
+	tx.delete("a", "b", u64::MIN, u64::MAX, "%").unwrap();
+	tx.commit().unwrap();
+
+	let db = DatabaseReader::new(t.path()).unwrap();
+	assert_eq!(db.last_format("c").unwrap().as_deref(), Some("f"));
+	assert_eq!(db.last_format("b").unwrap().as_deref(), Some("s"));
+	assert_eq!(db.last_format("a").unwrap(), None);
+	assert_eq!(db.last_format("d").unwrap(), None);
+	let mut tx = CreateTx::new(t.path()).unwrap();
+	tx.set_strict(std::sync::Arc::new(db));
+	tx.add_record("a", ts(6), record("z")).unwrap();
+	assert!(tx.add_record("c", ts(6), record(6u32)).is_err());
+}
+
+#[test]
+#[cfg(feature = "derive")]
+fn decode_rows() {
+	#[derive(crate::FromRow, crate::RecordBuilder, Debug, PartialEq)]
+	struct Reading<'a> {
+		temperature: f64,
+		humidity: Option<f32>,
+		name: &'a str,
+	}
+
+	#[derive(crate::FromRow, crate::RecordBuilder, Debug, PartialEq)]
+	struct Count(u32, Decimal);
+
+	let t = tempfile::TempDir::new().unwrap();
+	let ts = |s: i64| chrono::DateTime::from_timestamp(s, 0).unwrap().naive_utc();
+	{
+		let mut tx = CreateTx::new(t.path()).unwrap();
+		tx.add_record("a", ts(1), (1.5f64, 2u32, "x")).unwrap();
+		let reading = Reading {
+			temperature: 20.5,
+			humidity: None,
+			name: "kitchen",
+		};
+		tx.add_record("b", ts(1), reading).unwrap();
+		tx.add_record("c", ts(1), Count(7, Decimal::new(125, 2)))
+			.unwrap();
+		tx.commit_to(&t.path().join("main")).unwrap();
+	}
+
+	let w = std::fs::File::open(t.path().join("main")).unwrap();
+	let o = Reader::new(w).unwrap().left().unwrap();
+	let records: Vec<_> = o.get_range(..).collect();
+	assert_eq!(records[0].format(), "Fus");
+	assert_eq!(records[0].decode::<(f64, u64, &str)>(), (1.5, 2, "x"));
+	assert_eq!(
+		records[0].decode::<(Option<f64>, u32, String)>(),
+		(Some(1.5), 2, "x".to_string())
+	);
+	// the number of columns and their types must match
+	assert!(records[0].decode_checked::<(f64, u32)>().is_err());
+	assert!(records[0]
+		.decode_checked::<(f64, u32, &str, u32)>()
+		.is_err());
+	assert!(records[0].decode_checked::<(f32, u32, &str)>().is_err());
+
+	assert_eq!(records[1].format(), "Fnfs");
+	assert_eq!(
+		records[1].decode::<Reading>(),
+		Reading {
+			temperature: 20.5,
+			humidity: None,
+			name: "kitchen",
+		}
+	);
+	assert_eq!(records[2].format(), "ud2");
+	assert_eq!(records[2].decode::<Count>(), Count(7, Decimal::new(125, 2)));
+}
+
+#[test]
+fn column_batches() {
+	use crate::ColumnValues;
+
+	let t = tempfile::TempDir::new().unwrap();
+	std::fs::File::create(t.path().join("main")).unwrap();
+	let ts = |s: i64| chrono::DateTime::from_timestamp(s, 0).unwrap().naive_utc();
+	for tx_n in 0..2 {
+		let mut tx = CreateTx::new(t.path()).unwrap();
+		for n in 0..3 {
+			let s = (n * 2 + tx_n) as i64;
+			tx.add_record("a", ts(s), (s as f64, Some(s as u8).filter(|s| s % 3 != 0)))
+				.unwrap();
+		}
+		tx.commit().unwrap();
+	}
+	let mut tx = CreateTx::new(t.path()).unwrap();
+	tx.add_record("b", ts(1), record("x").add(Decimal::new(15, 1)))
+		.unwrap();
+	tx.add_record("b", ts(2), record(7u32)).unwrap();
+	tx.commit().unwrap();
+
+	let db = DatabaseReader::new(t.path()).unwrap();
+	let batches: Vec<_> = db
+		.get_range(..)
+		.into_batches()
+		.map(Result::unwrap)
+		.collect();
+	assert_eq!(batches.len(), 3);
+
+	// the records of both transactions are merged into one batch
+	assert_eq!(batches[0].key, "a");
+	assert_eq!(batches[0].format, "FnC");
+	assert_eq!(
+		batches[0].timestamps,
+		(0..6).map(|s| s * 1_000_000_000).collect::<Vec<_>>()
+	);
+	assert_eq!(
+		batches[0].columns,
+		vec![
+			ColumnValues::F64(vec![0.0, 1.0, 2.0, 3.0, 4.0, 5.0]),
+			ColumnValues::Nullable {
+				present: vec![false, true, true, false, true, true],
+				values: Box::new(ColumnValues::U8(vec![0, 1, 2, 0, 4, 5])),
+			},
+		]
+	);
+
+	// a new format starts a new batch
+	assert_eq!(batches[1].format, "sd1");
+	assert_eq!(
+		batches[1].columns,
+		vec![
+			ColumnValues::String(vec!["x".to_string()]),
+			ColumnValues::Decimal {
+				scale: 1,
+				mantissas: vec![15]
+			},
+		]
+	);
+	assert_eq!(batches[2].len(), 1);
+	assert_eq!(batches[2].columns, vec![ColumnValues::U32(vec![7])]);
+
+	// a single file can be batched without a DatabaseReader
+	let main = std::fs::File::open(&db.transaction_paths()[1]).unwrap();
+	let reader = Reader::new(main).unwrap().left().unwrap();
+	let batches: Vec<_> = reader.get("a").into_batches().map(Result::unwrap).collect();
+	assert_eq!(batches[0].timestamps.len(), 3);
+	assert_eq!(batches[0].columns[0].len(), 3);
+
+	// a record that can't be decoded is an error
+	let data = b"au\0\0\0\0\0\0\0\x01\0\x07".to_vec();
+	let truncated = crate::Record {
+		key_pos: 0,
+		key_len: 1,
+		fmt_pos: 1,
+		fmt_len: 1,
+		value_pos: 2,
+		value_len: data.len() - 2,
+		data: std::sync::Arc::new(data),
+	};
+	let mut batches = crate::ColumnBatches::new(vec![truncated]);
+	assert!(batches.next().unwrap().is_err());
+	assert!(batches.next().is_none());
+}
+
+#[test]
+#[cfg(feature = "arrow")]
+fn arrow_round_trip() {
+	let t = tempfile::TempDir::new().unwrap();
+	std::fs::File::create(t.path().join("main")).unwrap();
+	std::fs::write(
+		t.path().join("schema"),
+		"a temperature:F count:nC name:s price:d2\n",
+	)
+	.unwrap();
+	let ts = |s: i64| chrono::DateTime::from_timestamp(s, 0).unwrap().naive_utc();
+	let mut tx = CreateTx::new(t.path()).unwrap();
+	for key in ["a", "b"] {
+		for s in 0..4 {
+			let count = Some(s as u8).filter(|s| s % 2 == 0);
+			let r = record(s as f64).add(count).add(key).add(Decimal::new(s, 2));
+			tx.add_record(key, ts(s), r).unwrap();
+		}
+	}
+	tx.commit().unwrap();
+
+	let db = DatabaseReader::new(t.path()).unwrap();
+	let mut stream = vec![];
+	let count = crate::write_arrow(db.get_range(..), db.schema().unwrap(), &mut stream).unwrap();
+	assert_eq!(count, 8);
+
+	let reader = arrow_ipc::reader::StreamReader::try_new(&stream[..], None).unwrap();
+	let names: Vec<_> = reader
+		.schema()
+		.fields()
+		.iter()
+		.map(|f| f.name().clone())
+		.collect();
+	assert_eq!(
+		names,
+		["key", "time", "temperature", "count", "name", "price"]
+	);
+
+	let t2 = tempfile::TempDir::new().unwrap();
+	std::fs::File::create(t2.path().join("main")).unwrap();
+	let mut tx = CreateTx::new(t2.path()).unwrap();
+	let count = crate::read_arrow(&mut tx, std::io::Cursor::new(&stream)).unwrap();
+	assert_eq!(count, 8);
+	tx.commit().unwrap();
+
+	let db2 = DatabaseReader::new(t2.path()).unwrap();
+	let original: Vec<_> = db
+		.get_range(..)
+		.map(|r| (r.key().to_owned(), r.raw().to_owned()))
+		.collect();
+	let imported: Vec<_> = db2
+		.get_range(..)
+		.map(|r| (r.key().to_owned(), r.raw().to_owned()))
+		.collect();
+	assert_eq!(original, imported);
+	assert_eq!(db2.get("b").into_iter().next().unwrap().format(), "FnCsd2");
+
+	// records of different formats can't be in one stream
+	let mut tx = CreateTx::new(t.path()).unwrap();
+	tx.add_record("c", ts(0), record(1u32)).unwrap();
+	tx.commit().unwrap();
+	let db = DatabaseReader::new(t.path()).unwrap();
+	assert!(crate::write_arrow(db.get_range(..), db.schema().unwrap(), vec![]).is_err());
+
+	// a row without a key isn't imported
+	use arrow_array::{ArrayRef, StringArray, TimestampNanosecondArray, UInt32Array};
+	let columns: Vec<(&str, ArrayRef)> = vec![
+		(
+			"key",
+			std::sync::Arc::new(StringArray::from(vec![Some("a"), None])),
+		),
+		(
+			"time",
+			std::sync::Arc::new(TimestampNanosecondArray::from(vec![1, 2])),
+		),
+		("value", std::sync::Arc::new(UInt32Array::from(vec![1, 2]))),
+	];
+	let batch = arrow_array::RecordBatch::try_from_iter(columns).unwrap();
+	let mut stream = vec![];
+	let mut writer =
+		arrow_ipc::writer::StreamWriter::try_new(&mut stream, &batch.schema()).unwrap();
+	writer.write(&batch).unwrap();
+	writer.finish().unwrap();
+	drop(writer);
+	let mut tx = CreateTx::new(t2.path()).unwrap();
+	let e = crate::read_arrow(&mut tx, std::io::Cursor::new(&stream)).unwrap_err();
+	assert!(e.to_string().contains("row 2 has no key"), "{}", e);
+
+	// the slot of a null can have any value, but a null is stored as zero
+	use arrow_array::{Decimal128Array, UInt32Array as U32};
+	let garbage = U32::try_new(vec![1, 99].into(), Some(vec![true, false].into())).unwrap();
+	let huge = Decimal128Array::try_new(vec![5, i128::MAX].into(), Some(vec![true, false].into()))
+		.unwrap()
+		.with_precision_and_scale(38, 2)
+		.unwrap();
+	let columns: Vec<(&str, ArrayRef)> = vec![
+		(
+			"key",
+			std::sync::Arc::new(StringArray::from(vec!["n", "n"])),
+		),
+		(
+			"time",
+			std::sync::Arc::new(TimestampNanosecondArray::from(vec![1, 2])),
+		),
+		("value", std::sync::Arc::new(garbage)),
+		("price", std::sync::Arc::new(huge)),
+	];
+	let batch = arrow_array::RecordBatch::try_from_iter(columns).unwrap();
+	let mut stream = vec![];
+	let mut writer =
+		arrow_ipc::writer::StreamWriter::try_new(&mut stream, &batch.schema()).unwrap();
+	writer.write(&batch).unwrap();
+	writer.finish().unwrap();
+	drop(writer);
+	let t3 = tempfile::TempDir::new().unwrap();
+	std::fs::File::create(t3.path().join("main")).unwrap();
+	let mut tx = CreateTx::new(t3.path()).unwrap();
+	assert_eq!(
+		crate::read_arrow(&mut tx, std::io::Cursor::new(&stream)).unwrap(),
+		2
+	);
+	tx.commit().unwrap();
+	let db3 = DatabaseReader::new(t3.path()).unwrap();
+	let records: Vec<_> = db3.get("n").into_iter().collect();
+	assert_eq!(records[1].format(), "nund2");
+	assert_eq!(records[1].raw()[8..], [0; 14]);
+	assert_eq!(
+		records[0].get::<Option<Decimal>>(1),
+		Some(Decimal::new(5, 2))
+	);
+}
+
+#[test]
+#[cfg(feature = "parquet")]
+fn parquet_partitions() {
+	use crate::Partitioning;
+
+	let t = tempfile::TempDir::new().unwrap();
+	std::fs::File::create(t.path().join("main")).unwrap();
+	let mut tx = CreateTx::new(t.path()).unwrap();
+	for key in ["a.1", "a.2", "b/1"] {
+		for hour in 0..48 {
+			let ts = chrono::DateTime::from_timestamp(hour * 3600, 0).unwrap();
+			tx.add_record(key, ts.naive_utc(), record(hour as f64).add(key))
+				.unwrap();
+		}
+	}
+	tx.commit().unwrap();
+	let db = DatabaseReader::new(t.path()).unwrap();
+
+	// the rows of each file, by partition
+	let read = |dir: &std::path::Path| {
+		let mut rows = std::collections::BTreeMap::new();
+		for partition in std::fs::read_dir(dir).unwrap() {
+			let partition = partition.unwrap();
+			for file in std::fs::read_dir(partition.path()).unwrap() {
+				let file = std::fs::File::open(file.unwrap().path()).unwrap();
+				let reader =
+					parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder::try_new(file)
+						.unwrap()
+						.build()
+						.unwrap();
+				let n: usize = reader.map(|b| b.unwrap().num_rows()).sum();
+				*rows
+					.entry(partition.file_name().into_string().unwrap())
+					.or_insert(0) += n;
+			}
+		}
+		rows.into_iter().collect::<Vec<_>>()
+	};
+
+	let out = tempfile::TempDir::new().unwrap();
+	let by_prefix = "prefix:.".parse().unwrap();
+	let n = crate::write_parquet(
+		db.get_range(..),
+		db.schema().unwrap(),
+		&by_prefix,
+		out.path(),
+	)
+	.unwrap();
+	assert_eq!(n, 144);
+	assert_eq!(
+		read(out.path()),
+		[
+			("prefix=a".to_string(), 96),
+			("prefix=b%2F1".to_string(), 48)
+		]
+	);
+
+	let out = tempfile::TempDir::new().unwrap();
+	let by_day = "time:day".parse().unwrap();
+	assert_eq!(by_day, Partitioning::Time { seconds: 86400 });
+	let n = crate::write_parquet(
+		db.get_filter(&crate::Wildcard::new("a.%")),
+		db.schema().unwrap(),
+		&by_day,
+		out.path(),
 	)
-	.expect("formatting");
-	std::io::Write::write_all(&mut out, b"\n").unwrap();
-	print_record(
-		&i.next().expect("row2"),
-		&mut out,
-		PrintTimestamp::FormatString("%F_%T"),
-		PrintRecordFormat::Yes,
-		&choice_string::parse("2").unwrap(),
+	.unwrap();
+	assert_eq!(n, 96);
+	assert_eq!(
+		read(out.path()),
+		[
+			("time=1970-01-01T000000".to_string(), 48),
+			("time=1970-01-02T000000".to_string(), 48)
+		]
+	);
+
+	// an export replaces the files of the one before it
+	let n = crate::write_parquet(
+		db.get_range(..),
+		db.schema().unwrap(),
+		&by_prefix,
+		out.path(),
 	)
-	.expect("formatting");
-	assert!(i.next().is_none());
+	.unwrap();
+	assert_eq!(n, 144);
+	assert_eq!(
+		read(out.path()),
+		[
+			("prefix=a".to_string(), 96),
+			("prefix=b%2F1".to_string(), 48)
+		]
+	);
 
+	// with only one file open, a partition gets a file each time it's reopened
+	let out = tempfile::TempDir::new().unwrap();
+	let n = crate::parquet::write_parquet_files(
+		db.get_filter(&crate::Wildcard::new("a.%")),
+		db.schema().unwrap(),
+		&by_day,
+		out.path(),
+		1,
+	)
+	.unwrap();
+	assert_eq!(n, 96);
 	assert_eq!(
-		&String::from_utf8(out).unwrap(),
-		"\
-			a\t2010-01-01_00:00:00\tuu\t10\n\
-			a\t2010-01-02_00:00:00\tuu\t30\
-		"
+		read(out.path()),
+		[
+			("time=1970-01-01T000000".to_string(), 48),
+			("time=1970-01-02T000000".to_string(), 48)
+		]
+	);
+	let files = std::fs::read_dir(out.path().join("time=1970-01-01T000000"))
+		.unwrap()
+		.count();
+	assert_eq!(files, 2);
+
+	// a failed export removes its files
+	let out = tempfile::TempDir::new().unwrap();
+	std::fs::create_dir_all(out.path().join("prefix=b%2F1/part-00000-00001-Fs.parquet")).unwrap();
+	assert!(crate::write_parquet(
+		db.get_range(..),
+		db.schema().unwrap(),
+		&by_prefix,
+		out.path(),
+	)
+	.is_err());
+	assert_eq!(
+		std::fs::read_dir(out.path().join("prefix=a"))
+			.unwrap()
+			.count(),
+		0
 	);
+
+	assert!("time:0".parse::<Partitioning>().is_err());
+	assert!("prefix:ab".parse::<Partitioning>().is_err());
 }
 
 #[test]
-#[should_panic]
-fn violate_time_order() {
+#[cfg(feature = "csv")]
+fn csv_import() {
+	use crate::{add_from_csv, CsvImport};
+
 	let t = tempfile::TempDir::new().unwrap();
+	std::fs::File::create(t.path().join("main")).unwrap();
+	let data = "\
+		when,price,sensor,count,note\n\
+		2024-01-02T00:00:00,1.5,b,3,\"hello, world\"\n\
+		2024-01-01T00:00:00,2,b,,x\n\
+		1704067200000000000,3.25,a,7,\n\
+		2024-01-01 00:00:00,4,b,,y\n";
+	let import = CsvImport {
+		key_column: "sensor".to_owned(),
+		time_column: "when".to_owned(),
+		..CsvImport::default()
+	};
+	// an integer needs a unit
+	let mut tx = CreateTx::new(t.path()).unwrap();
+	let e = add_from_csv(&mut tx, data.as_bytes(), &import).unwrap_err();
+	assert!(e.to_string().contains("line 4"), "{}", e);
+	let import = CsvImport {
+		timestamp_unit: Some(crate::TimestampUnit::Nanoseconds),
+		..import
+	};
+	assert_eq!(add_from_csv(&mut tx, data.as_bytes(), &import).unwrap(), 3);
+	tx.commit().unwrap();
 
-	{
-		let mut tx = CreateTx::new(t.path()).expect("creating tx");
-		let data = "a 2010-01-01_00:00:00 10\n\
-			a 2010-01-01_00:00:00 20\n";
+	let db = DatabaseReader::new(t.path()).unwrap();
+	let records: Vec<_> = db.get_range(..).collect();
+	assert_eq!(records[0].key(), "a");
+	assert_eq!(records[0].format(), "FnIs");
+	assert_eq!(
+		records[0].decode::<(f64, Option<i64>, &str)>(),
+		(3.25, Some(7), "")
+	);
+	// the later row of b at the same time replaces the earlier one
+	assert_eq!(
+		records[1].decode::<(f64, Option<i64>, &str)>(),
+		(4.0, None, "y")
+	);
+	assert_eq!(records[2].get::<&str>(2), "hello, world");
+
+	// a format and the columns can be given
+	let import = CsvImport {
+		key_column: "sensor".to_owned(),
+		time_column: "when".to_owned(),
+		value_columns: Some(vec!["note".to_owned(), "price".to_owned()]),
+		format: Some("sd1".to_owned()),
+		timestamp_unit: Some("s".parse().unwrap()),
+		..CsvImport::default()
+	};
+	let mut tx = CreateTx::new(t.path()).unwrap();
+	let data = "sensor,when,price,note\nc,0,2.5,z\n";
+	add_from_csv(&mut tx, data.as_bytes(), &import).unwrap();
+	tx.commit().unwrap();
+	let db = DatabaseReader::new(t.path()).unwrap();
+	let c = db.get("c").into_iter().next().unwrap();
+	assert_eq!(c.decode::<(&str, Decimal)>(), ("z", Decimal::new(25, 1)));
+	assert_eq!(c.timestamp_nanos(), 0);
 
-		add_from_stream(&mut tx, "u", &mut std::io::Cursor::new(data), Some("%F_%T"))
-			.expect("writing");
-		tx.commit_to(&t.path().join("main")).expect("committed");
-	}
+	// errors report their line
+	let mut tx = CreateTx::new(t.path()).unwrap();
+	let data = "sensor,when,price,note\nc,0,2.5,z\nc,1,2.55,z\n";
+	let e = add_from_csv(&mut tx, data.as_bytes(), &import).unwrap_err();
+	assert!(format!("{:?}", e).contains("line 3"), "{:?}", e);
+	let data = "sensor,price,note\nc,2.5,z\n";
+	assert!(add_from_csv(&mut tx, data.as_bytes(), &import).is_err());
 }
 
 #[test]
@@ -457,6 +1715,247 @@ fn multicolumn_string() {
 	assert_eq!(&String::from_utf8(out).unwrap(), data,);
 }
 
+#[test]
+fn ingest_errors() {
+	let t = tempfile::TempDir::new().unwrap();
+	std::fs::File::create(t.path().join("main")).unwrap();
+	let data = "a 1 5\nb 2 x\n\nc 3\nd zz 4\ne 5 6 7\nf 6 8\n";
+
+	// stops at the first bad line
+	let mut tx = CreateTx::new(t.path()).unwrap();
+	let e = add_from_stream(&mut tx, "u", &mut data.as_bytes(), None).unwrap_err();
+	assert_eq!((e.line, e.column, &e.text[..]), (2, Some(1), "x"));
+	assert!(matches!(e.reason, IngestFailure::Value(_)));
+	drop(tx);
+
+	let mut rejected = vec![];
+	let mut tx = CreateTx::new(t.path()).unwrap();
+	let n = add_from_stream_rejecting(
+		&mut tx,
+		Some("u"),
+		&mut data.as_bytes(),
+		None,
+		&mut |e, line| {
+			rejected.push((e.line, e.column, line.to_owned()));
+			Ok(())
+		},
+	)
+	.unwrap();
+	tx.commit().unwrap();
+	assert_eq!(n, 4);
+	assert_eq!(
+		rejected,
+		[
+			(2, Some(1), "b 2 x".to_owned()),
+			(4, Some(1), "c 3".to_owned()),
+			(5, None, "d zz 4".to_owned()),
+			(6, Some(2), "e 5 6 7".to_owned()),
+		]
+	);
+	let db = DatabaseReader::new(t.path()).unwrap();
+	let keys: Vec<_> = db.get_range(..).map(|r| r.key().to_owned()).collect();
+	assert_eq!(keys, ["a", "f"]);
+
+	// records that are out of order are bad lines too
+	let mut tx = CreateTx::new(t.path()).unwrap();
+	let e =
+		add_from_stream_with_fmt(&mut tx, &mut "b 1 u 1\na 1 u 1\n".as_bytes(), None).unwrap_err();
+	assert_eq!(e.line, 2);
+	assert!(e.is_bad_line());
+	let e = add_from_stream_with_fmt(&mut tx, &mut "c 1 q 1\n".as_bytes(), None).unwrap_err();
+	assert!(matches!(e.reason, IngestFailure::Format));
+}
+
+#[test]
+fn sorting_tx() {
+	let t = tempfile::TempDir::new().unwrap();
+	std::fs::File::create(t.path().join("main")).unwrap();
+	let scratch = tempfile::TempDir::new().unwrap();
+
+	let mut tx = crate::SortingCreateTx::new(CreateTx::new(t.path()).unwrap());
+	// a few records to each temporary file
+	tx.set_memory_budget(256);
+	tx.set_temp_dir(scratch.path());
+	for n in (0..100u32).rev() {
+		let key = format!("k{}", n % 7);
+		let ts = chrono::DateTime::from_timestamp(n as i64 % 10, 0)
+			.unwrap()
+			.naive_utc();
+		tx.add_record(&key, ts, record(n)).unwrap();
+	}
+	add_from_stream(
+		&mut tx,
+		"s",
+		&mut "k1 1000000000 one\nk0 0 zero\n".as_bytes(),
+		None,
+	)
+	.unwrap();
+	assert!(scratch.path().read_dir().unwrap().next().is_none());
+	tx.commit().unwrap();
+
+	let db = DatabaseReader::new(t.path()).unwrap();
+	let records: Vec<_> = db
+		.get_range(..)
+		.map(|r| {
+			(
+				r.key().to_owned(),
+				r.timestamp_nanos(),
+				r.format().to_owned(),
+			)
+		})
+		.collect();
+	let mut sorted = records.clone();
+	sorted.sort();
+	sorted.dedup();
+	assert_eq!(records, sorted);
+	// every key and second once, and the last added wins
+	assert_eq!(records.len(), 7 * 10);
+	let r = db.get("k1").into_iter().next().unwrap();
+	assert_eq!((r.timestamp_nanos(), r.format()), (0, "u"));
+	let r = db.get("k1").into_iter().nth(1).unwrap();
+	assert_eq!(r.get::<String>(0), "one");
+	let r = db.get("k0").into_iter().next().unwrap();
+	assert_eq!(r.get::<String>(0), "zero");
+}
+
+#[test]
+fn sorting_tx_strict() {
+	let t = tempfile::TempDir::new().unwrap();
+	std::fs::File::create(t.path().join("main")).unwrap();
+	let ts = |s: i64| chrono::DateTime::from_timestamp(s, 0).unwrap().naive_utc();
+	let mut tx = CreateTx::new(t.path()).unwrap();
+	tx.add_record("a", ts(1), record(1u32)).unwrap();
+	tx.commit().unwrap();
+	let db = std::sync::Arc::new(DatabaseReader::new(t.path()).unwrap());
+
+	let strict = || {
+		let mut tx = CreateTx::new(t.path()).unwrap();
+		tx.set_strict(db.clone());
+		crate::SortingCreateTx::new(tx)
+	};
+
+	// a key's format is checked in sorted order, even if other keys come between
+	let mut tx = strict();
+	tx.add_record("b", ts(2), record(2u32)).unwrap();
+	tx.add_record("a", ts(2), record(2u32)).unwrap();
+	tx.add_record("b", ts(1), record("x")).unwrap();
+	match tx.commit() {
+		Err(crate::WriteFailure::FormatChanged {
+			key,
+			format,
+			existing,
+		}) => {
+			assert_eq!(
+				(key, format, existing),
+				("b".into(), "u".into(), "s".into())
+			);
+		}
+		e => panic!("expected a format change, got {:?}", e),
+	}
+
+	let mut tx = strict();
+	tx.add_record("b", ts(2), record(2u32)).unwrap();
+	tx.add_record("a", ts(3), record(2.5f32)).unwrap();
+	assert!(tx.commit().is_err());
+
+	let mut tx = strict();
+	tx.add_record("b", ts(2), record(2u32)).unwrap();
+	tx.add_record("a", ts(3), record(3u32)).unwrap();
+	tx.add_record("b", ts(1), record(1u32)).unwrap();
+	tx.commit().unwrap();
+	assert_eq!(
+		DatabaseReader::new(t.path()).unwrap().get_range(..).count(),
+		4
+	);
+}
+
+#[test]
+fn output_formats() {
+	let t = tempfile::TempDir::new().unwrap();
+
+	let data = "\
+		a\t2010-01-01_00:00:00\tnFsx\t1.5 tab\\there,\\ \"q\" ff00\n\
+		b\t2010-01-02_00:00:00\tnFbd2U\t- true 3.10 18446744073709551615\n\
+		";
+	{
+		let mut tx = CreateTx::new(t.path()).expect("creating tx");
+		add_from_stream_with_fmt(&mut tx, &mut std::io::Cursor::new(data), Some("%F_%T"))
+			.expect("writing");
+		tx.commit_to(&t.path().join("main")).expect("committed");
+	}
+	let w = std::fs::File::open(t.path().join("main")).unwrap();
+	let o = Reader::new(w).unwrap().left().unwrap();
+	let records: Vec<_> = o.get_range(..).collect();
+
+	let schema = crate::Schema::parse("a value:nF label:s raw:x").unwrap();
+	let no_schema = crate::Schema::default();
+	let print = |output, ts, columns: &str, schema: &crate::Schema| {
+		let columns = choice_string::parse(columns).unwrap();
+		let mut out = vec![];
+		print_header_as(
+			&records[0],
+			&mut out,
+			output,
+			PrintRecordFormat::No,
+			&columns,
+			schema,
+		)
+		.unwrap();
+		for record in &records {
+			print_record_as(
+				record,
+				&mut out,
+				output,
+				ts,
+				PrintRecordFormat::No,
+				&columns,
+				schema,
+			)
+			.unwrap();
+			out.push(b'\n');
+		}
+		String::from_utf8(out).unwrap()
+	};
+	let date = PrintTimestamp::FormatString("%F");
+
+	assert_eq!(
+		print(OutputFormat::Csv, date, "1-4", &no_schema),
+		"key,time,column1,column2,column3\n\
+		a,2010-01-01,1.5,\"tab\there, \"\"q\"\"\",ff00\n\
+		b,2010-01-02,,true,3.10,18446744073709551615\n"
+	);
+	assert_eq!(
+		print(OutputFormat::Tsv, date, "2-3", &schema),
+		"key\ttime\tlabel\traw\n\
+		a\t2010-01-01\ttab\\there, \"q\"\tff00\n\
+		b\t2010-01-02\ttrue\t3.10\n"
+	);
+	assert_eq!(
+		print(
+			OutputFormat::JsonLines,
+			PrintTimestamp::Seconds,
+			"1-4",
+			&no_schema
+		),
+		"{\"key\":\"a\",\"time\":1262304000,\"values\":[1.5,\"tab\\there, \\\"q\\\"\",\"ff00\"]}\n\
+		{\"key\":\"b\",\"time\":1262390400,\"values\":[null,true,3.10,18446744073709551615]}\n"
+	);
+	assert_eq!(
+		print(
+			OutputFormat::JsonLines,
+			PrintTimestamp::Seconds,
+			"1,3",
+			&schema
+		),
+		"{\"key\":\"a\",\"time\":1262304000,\"values\":{\"value\":1.5,\"raw\":\"ff00\"}}\n\
+		{\"key\":\"b\",\"time\":1262390400,\"values\":[null,3.10]}\n"
+	);
+	assert_eq!(
+		"jsonl".parse::<OutputFormat>().unwrap(),
+		OutputFormat::JsonLines
+	);
+}
+
 #[test]
 fn write() {
 	let t = tempfile::TempDir::new().unwrap();
@@ -477,6 +1976,71 @@ fn write() {
 	let _ = o.find("a").unwrap();
 }
 
+#[test]
+fn segment_index() {
+	let t = tempfile::TempDir::new().unwrap();
+	let keys: Vec<String> = (0..40).map(|k| format!("key{:02}", k * 2)).collect();
+	{
+		let w = std::fs::File::create(t.path().join("w")).unwrap();
+		let mut w = Writer::new(BufWriter::new(w));
+		for key in &keys {
+			for ts in 0..40000u64 {
+				w.add_record(key, ts, record(ts.wrapping_mul(0x9e3779b97f4a7c15)))
+					.unwrap();
+			}
+		}
+		w.finish().unwrap();
+	}
+
+	// the same file, without its index
+	let file = std::fs::read(t.path().join("w")).unwrap();
+	let (segments_len, index) = crate::segment_index::decode(&file).unwrap();
+	assert!(index.segments.len() > 5, "{}", index.segments.len());
+	std::fs::write(t.path().join("unindexed"), &file[..segments_len]).unwrap();
+
+	let open = |name| {
+		let mut f = std::fs::File::open(t.path().join(name)).unwrap();
+		SegmentReader::open(&mut f).unwrap().left().unwrap()
+	};
+	let indexed = open("w");
+	let unindexed = open("unindexed");
+	assert_eq!(indexed.number_of_bytes(), unindexed.number_of_bytes());
+
+	let mut searches: Vec<String> = vec!["".into(), "a".into(), "z".into()];
+	for key in &keys {
+		searches.push(key.clone());
+		searches.push(format!("{}0", key));
+	}
+	let offset = |s: Option<crate::Segment>| s.map(|s| s.segment_offset);
+	for key in &searches {
+		assert_eq!(
+			offset(indexed.find(key)),
+			offset(unindexed.find(key)),
+			"{}",
+			key
+		);
+		// the first segment that ends at or after key
+		let mut expected = indexed.first();
+		while let Some(s) = expected.as_ref().filter(|s| s.last_key < key.as_str()) {
+			expected = indexed.segment_after(s);
+		}
+		assert_eq!(
+			offset(indexed.find_after(|o| o.cmp(key))),
+			offset(expected),
+			"{}",
+			key
+		);
+	}
+
+	let r = Reader::new(std::fs::File::open(t.path().join("w")).unwrap())
+		.unwrap()
+		.left()
+		.unwrap();
+	assert_eq!(r.get("key10").count(), 40000);
+	assert_eq!(r.get("key11").count(), 0);
+	assert_eq!(r.get_range("key10".."key14").count(), 80000);
+}
+
 #[test]
 fn database_merge1() {
 	let t = tempfile::TempDir::new().unwrap();
@@ -1035,6 +2599,60 @@ fn delete_all() {
 	assert_eq!(0, db.get_range(..).into_par_iter().count());
 }
 
+#[test]
+fn time_range_with_delete() {
+	use std::ops::Bound::*;
+	let (t, _) = make_big_database(2048);
+
+	{
+		let mut tx = CreateTx::new(t.path()).unwrap();
+		tx.delete("", "", 100, 200, "%").unwrap();
+		tx.commit().unwrap();
+	}
+
+	let db = DatabaseReader::new(t.path()).unwrap();
+	assert!(db.filter_out_for(&(Included(200), Unbounded)).is_empty());
+	assert_eq!(db.filter_out_for(&(Included(150), Unbounded)).len(), 1);
+	assert!(db.filter_out_for(&(Unbounded, Excluded(100))).is_empty());
+
+	let expected = |range: std::ops::Range<u64>| {
+		db.get_range(..)
+			.into_iter()
+			.filter(|r| range.contains(&r.timestamp_nanos()))
+			.count()
+	};
+
+	for range in [0..100, 50..150, 150..250, 200..300, 0..u64::MAX] {
+		let n = expected(range.clone());
+		assert_eq!(
+			db.get_range(..)
+				.with_time_range(range.clone())
+				.into_iter()
+				.count(),
+			n
+		);
+		assert_eq!(
+			db.get_range(..)
+				.with_time_range(range.clone())
+				.into_par_iter()
+				.count(),
+			n
+		);
+		#[cfg(feature = "by-key")]
+		{
+			let w = crate::Wildcard::new("%");
+			let by_key: usize = db
+				.get_filter_keys(&w)
+				.with_time_range(range.clone())
+				.into_iter()
+				.map(|k| k.count())
+				.sum();
+			assert_eq!(by_key, n);
+		}
+	}
+	assert_eq!(expected(100..200), 0);
+}
+
 #[test]
 fn delete_quantum_choice_eraser_compact() {
 	let t = tempfile::TempDir::new().unwrap();
@@ -1270,3 +2888,410 @@ delete_test!(prefix, "a%");
 delete_test!(infix, "%a%");
 delete_test!(suffix, "%a");
 delete_test!(inter, "a%a");
+
+#[test]
+fn write_buffer() {
+	use crate::{WriteBuffer, WRITE_BUFFER_JOURNAL};
+
+	let t = tempfile::TempDir::new().unwrap();
+	std::fs::File::create(t.path().join("main")).unwrap();
+	let time = |s: i64| chrono::DateTime::from_timestamp(s, 0).unwrap().naive_utc();
+	let values = |db: &DatabaseReader, filter: &str| -> Vec<(String, u64, u32)> {
+		db.get_filter(&crate::Wildcard::new(filter))
+			.into_iter()
+			.map(|r| {
+				(
+					r.key().to_owned(),
+					r.timestamp_nanos() / 1_000_000_000,
+					r.value(),
+				)
+			})
+			.collect()
+	};
+
+	let mut tx = CreateTx::new(t.path()).unwrap();
+	tx.add_record("a", time(1), record(1u32)).unwrap();
+	tx.add_record("b", time(1), record(1u32)).unwrap();
+	tx.commit().unwrap();
+
+	let buffer = WriteBuffer::open(t.path()).unwrap();
+	let mut batch = buffer.batch();
+	batch.add_record("c", time(2), record(3u32)).unwrap();
+	batch.add_record("a", time(1), record(2u32)).unwrap();
+	add_from_stream(&mut batch, "u", &mut "a 2000000000 4\n".as_bytes(), None).unwrap();
+	assert!(batch.add_record_raw("a", "u", &[0; 4]).is_err());
+	batch.commit().unwrap();
+	assert_eq!(buffer.len(), 3);
+
+	// readers see the buffered records, which replace the transaction's
+	let db = buffer.reader().unwrap();
+	let all = vec![
+		("a".to_owned(), 1, 2),
+		("a".to_owned(), 2, 4),
+		("b".to_owned(), 1, 1),
+		("c".to_owned(), 2, 3),
+	];
+	assert_eq!(values(&db, "%"), all);
+	assert_eq!(values(&db, "a"), all[0..2]);
+	assert_eq!(values(&db, "c%"), all[3..]);
+	let later: Vec<_> = db
+		.get_range(..)
+		.with_time_range(2_000_000_000..)
+		.into_iter()
+		.map(|r| r.key().to_owned())
+		.collect();
+	assert_eq!(later, ["a", "c"]);
+	#[cfg(feature = "by-key")]
+	assert_eq!(db.get_range_keys(..).into_iter().count(), 3);
+	assert_eq!(
+		values(&DatabaseReader::new(t.path()).unwrap(), "%").len(),
+		2
+	);
+
+	// the buffered records come back from the journal, but not a partial batch
+	drop(buffer);
+	let mut journal = std::fs::OpenOptions::new()
+		.append(true)
+		.open(t.path().join(WRITE_BUFFER_JOURNAL))
+		.unwrap();
+	std::io::Write::write_all(&mut journal, &[0, 0, 1, 0, 1, 2]).unwrap();
+	drop(journal);
+	let buffer = WriteBuffer::open(t.path()).unwrap();
+	assert_eq!(values(&buffer.reader().unwrap(), "%"), all);
+	assert!(WriteBuffer::open(t.path()).is_err());
+
+	// a reader keeps the records it was opened with
+	let db = buffer.reader().unwrap();
+	buffer.flush().unwrap();
+	assert!(buffer.is_empty());
+	assert_eq!(values(&db, "%"), all);
+	assert_eq!(values(&DatabaseReader::new(t.path()).unwrap(), "%"), all);
+	let journal = t.path().join(WRITE_BUFFER_JOURNAL);
+	assert_eq!(std::fs::metadata(journal).unwrap().len(), 0);
+
+	// batches flush once the buffer is full
+	let mut buffer = buffer;
+	buffer.set_flush_size(1);
+	let mut batch = buffer.batch();
+	batch.add_record("d", time(1), record(5u32)).unwrap();
+	batch.commit().unwrap();
+	assert!(buffer.is_empty());
+	assert!(!buffer.flush_if_due().unwrap());
+	assert_eq!(
+		values(&DatabaseReader::new(t.path()).unwrap(), "d"),
+		[("d".to_owned(), 1, 5)]
+	);
+}
+
+#[test]
+fn write_buffer_delete() {
+	use crate::WriteBuffer;
+
+	let t = tempfile::TempDir::new().unwrap();
+	std::fs::File::create(t.path().join("main")).unwrap();
+	let time = |s: i64| chrono::DateTime::from_timestamp(s, 0).unwrap().naive_utc();
+	let keys = |db: &DatabaseReader| -> Vec<String> {
+		db.get_range(..)
+			.into_iter()
+			.map(|r| r.key().to_owned())
+			.collect()
+	};
+	let delete = |first_key: &str, last_key: &str| {
+		let mut tx = CreateTx::new(t.path()).unwrap();
+		tx.delete(first_key, last_key, u64::MIN, u64::MAX, "%")
+			.unwrap();
+		tx.commit().unwrap();
+	};
+
+	// an empty main would be replaced by the delete marker
+	let mut tx = CreateTx::new(t.path()).unwrap();
+	tx.add_record("c", time(1), record(1u32)).unwrap();
+	tx.commit().unwrap();
+
+	let buffer = WriteBuffer::open(t.path()).unwrap();
+	let mut batch = buffer.batch();
+	batch.add_record("a", time(1), record(1u32)).unwrap();
+	batch.add_record("b", time(1), record(1u32)).unwrap();
+	batch.commit().unwrap();
+
+	// a delete hides the records that were buffered before it
+	delete("a", "b");
+	assert_eq!(keys(&buffer.reader().unwrap()), ["b", "c"]);
+	#[cfg(feature = "by-key")]
+	assert_eq!(
+		buffer
+			.reader()
+			.unwrap()
+			.get_range_keys(..)
+			.into_iter()
+			.count(),
+		2
+	);
+
+	// but not the ones that were buffered after it
+	let mut batch = buffer.batch();
+	batch.add_record("a", time(2), record(2u32)).unwrap();
+	batch.commit().unwrap();
+	assert_eq!(keys(&buffer.reader().unwrap()), ["a", "b", "c"]);
+
+	// they stay deleted after they're replayed from the journal and flushed
+	drop(buffer);
+	let buffer = WriteBuffer::open(t.path()).unwrap();
+	assert_eq!(keys(&buffer.reader().unwrap()), ["a", "b", "c"]);
+	buffer.flush().unwrap();
+	let db = DatabaseReader::new(t.path()).unwrap();
+	let a: Vec<u32> = db.get("a").into_iter().map(|r| r.value()).collect();
+	assert_eq!(a, [2]);
+	assert_eq!(keys(&db), ["a", "b", "c"]);
+}
+
+#[test]
+fn group_commit() {
+	use crate::GroupCommit;
+	use std::time::Duration;
+
+	let t = tempfile::TempDir::new().unwrap();
+	std::fs::File::create(t.path().join("main")).unwrap();
+	std::fs::write(t.path().join("schema"), "bad% value:u\n").unwrap();
+	let time = |s: i64| chrono::DateTime::from_timestamp(s, 0).unwrap().naive_utc();
+	// the first transaction would be written to the empty main
+	let mut tx = CreateTx::new(t.path()).unwrap();
+	tx.add_record("a", time(1), record(0u32)).unwrap();
+	tx.commit().unwrap();
+	let keys = || -> Vec<String> {
+		let db = DatabaseReader::new(t.path()).unwrap();
+		db.get_range(..)
+			.into_iter()
+			.map(|r| r.key().to_owned())
+			.collect()
+	};
+	let txes = || {
+		std::fs::read_dir(t.path())
+			.unwrap()
+			.filter(|e| {
+				let name = e.as_ref().unwrap().file_name();
+				name.to_str().unwrap().starts_with("tx.")
+			})
+			.count()
+	};
+
+	let mut group = GroupCommit::new(t.path(), Duration::from_millis(500)).unwrap();
+	// write the records of each batch to a temporary file
+	group.set_batch_memory(1);
+	let barrier = std::sync::Barrier::new(5);
+	std::thread::scope(|s| {
+		for n in 0..3u32 {
+			let (group, barrier, keys) = (&group, &barrier, &keys);
+			s.spawn(move || {
+				let mut batch = group.batch();
+				batch
+					.add_record(&format!("k{}", n), time(1), record(n))
+					.unwrap();
+				batch.add_record("shared", time(1), record(n)).unwrap();
+				barrier.wait();
+				batch.commit().unwrap();
+				// a batch's commit returns once its records can be read
+				assert!(keys().contains(&format!("k{}", n)));
+			});
+		}
+		// a batch with a bad record fails by itself
+		let (group, barrier) = (&group, &barrier);
+		s.spawn(move || {
+			let mut batch = group.batch();
+			batch.add_record("bad.ok", time(1), record(1u32)).unwrap();
+			assert!(matches!(
+				batch.add_record("bad.key", time(1), record(1i32)),
+				Err(crate::WriteFailure::SchemaViolation { .. })
+			));
+			drop(batch);
+			barrier.wait();
+		});
+
+		// nothing is committed until the window is over
+		barrier.wait();
+		std::thread::sleep(Duration::from_millis(100));
+		assert_eq!(keys(), ["a"]);
+	});
+	assert_eq!(keys(), ["a", "k0", "k1", "k2", "shared"]);
+	assert_eq!(txes(), 1);
+
+	// a batch after the group starts another one
+	let mut batch = group.batch();
+	batch.add_record("k3", time(1), record(3u32)).unwrap();
+	batch.commit().unwrap();
+	assert_eq!(keys(), ["a", "k0", "k1", "k2", "k3", "shared"]);
+	assert_eq!(txes(), 2);
+	// and an empty one doesn't commit anything
+	group.batch().commit().unwrap();
+	assert_eq!(txes(), 2);
+}
+
+#[test]
+fn group_commit_memory() {
+	use crate::GroupCommit;
+	use std::time::Duration;
+
+	let t = tempfile::TempDir::new().unwrap();
+	std::fs::File::create(t.path().join("main")).unwrap();
+	let time = |s: i64| chrono::DateTime::from_timestamp(s, 0).unwrap().naive_utc();
+
+	let mut group = GroupCommit::new(t.path(), Duration::from_millis(200)).unwrap();
+	// each batch stays in memory, but the group is written to temporary files
+	group.set_group_memory(64 * 1024);
+	let barrier = std::sync::Barrier::new(8);
+	std::thread::scope(|s| {
+		for n in 0..8u32 {
+			let (group, barrier) = (&group, &barrier);
+			s.spawn(move || {
+				let mut batch = group.batch();
+				for ts in 0..2000 {
+					batch
+						.add_record(&format!("k{}", n), time(ts), record(n + ts as u32))
+						.unwrap();
+				}
+				batch.add_record("shared", time(0), record(n)).unwrap();
+				barrier.wait();
+				batch.commit().unwrap();
+			});
+		}
+	});
+
+	let db = DatabaseReader::new(t.path()).unwrap();
+	for n in 0..8u32 {
+		let values: Vec<u32> = db.get(&format!("k{}", n)).map(|r| r.value()).collect();
+		assert_eq!(values, (n..n + 2000).collect::<Vec<_>>());
+	}
+	assert_eq!(db.get("shared").count(), 1);
+}
+
+#[test]
+fn compaction_policy() {
+	use crate::{Compaction, CompactionPolicy};
+
+	let t = tempfile::TempDir::new().unwrap();
+	std::fs::File::create(t.path().join("main")).unwrap();
+	let txes = || {
+		std::fs::read_dir(t.path())
+			.unwrap()
+			.filter(|e| {
+				let name = e.as_ref().unwrap().file_name();
+				name.to_str().unwrap().starts_with("tx.")
+			})
+			.count()
+	};
+	let add = |key: &str, n: u32| {
+		let mut tx = CreateTx::new(t.path()).unwrap();
+		for ts in 0..n {
+			tx.add_record(
+				key,
+				chrono::DateTime::from_timestamp(ts as i64, 0)
+					.unwrap()
+					.naive_utc(),
+				record(ts),
+			)
+			.unwrap();
+		}
+		tx.commit().unwrap();
+	};
+
+	let policy = CompactionPolicy {
+		max_transactions: 2,
+		max_transaction_ratio: 0.5,
+		..Default::default()
+	};
+	assert_eq!(policy.check(t.path()).unwrap(), None);
+	// the first transaction is written to the empty main
+	add("a", 10000);
+	assert_eq!(policy.check(t.path()).unwrap(), None);
+
+	add("b", 10);
+	add("c", 10);
+	assert_eq!(policy.check(t.path()).unwrap(), None);
+	add("d", 10);
+	assert_eq!(txes(), 3);
+	assert_eq!(policy.run(t.path()).unwrap(), Some(Compaction::Minor));
+	assert_eq!(txes(), 1);
+	assert_eq!(policy.check(t.path()).unwrap(), None);
+
+	add("e", 10000);
+	assert_eq!(policy.run(t.path()).unwrap(), Some(Compaction::Major));
+	assert_eq!(txes(), 0);
+
+	let db = DatabaseReader::new(t.path()).unwrap();
+	let mut keys = std::collections::BTreeMap::<String, usize>::new();
+	for r in db.get_range(..) {
+		*keys.entry(r.key().to_owned()).or_default() += 1;
+	}
+	assert_eq!(
+		keys.into_iter().collect::<Vec<_>>(),
+		[
+			("a".to_owned(), 10000),
+			("b".to_owned(), 10),
+			("c".to_owned(), 10),
+			("d".to_owned(), 10),
+			("e".to_owned(), 10000),
+		]
+	);
+}
+
+#[test]
+fn compaction_policy_deletes() {
+	use crate::{Compaction, CompactionPolicy};
+
+	let t = tempfile::TempDir::new().unwrap();
+	std::fs::File::create(t.path().join("main")).unwrap();
+	let txes = || {
+		std::fs::read_dir(t.path())
+			.unwrap()
+			.filter(|e| {
+				let name = e.as_ref().unwrap().file_name();
+				name.to_str().unwrap().starts_with("tx.")
+			})
+			.count()
+	};
+	let keys = || -> Vec<String> {
+		let db = DatabaseReader::new(t.path()).unwrap();
+		db.get_range(..)
+			.into_iter()
+			.map(|r| r.key().to_owned())
+			.collect()
+	};
+	let delete = |key: &str| {
+		let mut tx = CreateTx::new(t.path()).unwrap();
+		tx.delete(key, "", u64::MIN, u64::MAX, key).unwrap();
+		tx.commit().unwrap();
+	};
+
+	let mut tx = CreateTx::new(t.path()).unwrap();
+	let ts = chrono::DateTime::from_timestamp(0, 0).unwrap().naive_utc();
+	tx.add_record("a", ts, record(1u32)).unwrap();
+	tx.add_record("b", ts, record(1u32)).unwrap();
+	tx.commit().unwrap();
+
+	let policy = CompactionPolicy {
+		max_transactions: 2,
+		max_transaction_ratio: 0.5,
+		..Default::default()
+	};
+	// delete markers aren't transactions to compact
+	delete("a");
+	assert_eq!(keys(), ["b"]);
+	delete("x");
+	assert_eq!(txes(), 2);
+	assert_eq!(policy.check(t.path()).unwrap(), None);
+	assert_eq!(policy.run(t.path()).unwrap(), None);
+	assert_eq!(txes(), 2);
+
+	// but too many of them are removed by a major compaction
+	delete("y");
+	assert_eq!(policy.run(t.path()).unwrap(), Some(Compaction::Major));
+	assert_eq!(txes(), 0);
+	assert_eq!(keys(), ["b"]);
+	assert_eq!(policy.run(t.path()).unwrap(), None);
+
+	// and by any major compaction
+	delete("b");
+	crate::compact(t.path(), Compaction::Major, Default::default()).unwrap();
+	assert_eq!(txes(), 0);
+	assert!(keys().is_empty());
+}
@@ -6,6 +6,8 @@ use thiserror::Error;
 use std::io::Write;
 use std::sync::Arc;
 
+use crate::segment_index::IndexEntry;
+
 pub(crate) const SEGMENT_SIZE_GOAL: usize = 1024 * 1024;
 const SEGMENT_SIZE_EXTRA: usize = 1024 * 1024 + 1024 * 32;
 
@@ -26,6 +28,12 @@ pub(crate) struct Writer<W: Write + Send + 'static> {
 	current_key_data: Vec<u8>,
 	/// the most recent timestamp (used for ensuring ordering)
 	current_timestamp: crate::Timestamp,
+	/// the first timestamp in `current_key_data`
+	current_key_min_timestamp: Option<crate::Timestamp>,
+	/// the earliest timestamp in `current_segment_data`
+	segment_min_timestamp: crate::Timestamp,
+	/// the latest timestamp in `current_segment_data`
+	segment_max_timestamp: crate::Timestamp,
 	/// Used for verifying that the records comply with their format, if None, then they are variable (string) sized
 	current_record_size: Option<usize>,
 	/// these threads actually do the LZ4-ing
@@ -33,6 +41,8 @@ pub(crate) struct Writer<W: Write + Send + 'static> {
 	thread_handles: Vec<std::thread::JoinHandle<std::io::Result<()>>>,
 	/// a counter to keep each thread writing its output in the right order
 	thread_ordering: usize,
+	/// the hash of each key, for the index's Bloom filter
+	key_hashes: Vec<u64>,
 }
 
 struct WriterState<W: Write + Send> {
@@ -40,12 +50,18 @@ struct WriterState<W: Write + Send> {
 	prev_size: u32,
 	writer: W,
 	stored_size_last_key: u32,
-	last_key: Vec<u8>,
+	last_key: String,
+	/// the number of bytes written so far
+	written: usize,
+	/// the key range and offset of each segment written so far
+	index: Vec<IndexEntry>,
 }
 
 struct Header {
-	first_key: Vec<u8>,
-	last_key: Vec<u8>,
+	first_key: String,
+	last_key: String,
+	min_timestamp: crate::Timestamp,
+	max_timestamp: crate::Timestamp,
 }
 
 struct WorkerMessage {
@@ -54,6 +70,68 @@ struct WorkerMessage {
 	payload: Vec<u8>, // to compress
 }
 
+/// How the payload of each segment is compressed
+///
+/// The codec is recorded in each segment, so files written
+/// with any codec can be read back.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum Codec {
+	/// Store the payload uncompressed
+	None,
+	/// LZ4, which is fast to compress and decompress (the default)
+	#[default]
+	Lz4,
+	/// Zstandard at the given level (1-22), which is slower
+	/// but compresses much better; useful for archival compactions
+	Zstd(i32),
+}
+
+impl Codec {
+	pub(crate) fn id(&self) -> u8 {
+		match self {
+			Codec::None => 0,
+			Codec::Lz4 => 1,
+			Codec::Zstd(_) => 2,
+		}
+	}
+
+	/// the codec (at an arbitrary level) recorded in a segment header
+	pub(crate) fn from_id(id: u8) -> Option<Codec> {
+		match id {
+			0 => Some(Codec::None),
+			1 => Some(Codec::Lz4),
+			2 => Some(Codec::Zstd(0)),
+			_ => None,
+		}
+	}
+}
+
+/// Parses `none`, `lz4`, `zstd` (level 3, zstd's default) or `zstd:LEVEL` (1-22)
+impl std::str::FromStr for Codec {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"none" => Ok(Codec::None),
+			"lz4" => Ok(Codec::Lz4),
+			"zstd" => Ok(Codec::Zstd(3)),
+			s => {
+				if let Some(level) = s.strip_prefix("zstd:") {
+					let level = level
+						.parse()
+						.map_err(|e| format!("invalid zstd level \"{}\": {}", level, e))?;
+					if !(1..=22).contains(&level) {
+						return Err(format!("zstd level {} is not between 1 and 22", level));
+					}
+					Ok(Codec::Zstd(level))
+				} else {
+					Err(format!("unknown codec \"{}\"", s))
+				}
+			}
+		}
+	}
+}
+
 /// A reason a write could not be completed
 #[derive(Error, Debug)]
 pub enum WriteFailure {
@@ -71,15 +149,54 @@ pub enum WriteFailure {
 	#[error("the size of data ({0}) was not expected")]
 	IncorrectLength(usize),
 	/// An IO error from the OS
-	#[error("io error")]
+	#[error("io error: {0}")]
 	IOError(#[from] std::io::Error),
 	/// The timestamp was not in the expected format
 	#[error("Unable to parse the timestamp")]
 	UnableToParseTimestamp,
+	/// The format of a record of `key` isn't the format of its schema
+	#[error("the format `{format}` of a record of `{key}` is not `{expected}`, the format of its schema")]
+	SchemaViolation {
+		key: String,
+		format: String,
+		expected: String,
+	},
+	/// A record of `key` has the format `format`, but the key already has the format `existing`
+	#[error("a record of `{key}` has the format `{format}`, but the key already has the format `{existing}`")]
+	FormatChanged {
+		key: String,
+		format: String,
+		existing: String,
+	},
+	/// The format `{0}` has a character that isn't a column type, or a decimal's scale is too large
+	#[error("the format `{0}` is not valid")]
+	InvalidFormat(String),
+	/// The group commit that the records were added to failed
+	#[error("the group commit failed: {0}")]
+	GroupCommitFailed(std::sync::Arc<WriteFailure>),
+}
+
+/// Check that `data` is a timestamp followed by values of the size of `format`,
+/// for records that are added to a transaction later
+pub(crate) fn check_record_length(format: &str, data: &[u8]) -> Result<(), WriteFailure> {
+	if !crate::row_format::is_valid_format(format) {
+		return Err(WriteFailure::InvalidFormat(format.to_owned()));
+	}
+	if data.len() < crate::records::TIMESTAMP_SIZE {
+		return Err(WriteFailure::IncorrectLength(data.len()));
+	}
+	if let Some(size) = crate::row_format::row_format_size(format) {
+		if data.len() != size + crate::records::TIMESTAMP_SIZE {
+			return Err(WriteFailure::IncorrectLength(
+				size + crate::records::TIMESTAMP_SIZE,
+			));
+		}
+	}
+	Ok(())
 }
 
 impl<W: Write + Send> Writer<W> {
-	fn new_internal(writer: W, disable_compression: bool) -> Writer<W> {
+	pub(crate) fn with_codec(writer: W, codec: Codec) -> Writer<W> {
 		let num_worker_threads = 4usize;
 
 		let writer_state = WriterState {
@@ -87,7 +204,9 @@ impl<W: Write + Send> Writer<W> {
 			prev_size: 0,
 			writer,
 			stored_size_last_key: 0,
-			last_key: vec![],
+			last_key: String::new(),
+			written: 0,
+			index: vec![],
 		};
 
 		let writer_state = Arc::new(Mutex::new(writer_state));
@@ -101,7 +220,7 @@ impl<W: Write + Send> Writer<W> {
 			let writer_notifier = writer_notifier.clone();
 			let recv = recv.clone();
 			let h = std::thread::spawn(move || {
-				worker_thread(recv, &writer_state, &writer_notifier, disable_compression)
+				worker_thread(recv, &writer_state, &writer_notifier, codec)
 			});
 			thread_handles.push(h);
 		}
@@ -115,17 +234,26 @@ impl<W: Write + Send> Writer<W> {
 			current_key_data: Vec::with_capacity(SEGMENT_SIZE_EXTRA),
 			current_segment_data: Vec::with_capacity(SEGMENT_SIZE_EXTRA),
 			current_timestamp: 0,
+			current_key_min_timestamp: None,
+			segment_min_timestamp: crate::Timestamp::MAX,
+			segment_max_timestamp: 0,
 			worker_threads: Some(send),
 			thread_handles,
 			thread_ordering: 0,
 			current_record_size: None,
+			key_hashes: vec![],
 		}
 	}
+	#[cfg(test)]
 	pub fn new(writer: W) -> Writer<W> {
-		Self::new_internal(writer, false)
+		Self::with_codec(writer, Codec::Lz4)
 	}
 
 	fn new_key_begin(&mut self, key: &str, format: &str) {
+		if key != self.last_key || self.key_hashes.is_empty() {
+			self.key_hashes
+				.push(crate::segment_index::key_hash(key.as_bytes()));
+		}
 		self.last_key.replace_range(.., key);
 		self.last_format.replace_range(.., format);
 
@@ -156,9 +284,24 @@ impl<W: Write + Send> Writer<W> {
 				.extend_from_slice(&self.current_key_data);
 			self.current_key_data.clear();
 		}
+		if let Some(min) = self.current_key_min_timestamp.take() {
+			// within a key, the timestamps are sorted
+			self.segment_min_timestamp = self.segment_min_timestamp.min(min);
+			self.segment_max_timestamp = self.segment_max_timestamp.max(self.current_timestamp);
+		}
 		self.last_segment_key = self.last_key.clone();
 	}
 
+	/// Fail if the reader wouldn't accept `format`, which was already
+	/// checked if it's the format of the previous record
+	fn check_format(&self, format: &str) -> std::result::Result<(), WriteFailure> {
+		let checked = !self.current_key_data.is_empty() && format == self.last_format;
+		if !checked && !crate::row_format::is_valid_format(format) {
+			return Err(WriteFailure::InvalidFormat(format.to_owned()));
+		}
+		Ok(())
+	}
+
 	pub(crate) fn add_record_base(
 		&mut self,
 		key: &str,
@@ -166,6 +309,7 @@ impl<W: Write + Send> Writer<W> {
 		format: &str,
 		serialize_values: impl FnOnce(&mut Vec<u8>),
 	) -> std::result::Result<(), WriteFailure> {
+		self.check_format(format)?;
 		if self.current_key_data.is_empty() {
 			// this is the first key ever seen
 			self.new_key_begin(key, format);
@@ -210,6 +354,7 @@ impl<W: Write + Send> Writer<W> {
 		}
 
 		self.current_timestamp = timestamp;
+		self.current_key_min_timestamp.get_or_insert(timestamp);
 		serialize_values(&mut self.current_key_data);
 
 		Ok(())
@@ -258,6 +403,7 @@ impl<W: Write + Send> Writer<W> {
 		format: &str,
 		data: &[u8],
 	) -> std::result::Result<(), WriteFailure> {
+		self.check_format(format)?;
 		let timestamp = BigEndian::read_u64(&data[0..8]);
 		let constant_size =
 			crate::row_format::row_format_size(format).map(|m| m + crate::TIMESTAMP_SIZE);
@@ -282,8 +428,13 @@ impl<W: Write + Send> Writer<W> {
 	/// send the current segment to a worker thread to get written
 	pub fn store_current_segment(&mut self) -> std::io::Result<()> {
 		let header = Header {
-			first_key: self.first_segment_key.as_bytes().to_owned(),
-			last_key: self.last_segment_key.as_bytes().to_owned(),
+			first_key: self.first_segment_key.clone(),
+			last_key: self.last_segment_key.clone(),
+			min_timestamp: std::mem::replace(
+				&mut self.segment_min_timestamp,
+				crate::Timestamp::MAX,
+			),
+			max_timestamp: std::mem::replace(&mut self.segment_max_timestamp, 0),
 		};
 
 		let payload = std::mem::replace(
@@ -320,6 +471,11 @@ impl<W: Write + Send> Writer<W> {
 	}
 
 	fn fin(&mut self) -> std::io::Result<()> {
+		if self.worker_threads.is_none() {
+			// already finished
+			return Ok(());
+		}
+
 		// only have a key span segments if it's REALLY necessary
 		if !self.current_key_data.is_empty() {
 			self.flush_current_key();
@@ -334,6 +490,14 @@ impl<W: Write + Send> Writer<W> {
 		for th in self.thread_handles.drain(..) {
 			th.join().expect("thread can't be joined")?;
 		}
+
+		// an empty file stays empty
+		let mut wl = self.writer_state.as_ref().unwrap().lock();
+		if !wl.index.is_empty() {
+			let keys = crate::segment_index::BloomFilter::new(&self.key_hashes);
+			let index = crate::segment_index::encode(&wl.index, &keys);
+			wl.writer.write_all(&index)?;
+		}
 		Ok(())
 	}
 }
@@ -348,7 +512,7 @@ fn worker_thread<W: Write + Send>(
 	recv: channel::Receiver<WorkerMessage>,
 	writer_state: &Mutex<WriterState<W>>,
 	writer_notifier: &Condvar,
-	disable_compression: bool,
+	codec: Codec,
 ) -> std::io::Result<()> {
 	for message in recv {
 		let WorkerMessage {
@@ -357,14 +521,20 @@ fn worker_thread<W: Write + Send>(
 			payload,
 		} = message;
 
-		let compressed = if disable_compression {
-			payload
-		} else {
-			let mut encoder = lz4::EncoderBuilder::new().level(9).build(vec![]).unwrap();
-			encoder.write_all(&payload)?;
-			let (c, e) = encoder.finish();
-			e?;
-			c
+		let mut encoded = Vec::with_capacity(payload.len());
+		crate::payload_encoding::encode(&payload, &mut encoded);
+		drop(payload);
+
+		let compressed = match codec {
+			Codec::None => encoded,
+			Codec::Lz4 => {
+				let mut encoder = lz4::EncoderBuilder::new().level(9).build(vec![]).unwrap();
+				encoder.write_all(&encoded)?;
+				let (c, e) = encoder.finish();
+				e?;
+				c
+			}
+			Codec::Zstd(level) => zstd::stream::encode_all(&encoded[..], level)?,
 		};
 
 		let mut segmented: smallvec::SmallVec<[_; 4]> = smallvec::smallvec![];
@@ -378,6 +548,15 @@ fn worker_thread<W: Write + Send>(
 			segmented.push(&compressed[start..]);
 		}
 
+		// the checksum and length are of the payload as stored, with escapes
+		let mut hasher = crc32fast::Hasher::new();
+		let mut stored_len = 0;
+		for segment in &segmented {
+			hasher.update(segment);
+			stored_len += segment.len();
+		}
+		let checksum = hasher.finalize();
+
 		let mut wl = writer_state.lock();
 		while counter != wl.counter {
 			wl = writer_notifier.wait(wl);
@@ -388,6 +567,11 @@ fn worker_thread<W: Write + Send>(
 			let o = unsigned_varint::encode::u32(data, &mut buf);
 			vec.write_all(o)
 		}
+		fn wv64(vec: &mut impl Write, data: u64) -> std::io::Result<()> {
+			let mut buf = unsigned_varint::encode::u64_buffer();
+			let o = unsigned_varint::encode::u64(data, &mut buf);
+			vec.write_all(o)
+		}
 
 		let this_key_prev;
 		if wl.last_key == header.first_key {
@@ -403,18 +587,22 @@ fn worker_thread<W: Write + Send>(
 			let mut bc = WriteCounter::new(&mut wl.writer);
 
 			bc.write_all(crate::segment::SEGMENT_INVOCATION)?;
-			bc.write_u16::<BigEndian>(0x0100)?;
+			bc.write_u16::<BigEndian>(0x0500)?;
 
 			let ee = |e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e);
 
 			wv(&mut bc, header.first_key.len().try_into().map_err(ee)?)?;
 			wv(&mut bc, header.last_key.len().try_into().map_err(ee)?)?;
-			wv(&mut bc, compressed.len().try_into().map_err(ee)?)?;
+			wv(&mut bc, stored_len.try_into().map_err(ee)?)?;
 			wv(&mut bc, ps)?;
 			wv(&mut bc, this_key_prev)?;
+			wv64(&mut bc, header.min_timestamp)?;
+			wv64(&mut bc, header.max_timestamp)?;
+			bc.write_u8(codec.id())?;
+			bc.write_u32::<BigEndian>(checksum)?;
 
-			bc.write_all(&header.first_key)?;
-			bc.write_all(&header.last_key)?;
+			bc.write_all(header.first_key.as_bytes())?;
+			bc.write_all(header.last_key.as_bytes())?;
 
 			for segment in segmented {
 				bc.write_all(segment)
@@ -427,6 +615,13 @@ fn worker_thread<W: Write + Send>(
 		} else {
 			wl.stored_size_last_key = wrote_size;
 		}
+		let offset = wl.written;
+		wl.written += wrote_size as usize;
+		wl.index.push(IndexEntry {
+			first_key: header.first_key,
+			last_key: header.last_key.clone(),
+			offset,
+		});
 		wl.last_key = header.last_key;
 		wl.counter = counter + 1;
 		wl.prev_size = wrote_size;
@@ -465,12 +660,17 @@ fn near_boundary() {
 	// when a segment is about to overflow, no portion of the overflowing key should appear in it
 	// (all of it should go in the successive segment)
 	let q = "qqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqqq";
-	let mut w = Writer::new_internal(vec![], true);
-	w.current_key_data = vec![0x42u8; SEGMENT_SIZE_GOAL - 40];
-	w.first_segment_key = "a".to_string();
-	w.last_segment_key = "a".to_string();
+	let mut w = Writer::with_codec(vec![], Codec::None);
+	let mut n = 0u64;
+	while w.current_key_data.len() < SEGMENT_SIZE_GOAL - 40 {
+		w.add_record_raw("a", "U", &[n.to_be_bytes(), n.to_be_bytes()].concat())
+			.unwrap();
+		n += 1;
+	}
 	w.add_record_raw(q, "f", b"012345671234").unwrap();
 	w.add_record_raw("r", "f", b"012345671234").unwrap();
 	let v = w.finish().unwrap();
-	assert_eq!(memchr::memmem::find_iter(&v, q).count(), 2);
+	// the index at the end names q again
+	let (segments_len, _) = crate::segment_index::decode(&v).unwrap();
+	assert_eq!(memchr::memmem::find_iter(&v[..segments_len], q).count(), 2);
 }
@@ -0,0 +1,454 @@
+//! Buffer small writes in memory and add them to the database as one transaction.
+//!
+//! Each batch of records is appended to a journal file in the database's
+//! directory before it's buffered, so that buffered records survive a crash.
+//! The journal is a sequence of frames, each a big-endian `u32` length and
+//! CRC-32 followed by when the batch was buffered (a `u64` of nanoseconds
+//! since the epoch) and its records. A frame that was only partially written
+//! is discarded when the journal is replayed.
+//!
+//! A delete marker deletes the buffered records that were buffered before
+//! it was committed, which is known from the name of its transaction file.
+
+use crate::database_reader::DeleteMarkerPrecomputed;
+use crate::external_sort::SortRow;
+use crate::{CreateTx, DatabaseReader, Record, Timestamp, WriteFailure};
+use antidote::Mutex;
+use byteorder::{BigEndian, ByteOrder};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// The name of the journal file in the database's directory
+pub const WRITE_BUFFER_JOURNAL: &str = "write-buffer.journal";
+
+const FRAME_HEADER_SIZE: usize = 8;
+const BUFFERED_AT_SIZE: usize = 8;
+
+/// the buffered records, by key and then timestamp
+pub(crate) type BufferedMap = BTreeMap<String, BTreeMap<Timestamp, Buffered>>;
+
+/// A buffered record and when it was buffered (in nanoseconds since the epoch),
+/// which is cloned when a reader holds the records that are being changed
+pub(crate) struct Buffered(Record, Timestamp);
+
+impl Clone for Buffered {
+	fn clone(&self) -> Buffered {
+		Buffered(share(&self.0), self.1)
+	}
+}
+
+/// Nanoseconds since the epoch, like the names of transaction files
+fn now() -> std::io::Result<Timestamp> {
+	std::time::SystemTime::now()
+		.duration_since(std::time::SystemTime::UNIX_EPOCH)
+		.map_err(std::io::Error::other)?
+		.as_nanos()
+		.try_into()
+		.map_err(std::io::Error::other)
+}
+
+/// Buffers records in memory and adds them to the database as one transaction.
+///
+/// Add records with a [`WriteBatch`] from [`WriteBuffer::batch`]. They're
+/// flushed to a new transaction file once the buffer uses more than the flush size,
+/// or when a batch is committed or [`WriteBuffer::flush_if_due`] is called after
+/// the flush interval. Readers from [`WriteBuffer::reader`] see the buffered records.
+///
+/// Only one `WriteBuffer` can be open for a database at a time. Records
+/// that weren't flushed are read back from the journal when it's opened again.
+///
+/// ```no_run
+/// let buffer = sonnerie::WriteBuffer::open(std::path::Path::new("db")).unwrap();
+/// let mut batch = buffer.batch();
+/// batch.add_record(
+///    "key name",
+///    "2010-01-01T00:00:01".parse().unwrap(),
+///    sonnerie::record(1u32),
+///  ).unwrap();
+/// batch.commit().unwrap();
+/// let db = buffer.reader().unwrap();
+/// assert_eq!(db.get("key name").into_iter().count(), 1);
+/// ```
+pub struct WriteBuffer {
+	dir: PathBuf,
+	schema: crate::Schema,
+	flush_size: usize,
+	flush_interval: Duration,
+	buffer: Mutex<Buffer>,
+}
+
+struct Buffer {
+	journal: File,
+	records: Arc<BufferedMap>,
+	/// the memory that `records` uses, roughly
+	size: usize,
+	/// when the oldest record that hasn't been flushed was added
+	since: Option<Instant>,
+}
+
+impl Buffer {
+	fn insert(&mut self, row: SortRow, buffered_at: Timestamp) {
+		let mut data = row.key.into_bytes();
+		let key_len = data.len();
+		data.extend_from_slice(row.format.as_bytes());
+		data.extend_from_slice(&row.data);
+		self.size += std::mem::size_of::<Record>() + data.len();
+
+		let record = Record {
+			key_pos: 0,
+			key_len,
+			fmt_pos: key_len,
+			fmt_len: row.format.len(),
+			value_pos: key_len + row.format.len(),
+			value_len: row.data.len(),
+			data: Arc::new(data),
+		};
+		Arc::make_mut(&mut self.records)
+			.entry(record.key().to_owned())
+			.or_default()
+			.insert(record.timestamp_nanos(), Buffered(record, buffered_at));
+	}
+}
+
+impl WriteBuffer {
+	/// Open the write buffer of the database in `dir`, adding the records in its journal.
+	pub fn open(dir: &Path) -> std::io::Result<WriteBuffer> {
+		use fs2::FileExt;
+
+		let schema = crate::Schema::load(dir)?;
+		let journal = std::fs::OpenOptions::new()
+			.read(true)
+			.append(true)
+			.create(true)
+			.open(dir.join(WRITE_BUFFER_JOURNAL))?;
+		journal.try_lock_exclusive().map_err(|e| {
+			std::io::Error::new(
+				e.kind(),
+				format!("the write buffer is open in another process: {}", e),
+			)
+		})?;
+
+		let mut buffer = Buffer {
+			journal,
+			records: Default::default(),
+			size: 0,
+			since: None,
+		};
+
+		let mut frames = vec![];
+		buffer.journal.read_to_end(&mut frames)?;
+		let mut replayed = 0;
+		while let Some((len, buffered_at, mut rows)) = read_frame(&frames[replayed..]) {
+			while let Some(row) = SortRow::read(&mut rows)? {
+				buffer.insert(row, buffered_at);
+			}
+			replayed += len;
+		}
+		if replayed != frames.len() {
+			// the rest was only partially written
+			buffer.journal.set_len(replayed as u64)?;
+			buffer.journal.sync_data()?;
+		}
+		if !buffer.records.is_empty() {
+			buffer.since = Some(Instant::now());
+		}
+
+		Ok(WriteBuffer {
+			dir: dir.to_owned(),
+			schema,
+			flush_size: 64 * 1024 * 1024,
+			flush_interval: Duration::from_secs(10),
+			buffer: Mutex::new(buffer),
+		})
+	}
+
+	/// Flush once the buffered records use about this many bytes
+	/// of memory. The default is 64 MiB.
+	pub fn set_flush_size(&mut self, bytes: usize) {
+		self.flush_size = bytes;
+	}
+
+	/// Flush records once they've been buffered for this long.
+	/// The default is 10 seconds.
+	pub fn set_flush_interval(&mut self, interval: Duration) {
+		self.flush_interval = interval;
+	}
+
+	/// Start adding a batch of records, in any order
+	pub fn batch(&self) -> WriteBatch<'_> {
+		WriteBatch {
+			buffer: self,
+			rows: vec![],
+		}
+	}
+
+	/// Open the database with the records that are buffered now
+	///
+	/// Buffered records replace records in the database
+	/// with the same key and timestamp.
+	pub fn reader(&self) -> std::io::Result<DatabaseReader> {
+		// a flush can't happen between opening the files and taking the records
+		let buffer = self.buffer.lock();
+		let mut db = DatabaseReader::new(&self.dir)?;
+		db.buffered = Some(buffer.records.clone());
+		Ok(db)
+	}
+
+	/// The number of records that are buffered
+	pub fn len(&self) -> usize {
+		let buffer = self.buffer.lock();
+		buffer.records.values().map(|series| series.len()).sum()
+	}
+
+	/// True if no records are buffered
+	pub fn is_empty(&self) -> bool {
+		self.buffer.lock().records.is_empty()
+	}
+
+	/// Add the buffered records to the database as a new transaction, and empty the journal
+	pub fn flush(&self) -> Result<(), WriteFailure> {
+		let mut buffer = self.buffer.lock();
+		self.flush_locked(&mut buffer)
+	}
+
+	/// Flush if the buffer is full or its oldest record is older than the flush interval.
+	///
+	/// Returns true if it flushed.
+	pub fn flush_if_due(&self) -> Result<bool, WriteFailure> {
+		let mut buffer = self.buffer.lock();
+		if !self.is_due(&buffer) {
+			return Ok(false);
+		}
+		self.flush_locked(&mut buffer)?;
+		Ok(true)
+	}
+
+	fn is_due(&self, buffer: &Buffer) -> bool {
+		buffer.size >= self.flush_size
+			|| buffer
+				.since
+				.is_some_and(|since| since.elapsed() >= self.flush_interval)
+	}
+
+	fn flush_locked(&self, buffer: &mut Buffer) -> Result<(), WriteFailure> {
+		if buffer.records.is_empty() {
+			return Ok(());
+		}
+		// leave out the records that were deleted after they were buffered,
+		// the transaction is newer than the delete markers
+		let db = DatabaseReader::new(&self.dir)?;
+		let deletes = db.filter_out_by_time(&(Bound::Unbounded, Bound::Unbounded));
+		let mut tx = CreateTx::new(&self.dir)?;
+		for series in buffer.records.values() {
+			for buffered in series.values() {
+				if !is_deleted(&deletes, buffered) {
+					let record = &buffered.0;
+					tx.add_record_raw(record.key(), record.format(), record.raw())?;
+				}
+			}
+		}
+		tx.commit()?;
+		// if this fails, the records are added again from the journal,
+		// which is harmless because they replace themselves
+		buffer.journal.set_len(0)?;
+		buffer.journal.sync_data()?;
+
+		buffer.records = Default::default();
+		buffer.size = 0;
+		buffer.since = None;
+		Ok(())
+	}
+}
+
+/// The length of the first frame of `journal`, when it was buffered and
+/// its records, if it was completely written
+fn read_frame(journal: &[u8]) -> Option<(usize, Timestamp, &[u8])> {
+	let header = journal.get(0..FRAME_HEADER_SIZE)?;
+	let len = BigEndian::read_u32(&header[0..4]) as usize;
+	let crc = BigEndian::read_u32(&header[4..8]);
+	let body = journal.get(FRAME_HEADER_SIZE..FRAME_HEADER_SIZE + len)?;
+	if crc32fast::hash(body) != crc || body.len() < BUFFERED_AT_SIZE {
+		return None;
+	}
+	let (buffered_at, rows) = body.split_at(BUFFERED_AT_SIZE);
+	Some((
+		FRAME_HEADER_SIZE + len,
+		BigEndian::read_u64(buffered_at),
+		rows,
+	))
+}
+
+/// True if one of `deletes` was committed after `buffered` was buffered and deletes it
+fn is_deleted(deletes: &[(Timestamp, DeleteMarkerPrecomputed<'_>)], buffered: &Buffered) -> bool {
+	let Buffered(record, buffered_at) = buffered;
+	deletes
+		.iter()
+		.any(|(committed, marker)| committed > buffered_at && marker.deletes(record))
+}
+
+/// Records to add to a [`WriteBuffer`] together.
+///
+/// The records are buffered when the batch is committed, and are
+/// lost if it isn't. Of the records with the same key and timestamp,
+/// the one that was added last is kept.
+pub struct WriteBatch<'b> {
+	buffer: &'b WriteBuffer,
+	rows: Vec<SortRow>,
+}
+
+impl<'b> WriteBatch<'b> {
+	/// Add a record like [`CreateTx::add_record`], but in any order
+	pub fn add_record(
+		&mut self,
+		key: &str,
+		timestamp: chrono::NaiveDateTime,
+		values: impl crate::RecordBuilder,
+	) -> Result<(), WriteFailure> {
+		let mut format = compact_str::CompactString::default();
+		values.format_str(&mut format);
+		let timestamp = timestamp
+			.and_utc()
+			.timestamp_nanos_opt()
+			.ok_or(WriteFailure::UnableToParseTimestamp)? as Timestamp;
+		let mut data = timestamp.to_be_bytes().to_vec();
+		values.store(&mut data);
+		self.add_record_raw(key, &format, &data)
+	}
+
+	/// Add a record like [`CreateTx::add_record_raw`], but in any order
+	pub fn add_record_raw(
+		&mut self,
+		key: &str,
+		format: &str,
+		data: &[u8],
+	) -> Result<(), WriteFailure> {
+		// the records must be valid now, they can't be rejected when they're flushed
+		crate::write::check_record_length(format, data)?;
+		self.buffer.schema.check(key, format)?;
+		self.rows.push(SortRow {
+			key: key.to_owned(),
+			format: format.to_owned(),
+			data: data.to_owned(),
+		});
+		Ok(())
+	}
+
+	/// Write the records to the journal and buffer them,
+	/// flushing the buffer if it's full or old enough
+	pub fn commit(self) -> Result<(), WriteFailure> {
+		if self.rows.is_empty() {
+			return Ok(());
+		}
+		let buffered_at = now()?;
+		let mut frame = vec![0; FRAME_HEADER_SIZE];
+		frame.extend_from_slice(&buffered_at.to_be_bytes());
+		for row in &self.rows {
+			row.write(&mut frame)?;
+		}
+		let len = frame.len() - FRAME_HEADER_SIZE;
+		let crc = crc32fast::hash(&frame[FRAME_HEADER_SIZE..]);
+		BigEndian::write_u32(&mut frame[0..4], len as u32);
+		BigEndian::write_u32(&mut frame[4..8], crc);
+
+		let mut buffer = self.buffer.buffer.lock();
+		buffer.journal.write_all(&frame)?;
+		buffer.journal.sync_data()?;
+		for row in self.rows {
+			buffer.insert(row, buffered_at);
+		}
+		buffer.since.get_or_insert_with(Instant::now);
+		if self.buffer.is_due(&buffer) {
+			self.buffer.flush_locked(&mut buffer)?;
+		}
+		Ok(())
+	}
+}
+
+impl crate::RecordSink for WriteBatch<'_> {
+	fn add_record_raw(&mut self, key: &str, format: &str, data: &[u8]) -> Result<(), WriteFailure> {
+		WriteBatch::add_record_raw(self, key, format, data)
+	}
+}
+
+/// The buffered records of a [`DatabaseReader`] that are selected by a query
+pub(crate) struct BufferedRecords<'d> {
+	keys: std::collections::btree_map::Range<'d, String, BTreeMap<Timestamp, Buffered>>,
+	series: Option<std::collections::btree_map::Values<'d, Timestamp, Buffered>>,
+	matcher: Option<regex::Regex>,
+	prefix: &'d str,
+	timestamps: crate::TimestampRange,
+	deletes: Vec<(Timestamp, DeleteMarkerPrecomputed<'d>)>,
+}
+
+impl<'d> BufferedRecords<'d> {
+	pub(crate) fn new(
+		db: &'d DatabaseReader,
+		records: &'d BufferedMap,
+		matcher: Option<regex::Regex>,
+		prefix: &'d str,
+		range: &crate::CowStringRange<'_>,
+		timestamps: crate::TimestampRange,
+	) -> BufferedRecords<'d> {
+		let bounds: (Bound<&str>, Bound<&str>) = (
+			range.begin.as_ref().map(|k| &k[..]),
+			range.end.as_ref().map(|k| &k[..]),
+		);
+		BufferedRecords {
+			keys: records.range::<str, _>(bounds),
+			series: None,
+			matcher,
+			prefix,
+			timestamps,
+			deletes: db.filter_out_by_time(&timestamps),
+		}
+	}
+}
+
+impl<'d> Iterator for BufferedRecords<'d> {
+	type Item = Record;
+
+	fn next(&mut self) -> Option<Record> {
+		use std::ops::RangeBounds;
+
+		loop {
+			if let Some(series) = &mut self.series {
+				let timestamps = self.timestamps;
+				for buffered in series.by_ref() {
+					let record = &buffered.0;
+					if timestamps.contains(&record.timestamp_nanos())
+						&& !is_deleted(&self.deletes, buffered)
+					{
+						return Some(share(record));
+					}
+				}
+			}
+			let (key, series) = self.keys.next()?;
+			// the keys start at the prefix, so the rest don't have it either
+			if !key.starts_with(self.prefix) {
+				return None;
+			}
+			self.series = match &self.matcher {
+				Some(m) if !m.is_match(key) => None,
+				_ => Some(series.values()),
+			};
+		}
+	}
+}
+
+/// another `Record` with the same data
+fn share(record: &Record) -> Record {
+	Record {
+		key_pos: record.key_pos,
+		key_len: record.key_len,
+		fmt_pos: record.fmt_pos,
+		fmt_len: record.fmt_len,
+		value_pos: record.value_pos,
+		value_len: record.value_len,
+		data: record.data.clone(),
+	}
+}